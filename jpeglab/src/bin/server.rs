@@ -0,0 +1,91 @@
+//! jpeglab 的 HTTP 服务，便于从其他语言调用编解码器或做压力测试。接口见 `jpeglab::service`。
+
+use std::io;
+use std::io::Read;
+use std::sync::Arc;
+
+use clap::Parser;
+use tiny_http::Header;
+use tiny_http::Request;
+use tiny_http::Server;
+
+use jpeglab::service::Response;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(long, default_value = "127.0.0.1:8080", help = "Address to listen on")]
+    addr: String,
+
+    #[arg(
+        long,
+        help = "Number of worker threads",
+        long_help = "Number of worker threads, each handling one request at a time. Defaults to the number of CPUs."
+    )]
+    threads: Option<usize>,
+
+    #[arg(
+        long,
+        default_value_t = 64 << 20,
+        help = "Maximum request body size in bytes",
+        long_help = "Maximum request body size in bytes. Larger requests are answered with 413."
+    )]
+    max_body_size: u64,
+}
+
+fn respond(mut request: Request, max_body_size: u64) -> io::Result<()> {
+    let mut body = vec![];
+    request
+        .as_reader()
+        .take(max_body_size + 1)
+        .read_to_end(&mut body)?;
+    let response = if body.len() as u64 > max_body_size {
+        Response::error(413, format!("Body exceeds {} bytes", max_body_size))
+    } else {
+        jpeglab::service::handle(&request.method().to_string(), request.url(), &body)
+    };
+    println!(
+        "[INFO] {} {} -> {}，{} 字节",
+        request.method(),
+        request.url(),
+        response.status,
+        response.body.len()
+    );
+    let header = Header::from_bytes("Content-Type", response.content_type)
+        .map_err(|_| io::Error::other("Invalid header"))?;
+    request.respond(
+        tiny_http::Response::from_data(response.body)
+            .with_status_code(response.status)
+            .with_header(header),
+    )
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let server = Arc::new(Server::http(&args.addr).map_err(io::Error::other)?);
+    let threads = match args.threads {
+        Some(threads) => threads,
+        None => std::thread::available_parallelism()?.get(),
+    };
+    println!("[INFO] 在 {} 上监听，{} 个线程", args.addr, threads);
+
+    let max_body_size = args.max_body_size;
+    let workers: Vec<_> = (0..threads)
+        .map(|_| {
+            let server = server.clone();
+            std::thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    if let Err(e) = respond(request, max_body_size) {
+                        println!("[WARN] 响应失败：{}", e);
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker
+            .join()
+            .map_err(|_| io::Error::other("Worker panicked"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,580 @@
+//! 对 JPEG 文件的系数做统计，用于分析量化表和霍夫曼表是否适合一幅图像。只做熵解码，不计算 IDCT。
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use image::GrayImage;
+use image::RgbImage;
+
+use super::decode_step1::decode_step1;
+use super::decode_step2::DecodeHuffmanTable;
+use super::decode_step2::ScanDecoder;
+use super::encode_step1::Subsampling;
+use super::encode_step5::ZigzagDu;
+use super::encode_step6::get_category;
+use super::encode_step6::JpegOutputData;
+
+/// 各分量的名字，用于输出的文件名。
+pub const COMPONENT_NAMES: [&str; 3] = ["y", "cb", "cr"];
+
+/// 热力图中每个频率占的边长（像素）。
+const HEATMAP_CELL_SIZE: u32 = 32;
+
+/// 每个分量中 64 个频率位置上反量化后系数绝对值的平均值，按自然顺序。
+pub fn average_magnitudes(jpeg: &[u8]) -> io::Result<Vec<[[f64; 8]; 8]>> {
+    let jpeg_data = decode_step1(jpeg)?;
+    let components = &jpeg_data.components;
+    let mut sums = vec![[[0.0; 8]; 8]; components.len()];
+    let mut counts = vec![0_usize; components.len()];
+
+    let (mcus_per_row, mcu_rows) = jpeg_data.get_mcu_grid();
+    let mut decoder = ScanDecoder::new(&jpeg_data);
+    for _ in 0..mcus_per_row * mcu_rows {
+        decoder.decode_mcu(|i, _, zigzag_du| {
+            let table = &components[i].quatization_table.0;
+            let block = zigzag_du.to_quantized_du().0;
+            for (v, row) in block.iter().enumerate() {
+                for (u, &value) in row.iter().enumerate() {
+                    sums[i][v][u] += (value as f64 * table[v][u] as f64).abs();
+                }
+            }
+            counts[i] += 1;
+        })?;
+    }
+
+    Ok(sums
+        .into_iter()
+        .zip(counts)
+        .map(|(sum, count)| sum.map(|row| row.map(|value| value / count.max(1) as f64)))
+        .collect())
+}
+
+/// 一个分量的 DC 差分的统计。
+#[derive(Debug, Clone, Default)]
+pub struct DcStatistics {
+    /// 每个差分值出现的次数。
+    pub diffs: BTreeMap<i32, usize>,
+    /// 每个类别（差分的位数）出现的次数。基线 JPEG 的类别为 0 到 11。
+    pub categories: [usize; 12],
+    /// 文件中的 DC 霍夫曼表给每个类别的码长，没有该类别时为 `None`。
+    pub code_lengths: [Option<u8>; 12],
+}
+
+impl DcStatistics {
+    /// DU 的个数。
+    pub fn count(&self) -> usize {
+        self.categories.iter().sum()
+    }
+
+    pub fn mean(&self) -> f64 {
+        let sum: f64 = self.diffs.iter().map(|(&d, &n)| d as f64 * n as f64).sum();
+        sum / self.count().max(1) as f64
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        let mean = self.mean();
+        let sum: f64 = self
+            .diffs
+            .iter()
+            .map(|(&d, &n)| (d as f64 - mean).powi(2) * n as f64)
+            .sum();
+        (sum / self.count().max(1) as f64).sqrt()
+    }
+
+    /// 用文件中的霍夫曼表时，平均每个 DC 的位数，即码长加上类别本身的位数。
+    pub fn average_bits(&self) -> f64 {
+        let bits: usize = (0..12)
+            .map(|c| self.categories[c] * (self.code_lengths[c].unwrap_or(0) as usize + c))
+            .sum();
+        bits as f64 / self.count().max(1) as f64
+    }
+
+    /// 按类别分布的熵计算的平均位数，是为该图像专门生成的霍夫曼表能达到的下限。
+    pub fn ideal_bits(&self) -> f64 {
+        let count = self.count().max(1) as f64;
+        (0..12)
+            .filter(|&c| self.categories[c] != 0)
+            .map(|c| {
+                let p = self.categories[c] as f64 / count;
+                p * (c as f64 - p.log2())
+            })
+            .sum()
+    }
+}
+
+/// 每个分量的 DC 差分的统计。差分按扫描顺序计算，与熵编码时相同。
+pub fn dc_statistics(jpeg: &[u8]) -> io::Result<Vec<DcStatistics>> {
+    let jpeg_data = decode_step1(jpeg)?;
+    let components = &jpeg_data.components;
+    let mut ret: Vec<DcStatistics> = components
+        .iter()
+        .map(|component| DcStatistics {
+            code_lengths: std::array::from_fn(|c| component.dc_huffman_table.code_length(c as u8)),
+            ..Default::default()
+        })
+        .collect();
+    let mut preds = vec![0_i32; components.len()];
+
+    let (mcus_per_row, mcu_rows) = jpeg_data.get_mcu_grid();
+    let mut decoder = ScanDecoder::new(&jpeg_data);
+    for _ in 0..mcus_per_row * mcu_rows {
+        decoder.decode_mcu(|i, _, zigzag_du| {
+            let dc = zigzag_du.0[0] as i32;
+            let diff = dc - preds[i];
+            preds[i] = dc;
+            *ret[i].diffs.entry(diff).or_default() += 1;
+            let category = get_category(diff.unsigned_abs() as u16) as usize;
+            ret[i].categories[category.min(11)] += 1;
+        })?;
+    }
+    Ok(ret)
+}
+
+/// 差分的分布，每行为 `分量,差分,次数`，有表头。
+pub fn dc_diffs_csv(statistics: &[DcStatistics]) -> String {
+    let mut ret = "component,diff,count\n".to_string();
+    for (stats, name) in statistics.iter().zip(COMPONENT_NAMES) {
+        for (diff, count) in &stats.diffs {
+            ret += &format!("{},{},{}\n", name, diff, count);
+        }
+    }
+    ret
+}
+
+/// 类别的使用情况，每行为 `分量,类别,次数,码长`，有表头。表中没有的类别码长为空。
+pub fn dc_categories_csv(statistics: &[DcStatistics]) -> String {
+    let mut ret = "component,category,count,code_length\n".to_string();
+    for (stats, name) in statistics.iter().zip(COMPONENT_NAMES) {
+        for (category, (count, length)) in
+            stats.categories.iter().zip(stats.code_lengths).enumerate()
+        {
+            let length = length.map(|length| length.to_string()).unwrap_or_default();
+            ret += &format!("{},{},{},{}\n", name, category, count, length);
+        }
+    }
+    ret
+}
+
+/// 编码时每个 MCU 占用的位数，每行为 `MCU 的列,MCU 的行,Y,Cb,Cr,总位数`，有表头。
+/// `subsampling` 为编码时的子采样方式，决定 MCU 的大小。
+pub fn mcu_bits_csv(data: &JpegOutputData, subsampling: Subsampling) -> String {
+    let mcus_per_row = data.original_width.div_ceil(subsampling.mcu_size().0);
+    let mut ret = "mcu_x,mcu_y,y_bits,cb_bits,cr_bits,total_bits\n".to_string();
+    for (i, [y, cb, cr]) in data.summary.mcu_bits.iter().enumerate() {
+        ret += &format!(
+            "{},{},{},{},{},{}\n",
+            i % mcus_per_row,
+            i / mcus_per_row,
+            y,
+            cb,
+            cr,
+            y + cb + cr
+        );
+    }
+    ret
+}
+
+/// 与原图对齐的位密度图，每个 MCU 覆盖的像素的亮度与该 MCU 的总位数成正比，最多的 MCU 为白色。
+pub fn render_bit_density(data: &JpegOutputData, subsampling: Subsampling) -> GrayImage {
+    let (mcu_width, mcu_height) = subsampling.mcu_size();
+    let mcus_per_row = data.original_width.div_ceil(mcu_width);
+    let totals: Vec<u32> = data
+        .summary
+        .mcu_bits
+        .iter()
+        .map(|bits| bits.iter().sum())
+        .collect();
+    let max = totals.iter().copied().max().unwrap_or(0).max(1);
+    GrayImage::from_fn(
+        data.original_width as u32,
+        data.original_height as u32,
+        |x, y| {
+            let i = y as usize / mcu_height * mcus_per_row + x as usize / mcu_width;
+            image::Luma([(totals[i] as u64 * 255 / max as u64) as u8])
+        },
+    )
+}
+
+/// 把 `mcu_bits_csv` 和 `render_bit_density` 的结果写入 `dir` 中的 mcu_bits.csv 和 bit_density.png。
+pub fn save_bit_cost(
+    data: &JpegOutputData,
+    subsampling: Subsampling,
+    dir: &Path,
+) -> io::Result<()> {
+    std::fs::write(dir.join("mcu_bits.csv"), mcu_bits_csv(data, subsampling))?;
+    render_bit_density(data, subsampling)
+        .save_with_format(dir.join("bit_density.png"), image::ImageFormat::Png)
+        .map_err(io::Error::other)
+}
+
+/// 一个霍夫曼表编码的符号的统计。
+#[derive(Debug, Clone)]
+pub struct SymbolStatistics {
+    /// 使用该表的分量，例如 "cb+cr"。
+    pub name: String,
+    /// 每个符号出现的次数。
+    pub counts: [usize; 256],
+    /// 霍夫曼码字的总位数。
+    pub huffman_bits: usize,
+}
+
+impl SymbolStatistics {
+    /// 符号的个数。
+    pub fn count(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// 出现过的不同符号的个数。
+    pub fn distinct_symbols(&self) -> usize {
+        self.counts.iter().filter(|&&n| n != 0).count()
+    }
+
+    /// 按零阶熵计算的总位数，即每个符号独立时任何前缀码都不能更少。
+    pub fn entropy_bits(&self) -> f64 {
+        let count = self.count() as f64;
+        self.counts
+            .iter()
+            .filter(|&&n| n != 0)
+            .map(|&n| -(n as f64) * (n as f64 / count).log2())
+            .sum()
+    }
+
+    /// 平均码长（位/符号）。没有符号时为 0。
+    pub fn average_code_length(&self) -> f64 {
+        match self.count() {
+            0 => 0.0,
+            count => self.huffman_bits as f64 / count as f64,
+        }
+    }
+
+    /// 每个符号的熵（位/符号）。没有符号时为 0。
+    pub fn entropy_per_symbol(&self) -> f64 {
+        match self.count() {
+            0 => 0.0,
+            count => self.entropy_bits() / count as f64,
+        }
+    }
+
+    /// 冗余：平均码长比熵多出的位数（位/符号），即换成最适合这些符号的码表至多能省下的部分。
+    pub fn redundancy(&self) -> f64 {
+        self.average_code_length() - self.entropy_per_symbol()
+    }
+}
+
+/// 逐个霍夫曼表输出符号数、平均码长、熵和冗余，最后输出总计。
+pub fn print_huffman_statistics(report: &EntropyReport) {
+    for table in &report.tables {
+        let entropy = table.entropy_per_symbol();
+        println!(
+            "[INFO] {}：{} 个符号（{} 种），平均码长 {:.3} 位，熵 {:.3} 位，冗余 {:.3} 位（{:.1}%）",
+            table.name,
+            table.count(),
+            table.distinct_symbols(),
+            table.average_code_length(),
+            entropy,
+            table.redundancy(),
+            if entropy == 0.0 {
+                0.0
+            } else {
+                table.redundancy() / entropy * 100.0
+            }
+        );
+    }
+    println!("[INFO] 附加的值共 {} 位", report.value_bits);
+    println!(
+        "[INFO] 实际 {} 字节，按熵 {:.0} 字节，多出 {:.2}%",
+        report.actual_bits().div_ceil(8),
+        report.entropy_bits() / 8.0,
+        report.inefficiency() * 100.0
+    );
+}
+
+/// 输出优化的霍夫曼表相比默认的表，Y、Cb、Cr 的扫描数据各节省了多少。参数为各分量的位数。
+pub fn print_huffman_savings(default_bits: [usize; 3], optimized_bits: [usize; 3]) {
+    let total = |bits: [usize; 3]| bits.iter().sum::<usize>();
+    let names = COMPONENT_NAMES.iter().copied().chain(["total"]);
+    let default_bits = default_bits.into_iter().chain([total(default_bits)]);
+    let optimized_bits = optimized_bits.into_iter().chain([total(optimized_bits)]);
+    for ((name, default), optimized) in names.zip(default_bits).zip(optimized_bits) {
+        println!(
+            "[INFO] 优化的霍夫曼表，{}：默认 {} 位，优化后 {} 位，节省 {} 位（{:.2}%）",
+            name,
+            default,
+            optimized,
+            default as isize - optimized as isize,
+            if default == 0 {
+                0.0
+            } else {
+                (default as f64 - optimized as f64) / default as f64 * 100.0
+            }
+        );
+    }
+}
+
+/// 熵编码的符号流的熵与实际大小的比较。
+#[derive(Debug, Clone)]
+pub struct EntropyReport {
+    /// 每个 DC 表和 AC 表的统计，按分量的顺序，先 DC 后 AC。
+    pub tables: Vec<SymbolStatistics>,
+    /// 霍夫曼码字之后附加的值的总位数，与霍夫曼表无关。
+    pub value_bits: usize,
+}
+
+impl EntropyReport {
+    /// 实际的总位数，不含填充的 0x00 和最后一个字节的填充位。
+    pub fn actual_bits(&self) -> usize {
+        self.tables.iter().map(|t| t.huffman_bits).sum::<usize>() + self.value_bits
+    }
+
+    /// 霍夫曼码字按零阶熵计算时的总位数。
+    pub fn entropy_bits(&self) -> f64 {
+        self.tables.iter().map(|t| t.entropy_bits()).sum::<f64>() + self.value_bits as f64
+    }
+
+    /// 实际大小比熵多出的比例，表示最优的霍夫曼表至多还能节省多少。
+    pub fn inefficiency(&self) -> f64 {
+        let entropy = self.entropy_bits();
+        if entropy == 0.0 {
+            return 0.0;
+        }
+        self.actual_bits() as f64 / entropy - 1.0
+    }
+}
+
+/// 按第六步的规则，依次给出一个 DU 的 (是否 AC, 符号)，DC 的差分预测值为 `pred`。
+/// 返回所有附加的值的位数。
+fn for_each_symbol(du: &ZigzagDu, pred: i16, mut f: impl FnMut(bool, u8)) -> usize {
+    let diff = du.0[0].wrapping_sub(pred);
+    let category = get_category(diff.unsigned_abs());
+    f(false, category);
+    let mut value_bits = category as usize;
+
+    let mut zero_run_length = 0;
+    for &value in &du.0[1..] {
+        if value == 0 {
+            zero_run_length += 1;
+            continue;
+        }
+        // ZRL
+        while zero_run_length >= 16 {
+            f(true, 0xF0);
+            zero_run_length -= 16;
+        }
+        let category = get_category(value.unsigned_abs());
+        f(true, zero_run_length << 4 | category);
+        value_bits += category as usize;
+        zero_run_length = 0;
+    }
+    // EOB
+    if zero_run_length != 0 {
+        f(true, 0x00);
+    }
+    value_bits
+}
+
+/// 熵解码 JPEG 文件，重新得到第六步的符号流，统计每个霍夫曼表的符号。
+/// 共用同一个霍夫曼表的分量合并统计。
+pub fn entropy_report(jpeg: &[u8]) -> io::Result<EntropyReport> {
+    let jpeg_data = decode_step1(jpeg)?;
+    if jpeg_data.coefficients.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Entropy reports of files with more than one scan are not supported",
+        ));
+    }
+    let components = &jpeg_data.components;
+
+    // 每个分量的 DC 表和 AC 表在 `tables` 中的下标。
+    let mut tables: Vec<(Rc<DecodeHuffmanTable>, SymbolStatistics)> = vec![];
+    let mut index_of = |table: &Rc<DecodeHuffmanTable>, name: &str| match tables
+        .iter()
+        .position(|(t, _)| Rc::ptr_eq(t, table))
+    {
+        Some(i) => {
+            tables[i].1.name += &format!("+{}", name);
+            i
+        }
+        None => {
+            let stats = SymbolStatistics {
+                name: name.to_string(),
+                counts: [0; 256],
+                huffman_bits: 0,
+            };
+            tables.push((table.clone(), stats));
+            tables.len() - 1
+        }
+    };
+    let indices: Vec<[usize; 2]> = components
+        .iter()
+        .zip(COMPONENT_NAMES)
+        .map(|(c, name)| {
+            [
+                index_of(&c.dc_huffman_table, &format!("{} dc", name)),
+                index_of(&c.ac_huffman_table, &format!("{} ac", name)),
+            ]
+        })
+        .collect();
+
+    let mut preds = vec![0_i16; components.len()];
+    let mut value_bits = 0;
+    let (mcus_per_row, mcu_rows) = jpeg_data.get_mcu_grid();
+    let mut decoder = ScanDecoder::new(&jpeg_data);
+    for _ in 0..mcus_per_row * mcu_rows {
+        decoder.decode_mcu(|i, _, du| {
+            value_bits += for_each_symbol(du, preds[i], |is_ac, symbol| {
+                let (table, stats) = &mut tables[indices[i][is_ac as usize]];
+                stats.counts[symbol as usize] += 1;
+                stats.huffman_bits += table.code_length(symbol).unwrap_or(0) as usize;
+            });
+            preds[i] = du.0[0];
+        })?;
+    }
+
+    Ok(EntropyReport {
+        tables: tables.into_iter().map(|(_, stats)| stats).collect(),
+        value_bits,
+    })
+}
+
+/// 黑、红、黄、白的热力图配色，`t` 的范围是 0 到 1。
+pub(super) fn heat_color(t: f64) -> image::Rgb<u8> {
+    let channel = |start: f64| ((t * 3.0 - start).clamp(0.0, 1.0) * 255.0).round() as u8;
+    image::Rgb([channel(0.0), channel(1.0), channel(2.0)])
+}
+
+/// 把 8x8 的平均值画成热力图，左上角是 DC。
+/// DC 通常比高频大几个数量级，因此按 `ln(1 + x)` 归一化到最大值。
+pub fn render_heatmap(magnitudes: &[[f64; 8]; 8]) -> RgbImage {
+    let max = magnitudes
+        .as_flattened()
+        .iter()
+        .map(|&value| value.ln_1p())
+        .fold(0.0, f64::max);
+    RgbImage::from_fn(8 * HEATMAP_CELL_SIZE, 8 * HEATMAP_CELL_SIZE, |x, y| {
+        let value = magnitudes[(y / HEATMAP_CELL_SIZE) as usize][(x / HEATMAP_CELL_SIZE) as usize];
+        heat_color(if max > 0.0 { value.ln_1p() / max } else { 0.0 })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::encode_step7::make_jpeg;
+    use super::super::encode_to_vec;
+    use super::super::stripe::encode_striped;
+
+    #[test]
+    fn test_average_magnitudes() {
+        // 只有水平方向的变化，垂直方向的频率都是 0。
+        let image = RgbImage::from_fn(64, 32, |x, _| {
+            let value = if x % 8 < 4 { 40 } else { 200 };
+            image::Rgb([value, value, value])
+        });
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        let magnitudes = average_magnitudes(&jpeg).unwrap();
+        assert_eq!(magnitudes.len(), 3);
+
+        let y = &magnitudes[0];
+        assert!(y[0][1] > 100.0);
+        for row in &y[1..] {
+            assert!(row.iter().all(|&value| value == 0.0));
+        }
+        // 灰色图像的色度只有 DC。
+        assert!(magnitudes[1].as_flattened()[1..]
+            .iter()
+            .all(|&value| value == 0.0));
+
+        let heatmap = render_heatmap(y);
+        assert_eq!(heatmap.dimensions(), (256, 256));
+        assert_eq!(*heatmap.get_pixel(0, 255), image::Rgb([0, 0, 0]));
+        assert_ne!(*heatmap.get_pixel(32, 0), image::Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_mcu_bits() {
+        // 左边平坦，右边是噪声，右边的 MCU 应当占用更多的位。
+        let image = RgbImage::from_fn(40, 16, |x, y| {
+            let value = if x < 16 {
+                128
+            } else {
+                (x * 37 + y * 91) as u8 ^ 0x5A
+            };
+            image::Rgb([value, value, value])
+        });
+        // 按条带编码与整体编码的统计相同。
+        let data = encode_striped(&image, &Default::default()).unwrap();
+
+        let bits = &data.summary.mcu_bits;
+        assert_eq!(bits.len(), 3 * 2);
+        let total: u32 = bits.iter().flatten().sum();
+        assert_eq!(total as usize, data.scan.len());
+        assert!(bits[1][0] > bits[0][0]);
+
+        let csv = mcu_bits_csv(&data, Subsampling::Yuv422);
+        assert_eq!(csv.lines().count(), 1 + 6);
+        assert!(csv.lines().nth(4).unwrap().starts_with("0,1,"));
+
+        let density = render_bit_density(&data, Subsampling::Yuv422);
+        assert_eq!(density.dimensions(), (40, 16));
+        assert!(density.get_pixel(0, 0).0[0] < density.get_pixel(20, 0).0[0]);
+    }
+
+    #[test]
+    fn test_entropy_report() {
+        let image = RgbImage::from_fn(48, 24, |x, y| {
+            image::Rgb([(x * 5 + y * 3) as u8, (x * y % 97) as u8, (y * 9) as u8])
+        });
+        let data = encode_striped(&image, &Default::default()).unwrap();
+        let jpeg = make_jpeg(&data, &Default::default());
+        let report = entropy_report(&jpeg).unwrap();
+
+        // 重新得到的符号流与第六步编码的完全一致。
+        assert_eq!(report.actual_bits(), data.scan.len());
+        let names: Vec<&str> = report.tables.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["y dc", "y ac", "cb dc+cr dc", "cb ac+cr ac"]);
+        assert_eq!(report.tables[0].count(), 2 * 3 * 3);
+        assert!(report.entropy_bits() <= report.actual_bits() as f64);
+        assert!(report.inefficiency() > 0.0);
+        for table in &report.tables {
+            let bits = table.average_code_length() * table.count() as f64;
+            assert!((bits - table.huffman_bits as f64).abs() < 1e-6);
+            // 任何前缀码的平均码长都不小于熵。
+            assert!(table.redundancy() >= 0.0, "{}", table.name);
+            assert!(table.distinct_symbols() <= table.count());
+        }
+    }
+
+    #[test]
+    fn test_dc_statistics() {
+        // 每个 16x8 的 MCU 颜色不同，左右两个亮度块相同。
+        let image = RgbImage::from_fn(64, 16, |x, y| {
+            let value = (x / 16 * 40 + y / 8 * 20) as u8;
+            image::Rgb([value, value, value])
+        });
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        let statistics = dc_statistics(&jpeg).unwrap();
+        assert_eq!(statistics.len(), 3);
+
+        let y = &statistics[0];
+        assert_eq!(y.count(), 16);
+        // 每个 MCU 的第二个亮度块与第一个相同，差分为 0。
+        assert!(y.categories[0] >= 8);
+        assert_eq!(y.code_lengths[0], Some(2));
+        assert_eq!(y.code_lengths[11], Some(9));
+        assert!(y.ideal_bits() <= y.average_bits());
+
+        // 灰色图像的色度 DC 都是 0。
+        let cb = &statistics[1];
+        assert_eq!(cb.count(), 8);
+        assert_eq!(cb.categories[0], 8);
+        assert_eq!((cb.mean(), cb.std_dev()), (0.0, 0.0));
+
+        let csv = dc_categories_csv(&statistics);
+        assert_eq!(csv.lines().count(), 1 + 3 * 12);
+        assert!(csv.contains("\ny,0,"));
+        assert!(dc_diffs_csv(&statistics).starts_with("component,diff,count\n"));
+    }
+}
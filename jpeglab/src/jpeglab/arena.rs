@@ -0,0 +1,61 @@
+//! 复用 DU 缓冲区。
+
+use super::encode_step2::Du;
+use super::encode_step3::DctDu;
+use super::encode_step4::QuantizedDu;
+use super::encode_step5::ZigzagDu;
+
+/// 回收用过的缓冲区，之后取出时不必重新分配。
+#[derive(Debug)]
+pub struct BufferPool<T> {
+    free: Vec<Vec<T>>,
+}
+
+impl<T> Default for BufferPool<T> {
+    fn default() -> Self {
+        Self { free: vec![] }
+    }
+}
+
+impl<T> BufferPool<T> {
+    /// 取出一个空的缓冲区，容量至少为 `capacity`。
+    pub fn take(&mut self, capacity: usize) -> Vec<T> {
+        let mut buffer = self.free.pop().unwrap_or_default();
+        buffer.reserve(capacity);
+        buffer
+    }
+
+    /// 归还缓冲区。
+    pub fn recycle(&mut self, mut buffer: Vec<T>) {
+        buffer.clear();
+        self.free.push(buffer);
+    }
+}
+
+/// 各步使用的 DU 缓冲区。按条带处理时每个条带都经过相同的各步，
+/// 用完的缓冲区归还后由下一个条带复用，只有第一个条带需要分配内存。
+#[derive(Debug, Default)]
+pub struct ScratchArena {
+    pub du: BufferPool<Du>,
+    pub dct_du: BufferPool<DctDu>,
+    pub quantized_du: BufferPool<QuantizedDu>,
+    pub zigzag_du: BufferPool<ZigzagDu>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffer_reused() {
+        let mut pool = BufferPool::<ZigzagDu>::default();
+        let mut buffer = pool.take(16);
+        buffer.push(ZigzagDu([0; 64]));
+        let ptr = buffer.as_ptr();
+        pool.recycle(buffer);
+
+        let buffer = pool.take(16);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), ptr);
+    }
+}
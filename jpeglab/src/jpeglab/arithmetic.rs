@@ -0,0 +1,680 @@
+//! 算术编码，代替霍夫曼编码作为第六步的熵编码，写入 SOF9 和 DAC 段。
+//!
+//! 使用 JPEG 标准附录 D 的 QM 编码器和附录 F.1.4 的顺序编码模型，与 libjpeg 的 jcarith.c 相同。
+//! 每个符号都是一个二值判决，在所属的统计区（bin）中自适应地估计概率，因此不需要码表，
+//! 通常比默认的霍夫曼表小 5% 到 10%。条件参数使用默认值：DC 的 L = 0、U = 1，AC 的 Kx = 5。
+//! 很多解码器不支持算术编码，包括我们自己的解码器。
+
+use super::encode_step2::ComponentDus;
+use super::encode_step5::ZigzagDu;
+
+/// 表 D.3 的概率估计状态机：(Qe, Next_Index_LPS, Next_Index_MPS, Switch_MPS)。
+/// 最后一项不在表中，概率固定为 0.5，用于 AC 系数的符号，与 libjpeg 相同。
+#[rustfmt::skip]
+const QE_TABLE: [(u16, u8, u8, bool); 114] = [
+    (0x5A1D, 1, 1, true), (0x2586, 14, 2, false), (0x1114, 16, 3, false), (0x080B, 18, 4, false),
+    (0x03D8, 20, 5, false), (0x01DA, 23, 6, false), (0x00E5, 25, 7, false), (0x006F, 28, 8, false),
+    (0x0036, 30, 9, false), (0x001A, 33, 10, false), (0x000D, 35, 11, false), (0x0006, 9, 12, false),
+    (0x0003, 10, 13, false), (0x0001, 12, 13, false), (0x5A7F, 15, 15, true), (0x3F25, 36, 16, false),
+    (0x2CF2, 38, 17, false), (0x207C, 39, 18, false), (0x17B9, 40, 19, false), (0x1182, 42, 20, false),
+    (0x0CEF, 43, 21, false), (0x09A1, 45, 22, false), (0x072F, 46, 23, false), (0x055C, 48, 24, false),
+    (0x0406, 49, 25, false), (0x0303, 51, 26, false), (0x0240, 52, 27, false), (0x01B1, 54, 28, false),
+    (0x0144, 56, 29, false), (0x00F5, 57, 30, false), (0x00B7, 59, 31, false), (0x008A, 60, 32, false),
+    (0x0068, 62, 33, false), (0x004E, 63, 34, false), (0x003B, 32, 35, false), (0x002C, 33, 9, false),
+    (0x5AE1, 37, 37, true), (0x484C, 64, 38, false), (0x3A0D, 65, 39, false), (0x2EF1, 67, 40, false),
+    (0x261F, 68, 41, false), (0x1F33, 69, 42, false), (0x19A8, 70, 43, false), (0x1518, 72, 44, false),
+    (0x1177, 73, 45, false), (0x0E74, 74, 46, false), (0x0BFB, 75, 47, false), (0x09F8, 77, 48, false),
+    (0x0861, 78, 49, false), (0x0706, 79, 50, false), (0x05CD, 48, 51, false), (0x04DE, 50, 52, false),
+    (0x040F, 50, 53, false), (0x0363, 51, 54, false), (0x02D4, 52, 55, false), (0x025C, 53, 56, false),
+    (0x01F8, 54, 57, false), (0x01A4, 55, 58, false), (0x0160, 56, 59, false), (0x0125, 57, 60, false),
+    (0x00F6, 58, 61, false), (0x00CB, 59, 62, false), (0x00AB, 61, 63, false), (0x008F, 61, 32, false),
+    (0x5B12, 65, 65, true), (0x4D04, 80, 66, false), (0x412C, 81, 67, false), (0x37D8, 82, 68, false),
+    (0x2FE8, 83, 69, false), (0x293C, 84, 70, false), (0x2379, 86, 71, false), (0x1EDF, 87, 72, false),
+    (0x1AA9, 87, 73, false), (0x174E, 72, 74, false), (0x1424, 72, 75, false), (0x119C, 74, 76, false),
+    (0x0F6B, 74, 77, false), (0x0D51, 75, 78, false), (0x0BB6, 77, 79, false), (0x0A40, 77, 48, false),
+    (0x5832, 80, 81, true), (0x4D1C, 88, 82, false), (0x438E, 89, 83, false), (0x3BDD, 90, 84, false),
+    (0x34EE, 91, 85, false), (0x2EAE, 92, 86, false), (0x299A, 93, 87, false), (0x2516, 86, 71, false),
+    (0x5570, 88, 89, true), (0x4CA9, 95, 90, false), (0x44D9, 96, 91, false), (0x3E22, 97, 92, false),
+    (0x3824, 99, 93, false), (0x32B4, 99, 94, false), (0x2E17, 93, 86, false), (0x56A8, 95, 96, true),
+    (0x4F46, 101, 97, false), (0x47E5, 102, 98, false), (0x41CF, 103, 99, false), (0x3C3D, 104, 100, false),
+    (0x375E, 99, 93, false), (0x5231, 105, 102, false), (0x4C0F, 106, 103, false), (0x4639, 107, 104, false),
+    (0x415E, 103, 99, false), (0x5627, 105, 106, true), (0x50E7, 108, 107, false), (0x4B85, 109, 103, false),
+    (0x5597, 110, 109, false), (0x504F, 111, 107, false), (0x5A10, 110, 111, true), (0x5522, 112, 109, false),
+    (0x59EB, 112, 111, true), (0x5A1D, 113, 113, false),
+];
+
+/// 概率固定为 0.5 的统计区。
+const FIXED_BIN: u8 = 113;
+
+/// DC 的条件参数 L 和 U，AC 的条件参数 Kx。写入 DAC 段。
+pub const DC_CONDITIONING: (u8, u8) = (0, 1);
+pub const AC_CONDITIONING: u8 = 5;
+
+/// 统计区的状态：低 7 位为 `QE_TABLE` 的下标，最高位为 MPS（更可能出现的符号）。初始为 0。
+type Bin = u8;
+
+/// 在概率估计状态机中前进一步。
+fn next_state(bin: &mut Bin, is_mps: bool) {
+    let (_, next_lps, next_mps, switch_mps) = QE_TABLE[(*bin & 0x7F) as usize];
+    *bin = if is_mps {
+        (*bin & 0x80) | next_mps
+    } else {
+        ((*bin & 0x80) ^ if switch_mps { 0x80 } else { 0 }) | next_lps
+    };
+}
+
+/// QM 编码器（D.1）。输出的字节没有在 0xFF 之后填充 0x00，由第七步统一填充。
+struct QmEncoder {
+    /// C 寄存器，编码区间的下界，布局见 D.1.3。
+    c: u32,
+    /// A 寄存器，编码区间的大小。
+    a: u32,
+    /// 暂存的 0xFF 字节数，进位时变为 0x00。
+    sc: usize,
+    /// 暂存的 0x00 字节数，在末尾时可以丢弃。
+    zc: usize,
+    /// 还要移位多少次输出下一个字节。
+    ct: u32,
+    /// 最近一个不是 0xFF 的输出字节，进位时还可能加一。
+    buffer: Option<u8>,
+    out: Vec<u8>,
+}
+
+impl QmEncoder {
+    fn new() -> Self {
+        Self {
+            c: 0,
+            a: 0x10000,
+            sc: 0,
+            zc: 0,
+            ct: 11,
+            buffer: None,
+            out: vec![],
+        }
+    }
+
+    fn emit_zeros(&mut self) {
+        self.out.extend(std::iter::repeat_n(0, self.zc));
+        self.zc = 0;
+    }
+
+    /// 进位：`buffer` 加一，暂存的 0xFF 都变为 0x00。
+    fn carry(&mut self) {
+        if let Some(buffer) = self.buffer {
+            self.emit_zeros();
+            self.out.push(buffer + 1);
+        }
+        self.zc += self.sc;
+        self.sc = 0;
+    }
+
+    /// 不会再进位：输出 `buffer` 和暂存的 0xFF。
+    fn flush_buffer(&mut self) {
+        match self.buffer {
+            Some(0) => self.zc += 1,
+            Some(buffer) => {
+                self.emit_zeros();
+                self.out.push(buffer);
+            }
+            None => {}
+        }
+        if self.sc != 0 {
+            self.emit_zeros();
+            self.out.extend(std::iter::repeat_n(0xFF, self.sc));
+            self.sc = 0;
+        }
+    }
+
+    /// 在统计区 `bin` 中编码一个判决（D.1.4、D.1.5），之后重新归一化（D.1.6）。
+    fn encode(&mut self, bin: &mut Bin, value: bool) {
+        let qe = QE_TABLE[(*bin & 0x7F) as usize].0 as u32;
+        let is_mps = value == (*bin >> 7 != 0);
+        self.a -= qe;
+        if is_mps {
+            if self.a >= 0x8000 {
+                return;
+            }
+            // 条件交换：MPS 的区间更小时交换。
+            if self.a < qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+        } else if self.a >= qe {
+            self.c += self.a;
+            self.a = qe;
+        }
+        next_state(bin, is_mps);
+
+        while self.a < 0x8000 {
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+            if self.ct == 0 {
+                let byte = self.c >> 19;
+                if byte > 0xFF {
+                    self.carry();
+                    // C 中有 3 个间隔位，进位后新的字节不会是 0xFF。
+                    self.buffer = Some(byte as u8);
+                } else if byte == 0xFF {
+                    self.sc += 1;
+                } else {
+                    self.flush_buffer();
+                    self.buffer = Some(byte as u8);
+                }
+                self.c &= 0x7FFFF;
+                self.ct += 8;
+            }
+        }
+    }
+
+    /// 结束编码（D.1.8），输出剩下的字节。末尾的 0x00 不输出。之后可以重新开始编码。
+    fn finish(&mut self) -> Vec<u8> {
+        // 在编码区间中找末尾 0 最多的值。
+        let temp = (self.a - 1 + self.c) & 0xFFFF0000;
+        self.c = if temp < self.c { temp + 0x8000 } else { temp };
+        self.c <<= self.ct;
+        if self.c & 0xF8000000 != 0 {
+            self.carry();
+        } else {
+            self.flush_buffer();
+        }
+        if self.c & 0x7FFF800 != 0 {
+            self.emit_zeros();
+            self.out.push((self.c >> 19) as u8);
+            if self.c & 0x7F800 != 0 {
+                self.out.push((self.c >> 11) as u8);
+            }
+        }
+        std::mem::replace(self, Self::new()).out
+    }
+}
+
+/// 一个分量的统计区，按 F.1.4 的表 F.4 和 F.5 排列。
+#[derive(Clone)]
+struct ComponentBins {
+    dc: [Bin; 64],
+    ac: [Bin; 256],
+}
+
+impl Default for ComponentBins {
+    fn default() -> Self {
+        Self {
+            dc: [0; 64],
+            ac: [0; 256],
+        }
+    }
+}
+
+/// 算术编码的扫描数据。
+#[derive(Debug, Clone, Default)]
+pub struct ArithmeticScan {
+    /// 编码结果，没有在 0xFF 之后填充 0x00。
+    pub data: Vec<u8>,
+    /// 每个 RSTn 在 `data` 中的字节位置。
+    pub restarts: Vec<usize>,
+}
+
+/// 算术编码器，可以分多次输入 MCU。亮度和色度各有一组统计区，与霍夫曼编码时各有一组表相同。
+pub struct ArithmeticEncoder {
+    encoder: QmEncoder,
+    /// 亮度和色度的统计区。
+    bins: [ComponentBins; 2],
+    /// Y、Cb、Cr 的 DC 预测值。
+    dc_preds: [i16; 3],
+    /// Y、Cb、Cr 上一个 DC 差分的条件（F.1.4.4.1.2），为统计区 S0 在 `dc` 中的下标。
+    dc_contexts: [usize; 3],
+    fixed_bin: Bin,
+    restart_interval: Option<u16>,
+    mcu_count: usize,
+    scan: ArithmeticScan,
+}
+
+impl ArithmeticEncoder {
+    /// 每隔 `restart_interval` 个 MCU 插入一个 RSTn。为 `None` 或 0 时不插入。
+    pub fn new(restart_interval: Option<u16>) -> Self {
+        Self {
+            encoder: QmEncoder::new(),
+            bins: Default::default(),
+            dc_preds: [0; 3],
+            dc_contexts: [0; 3],
+            fixed_bin: FIXED_BIN,
+            restart_interval: restart_interval.filter(|&n| n != 0),
+            mcu_count: 0,
+            scan: ArithmeticScan::default(),
+        }
+    }
+
+    /// 按顺序编码一批 MCU。
+    pub fn encode_mcus(&mut self, dus: &ComponentDus<ZigzagDu>) {
+        for i in 0..dus.mcu_count() {
+            if let Some(interval) = self.restart_interval {
+                if self.mcu_count != 0 && self.mcu_count.is_multiple_of(interval as usize) {
+                    self.restart();
+                }
+            }
+            let (ys, cb, cr) = dus.mcu(i);
+            for y in ys {
+                self.encode_du(y, 0);
+            }
+            self.encode_du(cb, 1);
+            self.encode_du(cr, 2);
+            self.mcu_count += 1;
+        }
+    }
+
+    /// 重新同步：结束当前的编码，之后的统计区和 DC 预测值都重新开始。
+    fn restart(&mut self) {
+        let data = self.encoder.finish();
+        self.scan.data.extend(data);
+        self.scan.restarts.push(self.scan.data.len());
+        self.bins = Default::default();
+        self.dc_preds = [0; 3];
+        self.dc_contexts = [0; 3];
+    }
+
+    /// 编码一个 DU，`component` 为 0 到 2。
+    fn encode_du(&mut self, du: &ZigzagDu, component: usize) {
+        let encoder = &mut self.encoder;
+        let bins = &mut self.bins[(component != 0) as usize];
+
+        // DC（F.1.4.1）。
+        let s0 = self.dc_contexts[component];
+        let diff = du.0[0] - self.dc_preds[component];
+        self.dc_preds[component] = du.0[0];
+        if diff == 0 {
+            encoder.encode(&mut bins.dc[s0], false);
+            self.dc_contexts[component] = 0;
+        } else {
+            encoder.encode(&mut bins.dc[s0], true);
+            encoder.encode(&mut bins.dc[s0 + 1], diff < 0);
+            let sign_bin = if diff > 0 { s0 + 2 } else { s0 + 3 };
+            let m = encode_magnitude(encoder, &mut bins.dc, sign_bin, 20, diff);
+            // 差分的条件：0 为很小，4 和 8 为较小的正数和负数，12 和 16 为较大的正数和负数。
+            let (l, u) = DC_CONDITIONING;
+            self.dc_contexts[component] = if m < (1 << l) >> 1 {
+                0
+            } else if m > (1 << u) >> 1 {
+                if diff > 0 {
+                    12
+                } else {
+                    16
+                }
+            } else if diff > 0 {
+                4
+            } else {
+                8
+            };
+        }
+
+        // AC（F.1.4.2）。最后一个非零系数之后编码 EOB。
+        let end = du.0.iter().rposition(|&v| v != 0).unwrap_or(0);
+        let mut k = 1;
+        while k <= end {
+            let mut se = 3 * (k - 1);
+            encoder.encode(&mut bins.ac[se], false);
+            while du.0[k] == 0 {
+                encoder.encode(&mut bins.ac[se + 1], false);
+                se += 3;
+                k += 1;
+            }
+            encoder.encode(&mut bins.ac[se + 1], true);
+            encoder.encode(&mut self.fixed_bin, du.0[k] < 0);
+            let x2 = if k <= AC_CONDITIONING as usize {
+                189
+            } else {
+                217
+            };
+            encode_ac_magnitude(encoder, &mut bins.ac, se + 2, x2, du.0[k]);
+            k += 1;
+        }
+        if k <= 63 {
+            encoder.encode(&mut bins.ac[3 * (k - 1)], true);
+        }
+    }
+
+    /// 结束编码，返回扫描数据。
+    pub fn finish(mut self) -> ArithmeticScan {
+        let data = self.encoder.finish();
+        self.scan.data.extend(data);
+        self.scan
+    }
+}
+
+/// 编码非零 DC 差分的幅度（图 F.8、F.9），返回 2 的幂 m，满足 m <= |v| - 1 < 2m，|v| 为 1 时为 0。
+/// 第一个判决在 `first` 中，之后的类别判决从 `x1` 开始，每一位的值在类别判决的位置加 14 处。
+fn encode_magnitude(
+    encoder: &mut QmEncoder,
+    bins: &mut [Bin],
+    first: usize,
+    x1: usize,
+    value: i16,
+) -> u16 {
+    let v = value.unsigned_abs() - 1;
+    let mut st = first;
+    let mut m = 0;
+    if v != 0 {
+        encoder.encode(&mut bins[st], true);
+        m = 1;
+        st = x1;
+        let mut v2 = v >> 1;
+        while v2 != 0 {
+            encoder.encode(&mut bins[st], true);
+            m <<= 1;
+            st += 1;
+            v2 >>= 1;
+        }
+    }
+    encoder.encode(&mut bins[st], false);
+    encode_magnitude_bits(encoder, bins, st + 14, m, v);
+    m
+}
+
+/// 与 `encode_magnitude` 相同，但 AC 的第二个类别判决仍在 `first` 中，之后才从 `x2` 开始。
+fn encode_ac_magnitude(
+    encoder: &mut QmEncoder,
+    bins: &mut [Bin],
+    first: usize,
+    x2: usize,
+    value: i16,
+) {
+    let v = value.unsigned_abs() - 1;
+    let mut st = first;
+    let mut m = 0;
+    if v != 0 {
+        encoder.encode(&mut bins[st], true);
+        m = 1;
+        let mut v2 = v >> 1;
+        if v2 != 0 {
+            encoder.encode(&mut bins[st], true);
+            m <<= 1;
+            st = x2;
+            v2 >>= 1;
+            while v2 != 0 {
+                encoder.encode(&mut bins[st], true);
+                m <<= 1;
+                st += 1;
+                v2 >>= 1;
+            }
+        }
+    }
+    encoder.encode(&mut bins[st], false);
+    encode_magnitude_bits(encoder, bins, st + 14, m, v);
+}
+
+/// 从高到低编码 `v` 中低于 `m` 的各位（图 F.9），都在同一个统计区中。
+fn encode_magnitude_bits(encoder: &mut QmEncoder, bins: &mut [Bin], st: usize, m: u16, v: u16) {
+    let mut m = m >> 1;
+    while m != 0 {
+        encoder.encode(&mut bins[st], v & m != 0);
+        m >>= 1;
+    }
+}
+
+/// 算术编码所有 MCU。
+pub fn encode_arithmetic(
+    dus: &ComponentDus<ZigzagDu>,
+    restart_interval: Option<u16>,
+) -> ArithmeticScan {
+    let mut encoder = ArithmeticEncoder::new(restart_interval);
+    encoder.encode_mcus(dus);
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::arena::BufferPool;
+
+    /// QM 解码器（D.2），与 libjpeg 的 jdarith.c 相同。输入为没有填充的字节，结束后补 0。
+    struct QmDecoder<'a> {
+        c: u32,
+        a: u32,
+        ct: i32,
+        data: std::slice::Iter<'a, u8>,
+    }
+
+    impl<'a> QmDecoder<'a> {
+        fn new(data: &'a [u8]) -> Self {
+            Self {
+                c: 0,
+                a: 0,
+                ct: -16,
+                data: data.iter(),
+            }
+        }
+
+        fn decode(&mut self, bin: &mut Bin) -> bool {
+            while self.a < 0x8000 {
+                self.ct -= 1;
+                if self.ct < 0 {
+                    self.c = (self.c << 8) | *self.data.next().unwrap_or(&0) as u32;
+                    self.ct += 8;
+                    if self.ct < 0 {
+                        self.ct += 1;
+                        if self.ct == 0 {
+                            self.a = 0x8000;
+                        }
+                    }
+                }
+                self.a <<= 1;
+            }
+            let qe = QE_TABLE[(*bin & 0x7F) as usize].0 as u32;
+            let mut mps = *bin >> 7 != 0;
+            self.a -= qe;
+            let temp = self.a << self.ct;
+            if self.c >= temp {
+                self.c -= temp;
+                let is_mps = self.a < qe;
+                self.a = qe;
+                next_state(bin, is_mps);
+                mps ^= !is_mps;
+            } else if self.a < 0x8000 {
+                let is_mps = self.a >= qe;
+                next_state(bin, is_mps);
+                mps ^= !is_mps;
+            }
+            mps
+        }
+    }
+
+    /// 按 F.2.4 解码一个 DU，与 `ArithmeticEncoder::encode_du` 对应。
+    fn decode_du(
+        decoder: &mut QmDecoder,
+        bins: &mut ComponentBins,
+        fixed_bin: &mut Bin,
+        dc_context: &mut usize,
+        dc_pred: &mut i16,
+    ) -> ZigzagDu {
+        let decode_magnitude =
+            |decoder: &mut QmDecoder, bins: &mut [Bin], mut st: usize, x: usize, is_ac: bool| {
+                let mut m = 0_u16;
+                if decoder.decode(&mut bins[st]) {
+                    m = 1;
+                    if !is_ac || decoder.decode(&mut bins[st]) {
+                        if is_ac {
+                            m <<= 1;
+                        }
+                        st = x;
+                        while decoder.decode(&mut bins[st]) {
+                            m <<= 1;
+                            st += 1;
+                        }
+                    }
+                }
+                let mut v = m;
+                let mut bit = m >> 1;
+                while bit != 0 {
+                    if decoder.decode(&mut bins[st + 14]) {
+                        v |= bit;
+                    }
+                    bit >>= 1;
+                }
+                (m, v as i16 + 1)
+            };
+
+        let mut ret = ZigzagDu([0; 64]);
+        let s0 = *dc_context;
+        if !decoder.decode(&mut bins.dc[s0]) {
+            *dc_context = 0;
+        } else {
+            let negative = decoder.decode(&mut bins.dc[s0 + 1]);
+            let st = s0 + 2 + negative as usize;
+            let (m, v) = decode_magnitude(decoder, &mut bins.dc, st, 20, false);
+            *dc_context = if m > 1 { 12 } else { 4 } + 4 * negative as usize;
+            *dc_pred += if negative { -v } else { v };
+        }
+        ret.0[0] = *dc_pred;
+
+        let mut k = 1;
+        while k < 64 {
+            let mut st = 3 * (k - 1);
+            if decoder.decode(&mut bins.ac[st]) {
+                break;
+            }
+            while !decoder.decode(&mut bins.ac[st + 1]) {
+                st += 3;
+                k += 1;
+            }
+            let negative = decoder.decode(fixed_bin);
+            let x2 = if k <= AC_CONDITIONING as usize {
+                189
+            } else {
+                217
+            };
+            let (_, v) = decode_magnitude(decoder, &mut bins.ac, st + 2, x2, true);
+            ret.0[k] = if negative { -v } else { v };
+            k += 1;
+        }
+        ret
+    }
+
+    /// 解码 `scan`，每个 MCU 有 `luma_per_mcu` 个 Y。
+    fn decode(
+        scan: &ArithmeticScan,
+        mcu_count: usize,
+        luma_per_mcu: usize,
+        restart_interval: usize,
+    ) -> Vec<ZigzagDu> {
+        let mut ret = vec![];
+        let mut bounds = vec![0];
+        bounds.extend(&scan.restarts);
+        bounds.push(scan.data.len());
+        for (i, segment) in bounds.windows(2).enumerate() {
+            let mut decoder = QmDecoder::new(&scan.data[segment[0]..segment[1]]);
+            let mut bins: [ComponentBins; 2] = Default::default();
+            let mut fixed_bin = FIXED_BIN;
+            let mut dc_contexts = [0; 3];
+            let mut dc_preds = [0; 3];
+            let mcus = restart_interval.min(mcu_count - i * restart_interval);
+            for _ in 0..mcus {
+                for component in std::iter::repeat_n(0, luma_per_mcu).chain([1, 2]) {
+                    ret.push(decode_du(
+                        &mut decoder,
+                        &mut bins[(component != 0) as usize],
+                        &mut fixed_bin,
+                        &mut dc_contexts[component],
+                        &mut dc_preds[component],
+                    ));
+                }
+            }
+        }
+        ret
+    }
+
+    fn make_dus(mcu_count: usize) -> ComponentDus<ZigzagDu> {
+        // 类似量化后的系数：低频较大，高频大多为 0，偶尔有很大的值。
+        let mut seed = 12345_u32;
+        let mut random = move || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as i16
+        };
+        let mut du = |dc: i16| {
+            let mut ret = ZigzagDu([0; 64]);
+            ret.0[0] = dc;
+            for k in 1..64 {
+                let r = random();
+                ret.0[k] = match r % 64 {
+                    0 => r % 1000 - 500,
+                    v if (v as usize) < 40 / k + 2 => r % 9 - 4,
+                    _ => 0,
+                };
+            }
+            ret
+        };
+        let mut pool = BufferPool::default();
+        let mut ret = ComponentDus::with_pool(&mut pool, mcu_count, 2);
+        for i in 0..mcu_count {
+            let dc = (i as i16 % 17) * 30 - 200;
+            ret.y.extend([du(dc), du(dc + 3)]);
+            ret.cb.push(du(-dc / 4));
+            ret.cr.push(du(if i % 5 == 0 { 1000 } else { 0 }));
+        }
+        ret
+    }
+
+    #[test]
+    fn test_qm_encoder() {
+        // JPEG 标准 K.4 的测试序列，全部在同一个统计区中编码。
+        let input = [
+            0x00020051_u32,
+            0x000000C0,
+            0x0352872A,
+            0xAAAAAAAA,
+            0x82C02000,
+            0xFCD79EF6,
+            0x74EAABF7,
+            0x697EE74C,
+        ];
+        let mut encoder = QmEncoder::new();
+        let mut bin = 0;
+        for word in input {
+            for i in (0..32).rev() {
+                encoder.encode(&mut bin, word >> i & 1 != 0);
+            }
+        }
+        // 标准中的结果在 0xFF 之后填充了 0x00，之后是 EOI。
+        assert_eq!(
+            encoder.finish(),
+            [
+                0x65, 0x5B, 0x51, 0x44, 0xF7, 0x96, 0x9D, 0x51, 0x78, 0x55, 0xBF, 0xFF, 0xFC, 0x51,
+                0x84, 0xC7, 0xCE, 0xF9, 0x39, 0x00, 0x28, 0x7D, 0x46, 0x70, 0x8E, 0xCB, 0xC0, 0xF6,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_roundtrip() {
+        let dus = make_dus(60);
+        let expected: Vec<_> = (0..dus.mcu_count())
+            .flat_map(|i| {
+                let (ys, cb, cr) = dus.mcu(i);
+                ys.iter().chain([cb, cr]).map(|du| du.0)
+            })
+            .collect();
+        for restart_interval in [None, Some(1), Some(7)] {
+            let scan = encode_arithmetic(&dus, restart_interval);
+            let interval = restart_interval.map_or(60, |n| n as usize);
+            assert_eq!(scan.restarts.len(), 59 / interval);
+            let decoded = decode(&scan, 60, 2, interval);
+            let decoded: Vec<_> = decoded.iter().map(|du| du.0).collect();
+            assert!(decoded == expected, "{:?}", restart_interval);
+        }
+
+        // 分批输入与一次输入相同。
+        let mut encoder = ArithmeticEncoder::new(Some(7));
+        let mut pool = BufferPool::default();
+        for start in (0..60).step_by(20) {
+            let mut batch = ComponentDus::with_pool(&mut pool, 20, 2);
+            for i in start..start + 20 {
+                let (ys, cb, cr) = dus.mcu(i);
+                batch.y.extend(ys.iter().map(|du| ZigzagDu(du.0)));
+                batch.cb.push(ZigzagDu(cb.0));
+                batch.cr.push(ZigzagDu(cr.0));
+            }
+            encoder.encode_mcus(&batch);
+        }
+        let scan = encoder.finish();
+        assert_eq!(scan.data, encode_arithmetic(&dus, Some(7)).data);
+    }
+}
@@ -0,0 +1,210 @@
+//! Bjøntegaard-delta（BD-rate 和 BD-PSNR）：用一个数概括两条率失真曲线的差别。
+//! 每条曲线用三次多项式拟合，在两条曲线重叠的区间上积分求平均差。
+//! BD-rate 是相同 PSNR 下码率的平均变化（百分比，负数表示更省），BD-PSNR 是相同码率下 PSNR 的平均变化（dB）。
+
+use std::io;
+
+/// 率失真曲线上的一个点。码率可以用任意单位（字节、每像素位数等），两条曲线一致即可。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RdPoint {
+    pub rate: f64,
+    pub psnr: f64,
+}
+
+/// 三次多项式拟合至少需要的点数。
+const MIN_POINTS: usize = 4;
+
+/// 读取 CSV：每行的前两列为码率和 PSNR，不能解析为数的行（如表头）跳过。
+pub fn parse_rd_csv(text: &str) -> io::Result<Vec<RdPoint>> {
+    let mut ret = vec![];
+    for line in text.lines() {
+        let mut columns = line.split(',').map(|v| v.trim().parse::<f64>());
+        if let (Some(Ok(rate)), Some(Ok(psnr))) = (columns.next(), columns.next()) {
+            if rate <= 0.0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("The rate must be positive: {}", line),
+                ));
+            }
+            ret.push(RdPoint { rate, psnr });
+        }
+    }
+    Ok(ret)
+}
+
+/// 最小二乘拟合三次多项式，返回从常数项开始的系数。
+fn fit_cubic(xs: &[f64], ys: &[f64]) -> [f64; 4] {
+    // 正规方程的增广矩阵。
+    let mut m = [[0.0; 5]; 4];
+    for (&x, &y) in xs.iter().zip(ys) {
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().take(4).enumerate() {
+                *value += x.powi((i + j) as i32);
+            }
+            row[4] += x.powi(i as i32) * y;
+        }
+    }
+
+    // 列主元高斯消元。
+    for col in 0..4 {
+        let pivot = (col..4)
+            .max_by(|&a, &b| m[a][col].abs().total_cmp(&m[b][col].abs()))
+            .unwrap();
+        m.swap(col, pivot);
+        let pivot_row = m[col];
+        for (i, row) in m.iter_mut().enumerate() {
+            if i != col {
+                let factor = row[col] / pivot_row[col];
+                for (value, pivot_value) in row.iter_mut().zip(pivot_row).skip(col) {
+                    *value -= factor * pivot_value;
+                }
+            }
+        }
+    }
+    std::array::from_fn(|i| m[i][4] / m[i][i])
+}
+
+/// 多项式在 [low, high] 上的积分。
+fn integrate(p: &[f64; 4], low: f64, high: f64) -> f64 {
+    let antiderivative = |x: f64| {
+        p.iter()
+            .enumerate()
+            .map(|(i, c)| c * x.powi(i as i32 + 1) / (i + 1) as f64)
+            .sum::<f64>()
+    };
+    antiderivative(high) - antiderivative(low)
+}
+
+/// 把 y 拟合为 x 的三次多项式，返回两条曲线在 x 重叠区间上 y 的平均差（test - anchor）。
+fn average_difference(anchor: &[(f64, f64)], test: &[(f64, f64)]) -> io::Result<f64> {
+    for points in [anchor, test] {
+        if points.len() < MIN_POINTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "A curve needs at least {} points, got {}",
+                    MIN_POINTS,
+                    points.len()
+                ),
+            ));
+        }
+    }
+
+    let range = |points: &[(f64, f64)]| {
+        points.iter().fold(
+            (f64::INFINITY, f64::NEG_INFINITY),
+            |(low, high), &(x, _)| (low.min(x), high.max(x)),
+        )
+    };
+    let (anchor_low, anchor_high) = range(anchor);
+    let (test_low, test_high) = range(test);
+    let low = anchor_low.max(test_low);
+    let high = anchor_high.min(test_high);
+    if low >= high {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The two curves do not overlap",
+        ));
+    }
+
+    let fit = |points: &[(f64, f64)]| {
+        let (xs, ys): (Vec<f64>, Vec<f64>) = points.iter().copied().unzip();
+        fit_cubic(&xs, &ys)
+    };
+    let anchor = integrate(&fit(anchor), low, high);
+    let test = integrate(&fit(test), low, high);
+    Ok((test - anchor) / (high - low))
+}
+
+/// 相同 PSNR 下码率的平均变化，单位为百分比。
+pub fn bd_rate(anchor: &[RdPoint], test: &[RdPoint]) -> io::Result<f64> {
+    let points = |curve: &[RdPoint]| -> Vec<(f64, f64)> {
+        curve.iter().map(|p| (p.psnr, p.rate.ln())).collect()
+    };
+    let difference = average_difference(&points(anchor), &points(test))?;
+    Ok((difference.exp() - 1.0) * 100.0)
+}
+
+/// 相同码率下 PSNR 的平均变化，单位为 dB。
+pub fn bd_psnr(anchor: &[RdPoint], test: &[RdPoint]) -> io::Result<f64> {
+    let points = |curve: &[RdPoint]| -> Vec<(f64, f64)> {
+        curve.iter().map(|p| (p.rate.ln(), p.psnr)).collect()
+    };
+    average_difference(&points(anchor), &points(test))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn curve() -> Vec<RdPoint> {
+        [
+            (0.5, 30.0),
+            (1.0, 33.5),
+            (2.0, 37.0),
+            (4.0, 40.0),
+            (8.0, 42.5),
+        ]
+        .into_iter()
+        .map(|(rate, psnr)| RdPoint { rate, psnr })
+        .collect()
+    }
+
+    #[test]
+    fn test_parse_rd_csv() {
+        let points = parse_rd_csv("bpp,psnr\n0.5, 30.1,extra\n\n1,33\n").unwrap();
+        assert_eq!(
+            points,
+            [
+                RdPoint {
+                    rate: 0.5,
+                    psnr: 30.1
+                },
+                RdPoint {
+                    rate: 1.0,
+                    psnr: 33.0
+                }
+            ]
+        );
+        assert!(parse_rd_csv("0,30\n").is_err());
+    }
+
+    #[test]
+    fn test_bd() {
+        let anchor = curve();
+        assert!(bd_rate(&anchor, &anchor).unwrap().abs() < 1e-9);
+        assert!(bd_psnr(&anchor, &anchor).unwrap().abs() < 1e-9);
+
+        // 相同质量下码率少 10%。
+        let cheaper: Vec<RdPoint> = anchor
+            .iter()
+            .map(|p| RdPoint {
+                rate: p.rate * 0.9,
+                ..*p
+            })
+            .collect();
+        assert!((bd_rate(&anchor, &cheaper).unwrap() + 10.0).abs() < 1e-6);
+        assert!(bd_psnr(&anchor, &cheaper).unwrap() > 0.0);
+
+        // 相同码率下 PSNR 高 1 dB。
+        let better: Vec<RdPoint> = anchor
+            .iter()
+            .map(|p| RdPoint {
+                psnr: p.psnr + 1.0,
+                ..*p
+            })
+            .collect();
+        assert!((bd_psnr(&anchor, &better).unwrap() - 1.0).abs() < 1e-6);
+        assert!(bd_rate(&anchor, &better).unwrap() < 0.0);
+
+        assert!(bd_rate(&anchor, &anchor[..3]).is_err());
+        let disjoint: Vec<RdPoint> = anchor
+            .iter()
+            .map(|p| RdPoint {
+                psnr: p.psnr + 20.0,
+                ..*p
+            })
+            .collect();
+        assert!(bd_rate(&anchor, &disjoint).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! 按位读取熵编码数据。
+
+use std::io;
+
+/// 直接从 JPEG 文件的字节中按位读取熵编码数据，不复制数据。
+/// 读取时跳过 0xFF 之后填充的 0x00，遇到 RSTn 时停止，由 `restart` 跳过。
+///
+/// 内部维护一个 64 位的缓存，每次按字节补充，因此可以一次预读至多 16 位，
+/// 查霍夫曼表后再消耗实际的码长。
+#[derive(Debug, Clone)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// 下一个要读入缓存的字节的位置。
+    position: usize,
+    /// 缓存，低 `bits` 位有效，先读到的位在高位。
+    cache: u64,
+    /// 缓存中有效的位数。
+    bits: u32,
+    /// 遇到了没有填充的 0xFF，不再补充缓存。
+    invalid: bool,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+            cache: 0,
+            bits: 0,
+            invalid: false,
+        }
+    }
+
+    /// 是否已经读完所有数据。
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0 && self.position >= self.data.len()
+    }
+
+    /// 尚未读取的位数。0xFF 之后填充的 0x00 也计算在内，因此只是上限。
+    pub fn remaining_bits(&self) -> usize {
+        self.bits as usize + 8 * (self.data.len() - self.position)
+    }
+
+    /// 按字节补充缓存，直到缓存中至少有 49 位，或者没有更多的数据。
+    fn refill(&mut self) {
+        while self.bits <= 48 && !self.invalid {
+            let Some(&byte) = self.data.get(self.position) else {
+                break;
+            };
+            if byte == 0xFF {
+                if self.data.get(self.position + 1) != Some(&0x00) {
+                    self.invalid = true;
+                    break;
+                }
+                self.position += 1;
+            }
+            self.position += 1;
+            self.cache = (self.cache << 8) | byte as u64;
+            self.bits += 8;
+        }
+    }
+
+    /// 预读 `count` 位但不消耗，先读到的位是高位。`count` 至多为 16。
+    /// 数据不足时在后面补 0，是否真的有这么多位在消耗时检查。
+    pub fn peek_bits(&mut self, count: u32) -> u16 {
+        debug_assert!(count <= 16);
+        if self.bits < count {
+            self.refill();
+        }
+        let mask = (1_u64 << count) - 1;
+        let value = if self.bits >= count {
+            self.cache >> (self.bits - count)
+        } else {
+            self.cache << (count - self.bits)
+        };
+        (value & mask) as u16
+    }
+
+    /// 消耗 `count` 位。应当先用 `peek_bits` 预读。
+    pub fn skip_bits(&mut self, count: u32) -> io::Result<()> {
+        if self.bits < count {
+            self.refill();
+        }
+        if self.bits < count {
+            return Err(if self.invalid {
+                io::Error::new(io::ErrorKind::InvalidData, "Invalid image data")
+            } else {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected end of scan")
+            });
+        }
+        self.bits -= count;
+        self.cache &= (1_u64 << self.bits) - 1;
+        Ok(())
+    }
+
+    /// 丢弃当前字节中剩下的填充位，跳过标记 RST`n`，之后从下一个字节开始读取。
+    pub fn restart(&mut self, n: u8) -> io::Result<()> {
+        self.cache = 0;
+        self.bits = 0;
+        // 标记之前可以有任意多个填充的 0xFF。
+        let rest = &self.data[self.position..];
+        let marker = rest.iter().position(|&v| v != 0xFF).filter(|&i| i > 0);
+        match marker.map(|i| (i, rest[i])) {
+            Some((i, marker)) if marker == 0xD0 + n => {
+                self.position += i + 1;
+                self.invalid = false;
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Missing restart marker RST{}", n),
+            )),
+        }
+    }
+
+    /// 读取 1 位。
+    pub fn read_bit(&mut self) -> io::Result<bool> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    /// 读取 `count` 位，先读到的位是高位。`count` 至多为 16。
+    pub fn read_bits(&mut self, count: u8) -> io::Result<u16> {
+        let value = self.peek_bits(count as u32);
+        self.skip_bits(count as u32)?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bit_reader() {
+        let data = [0b1010_0000, 0xFF, 0x00, 0x80];
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+        assert_eq!(reader.read_bits(5).unwrap(), 0);
+        // 填充的 0x00 被跳过。
+        assert_eq!(reader.read_bits(8).unwrap(), 0xFF);
+        assert!(reader.read_bit().unwrap());
+        assert_eq!(reader.read_bits(7).unwrap(), 0);
+        assert!(reader.is_empty());
+        assert!(reader.read_bit().is_err());
+
+        // 没有填充的 0xFF 是非法的。
+        assert!(BitReader::new(&[0xFF, 0xD9]).read_bit().is_err());
+    }
+
+    #[test]
+    fn test_restart() {
+        let data = [0b1011_1111, 0xFF, 0xFF, 0xD3, 0x80, 0xFF, 0xD4];
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.read_bits(2).unwrap(), 0b10);
+        // 读到标记时停止。
+        assert!(reader.read_bits(8).is_err());
+        // 余下的填充位被丢弃。
+        reader.restart(3).unwrap();
+        assert!(reader.read_bit().unwrap());
+        assert_eq!(reader.read_bits(7).unwrap(), 0);
+        // 标记的序号不对。
+        assert!(reader.restart(5).is_err());
+        reader.restart(4).unwrap();
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_peek_past_end() {
+        let mut reader = BitReader::new(&[0b1100_0001]);
+        // 数据不足时补 0，但不能消耗不存在的位。
+        assert_eq!(reader.peek_bits(16), 0b1100_0001_0000_0000);
+        assert!(reader.skip_bits(9).is_err());
+        reader.skip_bits(2).unwrap();
+        assert_eq!(reader.peek_bits(8), 0b0000_0100);
+        reader.skip_bits(6).unwrap();
+        assert!(reader.is_empty());
+    }
+}
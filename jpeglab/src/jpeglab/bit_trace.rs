@@ -0,0 +1,222 @@
+//! 逐位跟踪熵编码和熵解码。第六步编码和解码的第二步在跟踪时记录每个符号：位置、值、行程、类别、
+//! 符号以及输出（或读入）的各位，可以像课程中的例子一样手工核对。记录很长，只适合很小的图像。
+
+use std::fmt;
+use std::io;
+
+use super::decode_step1::CompleteJpegData;
+use super::decode_step2::ScanDecoder;
+use super::encode_step2::ComponentDus;
+use super::encode_step5::ZigzagDu;
+use super::encode_step6::get_category;
+use super::encode_step6::HuffmanTables;
+use super::encode_step6::ScanEncoder;
+
+/// 分量的名称，下标与分量的下标相同。
+const COMPONENT_NAMES: [&str; 3] = ["Y", "Cb", "Cr"];
+
+/// 一个霍夫曼符号及其后附加的值。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodedSymbol {
+    /// 该符号覆盖的第一个系数在 Zigzag 顺序中的位置。DC 为 0，AC 的行程从该位置开始。
+    pub position: u8,
+    /// DC 为差分值，AC 为系数，EOB 和 ZRL 为 0。
+    pub value: i16,
+    /// 高四位为 0 的行程（AC），低四位为类别。
+    pub symbol: u8,
+    /// 霍夫曼码字，共 `code_length` 位。
+    pub code: u16,
+    pub code_length: u8,
+}
+
+impl CodedSymbol {
+    pub fn is_dc(&self) -> bool {
+        self.position == 0
+    }
+
+    pub fn run(&self) -> u8 {
+        self.symbol >> 4
+    }
+
+    pub fn category(&self) -> u8 {
+        self.symbol & 0x0F
+    }
+
+    /// 附加的值的各位，共 `category()` 位。负数为其绝对值各位取反。
+    pub fn value_bits(&self) -> u16 {
+        let category = self.category();
+        debug_assert_eq!(category, get_category(self.value.unsigned_abs()));
+        let bits = if self.value > 0 {
+            self.value as u16
+        } else {
+            !self.value.unsigned_abs()
+        };
+        bits & ((1_u32 << category) - 1) as u16
+    }
+
+    /// 码字和值的各位，中间以空格分开，例如 `100 010`。
+    pub fn bits(&self) -> String {
+        let to_string = |value: u16, length: u8| {
+            (0..length)
+                .rev()
+                .map(|i| if value >> i & 1 == 1 { '1' } else { '0' })
+                .collect::<String>()
+        };
+        let code = to_string(self.code, self.code_length);
+        match self.category() {
+            0 => code,
+            category => format!("{} {}", code, to_string(self.value_bits(), category)),
+        }
+    }
+}
+
+/// 带有所在 MCU 和 DU 的记录。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub mcu: usize,
+    /// 分量的下标，0 为 Y。
+    pub component: usize,
+    /// DU 在该分量中的下标（MCU 内）。
+    pub du: usize,
+    pub symbol: CodedSymbol,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = &self.symbol;
+        write!(
+            f,
+            "MCU {} {}{} [{:2}] ",
+            self.mcu,
+            COMPONENT_NAMES.get(self.component).unwrap_or(&"?"),
+            self.du,
+            s.position
+        )?;
+        if s.is_dc() {
+            write!(f, "DC diff {}", s.value)?;
+        } else if s.symbol == 0x00 {
+            write!(f, "EOB")?;
+        } else if s.symbol == 0xF0 {
+            write!(f, "ZRL")?;
+        } else {
+            write!(f, "AC run {} value {}", s.run(), s.value)?;
+        }
+        write!(
+            f,
+            ", category {}, symbol 0x{:02X}, bits {}",
+            s.category(),
+            s.symbol,
+            s.bits()
+        )
+    }
+}
+
+/// 每条记录一行。
+pub fn format_trace(trace: &[TraceEntry]) -> String {
+    trace.iter().map(|entry| format!("{}\n", entry)).collect()
+}
+
+/// 用第六步的编码器和编码时的霍夫曼表重新编码一遍，记录每个符号。
+pub fn trace_encode(
+    dus: &ComponentDus<ZigzagDu>,
+    huffman_tables: &HuffmanTables,
+) -> Vec<TraceEntry> {
+    let mut encoder = ScanEncoder::with_tables(dus.mcu_count(), huffman_tables.clone());
+    encoder.enable_trace();
+    encoder.encode_mcus(dus);
+    encoder.take_trace()
+}
+
+/// 熵解码整个扫描，记录每个符号。
+pub fn trace_decode(jpeg_data: &CompleteJpegData) -> io::Result<Vec<TraceEntry>> {
+    if jpeg_data.coefficients.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Bit traces of files with more than one scan are not supported",
+        ));
+    }
+    let (mcus_per_row, mcu_rows) = jpeg_data.get_mcu_grid();
+    let mut decoder = ScanDecoder::new(jpeg_data);
+    decoder.enable_trace();
+    for _ in 0..mcus_per_row * mcu_rows {
+        decoder.decode_mcu(|_, _, _| {})?;
+    }
+    Ok(decoder.take_trace())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::arena::ScratchArena;
+    use super::super::decode_step1::decode_step1;
+    use super::super::encode_step1::encode_step1;
+    use super::super::encode_step2::encode_step2;
+    use super::super::encode_step3::encode_step3;
+    use super::super::encode_step4::encode_step4;
+    use super::super::encode_step5::encode_step5;
+    use super::super::encode_step6::encode_step6;
+    use super::super::encode_step7::make_jpeg;
+    use super::super::options::EncodeOptions;
+
+    #[test]
+    fn test_bits() {
+        // 课程中的例子：DC 差分 -5 为类别 3，值的各位为 010。
+        let symbol = CodedSymbol {
+            position: 0,
+            value: -5,
+            symbol: 3,
+            code: 0b100,
+            code_length: 3,
+        };
+        assert_eq!(symbol.value_bits(), 0b010);
+        assert_eq!(symbol.bits(), "100 010");
+        let entry = TraceEntry {
+            mcu: 0,
+            component: 0,
+            du: 1,
+            symbol,
+        };
+        assert_eq!(
+            entry.to_string(),
+            "MCU 0 Y1 [ 0] DC diff -5, category 3, symbol 0x03, bits 100 010"
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_symmetric() {
+        let image = image::RgbImage::from_fn(40, 9, |x, y| {
+            image::Rgb([(x * 6) as u8, (y * 25) as u8, ((x ^ y) * 9) as u8])
+        });
+        let arena = &mut ScratchArena::default();
+        let yuv_image = encode_step1(&image, &Default::default(), Default::default()).unwrap();
+        let mcu_collection = encode_step2(&yuv_image, arena).unwrap();
+        let dct_mcu_collection = encode_step3(&mcu_collection, Default::default(), arena).unwrap();
+        let quantized_mcu_collection = encode_step4(
+            &dct_mcu_collection,
+            &EncodeOptions::default().quantization_tables(),
+            arena,
+        )
+        .unwrap();
+        let zigzag_mcu_collection = encode_step5(&quantized_mcu_collection, arena).unwrap();
+        let data = encode_step6(&zigzag_mcu_collection, &Default::default()).unwrap();
+
+        let encoded = trace_encode(&zigzag_mcu_collection.zigzag_dus, &data.huffman_tables);
+        // 所有符号的各位拼起来就是扫描数据。
+        let bits: String = encoded
+            .iter()
+            .map(|entry| entry.symbol.bits().replace(' ', ""))
+            .collect();
+        let scan: String = data
+            .scan
+            .iter()
+            .map(|bit| if *bit { '1' } else { '0' })
+            .collect();
+        assert_eq!(bits, scan);
+        assert!(encoded.iter().any(|entry| entry.symbol.symbol == 0x00));
+
+        let jpeg = make_jpeg(&data, &Default::default());
+        let decoded = trace_decode(&decode_step1(&jpeg).unwrap()).unwrap();
+        assert_eq!(encoded, decoded);
+    }
+}
@@ -0,0 +1,199 @@
+//! 以系数块表示的图像。只做熵解码，不反量化、不计算 IDCT，供无损变换和转码使用。
+
+use std::io;
+
+use super::decode_step1::decode_step1;
+use super::decode_step2::ScanDecoder;
+use super::encode_step2::ComponentDus;
+use super::encode_step4::QuantizedDu;
+use super::encode_step5::ZigzagDu;
+use super::encode_step6::HuffmanMode;
+use super::encode_step6::HuffmanTables;
+use super::encode_step6::ScanEncoder;
+use super::encode_step7::make_jpeg_with_layout;
+use super::encode_step7::FrameLayout;
+use super::encode_step7::ToVec;
+use super::options::EncodeOptions;
+use super::segments::read_segments;
+
+/// 一个分量的全部系数块，按行存储，系数为自然顺序，未反量化。
+#[derive(Debug, Clone)]
+pub struct BlockGrid {
+    /// 每行的块数。
+    pub width: usize,
+    /// 块的行数。
+    pub height: usize,
+    blocks: Vec<[[i16; 8]; 8]>,
+}
+
+impl BlockGrid {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            blocks: vec![[[0; 8]; 8]; width * height],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> &[[i16; 8]; 8] {
+        &self.blocks[y * self.width + x]
+    }
+
+    pub fn get_mut(&mut self, x: usize, y: usize) -> &mut [[i16; 8]; 8] {
+        &mut self.blocks[y * self.width + x]
+    }
+
+    pub fn blocks_mut(&mut self) -> impl Iterator<Item = &mut [[i16; 8]; 8]> {
+        self.blocks.iter_mut()
+    }
+}
+
+/// 熵解码后的图像。块的个数总是填充到整数个 MCU。
+#[derive(Debug, Clone)]
+pub struct CoefficientImage {
+    pub width: usize,
+    pub height: usize,
+    /// 采样因子和量化表。
+    pub layout: FrameLayout,
+    /// Y、Cb、Cr 的系数块。
+    pub grids: [BlockGrid; 3],
+    /// 原样保留的 APPn 和 COM 段，已包含标记和长度，见 `Segment::is_metadata`。
+    pub metadata: Vec<u8>,
+    /// 重新编码时沿用的重启间隔（MCU 个数）。
+    pub restart_interval: Option<u16>,
+}
+
+impl CoefficientImage {
+    /// 解析 JPEG 文件并熵解码出所有的系数块。
+    /// 重新编码时每个 MCU 有若干个 Y 和各一个 Cb、Cr，见 `ScanEncoder::encode_mcus`，
+    /// 因此 Cb 和 Cr 的采样因子必须为 (1, 1)，例如 YUV422、YUV411 和 YUV440 文件，且 Cb 和 Cr 共用量化表。
+    /// 只保留系数和重启间隔，渐进式的文件重新编码后是顺序的基线 JPEG。
+    pub fn read(jpeg: &[u8]) -> io::Result<Self> {
+        let jpeg_data = decode_step1(jpeg)?;
+        let components = &jpeg_data.components;
+        let sampling_factors: Vec<(u8, u8)> = components
+            .iter()
+            .map(|c| (c.horizontal_sampling_factor, c.vertical_sampling_factor))
+            .collect();
+        if components.len() != 3 || sampling_factors[1] != (1, 1) || sampling_factors[2] != (1, 1) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Only images whose Cb and Cr have the sampling factors (1, 1) can be transcoded",
+            ));
+        }
+        if components[1].quatization_table.0 != components[2].quatization_table.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Cb and Cr must share a quantization table",
+            ));
+        }
+
+        let (mcus_per_row, mcu_rows) = jpeg_data.get_mcu_grid();
+        let mut grids = [0, 1, 2].map(|i| {
+            let (h, v) = sampling_factors[i];
+            BlockGrid::new(mcus_per_row * h as usize, mcu_rows * v as usize)
+        });
+        let mut decoder = ScanDecoder::new(&jpeg_data);
+        for my in 0..mcu_rows {
+            for mx in 0..mcus_per_row {
+                decoder.decode_mcu(|i, j, zigzag_du| {
+                    let (h, v) = sampling_factors[i];
+                    let (h, v) = (h as usize, v as usize);
+                    *grids[i].get_mut(mx * h + j % h, my * v + j / h) =
+                        zigzag_du.to_quantized_du().0;
+                })?;
+            }
+        }
+
+        let metadata = read_segments(jpeg)?
+            .iter()
+            .filter(|segment| segment.is_metadata())
+            .flat_map(|segment| segment.to_vec())
+            .collect();
+
+        Ok(Self {
+            width: jpeg_data.width,
+            height: jpeg_data.height,
+            layout: FrameLayout {
+                sampling_factors: [
+                    sampling_factors[0],
+                    sampling_factors[1],
+                    sampling_factors[2],
+                ],
+                quantization_tables: [
+                    components[0].quatization_table.as_ref().clone(),
+                    components[1].quatization_table.as_ref().clone(),
+                ],
+            },
+            grids,
+            metadata,
+            restart_interval: jpeg_data.restart_interval,
+        })
+    }
+
+    /// MCU 的宽和高（像素）。
+    pub fn mcu_size(&self) -> (usize, usize) {
+        let (h, v) = self.layout.sampling_factors[0];
+        (8 * h as usize, 8 * v as usize)
+    }
+
+    /// 每行 MCU 的个数和 MCU 的行数。
+    pub fn mcu_grid(&self) -> (usize, usize) {
+        let (mcu_width, mcu_height) = self.mcu_size();
+        (
+            self.width.div_ceil(mcu_width),
+            self.height.div_ceil(mcu_height),
+        )
+    }
+
+    /// 按 MCU 的顺序重新熵编码，使用默认霍夫曼表，返回 JPEG 文件的内容。
+    pub fn to_jpeg(&self) -> Vec<u8> {
+        self.to_jpeg_with(HuffmanMode::Default)
+    }
+
+    /// 与 `to_jpeg` 相同，但指定霍夫曼表。`HuffmanMode::Optimized` 时先统计这些系数的符号，
+    /// 再以最优的表编码第二遍。系数不变，因此是无损的。
+    pub fn to_jpeg_with(&self, huffman: HuffmanMode) -> Vec<u8> {
+        let (mcus_per_row, mcu_rows) = self.mcu_grid();
+        let mcu_count = mcus_per_row * mcu_rows;
+        let (h, v) = self.layout.sampling_factors[0];
+        let mut dus = ComponentDus::<ZigzagDu> {
+            y: Vec::with_capacity(h as usize * v as usize * mcu_count),
+            cb: Vec::with_capacity(mcu_count),
+            cr: Vec::with_capacity(mcu_count),
+        };
+        for my in 0..mcu_rows {
+            for mx in 0..mcus_per_row {
+                for (i, &(h, v)) in self.layout.sampling_factors.iter().enumerate() {
+                    let (h, v) = (h as usize, v as usize);
+                    for j in 0..h * v {
+                        let block = self.grids[i].get(mx * h + j % h, my * v + j / h);
+                        let du = QuantizedDu(*block).zigzag();
+                        match i {
+                            0 => dus.y.push(du),
+                            1 => dus.cb.push(du),
+                            _ => dus.cr.push(du),
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut encoder = ScanEncoder::new(mcu_count);
+        encoder.set_restart_interval(self.restart_interval);
+        encoder.encode_mcus(&dus);
+        if huffman == HuffmanMode::Optimized {
+            let tables = HuffmanTables::optimal(encoder.symbol_counts());
+            encoder = ScanEncoder::with_tables(mcu_count, tables);
+            encoder.set_restart_interval(self.restart_interval);
+            encoder.encode_mcus(&dus);
+        }
+        let data = encoder.finish(self.width, self.height);
+        make_jpeg_with_layout(
+            &data,
+            &self.layout,
+            &self.metadata,
+            &EncodeOptions::default(),
+        )
+    }
+}
@@ -0,0 +1,105 @@
+//! 只解码一个分量，得到它自己分辨率的平面，例如 YUV422 的 Cb 宽度只有图像的一半。
+//! 霍夫曼码是连续的，其他分量仍要熵解码，但不计算 IDCT，也不做上采样和颜色转换。
+
+use std::io;
+
+use image::GrayImage;
+
+use super::decode_step1::decode_step1;
+use super::decode_step2::ScanDecoder;
+use super::decode_step3::for_each_dct_du;
+use super::encode_step3::DctPrecision;
+
+/// 要解码的分量。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Component {
+    Y,
+    Cb,
+    Cr,
+}
+
+impl Component {
+    /// 在帧头中的序号。
+    fn index(self) -> usize {
+        match self {
+            Component::Y => 0,
+            Component::Cb => 1,
+            Component::Cr => 2,
+        }
+    }
+}
+
+/// 解码 `component` 的平面。有多幅图像时只解码第一幅。
+pub fn decode_component(
+    buf: &[u8],
+    component: Component,
+    precision: DctPrecision,
+) -> io::Result<GrayImage> {
+    let jpeg_data = decode_step1(buf)?;
+    let index = component.index();
+    let info = jpeg_data.components.get(index).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("The image has no {:?} component", component),
+        )
+    })?;
+    let (hb, vb) = jpeg_data.get_mcu_size();
+    let (mcus_per_row, mcu_rows) = jpeg_data.get_mcu_grid();
+    // 一个样本覆盖的像素数。
+    let horizontal = hb / 8 / info.horizontal_sampling_factor as usize;
+    let vertical = vb / 8 / info.vertical_sampling_factor as usize;
+    let plane_width = mcus_per_row * hb / horizontal;
+    let mut plane = vec![0_u8; plane_width * (mcu_rows * vb / vertical)];
+
+    let mut decoder = ScanDecoder::new(&jpeg_data);
+    for_each_dct_du(&mut decoder, &jpeg_data, mcu_rows, |position, dct_du| {
+        if position.component != index {
+            return;
+        }
+        for (y_in_du, row) in dct_du.idct_with(precision).0.iter().enumerate() {
+            let start = (position.y + y_in_du) * plane_width + position.x;
+            for (value, &sample) in plane[start..start + 8].iter_mut().zip(row) {
+                *value = (sample + 128) as u8;
+            }
+        }
+    })?;
+
+    // 去掉填充。
+    let width = jpeg_data.width.div_ceil(horizontal);
+    let height = jpeg_data.height.div_ceil(vertical);
+    Ok(GrayImage::from_fn(width as u32, height as u32, |x, y| {
+        image::Luma([plane[y as usize * plane_width + x as usize]])
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::decode_step3::decode_step3;
+    use super::super::encode_to_vec;
+    use super::super::test_util::test_image;
+
+    #[test]
+    fn test_decode_component() {
+        let image = test_image();
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        let yuv = decode_step3(&decode_step1(&jpeg).unwrap(), Default::default()).unwrap();
+
+        // YUV422：Y 为原尺寸，色度横向减半。
+        for (component, plane, expected_width) in [
+            (Component::Y, &yuv.y, 37),
+            (Component::Cb, &yuv.u, 19),
+            (Component::Cr, &yuv.v, 19),
+        ] {
+            let decoded = decode_component(&jpeg, component, Default::default()).unwrap();
+            assert_eq!(decoded.dimensions(), (expected_width, 21));
+            // 与完整解码时的分量平面相同。填充后的宽度为 48 个像素。
+            let padded_width = 48 / plane.absolute_horizontal_sampling_factor;
+            for (x, y, p) in decoded.enumerate_pixels() {
+                let expected = plane.values[y as usize * padded_width + x as usize];
+                assert_eq!(p[0], expected, "{:?} ({}, {})", component, x, y);
+            }
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! 编码前的颜色类型转换，把任意输入图像转换为 `encode_step1` 需要的 Rgb8。
+//!
+//! 转换规则：
+//! - 灰度（L8, L16）：灰度值复制到 R、G、B 三个通道。
+//! - 带透明通道（La8, La16, Rgba8, Rgba16, Rgba32F）：JPEG 没有透明通道，按 alpha 合成到白色背景上。
+//! - 高位深（16 位、32 位浮点）：按比例四舍五入到 8 位。浮点数先截断到 [0, 1]。
+//!
+//! 12 位编码时用 `to_rgb16` 转换为 Rgb16，规则相同，但不降位深。
+
+use image::DynamicImage;
+use image::RgbImage;
+use image::Rgba;
+
+use super::decode16::Rgb16Image;
+
+/// 合成透明像素时使用的背景色。
+pub const BACKGROUND: [u8; 3] = [255, 255, 255];
+
+/// 把 16 位的通道值四舍五入到 8 位。
+fn reduce_to_8_bits(value: f64) -> u8 {
+    (value * 255.0 / 65535.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// 按上述规则把任意颜色类型的图像转换为 Rgb8。已经是 Rgb8 的图像不做任何改变。
+pub fn to_rgb8(image: DynamicImage) -> RgbImage {
+    if let DynamicImage::ImageRgb8(rgb) = image {
+        return rgb;
+    }
+
+    // 统一转换到 16 位 RGBA 再合成、降位深。8 位的值 v 在 16 位中为 257v，因此不透明的 8 位图像不会有误差。
+    let rgba = image.to_rgba16();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        image::Rgb(composite(rgba.get_pixel(x, y)).map(reduce_to_8_bits))
+    })
+}
+
+/// 按上述规则把任意颜色类型的图像转换为 Rgb16。已经是 Rgb16 的图像不做任何改变。
+pub fn to_rgb16(image: DynamicImage) -> Rgb16Image {
+    if let DynamicImage::ImageRgb16(rgb) = image {
+        return rgb;
+    }
+
+    let rgba = image.to_rgba16();
+    Rgb16Image::from_fn(rgba.width(), rgba.height(), |x, y| {
+        image::Rgb(composite(rgba.get_pixel(x, y)).map(|v| v.round().clamp(0.0, 65535.0) as u16))
+    })
+}
+
+/// 把 16 位的像素按 alpha 合成到背景上，结果仍以 0~65535 表示。
+fn composite(pixel: &Rgba<u16>) -> [f64; 3] {
+    let alpha = pixel[3] as f64 / 65535.0;
+    std::array::from_fn(|i| {
+        let background = BACKGROUND[i] as f64 * 257.0;
+        pixel[i] as f64 * alpha + background * (1.0 - alpha)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::GrayAlphaImage;
+    use image::GrayImage;
+    use image::ImageBuffer;
+    use image::RgbaImage;
+
+    #[test]
+    fn test_gray() {
+        let gray = GrayImage::from_fn(2, 1, |x, _| image::Luma([[0, 200][x as usize]]));
+        let rgb = to_rgb8(DynamicImage::ImageLuma8(gray));
+        assert_eq!(rgb.as_raw(), &[0, 0, 0, 200, 200, 200]);
+
+        let gray16: ImageBuffer<image::Luma<u16>, Vec<u16>> =
+            ImageBuffer::from_pixel(1, 1, image::Luma([65535]));
+        let rgb = to_rgb8(DynamicImage::ImageLuma16(gray16));
+        assert_eq!(rgb.as_raw(), &[255, 255, 255]);
+    }
+
+    #[test]
+    fn test_alpha() {
+        let rgba = RgbaImage::from_fn(3, 1, |x, _| {
+            image::Rgba([[10, 20, 30, 255], [10, 20, 30, 0], [0, 0, 0, 128]][x as usize])
+        });
+        let rgb = to_rgb8(DynamicImage::ImageRgba8(rgba));
+        // 不透明的像素不变，完全透明的像素变成背景色，半透明的像素合成。
+        assert_eq!(rgb.as_raw(), &[10, 20, 30, 255, 255, 255, 127, 127, 127]);
+
+        let gray_alpha = GrayAlphaImage::from_pixel(1, 1, image::LumaA([0, 0]));
+        let rgb = to_rgb8(DynamicImage::ImageLumaA8(gray_alpha));
+        assert_eq!(rgb.as_raw(), &BACKGROUND);
+    }
+
+    #[test]
+    fn test_16_bits() {
+        let rgb16: ImageBuffer<image::Rgb<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(3, 1, |x, _| image::Rgb([[0, 128, 32896][x as usize]; 3]));
+        let rgb = to_rgb8(DynamicImage::ImageRgb16(rgb16));
+        // 128 / 257 ≈ 0.498，32896 / 257 = 128。
+        assert_eq!(rgb.as_raw(), &[0, 0, 0, 0, 0, 0, 128, 128, 128]);
+    }
+
+    #[test]
+    fn test_to_rgb16() {
+        let rgb16: Rgb16Image =
+            ImageBuffer::from_fn(2, 1, |x, _| image::Rgb([[128, 32896][x as usize]; 3]));
+        assert_eq!(to_rgb16(DynamicImage::ImageRgb16(rgb16.clone())), rgb16);
+
+        // 8 位的值 v 变为 257v，透明的像素同样合成到背景上。
+        let rgba = RgbaImage::from_fn(2, 1, |x, _| {
+            image::Rgba([[10, 20, 30, 255], [10, 20, 30, 0]][x as usize])
+        });
+        let rgb = to_rgb16(DynamicImage::ImageRgba8(rgba));
+        assert_eq!(rgb.as_raw(), &[2570, 5140, 7710, 65535, 65535, 65535]);
+    }
+}
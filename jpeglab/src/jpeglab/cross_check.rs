@@ -0,0 +1,90 @@
+//! 交叉检查：同一个 JPEG 文件分别由 jpeglab 和 image 库解码，比较两幅图像。
+//! 两者的 IDCT 精度和色度上采样方式不同，结果不会完全相同，但正常的文件差异很小；
+//! 差异很大时，多半是某一方的解码有错。只比较最终的 RGB，要找出出错的一步见 `differential` 模块。
+
+use std::io;
+
+use image::ImageFormat;
+
+use super::decode_to_image;
+use super::options::DecodeOptions;
+
+/// 两个解码结果的差异，单位为样本值。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Deviation {
+    /// 所有样本中最大的绝对差。
+    pub max: u8,
+    /// 最大差出现的第一个像素。
+    pub max_position: (u32, u32),
+    /// 所有样本的平均绝对差。
+    pub mean: f64,
+    /// 至少有一个通道不同的像素数。
+    pub differing_pixels: usize,
+}
+
+/// 用两个解码器解码 `jpeg` 并比较。尺寸不同时出错。
+pub fn cross_check(jpeg: &[u8], options: &DecodeOptions) -> io::Result<Deviation> {
+    let ours = decode_to_image(jpeg, options)?;
+    let theirs = image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg)
+        .map_err(io::Error::other)?
+        .into_rgb8();
+    if ours.dimensions() != theirs.dimensions() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "The decoders disagree on the size: {}x{} and {}x{}",
+                ours.width(),
+                ours.height(),
+                theirs.width(),
+                theirs.height()
+            ),
+        ));
+    }
+
+    let mut ret = Deviation {
+        max: 0,
+        max_position: (0, 0),
+        mean: 0.0,
+        differing_pixels: 0,
+    };
+    let mut sum = 0_u64;
+    for ((x, y, p), q) in ours.enumerate_pixels().zip(theirs.pixels()) {
+        let differences = std::array::from_fn::<u8, 3, _>(|c| p[c].abs_diff(q[c]));
+        let max = differences.into_iter().max().unwrap_or_default();
+        if max > ret.max {
+            ret.max = max;
+            ret.max_position = (x, y);
+        }
+        if max > 0 {
+            ret.differing_pixels += 1;
+        }
+        sum += differences.iter().map(|&v| v as u64).sum::<u64>();
+    }
+    let samples = ours.len().max(1);
+    ret.mean = sum as f64 / samples as f64;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::RgbImage;
+
+    use super::super::encode_to_vec;
+
+    #[test]
+    fn test_cross_check() {
+        let image = RgbImage::from_fn(37, 21, |x, y| {
+            image::Rgb([(x * 7) as u8, (y * 12) as u8, ((x + y) * 4) as u8])
+        });
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        let deviation = cross_check(&jpeg, &Default::default()).unwrap();
+        assert!(deviation.mean < 2.0, "{:?}", deviation);
+        assert!(deviation.max < 32, "{:?}", deviation);
+        assert!(deviation.differing_pixels <= 37 * 21);
+
+        // 不是 JPEG 时 image 库也拒绝。
+        assert!(cross_check(b"not a jpeg", &Default::default()).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! 16 位的解码输出。
+//!
+//! 普通的解码在 IDCT 后把样本舍入为 8 位，颜色转换后再舍入一次。这里 IDCT 和颜色转换的结果都不舍入，
+//! 最后才按 0~255 对应 0~65535 缩放为 16 位，适合解码后还要进一步处理的场合。
+//! IDCT 总是以 f64 计算，不使用 `DecodeOptions::dct_precision`；也不按条带解码，不锐化。
+
+use std::io;
+use std::path::Path;
+
+use image::ImageBuffer;
+use image::ImageFormat;
+use image::Rgb;
+
+use super::decode_step1::decode_step1;
+use super::decode_step2::ScanDecoder;
+use super::decode_step3::for_each_dct_du;
+use super::options::DecodeOptions;
+
+/// 每个样本 16 位的 RGB 图像。
+pub type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
+
+/// 不舍入的分量平面。
+struct Plane {
+    /// 一个样本覆盖的像素数。
+    horizontal: usize,
+    vertical: usize,
+    width: usize,
+    values: Vec<f32>,
+}
+
+/// 解码为 16 位的 RGB 图像。有多幅图像时只解码第一幅。
+pub fn decode_to_image16(buf: &[u8], options: &DecodeOptions) -> io::Result<Rgb16Image> {
+    let jpeg_data = decode_step1(buf)?;
+    if jpeg_data.components.len() != 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The count of the components is not supported",
+        ));
+    }
+    let (hb, vb) = jpeg_data.get_mcu_size();
+    let (mcus_per_row, mcu_rows) = jpeg_data.get_mcu_grid();
+    let mut planes: Vec<_> = jpeg_data
+        .components
+        .iter()
+        .map(|c| {
+            let horizontal = hb / 8 / c.horizontal_sampling_factor as usize;
+            let vertical = vb / 8 / c.vertical_sampling_factor as usize;
+            let width = mcus_per_row * hb / horizontal;
+            Plane {
+                horizontal,
+                vertical,
+                width,
+                values: vec![0.0; width * (mcu_rows * vb / vertical)],
+            }
+        })
+        .collect();
+
+    let mut decoder = ScanDecoder::new(&jpeg_data);
+    for_each_dct_du(&mut decoder, &jpeg_data, mcu_rows, |position, dct_du| {
+        let plane = &mut planes[position.component];
+        for (y_in_du, row) in dct_du.idct_unrounded().iter().enumerate() {
+            let start = (position.y + y_in_du) * position.plane_width + position.x;
+            for (value, &sample) in plane.values[start..start + 8].iter_mut().zip(row) {
+                *value = (sample + 128.0) as f32;
+            }
+        }
+    })?;
+
+    let sample = |plane: &Plane, x: u32, y: u32| {
+        plane.values[y as usize / plane.vertical * plane.width + x as usize / plane.horizontal]
+    };
+    let conversion = &options.color_conversion;
+    Ok(Rgb16Image::from_fn(
+        jpeg_data.width as u32,
+        jpeg_data.height as u32,
+        |x, y| {
+            let rgb = conversion.yuv_to_rgb_f32(
+                sample(&planes[0], x, y),
+                sample(&planes[1], x, y),
+                sample(&planes[2], x, y),
+            );
+            Rgb(rgb.map(|v| (v * 257.0).round().clamp(0.0, 65535.0) as u16))
+        },
+    ))
+}
+
+/// 保存为 16 位的 PNG 或 TIFF，格式由扩展名决定。
+pub fn save_image16(image: &Rgb16Image, path: &Path) -> io::Result<()> {
+    let format = match path.extension().and_then(|v| v.to_str()) {
+        Some("png") => ImageFormat::Png,
+        Some("tif" | "tiff") => ImageFormat::Tiff,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "16-bit output must be a .png or .tif file",
+            ))
+        }
+    };
+    image
+        .save_with_format(path, format)
+        .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::decode_to_image;
+    use super::super::encode_to_vec;
+    use super::super::test_util::test_image;
+
+    #[test]
+    fn test_decode_to_image16() {
+        let image = test_image();
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        let image8 = decode_to_image(&jpeg, &Default::default()).unwrap();
+        let image16 = decode_to_image16(&jpeg, &Default::default()).unwrap();
+        assert_eq!(image16.dimensions(), image8.dimensions());
+
+        // 与 8 位的结果相比，只差两次舍入。
+        let mut fractional = false;
+        for (p8, p16) in image8.pixels().zip(image16.pixels()) {
+            for (&v8, &v16) in p8.0.iter().zip(&p16.0) {
+                assert!((v16 as f64 / 257.0 - v8 as f64).abs() <= 2.0);
+                fractional |= v16 % 257 != 0;
+            }
+        }
+        assert!(fractional);
+
+        let path = std::env::temp_dir().join(format!("jpeglab_16_{}.png", std::process::id()));
+        save_image16(&image16, &path).unwrap();
+        let loaded = image::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.as_rgb16().unwrap(), &image16);
+        assert!(save_image16(&image16, Path::new("out.bmp")).is_err());
+    }
+}
@@ -0,0 +1,477 @@
+//! 渐进式编码（SOF2）和有多个扫描的顺序编码的熵解码。
+//!
+//! 这样的文件中一个 DU 的系数分散在多个扫描中，频谱选择把 64 个系数分为几段，逐次逼近再把系数按位分为几次。
+//! 因此先把每个扫描的结果累积到系数缓冲区中，所有扫描结束后才得到完整的 DU，
+//! 再由 `ScanDecoder` 按 MCU 的顺序交给之后的各步，见标准 G.1.2。
+
+use std::io;
+
+use super::bit_reader::BitReader;
+use super::decode_step1::CompleteJpegData;
+use super::decode_step1::ScanData;
+use super::decode_step2::entropy_decode_value;
+use super::decode_step2::DecodeHuffmanTable;
+use super::encode_step5::ZigzagDu;
+
+/// 所有扫描累积得到的系数。
+#[derive(Debug)]
+pub struct CoefficientBuffer {
+    /// 每个分量的 DU，按从左到右、从上到下的顺序，填充到整数个 MCU。
+    components: Vec<ComponentCoefficients>,
+}
+
+#[derive(Debug)]
+struct ComponentCoefficients {
+    /// 每行的 DU 数。
+    width: usize,
+    dus: Vec<ZigzagDu>,
+}
+
+impl CoefficientBuffer {
+    /// 分量 `component` 中第 `y` 行第 `x` 列的 DU。超出图像时返回 `None`。
+    pub fn get(&self, component: usize, x: usize, y: usize) -> Option<&ZigzagDu> {
+        let c = &self.components[component];
+        c.dus.get(y * c.width + x)
+    }
+
+    fn get_mut(&mut self, component: usize, x: usize, y: usize) -> &mut ZigzagDu {
+        let c = &mut self.components[component];
+        &mut c.dus[y * c.width + x]
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// 一个扫描的解码状态，在 RSTn 处清零。
+#[derive(Default)]
+struct ScanState {
+    /// 各分量的 DC 预测值。
+    preds: Vec<i16>,
+    /// 之后还有多少个 DU 的这段系数全为 0（EOB 行程）。
+    eob_run: u32,
+}
+
+/// 依次解码所有扫描，把累积的系数放到 `jpeg_data.coefficients` 中。
+/// 每解码一个扫描就调用一次 `on_scan`，此时的系数只包括已经解码的扫描，可以用来显示逐次细化的图像。
+pub fn decode_scans(
+    jpeg_data: &mut CompleteJpegData,
+    scans: &[ScanData],
+    on_scan: &mut dyn FnMut(&CompleteJpegData) -> io::Result<()>,
+) -> io::Result<()> {
+    let (mcus_per_row, mcu_rows) = jpeg_data.get_mcu_grid();
+    let mut buffer = CoefficientBuffer {
+        components: jpeg_data
+            .components
+            .iter()
+            .map(|c| {
+                let width = mcus_per_row * c.horizontal_sampling_factor as usize;
+                let height = mcu_rows * c.vertical_sampling_factor as usize;
+                ComponentCoefficients {
+                    width,
+                    dus: (0..width * height).map(|_| ZigzagDu([0; 64])).collect(),
+                }
+            })
+            .collect(),
+    };
+    for scan in scans {
+        decode_scan(&mut buffer, jpeg_data, scan)?;
+        jpeg_data.coefficients = Some(buffer);
+        on_scan(jpeg_data)?;
+        buffer = jpeg_data.coefficients.take().unwrap();
+    }
+    jpeg_data.coefficients = Some(buffer);
+    Ok(())
+}
+
+/// 解码一个扫描，结果累积到 `buffer` 中。
+fn decode_scan(
+    buffer: &mut CoefficientBuffer,
+    jpeg_data: &CompleteJpegData,
+    scan: &ScanData,
+) -> io::Result<()> {
+    // 顺序编码的扫描 Ss 为 0，Se 为 63；渐进式编码中 DC 和 AC 不能在同一个扫描中，AC 的扫描只有一个分量。
+    let sequential = scan.ss == 0 && scan.se == 63 && scan.ah == 0 && scan.al == 0;
+    if scan.se > 63
+        || scan.ss > scan.se
+        || (scan.ss == 0 && scan.se != 0 && !sequential)
+        || (scan.ss != 0 && scan.components.len() != 1)
+        || scan.al > 13
+        || scan.components.is_empty()
+    {
+        return Err(invalid_data("Invalid scan parameters"));
+    }
+
+    // 扫描中依次解码的单元：交织时为 MCU，只有一个分量时为 DU，只包括与图像重叠的 DU（标准 A.2.2）。
+    // 每个单元中的 DU 为 (扫描中分量的序号, 列, 行)。
+    let (hb, vb) = jpeg_data.get_mcu_size();
+    let (mcus_per_row, mcu_rows) = jpeg_data.get_mcu_grid();
+    let units_per_row;
+    let unit_count;
+    let mut unit_dus: Vec<(usize, usize, usize)> = vec![];
+    let layout: Vec<(usize, usize)> = scan
+        .components
+        .iter()
+        .map(|c| {
+            let component = &jpeg_data.components[c.index];
+            (
+                component.horizontal_sampling_factor as usize,
+                component.vertical_sampling_factor as usize,
+            )
+        })
+        .collect();
+    if scan.components.len() == 1 {
+        let (h, v) = layout[0];
+        units_per_row = (jpeg_data.width * h).div_ceil(hb);
+        unit_count = units_per_row * (jpeg_data.height * v).div_ceil(vb);
+    } else {
+        units_per_row = mcus_per_row;
+        unit_count = mcus_per_row * mcu_rows;
+    }
+
+    let mut reader = BitReader::new(scan.data);
+    let mut state = ScanState {
+        preds: vec![0; scan.components.len()],
+        ..Default::default()
+    };
+    let interval = scan.restart_interval.map(|n| n as usize);
+    for unit in 0..unit_count {
+        if let Some(interval) = interval {
+            if unit != 0 && unit.is_multiple_of(interval) {
+                reader.restart(((unit / interval - 1) % 8) as u8)?;
+                state = ScanState {
+                    preds: vec![0; scan.components.len()],
+                    ..Default::default()
+                };
+            }
+        }
+
+        let (ux, uy) = (unit % units_per_row, unit / units_per_row);
+        unit_dus.clear();
+        if scan.components.len() == 1 {
+            unit_dus.push((0, ux, uy));
+        } else {
+            for (k, &(h, v)) in layout.iter().enumerate() {
+                for j in 0..h * v {
+                    unit_dus.push((k, ux * h + j % h, uy * v + j / h));
+                }
+            }
+        }
+        for &(k, x, y) in &unit_dus {
+            let component = &scan.components[k];
+            let du = &mut buffer.get_mut(component.index, x, y).0;
+            if scan.ss == 0 {
+                if scan.ah == 0 {
+                    let table = component.dc_huffman_table.as_deref().unwrap();
+                    let category = table.decode(&mut reader)?;
+                    let diff = entropy_decode_value(&mut reader, category)?;
+                    state.preds[k] = state.preds[k]
+                        .checked_add(diff)
+                        .ok_or_else(|| invalid_data("DC coefficient overflowed"))?;
+                    du[0] = shift(state.preds[k], scan.al)?;
+                } else if reader.read_bit()? {
+                    du[0] |= 1 << scan.al;
+                }
+            }
+            if scan.se == 0 {
+                continue;
+            }
+            let table = component.ac_huffman_table.as_deref().unwrap();
+            let ss = scan.ss.max(1) as usize;
+            let se = scan.se as usize;
+            if scan.ah == 0 {
+                decode_ac_first(&mut reader, table, du, (ss, se), scan.al, &mut state)?;
+            } else {
+                decode_ac_refine(&mut reader, table, du, (ss, se), scan.al, &mut state)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 逐次逼近中 `value` 左移 `al` 位。
+fn shift(value: i16, al: u8) -> io::Result<i16> {
+    i16::try_from((value as i32) << al).map_err(|_| invalid_data("Coefficient overflowed"))
+}
+
+/// AC 的第一次扫描，解码 `ss` 到 `se` 的系数，左移 `al` 位。
+fn decode_ac_first(
+    reader: &mut BitReader,
+    table: &DecodeHuffmanTable,
+    du: &mut [i16; 64],
+    (ss, se): (usize, usize),
+    al: u8,
+    state: &mut ScanState,
+) -> io::Result<()> {
+    if state.eob_run > 0 {
+        state.eob_run -= 1;
+        return Ok(());
+    }
+    let mut k = ss;
+    while k <= se {
+        let symbol = table.decode(reader)?;
+        let (r, s) = (symbol >> 4, symbol & 0x0F);
+        if s == 0 {
+            if r == 15 {
+                // ZRL
+                k += 16;
+                continue;
+            }
+            // EOBr：包括这个 DU 在内，一共 2^r 加上附加位个 DU 结束。
+            state.eob_run = (1 << r) - 1;
+            if r > 0 {
+                state.eob_run += reader.read_bits(r)? as u32;
+            }
+            break;
+        }
+        k += r as usize;
+        if k > se {
+            return Err(invalid_data("AC coefficients exceeded"));
+        }
+        du[k] = shift(entropy_decode_value(reader, s)?, al)?;
+        k += 1;
+    }
+    Ok(())
+}
+
+/// AC 的逐次逼近，第 `al` 位。新出现的非零系数为 ±1 左移 `al` 位，
+/// 经过的已非零的系数各读 1 位修正，见标准 G.1.2.3。
+fn decode_ac_refine(
+    reader: &mut BitReader,
+    table: &DecodeHuffmanTable,
+    du: &mut [i16; 64],
+    (ss, se): (usize, usize),
+    al: u8,
+    state: &mut ScanState,
+) -> io::Result<()> {
+    let p1 = 1_i16 << al;
+    let m1 = -1_i16 << al;
+    let refine = |reader: &mut BitReader, coefficient: &mut i16| -> io::Result<()> {
+        if reader.read_bit()? && *coefficient & p1 == 0 {
+            *coefficient += if *coefficient >= 0 { p1 } else { m1 };
+        }
+        Ok(())
+    };
+
+    let mut k = ss;
+    if state.eob_run == 0 {
+        while k <= se {
+            let symbol = table.decode(reader)?;
+            let (mut r, s) = ((symbol >> 4) as i32, symbol & 0x0F);
+            let value = match s {
+                0 if r < 15 => {
+                    state.eob_run = 1 << r;
+                    if r > 0 {
+                        state.eob_run += reader.read_bits(r as u8)? as u32;
+                    }
+                    break;
+                }
+                // ZRL：跳过 16 个原来为 0 的系数。
+                0 => 0,
+                1 => match reader.read_bit()? {
+                    true => p1,
+                    false => m1,
+                },
+                _ => {
+                    return Err(invalid_data(&format!(
+                        "Invalid AC symbol 0x{:02X} in a refinement scan",
+                        symbol
+                    )))
+                }
+            };
+            // 跳过 r 个原来为 0 的系数，经过的非零系数各修正 1 位。
+            while k <= se {
+                if du[k] != 0 {
+                    refine(reader, &mut du[k])?;
+                } else {
+                    r -= 1;
+                    if r < 0 {
+                        break;
+                    }
+                }
+                k += 1;
+            }
+            if value != 0 {
+                if k > se {
+                    return Err(invalid_data("AC coefficients exceeded"));
+                }
+                du[k] = value;
+            }
+            k += 1;
+        }
+    }
+    if state.eob_run > 0 {
+        // 在 EOB 行程中，只修正剩下的非零系数。
+        while k <= se {
+            if du[k] != 0 {
+                refine(reader, &mut du[k])?;
+            }
+            k += 1;
+        }
+        state.eob_run -= 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use clap::ValueEnum;
+
+    use super::super::coefficients::CoefficientImage;
+    use super::super::decode_incremental;
+    use super::super::decode_to_image;
+    use super::super::encode_step1::Subsampling;
+    use super::super::encode_step6::DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE;
+    use super::super::encode_step6::DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE;
+    use super::super::encode_to_vec;
+    use super::super::options::EncodeOptions;
+    use super::super::test_util::code;
+    use super::super::test_util::segment;
+    use super::super::test_util::test_image;
+    use super::super::test_util::to_scan_bytes;
+
+    #[test]
+    fn test_multiple_scans() {
+        let image = test_image();
+        for &subsampling in Subsampling::value_variants() {
+            for restart_interval in [None, Some(2), Some(3)] {
+                let options = EncodeOptions {
+                    subsampling,
+                    restart_interval,
+                    ..Default::default()
+                };
+                let expected = encode_to_vec(&image, &options).unwrap();
+                let expected = decode_to_image(&expected, &Default::default()).unwrap();
+                // 系数相同，只是分成了多个扫描。渐进式编码时自检也能通过。
+                for (progressive, non_interleaved) in [(true, false), (false, true)] {
+                    let options = EncodeOptions {
+                        progressive,
+                        non_interleaved,
+                        verify: progressive,
+                        ..options.clone()
+                    };
+                    let jpeg = encode_to_vec(&image, &options).unwrap();
+                    let decoded = decode_to_image(&jpeg, &Default::default()).unwrap();
+                    let context = (subsampling, restart_interval, progressive);
+                    assert!(decoded == expected, "{:?}", context);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental_refinement() {
+        let image = test_image();
+        let options = EncodeOptions {
+            progressive: true,
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&image, &options).unwrap();
+        let expected = decode_to_image(&jpeg, &Default::default()).unwrap();
+
+        // 每个扫描之后得到一幅逐次细化的整幅图像，最后一幅即最终的结果。
+        let mut refined = vec![];
+        let decoded = decode_incremental(&jpeg, &Default::default(), |image, rows| {
+            assert_eq!(rows, 0..image.height());
+            refined.push(image.clone());
+        })
+        .unwrap();
+        let scan_count = jpeg.windows(2).filter(|w| w == &[0xFF, 0xDA]).count();
+        assert!(scan_count > 1);
+        assert_eq!(refined.len(), scan_count);
+        assert!(refined[0] != expected);
+        assert!(refined[scan_count - 1] == expected);
+        assert!(decoded == expected);
+
+        // 只有一个扫描时按 MCU 行调用。
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        let mut rows = vec![];
+        decode_incremental(&jpeg, &Default::default(), |_, range| rows.push(range)).unwrap();
+        assert_eq!(rows, [0..8, 8..16, 16..21]);
+    }
+
+    #[test]
+    fn test_successive_approximation() {
+        // 8x8 的 YUV444 图像，量化表全为 1。Y 的 DC 为 5，Zigzag 顺序中的 AC 1、2、5 为 3、-2、1；
+        // Cb 和 Cr 的 DC 为 -3，没有 AC。系数分两次传输，第一次是除最低位以外的部分。
+        let dc = &*DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE;
+        let ac = &*DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE;
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(segment(0xDB, &[[0].as_slice(), &[1; 64]].concat()));
+        jpeg.extend(segment(
+            0xC2,
+            &[8, 0, 8, 0, 8, 3, 1, 0x11, 0, 2, 0x11, 0, 3, 0x11, 0],
+        ));
+        for (class, table) in [(0x00, dc), (0x10, ac)] {
+            jpeg.extend(segment(
+                0xC4,
+                &[[class].as_slice(), &table.codes, &table.values].concat(),
+            ));
+        }
+        let scans = [
+            // DC 的第一次扫描，Al 为 1：5 >> 1 = 2，-3 >> 1 = -2，各分量的差分分别预测。
+            (
+                vec![3, 1, 0, 2, 0, 3, 0, 0, 0, 0x01],
+                format!("{} 10 {} 01 {} 01", code(dc, 2), code(dc, 2), code(dc, 2)),
+            ),
+            // Y 的 AC 的第一次扫描：3 / 2 = 1，-2 / 2 = -1，1 / 2 = 0。
+            (
+                vec![1, 1, 0, 1, 63, 0x01],
+                format!(
+                    "{} 1 {} 0 {}",
+                    code(ac, 0x01),
+                    code(ac, 0x01),
+                    code(ac, 0x00)
+                ),
+            ),
+            // DC 的逐次逼近，每个分量 1 位。
+            (vec![3, 1, 0, 2, 0, 3, 0, 0, 0, 0x10], "1 1 1".to_string()),
+            // AC 的逐次逼近：跳过 2 个原来为 0 的系数后是新的 +1，经过的 2 和 -2 各修正 1 位，之后是 EOB。
+            (
+                vec![1, 1, 0, 1, 63, 0x10],
+                format!("{} 1 1 0 {}", code(ac, 0x21), code(ac, 0x00)),
+            ),
+        ];
+        for (header, bits) in &scans {
+            jpeg.extend(segment(0xDA, header));
+            jpeg.extend(to_scan_bytes(bits));
+        }
+        jpeg.extend([0xFF, 0xD9]);
+
+        let coefficients = CoefficientImage::read(&jpeg).unwrap();
+        let mut y = [[0_i16; 8]; 8];
+        y[0][0] = 5;
+        y[0][1] = 3;
+        y[1][0] = -2;
+        y[0][2] = 1;
+        assert_eq!(coefficients.grids[0].get(0, 0), &y);
+        let mut chroma = [[0_i16; 8]; 8];
+        chroma[0][0] = -3;
+        assert_eq!(coefficients.grids[1].get(0, 0), &chroma);
+        assert_eq!(coefficients.grids[2].get(0, 0), &chroma);
+
+        // 与相同系数的顺序编码的结果相同。其他解码器解析出的系数也相同。
+        let baseline = coefficients.to_jpeg();
+        let decode = |jpeg: &[u8]| decode_to_image(jpeg, &Default::default()).unwrap();
+        assert!(decode(&jpeg) == decode(&baseline));
+        let decode = |jpeg: &[u8]| {
+            image::load_from_memory_with_format(jpeg, image::ImageFormat::Jpeg)
+                .unwrap()
+                .to_rgb8()
+        };
+        assert!(decode(&jpeg) == decode(&baseline));
+
+        // 逐次逼近的 AC 扫描中，新的系数只能是 ±1。
+        let mut invalid = jpeg.clone();
+        let last_scan = invalid.len() - 2 - to_scan_bytes(&scans[3].1).len();
+        invalid.truncate(last_scan);
+        invalid.extend(to_scan_bytes(&format!(
+            "{} 11 {}",
+            code(ac, 0x22),
+            code(ac, 0x00)
+        )));
+        invalid.extend([0xFF, 0xD9]);
+        assert!(decode_to_image(&invalid, &Default::default()).is_err());
+    }
+}
@@ -3,16 +3,16 @@ use std::convert::TryInto;
 use std::io;
 use std::rc::Rc;
 
-use bitvec::order::Lsb0;
-use bitvec::vec::BitVec;
-use bitvec::view::BitView;
 use bytebuffer::ByteBuffer;
 use bytebuffer::Endian;
 
+use super::decode_progressive::decode_scans;
+use super::decode_progressive::CoefficientBuffer;
+use super::decode_step2::DecodeHuffmanTable;
 use super::encode_step4::QuantizationTable;
-use super::encode_step6::CachedHuffmanTable;
 use super::encode_step6::JpegHuffmanTable;
 use super::encode_step7::APP0;
+use super::zigzag::from_zigzag;
 
 /// 分量信息。来源于 SOF0 和 SOS。
 #[derive(Debug, Clone)]
@@ -24,46 +24,76 @@ pub struct Component {
     /// 量化表。
     pub quatization_table: Rc<QuantizationTable>,
     /// DC 霍夫曼表。
-    pub dc_huffman_table: Rc<CachedHuffmanTable>,
+    pub dc_huffman_table: Rc<DecodeHuffmanTable>,
     /// AC 霍夫曼表。
-    pub ac_huffman_table: Rc<CachedHuffmanTable>,
+    pub ac_huffman_table: Rc<DecodeHuffmanTable>,
 }
 
 /// 临时分量信息。
 #[derive(Debug)]
 struct TempComponent {
+    pub id: u8,
     pub horizontal_sampling_factor: u8,
     pub vertical_sampling_factor: u8,
     pub quatization_table_id: u8,
-    pub dc_huffman_table_id: u8,
-    pub ac_huffman_table_id: u8,
 }
 
 /// 解码 JPEG 图像所需的完整数据，使用方便编程的格式。
-#[derive(Debug)]
-pub struct CompleteJpegData {
+#[derive(Debug, Default)]
+pub struct CompleteJpegData<'a> {
     /// 图像宽度，列数。
     pub width: usize,
     /// 图像高度，行数。
     pub height: usize,
-    /// 分量信息。
-    pub components: Vec<Component>,
-    /// 图像数据。
-    pub scan: BitVec,
+    /// 分量信息。与之后的各步共享，不复制。
+    pub components: Rc<[Component]>,
+    /// DRI 中的重新同步间隔，没有 DRI 或为 0 时为 `None`。
+    pub restart_interval: Option<u16>,
+    /// 图像数据，即原始 JPEG 数据中熵编码的部分，仍含有 0xFF 之后填充的 0x00。
+    /// 有多个扫描时为空，见 `coefficients`。
+    pub scan: &'a [u8],
+    /// 渐进式编码或有多个扫描的顺序编码时，所有扫描累积得到的系数。
+    pub coefficients: Option<CoefficientBuffer>,
 }
 
-impl Default for CompleteJpegData {
-    fn default() -> Self {
-        Self {
-            width: Default::default(),
-            height: Default::default(),
-            components: Default::default(),
-            scan: Default::default(),
-        }
-    }
+/// 扫描中的一个分量。
+#[derive(Debug)]
+pub struct ScanComponent {
+    /// 在帧中的下标。
+    pub index: usize,
+    /// 霍夫曼表。扫描用不到的表为 `None`，例如 AC 的扫描中的 DC 表。
+    pub dc_huffman_table: Option<Rc<DecodeHuffmanTable>>,
+    pub ac_huffman_table: Option<Rc<DecodeHuffmanTable>>,
+}
+
+/// 一个扫描。霍夫曼表和重新同步间隔可以在扫描之间重新定义，因此在扫描开始时就确定下来。
+#[derive(Debug)]
+pub struct ScanData<'a> {
+    pub components: Vec<ScanComponent>,
+    /// 频谱选择的开始和结束，为 Zigzag 顺序中的位置。
+    pub ss: u8,
+    pub se: u8,
+    /// 逐次逼近中上一次和这一次的位置。第一次扫描时 `ah` 为 0。
+    pub ah: u8,
+    pub al: u8,
+    pub restart_interval: Option<u16>,
+    /// 熵编码的数据，与 `CompleteJpegData::scan` 相同。
+    pub data: &'a [u8],
 }
 
-fn parse_app0(block: &[u8]) -> io::Result<APP0> {
+/// SOS 中的一个分量：(在帧中的下标, DC 表的 ID, AC 表的 ID)。
+type SosComponent = (usize, u8, u8);
+
+/// SOS 的内容。
+struct ScanHeader {
+    components: Vec<SosComponent>,
+    ss: u8,
+    se: u8,
+    ah: u8,
+    al: u8,
+}
+
+pub fn parse_app0(block: &[u8]) -> io::Result<APP0> {
     let mut buf = ByteBuffer::from_bytes(block);
     let mut ret = APP0::default();
     ret.length = block.len() as u16 + 2;
@@ -75,69 +105,41 @@ fn parse_app0(block: &[u8]) -> io::Result<APP0> {
     ret.y_density = buf.read_u16()?;
     ret.x_thumbnail = buf.read_u8()?;
     ret.y_thumbnail = buf.read_u8()?;
+    // 缩略图不完整时只保留实际有的字节。
+    let thumbnail_len = 3 * ret.x_thumbnail as usize * ret.y_thumbnail as usize;
+    ret.thumbnail = buf.read_bytes(thumbnail_len.min(buf.len() - buf.get_rpos()))?;
 
     Ok(ret)
 }
 
 /// 量化表也是 Zigzag 形式存储的！！！
-fn parse_dqt(block: &[u8]) -> io::Result<QuantizationTable> {
+/// 一个 DQT 可以依次包含多个量化表。
+/// 解析 DQT 中的所有量化表，返回 (ID, 量化表)。
+pub fn parse_dqt(block: &[u8]) -> io::Result<Vec<(u8, QuantizationTable)>> {
     let mut buf = ByteBuffer::from_bytes(block);
-    let mut ret = QuantizationTable(Default::default());
-    let precision_and_id = buf.read_u8()?;
-    let _id = precision_and_id & 0x0F; // 忽略 ID，假设按顺序。
-    let precision = precision_and_id >> 4;
-
-    let output = &mut ret.0;
+    let mut ret = vec![];
 
-    let mut x = 0;
-    let mut y = 0;
-    let mut idx = 0;
+    while buf.get_rpos() < buf.len() {
+        let precision_and_id = buf.read_u8()?;
+        let id = precision_and_id & 0x0F;
+        let precision = precision_and_id >> 4;
 
-    while idx < 64 {
-        while idx < 64 {
-            output[x][y] = if precision == 0 {
+        let mut values = [0_u16; 64];
+        for value in values.iter_mut() {
+            *value = if precision == 0 {
                 buf.read_u8()? as u16
             } else {
-                buf.read_u16()? as u16
+                buf.read_u16()?
             };
-            idx += 1;
-            // 优先处理 y == 7，因为有对角线。
-            if y == 7 {
-                x += 1;
-                break;
-            } else if x == 0 {
-                y += 1;
-                break;
-            } else {
-                x -= 1;
-                y += 1;
-            }
-        }
-        while idx < 64 {
-            output[x][y] = if precision == 0 {
-                buf.read_u8()? as u16
-            } else {
-                buf.read_u16()? as u16
-            };
-            idx += 1;
-            // 优先处理 x == 7，因为有对角线。
-            if x == 7 {
-                y += 1;
-                break;
-            } else if y == 0 {
-                x += 1;
-                break;
-            } else {
-                x += 1;
-                y -= 1;
-            }
         }
+        ret.push((id, QuantizationTable(from_zigzag(&values))));
     }
 
     Ok(ret)
 }
 
-fn parse_sof0(block: &[u8], jpeg_data: &mut CompleteJpegData) -> io::Result<Vec<TempComponent>> {
+/// 解析 SOF0 或 SOF2，两者的格式相同。
+fn parse_sof(block: &[u8], jpeg_data: &mut CompleteJpegData) -> io::Result<Vec<TempComponent>> {
     let mut buf = ByteBuffer::from_bytes(block);
     let mut ret = vec![];
 
@@ -158,97 +160,217 @@ fn parse_sof0(block: &[u8], jpeg_data: &mut CompleteJpegData) -> io::Result<Vec<
         ));
     }
     for _ in 0..n_components {
-        let _id = buf.read_u8()?; // 忽略 ID，假设按顺序。
+        let id = buf.read_u8()?;
         let sampling_factors = buf.read_u8()?;
         let horizontal_sampling_factor = sampling_factors >> 4;
         let vertical_sampling_factor = sampling_factors & 0x0F;
         let quatization_table_id = buf.read_u8()?;
         ret.push(TempComponent {
+            id,
             horizontal_sampling_factor,
             vertical_sampling_factor,
             quatization_table_id,
-            dc_huffman_table_id: Default::default(),
-            ac_huffman_table_id: Default::default(),
         });
     }
+    // 采样因子为 1~4。一个分量的样本必须覆盖整数个像素，例如不支持 3 和 2 同时出现。
+    if ret.iter().any(|c| {
+        !(1..=4).contains(&c.horizontal_sampling_factor)
+            || !(1..=4).contains(&c.vertical_sampling_factor)
+    }) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid sampling factors",
+        ));
+    }
+    let max_h = ret
+        .iter()
+        .map(|c| c.horizontal_sampling_factor)
+        .max()
+        .unwrap();
+    let max_v = ret
+        .iter()
+        .map(|c| c.vertical_sampling_factor)
+        .max()
+        .unwrap();
+    if ret.iter().any(|c| {
+        max_h % c.horizontal_sampling_factor != 0 || max_v % c.vertical_sampling_factor != 0
+    }) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Fractional sampling factors are not supported",
+        ));
+    }
 
     Ok(ret)
 }
 
-/// 返回 (霍夫曼表, 类别, ID)。
-fn parse_dht(block: &[u8]) -> io::Result<(CachedHuffmanTable, u8, u8)> {
+/// 一个 DHT 可以依次包含多个霍夫曼表。返回每个表的 (霍夫曼表, 类别, ID)。
+pub fn parse_dht(block: &[u8]) -> io::Result<Vec<(JpegHuffmanTable, u8, u8)>> {
     let mut buf = ByteBuffer::from_bytes(block);
-    let mut ret = JpegHuffmanTable::new();
-
-    let table_class_and_id = buf.read_u8()?;
-    let table_class = table_class_and_id >> 4;
-    let id = table_class_and_id & 0x0F;
+    let mut ret = vec![];
 
-    for i in 0..ret.codes.len() {
-        ret.codes[i] = buf.read_u8()?;
-    }
     while buf.get_rpos() < buf.len() {
-        let value = buf.read_u8()?;
-        ret.values.push(value);
+        let mut table = JpegHuffmanTable::new();
+
+        let table_class_and_id = buf.read_u8()?;
+        let table_class = table_class_and_id >> 4;
+        let id = table_class_and_id & 0x0F;
+
+        for i in 0..table.codes.len() {
+            table.codes[i] = buf.read_u8()?;
+        }
+        // 值的个数由位表决定。
+        let count = table.codes.iter().map(|&x| x as usize).sum();
+        table.values = buf.read_bytes(count)?;
+
+        ret.push((table, table_class, id));
     }
 
-    Ok((ret.to_cached(), table_class, id))
+    Ok(ret)
 }
 
-fn parse_sos(block: &[u8], temp_components: &mut Vec<TempComponent>) -> io::Result<()> {
+fn parse_sos(block: &[u8], temp_components: &[TempComponent]) -> io::Result<ScanHeader> {
     let mut buf = ByteBuffer::from_bytes(block);
 
     let n_components = buf.read_u8()? as usize;
-    for i in 0..n_components {
-        let _id = buf.read_u8()?;
+    let mut components = vec![];
+    for _ in 0..n_components {
+        let id = buf.read_u8()?;
+        let index = temp_components
+            .iter()
+            .position(|c| c.id == id)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown component {} in SOS", id),
+                )
+            })?;
         let huffman_tables = buf.read_u8()?;
-        let dc_huffman_table_id = huffman_tables >> 4;
-        let ac_huffman_table_id = huffman_tables & 0x0F;
-        temp_components[i].dc_huffman_table_id = dc_huffman_table_id;
-        temp_components[i].ac_huffman_table_id = ac_huffman_table_id;
+        components.push((index, huffman_tables >> 4, huffman_tables & 0x0F));
     }
+    let ss = buf.read_u8()?;
+    let se = buf.read_u8()?;
+    let ah_al = buf.read_u8()?;
+
+    Ok(ScanHeader {
+        components,
+        ss,
+        se,
+        ah: ah_al >> 4,
+        al: ah_al & 0x0F,
+    })
+}
 
-    Ok(())
+/// 找到熵编码数据的范围，不复制数据。0xFF 之后填充的 0x00 在解码时再跳过。
+/// 返回熵编码数据的长度，数据之后是下一个标记或者文件结束。
+fn parse_image_data(data: &[u8]) -> io::Result<usize> {
+    let mut idx = 0;
+    while idx < data.len() {
+        if data[idx] != 0xFF {
+            idx += 1;
+            continue;
+        }
+        // 标记之前可以有任意多个填充的 0xFF。
+        let marker = data[idx + 1..]
+            .iter()
+            .position(|&v| v != 0xFF)
+            .map_or(data.len(), |offset| idx + 1 + offset);
+        match data.get(marker) {
+            Some(0x00) if marker == idx + 1 => idx += 2,
+            // RST0~RST7 留在扫描数据中，解码时再跳过。
+            Some(0xD0..=0xD7) => idx = marker + 1,
+            // EOI，或者渐进式编码中下一个扫描之前的 DHT、DQT、DRI、SOS 等。
+            Some(0xD9 | 0xDA | 0xC4 | 0xDB | 0xDD | 0xE0..=0xEF | 0xFE) | None => return Ok(idx),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid image data",
+                ));
+            }
+        }
+    }
+
+    Ok(idx)
 }
 
-fn parse_image_data(buf: &mut ByteBuffer) -> io::Result<BitVec> {
-    let mut ret = BitVec::new();
+/// 第一步：从原始的 JPEG 数据中解析出解码所需的完整数据。
+/// 只解析第一幅图像，EOI 之后的数据忽略。首尾相接的多幅图像（如 MJPEG）见 `decode_step1_all`。
+pub fn decode_step1(data: &[u8]) -> io::Result<CompleteJpegData<'_>> {
+    decode_step1_with(data, |_| Ok(()))
+}
 
-    let mut is_pre_ff = false;
-    while buf.get_rpos() < buf.len() {
-        let byte = buf.read_u8()?;
-
-        if !is_pre_ff && byte != 0xFF || is_pre_ff && byte == 0x00 {
-            let byte = if is_pre_ff { 0xFF } else { byte };
-            let mut bits = byte.view_bits::<Lsb0>().to_owned();
-            bits.reverse();
-            ret.append(&mut bits);
-        } else if !is_pre_ff && byte == 0xFF {
-            // Skip.
-        } else if is_pre_ff && byte == 0xD9 {
-            // EOI.
-            break;
-        } else {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid image data",
-            ));
+/// 与 `decode_step1` 相同，但渐进式编码或有多个扫描时每解码一个扫描就调用一次 `on_scan`，
+/// 参数中的系数只包括已经解码的扫描。只有一个扫描时不调用。
+pub fn decode_step1_with(
+    data: &[u8],
+    mut on_scan: impl FnMut(&CompleteJpegData) -> io::Result<()>,
+) -> io::Result<CompleteJpegData<'_>> {
+    decode_one(data, &mut on_scan).map(|(jpeg_data, _)| jpeg_data)
+}
+
+/// 解析首尾相接的每一幅图像，例如 MJPEG 流。只有 EOI 之后紧接着 SOI（之间可以有 0x00 或 0xFF 填充）
+/// 时才是下一幅图像，否则之后的数据忽略。第一幅之后的图像解析失败时给出警告，保留已解析的图像。
+pub fn decode_step1_all(data: &[u8]) -> io::Result<Vec<CompleteJpegData<'_>>> {
+    let (jpeg_data, mut start) = decode_one(data, &mut |_| Ok(()))?;
+    let mut ret = vec![jpeg_data];
+    while let Some(offset) = next_soi(&data[start..]) {
+        start += offset;
+        match decode_one(&data[start..], &mut |_| Ok(())) {
+            Ok((jpeg_data, consumed)) => {
+                ret.push(jpeg_data);
+                start += consumed;
+            }
+            Err(e) => {
+                println!("[WARN] 忽略第 {} 幅图像及之后的数据：{}", ret.len() + 1, e);
+                break;
+            }
         }
+    }
+    Ok(ret)
+}
 
-        is_pre_ff = if byte == 0xFF { true } else { false };
+/// EOI 之后的 SOI 的位置。
+fn next_soi(data: &[u8]) -> Option<usize> {
+    let padding = data.iter().take_while(|&&v| v == 0x00 || v == 0xFF).count();
+    match (padding.checked_sub(1).map(|i| data[i]), data.get(padding)) {
+        (Some(0xFF), Some(0xD8)) => Some(padding - 1),
+        _ => None,
     }
+}
 
-    Ok(ret)
+/// 所有分量的 DU 个数，不含 MCU 的填充。
+fn du_count(jpeg_data: &CompleteJpegData, components: &[TempComponent]) -> usize {
+    let max_h = components
+        .iter()
+        .map(|c| c.horizontal_sampling_factor)
+        .max();
+    let max_v = components.iter().map(|c| c.vertical_sampling_factor).max();
+    components
+        .iter()
+        .map(|c| {
+            let h = c.horizontal_sampling_factor as usize;
+            let v = c.vertical_sampling_factor as usize;
+            let width = (jpeg_data.width * h).div_ceil(max_h.unwrap_or(1) as usize);
+            let height = (jpeg_data.height * v).div_ceil(max_v.unwrap_or(1) as usize);
+            width.div_ceil(8) * height.div_ceil(8)
+        })
+        .sum()
 }
 
-/// 第一步：从原始的 JPEG 数据中解析出解码所需的完整数据。
-pub fn decode_step1(buf: &[u8]) -> io::Result<CompleteJpegData> {
+/// 解析一幅图像，同时返回到 EOI 为止消耗的长度。`on_scan` 见 `decode_step1_with`。
+#[tracing::instrument(skip_all, fields(bytes = data.len(), width, height, scan_bytes))]
+fn decode_one<'a>(
+    data: &'a [u8],
+    on_scan: &mut dyn FnMut(&CompleteJpegData) -> io::Result<()>,
+) -> io::Result<(CompleteJpegData<'a>, usize)> {
     let mut ret = CompleteJpegData::default();
-    let mut temp_components = vec![]; // 忽略 ID，假设分量按顺序。
-    let mut quantization_tables = vec![];
-    let mut huffman_tables = BTreeMap::<(u8, u8), Rc<CachedHuffmanTable>>::new();
+    let mut temp_components = vec![];
+    let mut progressive = false;
+    let mut scans = vec![];
+    let mut quantization_tables = BTreeMap::<u8, Rc<QuantizationTable>>::new();
+    let mut huffman_tables = BTreeMap::<(u8, u8), Rc<DecodeHuffmanTable>>::new();
 
-    let mut buf = ByteBuffer::from_bytes(buf);
+    let mut buf = ByteBuffer::from_bytes(data);
     buf.set_endian(Endian::BigEndian);
     while buf.get_rpos() < buf.len() {
         buf.read_u8().and_then(|v| {
@@ -261,7 +383,11 @@ pub fn decode_step1(buf: &[u8]) -> io::Result<CompleteJpegData> {
                 Ok(v)
             }
         })?;
-        let block_type = buf.read_u8()?;
+        // 标记之前可以有任意多个填充的 0xFF。
+        let mut block_type = buf.read_u8()?;
+        while block_type == 0xFF {
+            block_type = buf.read_u8()?;
+        }
 
         match block_type {
             // SOI
@@ -271,33 +397,62 @@ pub fn decode_step1(buf: &[u8]) -> io::Result<CompleteJpegData> {
                 let block = read_block(&mut buf)?;
                 let _app0 = parse_app0(&block)?; // 不使用。
             }
-            // APPn
-            0xE1..=0xEF => {
+            // APPn 和 COM
+            0xE1..=0xEF | 0xFE => {
                 let _block = read_block(&mut buf)?;
             }
             // DQT
             0xDB => {
                 let block = read_block(&mut buf)?;
-                let dqt = parse_dqt(&block)?;
-                quantization_tables.push(Rc::new(dqt));
+                for (id, table) in parse_dqt(&block)? {
+                    quantization_tables.insert(id, Rc::new(table));
+                }
+            }
+            // SOF0 和 SOF2
+            0xC0 | 0xC2 => {
+                let block = read_block(&mut buf)?;
+                temp_components = parse_sof(&block, &mut ret)?;
+                progressive = block_type == 0xC2;
+            }
+            // 算术编码的 SOF9 到 SOF11、SOF13 到 SOF15，以及 DAC
+            0xC9..=0xCF => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Arithmetic coding is not supported",
+                ));
             }
-            // SOF0（不支持 SOF2）
-            0xC0 => {
+            // DRI
+            0xDD => {
                 let block = read_block(&mut buf)?;
-                temp_components = parse_sof0(&block, &mut ret)?;
+                let interval = block.get(..2).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Invalid DRI block")
+                })?;
+                ret.restart_interval =
+                    Some(u16::from_be_bytes([interval[0], interval[1]])).filter(|&n| n != 0);
             }
             // DHT
             0xC4 => {
                 let block = read_block(&mut buf)?;
-                let (table, table_class, id) = parse_dht(&block)?;
-                huffman_tables.insert((table_class, id), Rc::new(table));
+                for (table, table_class, id) in parse_dht(&block)? {
+                    huffman_tables.insert((table_class, id), Rc::new(table.to_decode_table()));
+                }
             }
             // SOS and image data
             0xDA => {
                 let block = read_block(&mut buf)?;
-                parse_sos(&block, &mut temp_components)?;
-                ret.scan = parse_image_data(&mut buf)?;
+                let header = parse_sos(&block, &temp_components)?;
+                let start = buf.get_rpos();
+                let length = parse_image_data(&data[start..])?;
+                scans.push(to_scan_data(
+                    header,
+                    &huffman_tables,
+                    ret.restart_interval,
+                    &data[start..start + length],
+                )?);
+                buf.set_rpos(start + length);
             }
+            // EOI，之后可能是下一幅图像。
+            0xD9 => break,
             _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
@@ -307,18 +462,122 @@ pub fn decode_step1(buf: &[u8]) -> io::Result<CompleteJpegData> {
         }
     }
 
-    for t in temp_components {
-        let component = Component {
-            horizontal_sampling_factor: t.horizontal_sampling_factor,
-            vertical_sampling_factor: t.vertical_sampling_factor,
-            quatization_table: quantization_tables[t.quatization_table_id as usize].clone(),
-            dc_huffman_table: huffman_tables[&(0, t.dc_huffman_table_id)].clone(),
-            ac_huffman_table: huffman_tables[&(1, t.ac_huffman_table_id)].clone(),
-        };
-        ret.components.push(component);
+    if temp_components.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No frame header found",
+        ));
+    }
+    if scans.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "No scan found"));
+    }
+    // 每个 DU 在扫描数据中至少占 1 位。在为图像分配内存之前检查，以免 SOF 中伪造的大尺寸耗尽内存。
+    let scan_bytes: usize = scans.iter().map(|s| s.data.len()).sum();
+    if du_count(&ret, &temp_components) > scan_bytes.saturating_mul(8) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame size exceeds the scan data",
+        ));
     }
 
-    Ok(ret)
+    let span = tracing::Span::current();
+    span.record("width", ret.width);
+    span.record("height", ret.height);
+    span.record(
+        "scan_bytes",
+        scans.iter().map(|s| s.data.len()).sum::<usize>(),
+    );
+
+    // 只有一个包含所有分量的顺序扫描时直接按 MCU 熵解码，否则先累积所有扫描的系数。
+    let single = !progressive
+        && scans.len() == 1
+        && scans[0].components.len() == temp_components.len()
+        && scans[0]
+            .components
+            .iter()
+            .enumerate()
+            .all(|(i, c)| c.index == i);
+    // 每个分量使用最后一个用到它的扫描中的霍夫曼表，只用于统计和输出。
+    let empty = Rc::new(JpegHuffmanTable::new().to_decode_table());
+    let table_of = |index: usize, ac: bool| {
+        scans
+            .iter()
+            .rev()
+            .flat_map(|s| &s.components)
+            .filter(|c| c.index == index)
+            .find_map(|c| match ac {
+                false => c.dc_huffman_table.clone(),
+                true => c.ac_huffman_table.clone(),
+            })
+            .unwrap_or_else(|| Rc::clone(&empty))
+    };
+    ret.components = temp_components
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let table = quantization_tables
+                .get(&t.quatization_table_id)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Missing quantization table {}", t.quatization_table_id),
+                    )
+                })?;
+            Ok(Component {
+                horizontal_sampling_factor: t.horizontal_sampling_factor,
+                vertical_sampling_factor: t.vertical_sampling_factor,
+                quatization_table: Rc::clone(table),
+                dc_huffman_table: table_of(i, false),
+                ac_huffman_table: table_of(i, true),
+            })
+        })
+        .collect::<io::Result<_>>()?;
+    if single {
+        ret.scan = scans[0].data;
+    } else {
+        decode_scans(&mut ret, &scans, on_scan)?;
+    }
+
+    Ok((ret, buf.get_rpos()))
+}
+
+/// 确定扫描用到的霍夫曼表。DC 的第一次扫描需要 DC 表，包括 AC 系数的扫描需要 AC 表。
+fn to_scan_data<'a>(
+    header: ScanHeader,
+    huffman_tables: &BTreeMap<(u8, u8), Rc<DecodeHuffmanTable>>,
+    restart_interval: Option<u16>,
+    data: &'a [u8],
+) -> io::Result<ScanData<'a>> {
+    let table = |class: u8, id: u8| {
+        huffman_tables.get(&(class, id)).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Missing Huffman table {} of class {}", id, class),
+            )
+        })
+    };
+    let needs_dc = header.ss == 0 && header.ah == 0;
+    let needs_ac = header.se > 0;
+    let components = header
+        .components
+        .iter()
+        .map(|&(index, dc_id, ac_id)| {
+            Ok(ScanComponent {
+                index,
+                dc_huffman_table: needs_dc.then(|| table(0, dc_id)).transpose()?,
+                ac_huffman_table: needs_ac.then(|| table(1, ac_id)).transpose()?,
+            })
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(ScanData {
+        components,
+        ss: header.ss,
+        se: header.se,
+        ah: header.ah,
+        al: header.al,
+        restart_interval,
+        data,
+    })
 }
 
 fn read_block(buf: &mut ByteBuffer) -> Result<Vec<u8>, io::Error> {
@@ -333,3 +592,93 @@ fn read_block(buf: &mut ByteBuffer) -> Result<Vec<u8>, io::Error> {
     let block = buf.read_bytes(length)?;
     Ok(block)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::decode_to_image;
+    use super::super::encode_step6::EntropyCoding;
+    use super::super::encode_to_vec;
+    use super::super::test_util::test_image;
+    use super::super::EncodeOptions;
+
+    #[test]
+    fn test_fill_bytes() {
+        let image = test_image();
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        let expected = decode_step1(&jpeg).unwrap();
+
+        // 在 SOI 之后的每个标记和 EOI 之前插入填充的 0xFF。
+        let eoi = jpeg.len() - 2;
+        let scan_start = jpeg.len() - 2 - expected.scan.len();
+        let mut filled = jpeg[..2].to_vec();
+        let mut idx = 2;
+        while idx < scan_start {
+            let length = u16::from_be_bytes([jpeg[idx + 2], jpeg[idx + 3]]) as usize;
+            filled.extend([0xFF, 0xFF, 0xFF]);
+            filled.extend(&jpeg[idx..idx + 2 + length]);
+            idx += 2 + length;
+        }
+        filled.extend(&jpeg[scan_start..eoi]);
+        filled.extend([0xFF, 0xFF]);
+        filled.extend(&jpeg[eoi..]);
+        filled.extend([0xFF, 0xD8, 0xFF, 0xFF]);
+
+        let (jpeg_data, consumed) = decode_one(&filled, &mut |_| Ok(())).unwrap();
+        assert_eq!(jpeg_data.scan, expected.scan);
+        assert_eq!(consumed, filled.len() - 4);
+        assert_eq!((jpeg_data.width, jpeg_data.height), (37, 21));
+
+        // 扫描数据中单独的 0xFF 之后必须是 0x00 或标记。
+        let mut invalid = jpeg.clone();
+        invalid[eoi - 1] = 0xFF;
+        invalid.insert(eoi, 0x12);
+        assert!(decode_step1(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let jpeg = encode_to_vec(&test_image(), &Default::default()).unwrap();
+        let count = |trailer: &[u8]| decode_step1_all(&[&jpeg, trailer].concat()).unwrap().len();
+
+        // EOI 之后不是 SOI 的数据忽略，即使其中有 FF D8。
+        assert_eq!(count(&[0x12, 0xFF, 0xD8, 0xFF, 0x00, 0x34]), 1);
+        // 空的图像解析失败，保留之前的图像。
+        assert_eq!(count(&[0xFF, 0xD8, 0xFF, 0xD9]), 1);
+        assert_eq!(count(&[0xFF, 0xD8]), 1);
+        // 填充之后的 SOI 是下一幅图像。
+        assert_eq!(count(&[[0x00, 0xFF].as_slice(), &jpeg].concat()), 2);
+        assert_eq!(
+            count(&[[0x00].as_slice(), &jpeg, &[0x56, 0xFF, 0xD8]].concat()),
+            2
+        );
+    }
+
+    #[test]
+    fn test_quantization_table_ids() {
+        let jpeg = encode_to_vec(&test_image(), &Default::default()).unwrap();
+        let expected = decode_to_image(&jpeg, &Default::default()).unwrap();
+
+        // 量化表按 ID 查找，与在 DQT 中出现的顺序无关。
+        let dqt = jpeg.windows(2).position(|w| w == [0xFF, 0xDB]).unwrap() + 4;
+        assert_eq!((jpeg[dqt], jpeg[dqt + 65]), (0, 1));
+        let mut swapped = jpeg[..dqt].to_vec();
+        swapped.extend(&jpeg[dqt + 65..dqt + 130]);
+        swapped.extend(&jpeg[dqt..dqt + 65]);
+        swapped.extend(&jpeg[dqt + 130..]);
+        assert!(decode_to_image(&swapped, &Default::default()).unwrap() == expected);
+    }
+
+    #[test]
+    fn test_arithmetic_coding() {
+        let options = EncodeOptions {
+            entropy_coding: EntropyCoding::Arithmetic,
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&test_image(), &options).unwrap();
+        let error = decode_to_image(&jpeg, &Default::default()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(error.to_string(), "Arithmetic coding is not supported");
+    }
+}
@@ -1,55 +1,122 @@
-use std::io;
-
-use bitvec::field::BitField;
-use bitvec::vec::BitVec;
-
+use super::bit_reader::BitReader;
+use super::bit_trace::CodedSymbol;
+use super::bit_trace::TraceEntry;
+use super::decode_progressive::CoefficientBuffer;
 use super::decode_step1::CompleteJpegData;
 use super::decode_step1::Component;
 use super::encode_step5::ZigzagDu;
-use super::encode_step6::CachedHuffmanTable;
+use super::encode_step6::JpegHuffmanTable;
+use super::encode_step6::SymbolSink;
+use std::io;
 
+/// 查表时直接预读的位数。不超过该长度的码字查一次表即可解码。
+const LOOKAHEAD_BITS: u32 = 9;
+
+/// 解码用的霍夫曼码表。利用范式霍夫曼编码的性质，同一长度的码字是连续的整数，
+/// 只需记录每个长度的最大码字和符号的偏移，见 JPEG 标准 F.2.2.3。
 #[derive(Debug)]
-pub struct DecodeZigzagMcuCollection {
-    pub width: usize,
-    pub height: usize,
-    pub components: Vec<Component>,
-    pub zigzag_dus: Vec<ZigzagDu>,
+pub struct DecodeHuffmanTable {
+    /// 预读 `LOOKAHEAD_BITS` 位后直接查到的 (符号, 码长)。码长为 0 表示码字更长。
+    lookup: Vec<(u8, u8)>,
+    /// 长度为 l 的最大码字，没有该长度的码字时为 -1。
+    max_code: [i32; 17],
+    /// 长度为 l 的码字加上该偏移即为符号在 `values` 中的下标。
+    value_offset: [i32; 17],
+    values: Vec<u8>,
 }
 
-struct DcDecoder<'a> {
-    pub sum: i16,
-    pub huffman_table: &'a CachedHuffmanTable,
-}
+impl JpegHuffmanTable {
+    pub fn to_decode_table(&self) -> DecodeHuffmanTable {
+        let mut lookup = vec![(0, 0); 1 << LOOKAHEAD_BITS];
+        let mut max_code = [-1; 17];
+        let mut value_offset = [0; 17];
 
-struct AcDecoder<'a> {
-    pub huffman_table: &'a CachedHuffmanTable,
+        let mut code = 0_i32;
+        let mut k = 0_i32;
+        for length in 1..=16 {
+            let count = self.codes[length - 1] as i32;
+            if count != 0 {
+                value_offset[length] = k - code;
+                for c in code..code + count {
+                    let symbol = self.values.get((c + k - code) as usize);
+                    if let (Some(&symbol), true) = (symbol, length as u32 <= LOOKAHEAD_BITS) {
+                        // 以该码字开头的所有预读结果都对应该符号。
+                        let shift = LOOKAHEAD_BITS - length as u32;
+                        let start = (c as usize) << shift;
+                        lookup[start..start + (1 << shift)].fill((symbol, length as u8));
+                    }
+                }
+                code += count;
+                k += count;
+                max_code[length] = code - 1;
+            }
+            code <<= 1;
+        }
+
+        DecodeHuffmanTable {
+            lookup,
+            max_code,
+            value_offset,
+            values: self.values.clone(),
+        }
+    }
 }
 
-fn entropy_decode_category(
-    scan: &BitVec,
-    offset: &mut usize,
-    huffman_table: &CachedHuffmanTable,
-) -> io::Result<u8> {
-    let ht = &huffman_table.0;
-    let mut symbol = None;
-    for (k, v) in ht {
-        if scan[*offset..].starts_with(&v) {
-            symbol = Some(*k);
-            *offset += v.len();
-            break;
-        }
-    }
-    if let Some(symbol) = symbol {
-        Ok(symbol)
-    } else {
+impl DecodeHuffmanTable {
+    /// 符号 `symbol` 的码长。表中没有该符号时返回 `None`。
+    pub fn code_length(&self, symbol: u8) -> Option<u8> {
+        self.code(symbol).map(|(_, length)| length)
+    }
+
+    /// 符号 `symbol` 的 (码字, 码长)。表中没有该符号时返回 `None`。
+    pub fn code(&self, symbol: u8) -> Option<(u16, u8)> {
+        let index = self.values.iter().position(|&value| value == symbol)? as i32;
+        // 同一长度的符号在 `values` 中连续，最后一个的下标为最大码字加上偏移。
+        (1..=16)
+            .filter(|&length| self.max_code[length] >= 0)
+            .find(|&length| index <= self.max_code[length] + self.value_offset[length])
+            .map(|length| ((index - self.value_offset[length]) as u16, length as u8))
+    }
+
+    /// 解码一个符号。霍夫曼码字至多 16 位。
+    pub fn decode(&self, reader: &mut BitReader) -> io::Result<u8> {
+        let peek = reader.peek_bits(16) as i32;
+
+        let (symbol, length) = self.lookup[(peek >> (16 - LOOKAHEAD_BITS)) as usize];
+        if length != 0 {
+            reader.skip_bits(length as u32)?;
+            return Ok(symbol);
+        }
+
+        for length in LOOKAHEAD_BITS as usize + 1..=16 {
+            let code = peek >> (16 - length);
+            if code <= self.max_code[length] {
+                let symbol = self.values.get((code + self.value_offset[length]) as usize);
+                if let Some(&symbol) = symbol {
+                    reader.skip_bits(length as u32)?;
+                    return Ok(symbol);
+                }
+                break;
+            }
+        }
         Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            "Fail to decode DC coefficient",
+            "Fail to decode a Huffman code",
         ))
     }
 }
 
-fn entropy_decode_value(scan: &BitVec, offset: &mut usize, category: u8) -> io::Result<i16> {
+struct DcDecoder<'a> {
+    pub sum: i16,
+    pub huffman_table: &'a DecodeHuffmanTable,
+}
+
+struct AcDecoder<'a> {
+    pub huffman_table: &'a DecodeHuffmanTable,
+}
+
+/// 读取类别为 `category` 的附加位，得到值。
+pub fn entropy_decode_value(reader: &mut BitReader, category: u8) -> io::Result<i16> {
     if category >> 4 != 0 {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
@@ -60,50 +127,53 @@ fn entropy_decode_value(scan: &BitVec, offset: &mut usize, category: u8) -> io::
     if category == 0 {
         return Ok(0);
     }
-    let mut bits = scan[*offset..*offset + category as usize].to_owned();
-    *offset += category as usize;
-    let is_positive = bits[0];
-    bits.reverse(); // MSB0 to LSB0.
-    let abs_bits = if is_positive {
-        bits
-    } else {
-        bits.iter().map(|v| !v).collect()
-    };
-    let abs_value = abs_bits.load::<u16>();
+    let bits = reader.read_bits(category)? as i32;
+    // 最高位为 1 表示正数，否则为负数，其绝对值为各位取反。
+    let is_positive = bits >> (category - 1) != 0;
     let value = if is_positive {
-        abs_value as i16
+        bits
     } else {
-        -(abs_value as i16)
+        bits - ((1 << category) - 1)
     };
-    Ok(value)
+    Ok(value as i16)
 }
 
 impl<'a> DcDecoder<'a> {
-    fn new(huffman_table: &'a CachedHuffmanTable) -> Self {
+    fn new(huffman_table: &'a DecodeHuffmanTable) -> Self {
         Self {
             sum: 0,
             huffman_table,
         }
     }
 
-    fn decode(&mut self, scan: &BitVec, offset: &mut usize) -> io::Result<i16> {
-        let category = entropy_decode_category(scan, offset, &self.huffman_table)?;
-        let diff = entropy_decode_value(scan, offset, category)?;
-        self.sum += diff;
+    fn decode(&mut self, reader: &mut BitReader, sink: &mut impl SymbolSink) -> io::Result<i16> {
+        let category = self.huffman_table.decode(reader)?;
+        let diff = entropy_decode_value(reader, category)?;
+        sink(0, category, diff);
+        self.sum = self.sum.checked_add(diff).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "DC coefficient overflowed")
+        })?;
         Ok(self.sum)
     }
 }
 
 impl<'a> AcDecoder<'a> {
-    fn new(huffman_table: &'a CachedHuffmanTable) -> Self {
+    fn new(huffman_table: &'a DecodeHuffmanTable) -> Self {
         Self { huffman_table }
     }
 
-    fn decode(&self, scan: &BitVec, offset: &mut usize, du: &mut [i16; 64]) -> io::Result<()> {
+    fn decode(
+        &self,
+        reader: &mut BitReader,
+        du: &mut [i16; 64],
+        sink: &mut impl SymbolSink,
+    ) -> io::Result<()> {
         let mut idx = 1;
         while idx < du.len() {
-            let symbol = entropy_decode_category(scan, offset, &self.huffman_table)?;
+            let symbol = self.huffman_table.decode(reader)?;
+            let start = idx as u8;
             if symbol == 0x00 {
+                sink(start, symbol, 0);
                 // EOB
                 while idx < du.len() {
                     du[idx] = 0;
@@ -113,6 +183,14 @@ impl<'a> AcDecoder<'a> {
             }
 
             let zrl = symbol >> 4;
+            let category = symbol & 0x0F;
+            // 类别为 0 的符号只有 EOB (0/0) 和 ZRL (F/0)。
+            if category == 0 && zrl != 0x0F {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid AC symbol 0x{:02X}", symbol),
+                ));
+            }
             for _ in 0..zrl {
                 if idx >= du.len() {
                     return Err(io::Error::new(
@@ -123,21 +201,21 @@ impl<'a> AcDecoder<'a> {
                 du[idx] = 0;
                 idx += 1;
             }
-            let category = symbol & 0x0F;
             if idx >= du.len() {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
                     "AC coefficients exceeded",
                 ));
             }
-            du[idx] = entropy_decode_value(scan, offset, category)?;
+            du[idx] = entropy_decode_value(reader, category)?;
+            sink(start, symbol, du[idx]);
             idx += 1;
         }
         Ok(())
     }
 }
 
-impl CompleteJpegData {
+impl CompleteJpegData<'_> {
     pub fn get_du_count(&self) -> usize {
         let max_h = self
             .components
@@ -168,51 +246,282 @@ impl CompleteJpegData {
     }
 }
 
-/// 第二步：解码熵编码，得到一系列 Zigzag 形式的 DU。
-pub fn decode_step2(jpeg_data: &CompleteJpegData) -> io::Result<DecodeZigzagMcuCollection> {
-    let mut zigzag_dus = vec![];
+impl CompleteJpegData<'_> {
+    /// MCU 的宽和高（像素）。
+    pub fn get_mcu_size(&self) -> (usize, usize) {
+        let max_h = self
+            .components
+            .iter()
+            .map(|c| c.horizontal_sampling_factor as usize)
+            .max()
+            .unwrap();
+        let max_v = self
+            .components
+            .iter()
+            .map(|c| c.vertical_sampling_factor as usize)
+            .max()
+            .unwrap();
+        (8 * max_h, 8 * max_v)
+    }
+
+    /// 每行 MCU 的个数和 MCU 的行数。
+    pub fn get_mcu_grid(&self) -> (usize, usize) {
+        let (hb, vb) = self.get_mcu_size();
+        (self.width.div_ceil(hb), self.height.div_ceil(vb))
+    }
+}
+
+/// 熵解码器，可以分多次解码 MCU，例如按条带解码时。DC 的差分预测值在多次解码之间保留，
+/// 只在每个 RSTn 处清零。有多个扫描时，系数已在第一步中解码，这里只按 MCU 的顺序取出。
+pub struct ScanDecoder<'a> {
+    components: &'a [Component],
+    reader: BitReader<'a>,
+    restart_interval: Option<u16>,
+    coefficients: Option<&'a CoefficientBuffer>,
+    mcus_per_row: usize,
+    dc_decoders: Vec<DcDecoder<'a>>,
+    /// 已解码的 MCU 数。
+    mcu_count: usize,
+    /// 调用 `enable_trace` 后记录每个符号。
+    trace: Option<Vec<TraceEntry>>,
+}
+
+impl<'a> ScanDecoder<'a> {
+    pub fn new(jpeg_data: &'a CompleteJpegData) -> Self {
+        Self {
+            components: &jpeg_data.components,
+            reader: BitReader::new(jpeg_data.scan),
+            restart_interval: jpeg_data.restart_interval,
+            coefficients: jpeg_data.coefficients.as_ref(),
+            mcus_per_row: jpeg_data.get_mcu_grid().0,
+            dc_decoders: jpeg_data
+                .components
+                .iter()
+                .map(|component| DcDecoder::new(&component.dc_huffman_table))
+                .collect(),
+            mcu_count: 0,
+            trace: None,
+        }
+    }
 
-    let scan = &jpeg_data.scan;
-    let mut dc_decoders = Vec::<DcDecoder>::new();
-    for component in &jpeg_data.components {
-        dc_decoders.push(DcDecoder::new(&component.dc_huffman_table));
+    /// 之后解码的每个符号都记录下来，由 `take_trace` 取出。只适合很小的图像。
+    pub fn enable_trace(&mut self) {
+        self.trace.get_or_insert_with(Vec::new);
     }
-    let du_count = jpeg_data.get_du_count();
 
-    let mut offset = 0;
-    let mut du_idx = 0;
-    while du_idx < du_count && offset < scan.len() {
-        // MCU。
-        for (i, component) in jpeg_data.components.iter().enumerate() {
+    /// 取出已记录的符号。
+    pub fn take_trace(&mut self) -> Vec<TraceEntry> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// 尚未解码的位数的上限，见 `BitReader::remaining_bits`。
+    pub fn remaining_bits(&self) -> usize {
+        self.reader.remaining_bits()
+    }
+
+    /// 解码下一个 MCU。每解码出一个 DU，就以分量的下标、DU 在该分量中的下标和 DU 调用 `f`。
+    pub fn decode_mcu(&mut self, mut f: impl FnMut(usize, usize, &ZigzagDu)) -> io::Result<()> {
+        if let Some(buffer) = self.coefficients {
+            return self.next_buffered_mcu(buffer, f);
+        }
+        // 每 `restart_interval` 个 MCU 之后是 RSTn，n 依次为 0~7。
+        if let Some(interval) = self.restart_interval.map(|n| n as usize) {
+            if self.mcu_count != 0 && self.mcu_count.is_multiple_of(interval) {
+                let n = (self.mcu_count / interval - 1) % 8;
+                self.reader.restart(n as u8)?;
+                for dc_decoder in &mut self.dc_decoders {
+                    dc_decoder.sum = 0;
+                }
+            }
+        }
+        if self.reader.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "DUs are not sufficient",
+            ));
+        }
+
+        for (i, component) in self.components.iter().enumerate() {
             // 一个分量连续存储 H * V 个 DU。
             let sf = component.horizontal_sampling_factor * component.vertical_sampling_factor;
-            for _ in 0..sf {
-                let mut du = [0; 64];
+            for j in 0..sf as usize {
+                let mut du = ZigzagDu([0; 64]);
+                let mcu = self.mcu_count;
+                let trace = &mut self.trace;
+                let mut sink = |position, symbol, value| {
+                    if let Some(trace) = trace {
+                        let table = match position {
+                            0 => &component.dc_huffman_table,
+                            _ => &component.ac_huffman_table,
+                        };
+                        let (code, code_length) = table.code(symbol).unwrap_or_default();
+                        trace.push(TraceEntry {
+                            mcu,
+                            component: i,
+                            du: j,
+                            symbol: CodedSymbol {
+                                position,
+                                value,
+                                symbol,
+                                code,
+                                code_length,
+                            },
+                        });
+                    }
+                };
 
                 // DC 系数。
-                du[0] = dc_decoders[i].decode(scan, &mut offset)?;
+                du.0[0] = self.dc_decoders[i].decode(&mut self.reader, &mut sink)?;
 
                 // AC 系数。
                 let ac_decoder = AcDecoder::new(&component.ac_huffman_table);
-                ac_decoder.decode(scan, &mut offset, &mut du)?;
+                ac_decoder.decode(&mut self.reader, &mut du.0, &mut sink)?;
 
-                zigzag_dus.push(ZigzagDu(du));
-                du_idx += 1;
+                f(i, j, &du);
             }
         }
+        self.mcu_count += 1;
+
+        Ok(())
     }
 
-    if du_idx < du_count {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "DUs are not sufficient",
-        ));
+    /// 从系数缓冲区中取出下一个 MCU，DU 的顺序与 `decode_mcu` 相同。
+    fn next_buffered_mcu(
+        &mut self,
+        buffer: &CoefficientBuffer,
+        mut f: impl FnMut(usize, usize, &ZigzagDu),
+    ) -> io::Result<()> {
+        let (mx, my) = (
+            self.mcu_count % self.mcus_per_row,
+            self.mcu_count / self.mcus_per_row,
+        );
+        for (i, component) in self.components.iter().enumerate() {
+            let h = component.horizontal_sampling_factor as usize;
+            let v = component.vertical_sampling_factor as usize;
+            for j in 0..h * v {
+                let du = buffer
+                    .get(i, mx * h + j % h, my * v + j / h)
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "DUs are not sufficient")
+                    })?;
+                f(i, j, du);
+            }
+        }
+        self.mcu_count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bitvec::field::BitField;
+
+    use super::super::decode_step1::decode_step1;
+    use super::super::decode_to_image;
+    use super::super::encode_step6::JpegBits;
+    use super::super::encode_step6::DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE;
+    use super::super::encode_to_vec;
+    use super::super::options::EncodeOptions;
+    use super::super::test_util::test_image;
+
+    #[test]
+    fn test_invalid_ac_symbol() {
+        // 只有一个码字 0，对应非法的符号 3/0。
+        let table = JpegHuffmanTable {
+            codes: [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            values: vec![0x30],
+        }
+        .to_decode_table();
+        let mut reader = BitReader::new(&[0x00]);
+        let mut du = [0; 64];
+
+        let result = AcDecoder::new(&table).decode(&mut reader, &mut du, &mut |_, _, _| {});
+        assert!(result.is_err());
     }
 
-    Ok(DecodeZigzagMcuCollection {
-        width: jpeg_data.width,
-        height: jpeg_data.height,
-        components: jpeg_data.components.clone(),
-        zigzag_dus,
-    })
+    #[test]
+    fn test_decode_table() {
+        // 依次编码所有符号，再逐个解码，包括超过预读长度的码字。
+        let table = &*DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE;
+        let cached = table.to_cached();
+        let mut bits = JpegBits::new();
+        for symbol in &table.values {
+            bits.extend_from_bitslice(&cached.0[symbol]);
+        }
+        let mut data = bits.into_vec();
+        // 按 JPEG 的规则填充 0x00。
+        data = data
+            .into_iter()
+            .flat_map(|b| if b == 0xFF { vec![0xFF, 0x00] } else { vec![b] })
+            .collect();
+
+        let decode_table = table.to_decode_table();
+        let mut reader = BitReader::new(&data);
+        for &symbol in &table.values {
+            assert_eq!(decode_table.decode(&mut reader).unwrap(), symbol);
+            let code = &cached.0[&symbol];
+            let length = code.len() as u8;
+            assert_eq!(decode_table.code_length(symbol), Some(length));
+            assert_eq!(
+                decode_table.code(symbol),
+                Some((code.load_be::<u16>(), length))
+            );
+        }
+        assert_eq!(decode_table.code_length(0xFF), None);
+    }
+
+    #[test]
+    fn test_restart_markers() {
+        let image = test_image();
+        let expected = decode_to_image(
+            &encode_to_vec(&image, &Default::default()).unwrap(),
+            &Default::default(),
+        )
+        .unwrap();
+        // 一共 9 个 MCU，间隔为 1 时 RSTn 的序号会回绕。
+        for interval in [1, 2, 4, 9, 100] {
+            let options = EncodeOptions {
+                restart_interval: Some(interval),
+                ..Default::default()
+            };
+            let jpeg = encode_to_vec(&image, &options).unwrap();
+            let decoded = decode_to_image(&jpeg, &Default::default()).unwrap();
+            assert!(decoded == expected, "{}", interval);
+
+            // RSTn 之前可以有填充的 0xFF。
+            let jpeg_data = decode_step1(&jpeg).unwrap();
+            let scan_start = jpeg.len() - 2 - jpeg_data.scan.len();
+            let mut filled = jpeg[..scan_start].to_vec();
+            for (i, &byte) in jpeg_data.scan.iter().enumerate() {
+                if i > 0 && jpeg_data.scan[i - 1] == 0xFF && (0xD0..=0xD7).contains(&byte) {
+                    filled.push(0xFF);
+                }
+                filled.push(byte);
+            }
+            filled.extend(&jpeg[jpeg.len() - 2..]);
+            let decoded = decode_to_image(&filled, &Default::default()).unwrap();
+            assert!(decoded == expected, "{}", interval);
+        }
+
+        // 去掉一个 RSTn。
+        let options = EncodeOptions {
+            restart_interval: Some(2),
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&image, &options).unwrap();
+        let rst = jpeg.windows(2).position(|w| w == [0xFF, 0xD0]).unwrap();
+        let mut missing = jpeg.clone();
+        missing.drain(rst..rst + 2);
+        assert!(decode_to_image(&missing, &Default::default()).is_err());
+    }
+
+    #[test]
+    fn test_value_past_end_of_scan() {
+        let mut reader = BitReader::new(&[0b1010_0111]);
+        assert_eq!(entropy_decode_value(&mut reader, 3).unwrap(), 5);
+        assert_eq!(entropy_decode_value(&mut reader, 2).unwrap(), -3);
+        assert!(entropy_decode_value(&mut reader, 4).is_err());
+    }
 }
@@ -1,17 +1,25 @@
-use std::f64::consts::PI;
 use std::io;
 
-use super::decode_step2::DecodeZigzagMcuCollection;
+use super::decode_step1::CompleteJpegData;
+use super::decode_step2::ScanDecoder;
 use super::encode_step2::Du;
 use super::encode_step3::DctDu;
+use super::encode_step3::DctFloat;
+use super::encode_step3::DctPrecision;
 use super::encode_step4::QuantizationTable;
 use super::encode_step4::QuantizedDu;
 use super::encode_step5::ZigzagDu;
+use super::int_dct::idct_ifast;
+use super::int_dct::idct_islow;
+use super::zigzag::from_zigzag;
 
 #[derive(Debug, Clone)]
 pub struct YuvComponent {
+    /// 一个样本覆盖的像素数。
     pub absolute_horizontal_sampling_factor: usize,
     pub absolute_vertical_sampling_factor: usize,
+    /// 填充后的平面宽度，即 `values` 中每行的样本数。
+    pub width: usize,
     pub values: Vec<u8>,
 }
 
@@ -26,47 +34,7 @@ pub struct DecodedYuvImage {
 
 impl ZigzagDu {
     pub fn to_quantized_du(&self) -> QuantizedDu {
-        let input = &self.0;
-        let mut ret = [[0; 8]; 8];
-
-        let mut x = 0;
-        let mut y = 0;
-        let mut idx = 0;
-
-        while idx < input.len() {
-            while idx < input.len() {
-                ret[x][y] = input[idx];
-                idx += 1;
-                // 优先处理 y == 7，因为有对角线。
-                if y == 7 {
-                    x += 1;
-                    break;
-                } else if x == 0 {
-                    y += 1;
-                    break;
-                } else {
-                    x -= 1;
-                    y += 1;
-                }
-            }
-            while idx < input.len() {
-                ret[x][y] = input[idx];
-                idx += 1;
-                // 优先处理 x == 7，因为有对角线。
-                if x == 7 {
-                    y += 1;
-                    break;
-                } else if y == 0 {
-                    x += 1;
-                    break;
-                } else {
-                    x += 1;
-                    y -= 1;
-                }
-            }
-        }
-
-        QuantizedDu(ret)
+        QuantizedDu(from_zigzag(&self.0))
     }
 }
 
@@ -78,7 +46,8 @@ impl QuantizedDu {
 
         for x in 0..8 {
             for y in 0..8 {
-                ret[x][y] = (input[x][y] * qt[x][y] as i16) as f64;
+                // 畸形数据的系数可能很大，用 32 位相乘避免溢出。
+                ret[x][y] = (input[x][y] as i32 * qt[x][y] as i32) as f64;
             }
         }
 
@@ -86,174 +55,210 @@ impl QuantizedDu {
     }
 }
 
-impl DctDu {
-    pub fn idct(&self) -> Du {
-        const N: usize = 8;
+/// 按定义计算 IDCT，不舍入。
+fn idct_samples_generic<T: DctFloat>(dct_du: &DctDu) -> [[f64; 8]; 8] {
+    const N: usize = 8;
 
-        let first_factor = (1.0 / N as f64).sqrt();
-        let others_factor = (2.0 / N as f64).sqrt();
+    let first_factor = T::from_f64((1.0 / N as f64).sqrt());
+    let others_factor = T::from_f64((2.0 / N as f64).sqrt());
+    let cos = T::cos_table();
 
-        let input = &self.0;
-        let mut one = [[0_f64; N]; N];
-        let mut ret = [[0_f64; N]; N];
+    let input = dct_du.0.map(|inner| inner.map(T::from_f64));
+    let mut one = [[T::default(); N]; N];
+    let mut ret = [[T::default(); N]; N];
 
-        for x in 0..N {
-            for y in 0..N {
-                for u in 0..N {
-                    one[x][y] += if u == 0 { first_factor } else { others_factor }
-                        * input[u][y]
-                        * (((2 * x + 1) * u) as f64 * PI / (2 * N) as f64).cos();
-                }
+    for x in 0..N {
+        for y in 0..N {
+            for u in 0..N {
+                one[x][y] +=
+                    if u == 0 { first_factor } else { others_factor } * input[u][y] * cos[u][x];
             }
         }
+    }
 
-        for y in 0..N {
-            for x in 0..N {
-                for v in 0..N {
-                    ret[x][y] += if v == 0 { first_factor } else { others_factor }
-                        * one[x][v]
-                        * (((2 * y + 1) * v) as f64 * PI / (2 * N) as f64).cos();
-                }
+    for y in 0..N {
+        for x in 0..N {
+            for v in 0..N {
+                ret[x][y] +=
+                    if v == 0 { first_factor } else { others_factor } * one[x][v] * cos[v][y];
             }
         }
-
-        Du(ret.map(|inner| inner.map(|it| it.round().clamp(-128.0, 127.0) as i8)))
     }
+
+    ret.map(|inner| inner.map(|it| it.to_f64()))
 }
 
-fn quantized_du_to_dus(
-    decode_zigzag_mcu_collection: &DecodeZigzagMcuCollection,
-    quantized_dus: &Vec<QuantizedDu>,
-) -> Vec<Du> {
-    let mut ret = vec![];
-    let mut idx = 0;
-    while idx < quantized_dus.len() {
-        for component in &decode_zigzag_mcu_collection.components {
-            let sf = component.horizontal_sampling_factor * component.vertical_sampling_factor;
-            for _ in 0..sf {
-                let quantized_du = &quantized_dus[idx];
-                let dct_du = quantized_du.to_dct_du(&component.quatization_table);
-                let du = dct_du.idct();
-                ret.push(du);
-                idx += 1;
-            }
+fn idct_generic<T: DctFloat>(dct_du: &DctDu) -> Du {
+    let samples = idct_samples_generic::<T>(dct_du);
+    Du(samples.map(|inner| inner.map(|it| it.round().clamp(-128.0, 127.0) as i16)))
+}
+
+impl DctDu {
+    pub fn idct(&self) -> Du {
+        self.idct_with(DctPrecision::F64)
+    }
+
+    /// 以 f64 计算 IDCT，结果不舍入也不截断，仍减去了 128。
+    pub fn idct_unrounded(&self) -> [[f64; 8]; 8] {
+        idct_samples_generic::<f64>(self)
+    }
+
+    /// 以指定的精度计算 IDCT。
+    pub fn idct_with(&self, precision: DctPrecision) -> Du {
+        match precision {
+            DctPrecision::F64 => idct_generic::<f64>(self),
+            DctPrecision::F32 => idct_generic::<f32>(self),
+            DctPrecision::Fast => idct_ifast(self),
+            DctPrecision::Accurate => idct_islow(self),
         }
     }
-    ret
 }
 
-fn make_decoded_yuv_image(
-    decode_zigzag_mcu_collection: &DecodeZigzagMcuCollection,
-    dus: &Vec<Du>,
+/// 按分量的采样因子分配填充后的 YUV 图像，高度为 `height` 的图像需要 `mcu_rows` 行 MCU。
+fn make_empty_yuv_image(
+    jpeg_data: &CompleteJpegData,
+    height: usize,
+    mcu_rows: usize,
 ) -> io::Result<DecodedYuvImage> {
-    if decode_zigzag_mcu_collection.components.len() != 3 {
+    if jpeg_data.components.len() != 3 {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
             "The count of the components is not supported",
         ));
     }
 
-    let max_h = decode_zigzag_mcu_collection
-        .components
-        .iter()
-        .map(|c| c.horizontal_sampling_factor as usize)
-        .max()
-        .unwrap();
-    let hb = 8 * max_h;
-    let max_v = decode_zigzag_mcu_collection
-        .components
-        .iter()
-        .map(|c| c.vertical_sampling_factor as usize)
-        .max()
-        .unwrap();
-    let vb = 8 * max_v;
-
-    let padded_width = (decode_zigzag_mcu_collection.width + hb - 1) / hb * hb;
-    let padded_height = (decode_zigzag_mcu_collection.height + vb - 1) / vb * vb;
-
-    let mut yuv_components = vec![];
-    for c in &decode_zigzag_mcu_collection.components {
+    let (hb, vb) = jpeg_data.get_mcu_size();
+    let (mcus_per_row, _) = jpeg_data.get_mcu_grid();
+    let max_h = hb / 8;
+    let max_v = vb / 8;
+    let padded_width = mcus_per_row * hb;
+    let padded_height = mcu_rows * vb;
+
+    let mut yuv_components = jpeg_data.components.iter().map(|c| {
         let hs = max_h / c.horizontal_sampling_factor as usize;
         let vs = max_v / c.vertical_sampling_factor as usize;
-        let cw = padded_width / hs;
-        let ch = padded_height / vs;
-        let mut values = vec![];
-        values.resize(cw * ch, Default::default());
-        yuv_components.push(YuvComponent {
+        YuvComponent {
             absolute_horizontal_sampling_factor: hs,
             absolute_vertical_sampling_factor: vs,
-            values,
-        })
-    }
+            width: padded_width / hs,
+            values: vec![0; padded_width / hs * (padded_height / vs)],
+        }
+    });
 
-    let mut idx = 0;
-    // y start.
-    for ys in (0..padded_height).step_by(vb) {
-        // x start.
-        for xs in (0..padded_width).step_by(hb) {
-            for c in &mut yuv_components {
-                // Horizontal sampling factor (absolute).
-                let hs = c.absolute_horizontal_sampling_factor;
-                // Vertical sampling factor (absolute).
-                let vs = c.absolute_vertical_sampling_factor;
-                // Component width.
-                let cw = padded_width / hs;
-                let values = &mut c.values;
-                for y_du_idx in 0..(max_v / vs) {
-                    for x_du_idx in 0..(max_h / hs) {
-                        let du = &dus[idx];
-                        for y_in_du in 0..8 {
-                            for x_in_du in 0..8 {
-                                let y = ys / vs + 8 * y_du_idx + y_in_du;
-                                let x = xs / hs + 8 * x_du_idx + x_in_du;
-                                values[y * cw + x] =
-                                    (du.0[y_in_du][x_in_du] as u8).wrapping_add(128);
-                            }
-                        }
-                        idx += 1;
-                    }
-                }
-            }
+    Ok(DecodedYuvImage {
+        width: jpeg_data.width,
+        height,
+        y: yuv_components.next().unwrap(),
+        u: yuv_components.next().unwrap(),
+        v: yuv_components.next().unwrap(),
+    })
+}
+
+/// DU 在分量平面中的位置。
+pub(super) struct DuPosition {
+    /// 分量的序号。
+    pub component: usize,
+    /// 分量平面的宽度。
+    pub plane_width: usize,
+    /// DU 左上角在平面中的横纵坐标。
+    pub x: usize,
+    pub y: usize,
+}
+
+/// 从熵解码器中依次解码 `mcu_rows` 行 MCU，把反 Zigzag 和反量化后的每个 DU 连同它的位置交给 `f`。
+pub(super) fn for_each_dct_du(
+    decoder: &mut ScanDecoder,
+    jpeg_data: &CompleteJpegData,
+    mcu_rows: usize,
+    mut f: impl FnMut(DuPosition, DctDu),
+) -> io::Result<()> {
+    let (mcus_per_row, _) = jpeg_data.get_mcu_grid();
+
+    for my in 0..mcu_rows {
+        for mx in 0..mcus_per_row {
+            decoder.decode_mcu(|i, j, zigzag_du| {
+                let component = &jpeg_data.components[i];
+                let h = component.horizontal_sampling_factor as usize;
+                let v = component.vertical_sampling_factor as usize;
+                let dct_du = zigzag_du
+                    .to_quantized_du()
+                    .to_dct_du(&component.quatization_table);
+                // MCU 中的 DU 按行排列。
+                let position = DuPosition {
+                    component: i,
+                    plane_width: mcus_per_row * 8 * h,
+                    x: (mx * h + j % h) * 8,
+                    y: (my * v + j / h) * 8,
+                };
+                f(position, dct_du);
+            })?;
         }
     }
+    Ok(())
+}
 
-    if idx != dus.len() {
-        println!("{} {}", idx, dus.len());
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Not all DUs were consumed",
-        ));
-    }
+/// 从熵解码器中依次解码 `mcu_rows` 行 MCU。每解码出一个 DU 就反 Zigzag、反量化并计算 IDCT，
+/// 直接写入分量平面，不保存中间结果。`height` 为这些 MCU 行中有效的像素行数。
+#[tracing::instrument(skip_all, fields(mcu_rows = mcu_rows, height = height))]
+pub fn decode_mcu_rows(
+    decoder: &mut ScanDecoder,
+    jpeg_data: &CompleteJpegData,
+    mcu_rows: usize,
+    height: usize,
+    precision: DctPrecision,
+) -> io::Result<DecodedYuvImage> {
+    let mut image = make_empty_yuv_image(jpeg_data, height, mcu_rows)?;
+
+    for_each_dct_du(decoder, jpeg_data, mcu_rows, |position, dct_du| {
+        let du = dct_du.idct_with(precision);
+        let plane = match position.component {
+            0 => &mut image.y.values,
+            1 => &mut image.u.values,
+            _ => &mut image.v.values,
+        };
+        for (y_in_du, row) in du.0.iter().enumerate() {
+            let start = (position.y + y_in_du) * position.plane_width + position.x;
+            for (value, &sample) in plane[start..start + 8].iter_mut().zip(row) {
+                *value = (sample + 128) as u8;
+            }
+        }
+    })?;
 
-    Ok(DecodedYuvImage {
-        width: decode_zigzag_mcu_collection.width,
-        height: decode_zigzag_mcu_collection.height,
-        y: yuv_components[0].clone(),
-        u: yuv_components[1].clone(),
-        v: yuv_components[2].clone(),
-    })
+    Ok(image)
 }
 
-/// 第三步：直接解码为填充的 YUV 图像。
+/// 第二步和第三步：熵解码，同时直接解码为填充的 YUV 图像。
+#[tracing::instrument(skip_all, fields(width = jpeg_data.width, height = jpeg_data.height, ?precision))]
 pub fn decode_step3(
-    decode_zigzag_mcu_collection: &DecodeZigzagMcuCollection,
+    jpeg_data: &CompleteJpegData,
+    precision: DctPrecision,
 ) -> io::Result<DecodedYuvImage> {
-    let quantized_dus: Vec<QuantizedDu> = decode_zigzag_mcu_collection
-        .zigzag_dus
-        .iter()
-        .map(|it| it.to_quantized_du())
-        .collect();
-    let dus = quantized_du_to_dus(decode_zigzag_mcu_collection, &quantized_dus);
-    let decoded_yuv_image = make_decoded_yuv_image(decode_zigzag_mcu_collection, &dus)?;
-    Ok(decoded_yuv_image)
+    let (_, mcu_rows) = jpeg_data.get_mcu_grid();
+    let mut decoder = ScanDecoder::new(jpeg_data);
+    decode_mcu_rows(
+        &mut decoder,
+        jpeg_data,
+        mcu_rows,
+        jpeg_data.height,
+        precision,
+    )
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    use super::super::coefficients::BlockGrid;
+    use super::super::coefficients::CoefficientImage;
+    use super::super::decode_to_image;
     use super::super::encode_step3::dct;
     use super::super::encode_step4::LUMINANCE_QUANTIZATION_TABLE;
+    use super::super::encode_step6::DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE;
+    use super::super::encode_step6::DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE;
+    use super::super::encode_step7::FrameLayout;
+    use super::super::test_util::code;
+    use super::super::test_util::segment;
+    use super::super::test_util::to_scan_bytes;
 
     #[test]
     fn test_zigzag() {
@@ -315,7 +320,7 @@ mod test {
 
     #[test]
     fn test_idct() {
-        const DU_TABLE: [[i8; 8]; 8] = [
+        const DU_TABLE: [[i16; 8]; 8] = [
             [-76, -73, -67, -62, -58, -67, -64, -55],
             [-65, -69, -73, -38, -19, -43, -59, -56],
             [-66, -69, -60, -15, 16, -24, -62, -55],
@@ -331,4 +336,156 @@ mod test {
 
         assert_eq!(idct.0, DU_TABLE);
     }
+
+    #[test]
+    fn test_idct_precision() {
+        const DU_TABLE: [[i16; 8]; 8] = [
+            [-76, -73, -67, -62, -58, -67, -64, -55],
+            [-65, -69, -73, -38, -19, -43, -59, -56],
+            [-66, -69, -60, -15, 16, -24, -62, -55],
+            [-65, -70, -57, -6, 26, -22, -58, -59],
+            [-61, -67, -60, -24, -2, -40, -60, -58],
+            [-49, -63, -68, -58, -51, -60, -70, -53],
+            [-43, -57, -64, -69, -73, -67, -63, -45],
+            [-41, -49, -59, -60, -63, -52, -50, -34],
+        ];
+
+        let dct_du_table = dct(&Du(DU_TABLE));
+        let idct = dct_du_table.idct_with(DctPrecision::F32);
+
+        assert_eq!(idct.0, DU_TABLE);
+
+        // 整数 IDCT 的输入是整数，先把系数舍入。
+        let rounded = DctDu(dct_du_table.0.map(|row| row.map(f64::round)));
+        let reference = rounded.idct();
+        for (precision, tolerance) in [(DctPrecision::Accurate, 1), (DctPrecision::Fast, 2)] {
+            let idct = rounded.idct_with(precision);
+            for (row, expected) in idct.0.iter().zip(reference.0) {
+                for (&value, expected) in row.iter().zip(expected) {
+                    assert!((value - expected).abs() <= tolerance);
+                }
+            }
+        }
+    }
+
+    /// 64x32 的图像，Y 的块各不相同，色度只有 DC 且每块不同。
+    fn coefficient_image(
+        (h, v): (usize, usize),
+        chroma: impl Fn(usize, usize) -> i16,
+    ) -> CoefficientImage {
+        let mut grids = [(8, 4), (8 / h, 4 / v), (8 / h, 4 / v)].map(|(w, h)| BlockGrid::new(w, h));
+        for y in 0..4 {
+            for x in 0..8 {
+                let block = grids[0].get_mut(x, y);
+                block[0][0] = (x * 8 + y * 3) as i16 - 20;
+                block[0][1] = x as i16 - 4;
+                block[1][0] = 2 - y as i16;
+            }
+        }
+        for (i, grid) in grids.iter_mut().enumerate().skip(1) {
+            let sign = if i == 1 { 1 } else { -1 };
+            for y in 0..grid.height {
+                for x in 0..grid.width {
+                    grid.get_mut(x, y)[0][0] = chroma(x, y) * sign;
+                }
+            }
+        }
+        CoefficientImage {
+            width: 64,
+            height: 32,
+            layout: FrameLayout {
+                sampling_factors: [(h as u8, v as u8), (1, 1), (1, 1)],
+                ..Default::default()
+            },
+            grids,
+            metadata: vec![],
+            restart_interval: None,
+        }
+    }
+
+    #[test]
+    fn test_sampling_factors() {
+        // 色度的块只有 DC，上采样时复制样本，与把每个块复制为 h x v 个块的 YUV444 图像相同。
+        let chroma = |x: usize, y: usize| (x * 5 + y * 7) as i16 - 12;
+        for factors in [(1, 1), (2, 1), (1, 2), (2, 2), (4, 1)] {
+            let (h, v) = factors;
+            let jpeg = coefficient_image(factors, chroma).to_jpeg();
+            let expected = coefficient_image((1, 1), |x, y| chroma(x / h, y / v)).to_jpeg();
+            let decode = |jpeg: &[u8]| decode_to_image(jpeg, &Default::default()).unwrap();
+            assert!(decode(&jpeg) == decode(&expected), "{:?}", factors);
+        }
+    }
+
+    #[test]
+    fn test_uniform_sampling_factors() {
+        // 8x16 的图像，三个分量的采样因子都是 2x2，一个 16x16 的 MCU 中依次是 4 个 Y、4 个 Cb、4 个 Cr，
+        // 左边的一列 DU 与采样因子都是 1x1 时相同。只有 DC，量化表全为 1。
+        let dc_values = [[40, 80, -40, 0], [32, -32, 64, 0], [0, 96, -64, 32]];
+        let dc_table = &*DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE;
+        let ac_table = &*DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE;
+        let mut bits = String::new();
+        for values in dc_values {
+            let mut pred = 0;
+            for value in values {
+                let diff: i16 = value - pred;
+                pred = value;
+                let category = 16 - diff.unsigned_abs().leading_zeros();
+                bits += &code(dc_table, category as u8);
+                let extra = if diff >= 0 {
+                    diff
+                } else {
+                    diff + (1 << category) - 1
+                };
+                if category > 0 {
+                    bits += &format!("{:0width$b}", extra, width = category as usize);
+                }
+                bits += &code(ac_table, 0x00);
+            }
+        }
+
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(segment(0xDB, &[[0].as_slice(), &[1; 64]].concat()));
+        let sof = [8, 0, 16, 0, 8, 3, 1, 0x22, 0, 2, 0x22, 0, 3, 0x22, 0];
+        let sof_start = jpeg.len() + 4;
+        jpeg.extend(segment(0xC0, &sof));
+        for (class, table) in [(0x00, dc_table), (0x10, ac_table)] {
+            jpeg.extend(segment(
+                0xC4,
+                &[[class].as_slice(), &table.codes, &table.values].concat(),
+            ));
+        }
+        jpeg.extend(segment(0xDA, &[3, 1, 0, 2, 0, 3, 0, 0, 63, 0]));
+        jpeg.extend(to_scan_bytes(&bits));
+        jpeg.extend([0xFF, 0xD9]);
+
+        let mut expected = CoefficientImage {
+            width: 8,
+            height: 16,
+            layout: FrameLayout {
+                sampling_factors: [(1, 1); 3],
+                quantization_tables: [
+                    QuantizationTable([[1; 8]; 8]),
+                    QuantizationTable([[1; 8]; 8]),
+                ],
+            },
+            grids: [0; 3].map(|_| BlockGrid::new(1, 2)),
+            metadata: vec![],
+            restart_interval: None,
+        };
+        for (grid, values) in expected.grids.iter_mut().zip(dc_values) {
+            for (j, value) in values.into_iter().enumerate().step_by(2) {
+                grid.get_mut(0, j / 2)[0][0] = value;
+            }
+        }
+        let decode = |jpeg: &[u8]| decode_to_image(jpeg, &Default::default());
+        assert!(decode(&jpeg).unwrap() == decode(&expected.to_jpeg()).unwrap());
+
+        // 采样因子为 0，或者 3 和 2 同时出现。
+        for (y_factors, chroma_factors) in [(0x02, 0x22), (0x32, 0x22)] {
+            let mut invalid = jpeg.clone();
+            invalid[sof_start + 7] = y_factors;
+            invalid[sof_start + 10] = chroma_factors;
+            assert!(decode(&invalid).is_err());
+        }
+    }
 }
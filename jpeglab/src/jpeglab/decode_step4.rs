@@ -1,30 +1,28 @@
 use std::io;
+use std::path::Path;
 
 use image::ImageBuffer;
 use image::ImageFormat;
+use image::RgbImage;
 
 use super::decode_step3::DecodedYuvImage;
-use super::encode_step1::yuv_to_rgb;
+use super::encode_step1::ColorConversion;
+use super::encode_step1::YuvToRgbTable;
+use super::options::DecodeOptions;
+use super::sharpen::unsharp_mask;
 
-/// 第四步：将 YUV 转换为 RGB，输出 BMP 文件。
-/// 文件名为 out.bmp。
-pub fn decode_step4(decoded_yuv_image: &DecodedYuvImage) -> io::Result<()> {
-    // 使用外部库完成输出 BMP。
+/// 将 YUV 转换为 RGB。
+#[tracing::instrument(skip_all, fields(width = decoded_yuv_image.width, height = decoded_yuv_image.height))]
+pub fn to_rgb_image(
+    decoded_yuv_image: &DecodedYuvImage,
+    color_conversion: &ColorConversion,
+) -> RgbImage {
     let mut img = ImageBuffer::new(
         decoded_yuv_image.width as u32,
         decoded_yuv_image.height as u32,
     );
 
-    let max_h = *[
-        decoded_yuv_image.y.absolute_horizontal_sampling_factor,
-        decoded_yuv_image.u.absolute_horizontal_sampling_factor,
-        decoded_yuv_image.v.absolute_horizontal_sampling_factor,
-    ]
-    .iter()
-    .max()
-    .unwrap();
-    let hb = 8 * max_h;
-    let padded_width = (decoded_yuv_image.width + hb - 1) / hb * hb;
+    let table = YuvToRgbTable::new(color_conversion);
 
     for (x, y, pixel) in img.enumerate_pixels_mut() {
         let x = x as usize;
@@ -35,27 +33,56 @@ pub fn decode_step4(decoded_yuv_image: &DecodedYuvImage) -> io::Result<()> {
         let vs = c.absolute_vertical_sampling_factor;
         let yc = y / vs;
         let xc = x / hs;
-        let y_ = c.values[yc * padded_width / hs + xc];
+        let y_ = c.values[yc * c.width + xc];
 
         let c = &decoded_yuv_image.u;
         let hs = c.absolute_horizontal_sampling_factor;
         let vs = c.absolute_vertical_sampling_factor;
         let yc = y / vs;
         let xc = x / hs;
-        let u = c.values[yc * padded_width / hs + xc];
+        let u = c.values[yc * c.width + xc];
 
         let c = &decoded_yuv_image.v;
         let hs = c.absolute_horizontal_sampling_factor;
         let vs = c.absolute_vertical_sampling_factor;
         let yc = y / vs;
         let xc = x / hs;
-        let v = c.values[yc * padded_width / hs + xc];
+        let v = c.values[yc * c.width + xc];
 
-        let (r, g, b) = yuv_to_rgb(y_, u, v);
+        let (r, g, b) = table.convert(y_, u, v);
         *pixel = image::Rgb([r, g, b]);
     }
 
-    img.save_with_format("out.bmp", ImageFormat::Bmp)
+    img
+}
+
+/// 要求时锐化转换后的 RGB 图像。
+pub fn sharpen_output(img: RgbImage, options: &DecodeOptions) -> RgbImage {
+    match &options.sharpening {
+        Some(sharpening) => unsharp_mask(&img, sharpening),
+        None => img,
+    }
+}
+
+/// 第四步：将 YUV 转换为 RGB，要求时锐化，输出 BMP 文件。
+/// 文件名为 out.bmp。
+pub fn decode_step4(
+    decoded_yuv_image: &DecodedYuvImage,
+    options: &DecodeOptions,
+) -> io::Result<()> {
+    let img = to_rgb_image(decoded_yuv_image, &options.color_conversion);
+    save_bmp(&sharpen_output(img, options))
+}
+
+/// 将解码结果保存为 out.bmp。
+pub fn save_bmp(img: &RgbImage) -> io::Result<()> {
+    save_bmp_to(img, Path::new("out.bmp"))
+}
+
+/// 将解码结果保存为 BMP 文件 `path`。
+pub fn save_bmp_to(img: &RgbImage, path: &Path) -> io::Result<()> {
+    // 使用外部库完成输出 BMP。
+    img.save_with_format(path, ImageFormat::Bmp)
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Fail to write to BMP file"))?;
 
     Ok(())
@@ -0,0 +1,230 @@
+//! 比较两个 JPEG 文件的标记段，找出帧头、量化表、霍夫曼表、重新同步间隔和元数据的差别，不解码图像。
+//! 用于找出两个编码器的输出表现不同的原因。
+
+use std::io;
+
+use super::info::read_info;
+use super::segments::read_segments;
+use super::segments::Segment;
+use super::tables::read_tables;
+use super::tables::TableSet;
+
+/// ((类别, ID), (各长度的码字数, 符号))。
+type HuffmanEntry = ((u8, u8), ([u8; 16], Vec<u8>));
+
+/// 只从标记段中得到的、需要比较的信息。
+struct Markers {
+    /// SOF 的类型，例如 SOF0 为 0xC0。
+    frame_type: Option<u8>,
+    /// DRI 中的重新同步间隔，没有 DRI 时为 0。
+    restart_interval: u16,
+    /// APPn 和 COM 段的描述，按出现顺序。
+    metadata: Vec<String>,
+}
+
+/// 描述一个元数据段：标记、标识和长度。
+fn describe(segment: &Segment) -> String {
+    let name = match segment.marker {
+        0xFE => "COM".to_string(),
+        marker => format!("APP{}", marker - 0xE0),
+    };
+    // APPn 的内容以 NUL 结尾的标识开头，例如 "Exif"、"JFIF"。
+    let identifier = match segment.data.iter().position(|&v| v == 0) {
+        Some(end) if segment.marker != 0xFE && end <= 32 => {
+            String::from_utf8_lossy(&segment.data[..end]).into_owned()
+        }
+        _ => String::new(),
+    };
+    if identifier.is_empty() {
+        format!("{} ({} bytes)", name, segment.data.len())
+    } else {
+        format!("{} {} ({} bytes)", name, identifier, segment.data.len())
+    }
+}
+
+fn read_markers(jpeg: &[u8]) -> io::Result<Markers> {
+    let mut ret = Markers {
+        frame_type: None,
+        restart_interval: 0,
+        metadata: vec![],
+    };
+    for segment in read_segments(jpeg)? {
+        match segment.marker {
+            0xC0..=0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => {
+                ret.frame_type = Some(segment.marker)
+            }
+            0xDD => {
+                let data = segment.data.get(..2).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Invalid DRI block")
+                })?;
+                ret.restart_interval = u16::from_be_bytes([data[0], data[1]]);
+            }
+            0xE0..=0xEF | 0xFE => ret.metadata.push(describe(&segment)),
+            _ => {}
+        }
+    }
+    Ok(ret)
+}
+
+/// 键为 `key` 的最后一个值。
+fn find<K: PartialEq, V>(items: &[(K, V)], key: K) -> Option<&V> {
+    items.iter().rev().find(|(k, _)| *k == key).map(|(_, v)| v)
+}
+
+/// 按键比较两组值，`format` 描述两个不同的值。
+fn diff_keyed<K: PartialEq + Copy, V: PartialEq>(
+    ret: &mut Vec<String>,
+    what: impl Fn(K) -> String,
+    a: &[(K, V)],
+    b: &[(K, V)],
+    format: impl Fn(&V, &V) -> String,
+) {
+    let mut keys: Vec<K> = vec![];
+    for (key, _) in a.iter().chain(b) {
+        if !keys.contains(key) {
+            keys.push(*key);
+        }
+    }
+    for key in keys {
+        match (find(a, key), find(b, key)) {
+            (Some(_), None) => ret.push(format!("{}: only in the first file", what(key))),
+            (None, Some(_)) => ret.push(format!("{}: only in the second file", what(key))),
+            (Some(x), Some(y)) if x != y => ret.push(format!("{}: {}", what(key), format(x, y))),
+            _ => {}
+        }
+    }
+}
+
+/// 比较两个 JPEG 文件，每个差别一行。没有差别时返回空。
+/// 同一个 ID 的表定义了多次时，比较最后一次定义的表。
+pub fn diff_markers(a: &[u8], b: &[u8]) -> io::Result<Vec<String>> {
+    let mut ret = vec![];
+    let (info_a, info_b) = (read_info(a)?, read_info(b)?);
+    if (info_a.width, info_a.height) != (info_b.width, info_b.height) {
+        ret.push(format!(
+            "dimensions: {}x{} vs {}x{}",
+            info_a.width, info_a.height, info_b.width, info_b.height
+        ));
+    }
+    if info_a.sampling_factors != info_b.sampling_factors {
+        ret.push(format!(
+            "sampling factors: {:?} vs {:?}",
+            info_a.sampling_factors, info_b.sampling_factors
+        ));
+    }
+
+    let (markers_a, markers_b) = (read_markers(a)?, read_markers(b)?);
+    if markers_a.frame_type != markers_b.frame_type {
+        let name = |t: Option<u8>| t.map_or("none".to_string(), |t| format!("SOF{}", t - 0xC0));
+        ret.push(format!(
+            "frame type: {} vs {}",
+            name(markers_a.frame_type),
+            name(markers_b.frame_type)
+        ));
+    }
+
+    let (tables_a, tables_b) = (read_tables(a)?, read_tables(b)?);
+    let quantization = |tables: &TableSet| -> Vec<(u8, [[u16; 8]; 8])> {
+        tables
+            .quantization_tables
+            .iter()
+            .map(|(id, table)| (*id, table.0))
+            .collect()
+    };
+    diff_keyed(
+        &mut ret,
+        |id| format!("quantization table {}", id),
+        &quantization(&tables_a),
+        &quantization(&tables_b),
+        |x, y| {
+            let pairs = x.iter().flatten().zip(y.iter().flatten());
+            let count = pairs.filter(|(p, q)| p != q).count();
+            let sum = |t: &[[u16; 8]; 8]| t.iter().flatten().map(|&v| v as u32).sum::<u32>();
+            format!(
+                "{} of 64 values differ, sums {} vs {}",
+                count,
+                sum(x),
+                sum(y)
+            )
+        },
+    );
+    let huffman = |tables: &TableSet| -> Vec<HuffmanEntry> {
+        tables
+            .huffman_tables
+            .iter()
+            .map(|(class, id, table)| ((*class, *id), (table.codes, table.values.clone())))
+            .collect()
+    };
+    diff_keyed(
+        &mut ret,
+        |(class, id)| {
+            format!(
+                "{} Huffman table {}",
+                if class == 0 { "DC" } else { "AC" },
+                id
+            )
+        },
+        &huffman(&tables_a),
+        &huffman(&tables_b),
+        |(codes_x, _), (codes_y, _)| {
+            if codes_x != codes_y {
+                format!("code length counts {:?} vs {:?}", codes_x, codes_y)
+            } else {
+                "same code lengths, different symbols".to_string()
+            }
+        },
+    );
+
+    if markers_a.restart_interval != markers_b.restart_interval {
+        ret.push(format!(
+            "restart interval: {} vs {}",
+            markers_a.restart_interval, markers_b.restart_interval
+        ));
+    }
+    if markers_a.metadata != markers_b.metadata {
+        ret.push(format!(
+            "metadata: [{}] vs [{}]",
+            markers_a.metadata.join(", "),
+            markers_b.metadata.join(", ")
+        ));
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::RgbImage;
+
+    use super::super::encode_step4::QuantizationPreset;
+    use super::super::encode_to_vec;
+    use super::super::metadata::XmpPacket;
+    use super::super::options::EncodeOptions;
+    use super::super::test_util::test_image;
+
+    #[test]
+    fn test_diff_markers() {
+        let image = test_image();
+        let jpeg = encode_to_vec(&image, &EncodeOptions::default()).unwrap();
+        assert!(diff_markers(&jpeg, &jpeg).unwrap().is_empty());
+
+        let other = encode_to_vec(
+            &RgbImage::new(40, 21),
+            &EncodeOptions {
+                quantization: QuantizationPreset::Flat,
+                xmp: Some(XmpPacket::new("<x:xmpmeta/>".to_string()).unwrap()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let diff = diff_markers(&jpeg, &other).unwrap();
+        assert_eq!(diff[0], "dimensions: 37x21 vs 40x21");
+        assert!(diff[1].starts_with("quantization table 0: "));
+        assert!(diff[2].starts_with("quantization table 1: "));
+        // 霍夫曼表都是标准的。
+        assert_eq!(diff.len(), 4);
+        assert!(diff[3].starts_with("metadata: [APP0 JFIF (14 bytes)] vs [APP0 JFIF (14 bytes), "));
+        assert!(diff[3].contains("APP1 http://ns.adobe.com/xap/1.0/ ("));
+    }
+}
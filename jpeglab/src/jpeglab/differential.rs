@@ -0,0 +1,230 @@
+//! 差分测试：同一个 JPEG 文件分别由 jpeglab 和 jpeg-decoder 解码，逐步比较结果，找出最先出现差异的一步。
+//! jpeg-decoder 不提供系数，因此按它能输出的结果分为四步：文件头、Y 平面（熵解码、反量化和 IDCT）、
+//! 上采样后的色度平面、转换后的 RGB。某一步的差异超过阈值而之前各步都没有时，问题就出在这一步。
+
+use std::fmt;
+use std::io;
+use std::io::Cursor;
+use std::ops::Range;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::RgbImage;
+use jpeg_decoder::ColorTransform;
+use jpeg_decoder::Decoder;
+use jpeg_decoder::PixelFormat;
+
+use super::decode_step1::decode_step1;
+use super::decode_step3::decode_step3;
+use super::decode_step3::YuvComponent;
+use super::decode_step4::to_rgb_image;
+use super::encode_to_vec;
+use super::options::DecodeOptions;
+use super::options::EncodeOptions;
+
+/// Y 平面只差 IDCT 的精度。
+const LUMA_TOLERANCE: u8 = 2;
+/// jpeg-decoder 对色度做线性插值，jpeglab 直接复制，因此色度和 RGB 只比较平均差异。
+const CHROMA_MEAN_TOLERANCE: f64 = 2.0;
+const RGB_MEAN_TOLERANCE: f64 = 3.0;
+
+/// 比较的各步，按解码顺序排列。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Header,
+    Luma,
+    Chroma,
+    Rgb,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Header => "header",
+            Stage::Luma => "entropy decoding, dequantization and IDCT (Y plane)",
+            Stage::Chroma => "chroma upsampling (Cb and Cr planes)",
+            Stage::Rgb => "color conversion (RGB)",
+        })
+    }
+}
+
+/// 两个平面之间的差异。
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PlaneDiff {
+    pub max: u8,
+    pub mean: f64,
+    /// 第一个差异最大的样本的位置 (x, y)。
+    pub worst: (usize, usize),
+}
+
+impl PlaneDiff {
+    /// `theirs` 为交错的三个通道，比较 `channels` 中的各个通道。`ours(x, y, channel)` 给出对应的样本。
+    fn new(
+        width: usize,
+        height: usize,
+        channels: Range<usize>,
+        ours: impl Fn(usize, usize, usize) -> u8,
+        theirs: &[u8],
+    ) -> Self {
+        let mut ret = Self::default();
+        let mut sum = 0_u64;
+        for y in 0..height {
+            for x in 0..width {
+                for channel in channels.clone() {
+                    let diff = ours(x, y, channel).abs_diff(theirs[(y * width + x) * 3 + channel]);
+                    sum += diff as u64;
+                    if diff > ret.max {
+                        ret.max = diff;
+                        ret.worst = (x, y);
+                    }
+                }
+            }
+        }
+        ret.mean = sum as f64 / (width * height * channels.len()) as f64;
+        ret
+    }
+}
+
+impl fmt::Display for PlaneDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "max {} at ({}, {}), mean {:.3}",
+            self.max, self.worst.0, self.worst.1, self.mean
+        )
+    }
+}
+
+/// 一个文件的比较结果。文件头不一致时不再比较像素。
+#[derive(Debug, Clone, Default)]
+pub struct DifferentialReport {
+    /// 文件头中不一致的字段。
+    pub header: Vec<String>,
+    pub luma: Option<PlaneDiff>,
+    pub chroma: Option<PlaneDiff>,
+    pub rgb: Option<PlaneDiff>,
+}
+
+impl DifferentialReport {
+    /// 第一个差异超过阈值的步骤。
+    pub fn first_divergence(&self) -> Option<Stage> {
+        if !self.header.is_empty() {
+            return Some(Stage::Header);
+        }
+        if self.luma.is_some_and(|d| d.max > LUMA_TOLERANCE) {
+            return Some(Stage::Luma);
+        }
+        if self.chroma.is_some_and(|d| d.mean > CHROMA_MEAN_TOLERANCE) {
+            return Some(Stage::Chroma);
+        }
+        if self.rgb.is_some_and(|d| d.mean > RGB_MEAN_TOLERANCE) {
+            return Some(Stage::Rgb);
+        }
+        None
+    }
+}
+
+impl fmt::Display for DifferentialReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for mismatch in &self.header {
+            writeln!(f, "{}: {}", Stage::Header, mismatch)?;
+        }
+        for (stage, diff) in [
+            (Stage::Luma, self.luma),
+            (Stage::Chroma, self.chroma),
+            (Stage::Rgb, self.rgb),
+        ] {
+            if let Some(diff) = diff {
+                writeln!(f, "{}: {}", stage, diff)?;
+            }
+        }
+        match self.first_divergence() {
+            Some(stage) => write!(f, "first divergence: {}", stage),
+            None => write!(f, "no divergence"),
+        }
+    }
+}
+
+fn reference_decode(jpeg: &[u8], transform: ColorTransform) -> io::Result<Vec<u8>> {
+    let mut decoder = Decoder::new(jpeg);
+    decoder.set_color_transform(transform);
+    decoder
+        .decode()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// 分量平面中 (x, y) 处的样本，与 `to_rgb_image` 的取法相同。
+fn sample(component: &YuvComponent, x: usize, y: usize) -> u8 {
+    let hs = component.absolute_horizontal_sampling_factor;
+    let vs = component.absolute_vertical_sampling_factor;
+    component.values[y / vs * component.width + x / hs]
+}
+
+/// 用两个解码器解码同一个文件并比较。只支持 jpeglab 能解码的三个分量的基线 JPEG。
+pub fn compare_decoders(jpeg: &[u8], options: &DecodeOptions) -> io::Result<DifferentialReport> {
+    let mut report = DifferentialReport::default();
+
+    let jpeg_data = decode_step1(jpeg)?;
+    let mut decoder = Decoder::new(jpeg);
+    if let Err(e) = decoder.read_info() {
+        report
+            .header
+            .push(format!("reference rejects the file: {}", e));
+        return Ok(report);
+    }
+    let info = decoder.info().unwrap();
+    if (info.width as usize, info.height as usize) != (jpeg_data.width, jpeg_data.height) {
+        report.header.push(format!(
+            "size {}x{}, reference {}x{}",
+            jpeg_data.width, jpeg_data.height, info.width, info.height
+        ));
+    }
+    if info.pixel_format != PixelFormat::RGB24 {
+        report.header.push(format!(
+            "{} components, reference pixel format {:?}",
+            jpeg_data.components.len(),
+            info.pixel_format
+        ));
+    }
+    if !report.header.is_empty() {
+        return Ok(report);
+    }
+
+    let (width, height) = (jpeg_data.width, jpeg_data.height);
+    let ours = decode_step3(&jpeg_data, options.dct_precision)?;
+    // 0.3 版的 `ColorTransform::None` 不交错输出各分量，`RGB` 则原样交错输出，不做颜色转换。
+    let theirs = reference_decode(jpeg, ColorTransform::RGB)?;
+    let components = [&ours.y, &ours.u, &ours.v];
+    let ours_sample = |x, y, channel: usize| sample(components[channel], x, y);
+    report.luma = Some(PlaneDiff::new(width, height, 0..1, ours_sample, &theirs));
+    report.chroma = Some(PlaneDiff::new(width, height, 1..3, ours_sample, &theirs));
+
+    let ours = to_rgb_image(&ours, &options.color_conversion);
+    let theirs = reference_decode(jpeg, ColorTransform::YCbCr)?;
+    report.rgb = Some(PlaneDiff::new(
+        width,
+        height,
+        0..3,
+        |x, y, channel| ours.get_pixel(x as u32, y as u32)[channel],
+        &theirs,
+    ));
+
+    Ok(report)
+}
+
+/// 同一幅图像分别由 jpeglab 和 image 库编码，两个文件都用两个解码器解码并比较。
+/// 前者检查 jpeglab 的编码器，后者检查 jpeglab 的解码器。
+pub fn compare_encoders(
+    image: &RgbImage,
+    encode_options: &EncodeOptions,
+    decode_options: &DecodeOptions,
+) -> io::Result<[(&'static str, DifferentialReport); 2]> {
+    let ours = encode_to_vec(image, encode_options)?;
+    let mut theirs = vec![];
+    JpegEncoder::new_with_quality(Cursor::new(&mut theirs), 90)
+        .encode_image(image)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok([
+        ("jpeglab", compare_decoders(&ours, decode_options)?),
+        ("reference", compare_decoders(&theirs, decode_options)?),
+    ])
+}
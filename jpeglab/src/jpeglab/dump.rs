@@ -0,0 +1,178 @@
+//! 把编码各步的完整结果写入文件，便于离线检查每一步。
+//! 第一步的三个平面写为 PGM，之后各步的 DU 按分量写为小端的二进制文件，
+//! 最后写出 steps.json 说明每个文件的内容。
+
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use super::encode_step1::MyYuvImage;
+use super::encode_step1::PlaneLayout;
+use super::encode_step2::ComponentDus;
+use super::encode_step2::Du;
+use super::encode_step3::DctDu;
+use super::encode_step4::QuantizedDu;
+use super::encode_step5::ZigzagDu;
+
+/// 可以写入文件的 DU。每个 DU 依次写出 64 个值。
+pub trait DumpDu {
+    /// 值的类型，写在 steps.json 中。
+    const DTYPE: &'static str;
+    /// 值的顺序，写在 steps.json 中。
+    const ORDER: &'static str = "natural";
+
+    fn write_to(&self, out: &mut Vec<u8>);
+}
+
+impl DumpDu for Du {
+    const DTYPE: &'static str = "i16le";
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend(self.0.as_flattened().iter().flat_map(|v| v.to_le_bytes()));
+    }
+}
+
+impl DumpDu for DctDu {
+    const DTYPE: &'static str = "f64le";
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend(self.0.as_flattened().iter().flat_map(|v| v.to_le_bytes()));
+    }
+}
+
+impl DumpDu for QuantizedDu {
+    const DTYPE: &'static str = "i16le";
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend(self.0.as_flattened().iter().flat_map(|v| v.to_le_bytes()));
+    }
+}
+
+impl DumpDu for ZigzagDu {
+    const DTYPE: &'static str = "i16le";
+    const ORDER: &'static str = "zigzag";
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend(self.0.iter().flat_map(|v| v.to_le_bytes()));
+    }
+}
+
+/// 依次写出各步的结果，最后由 `finish` 写出 steps.json。
+pub struct StepDumper {
+    dir: PathBuf,
+    /// steps.json 中每个文件的说明。
+    entries: Vec<String>,
+}
+
+impl StepDumper {
+    /// 目录不存在时创建。
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            entries: vec![],
+        })
+    }
+
+    fn write_plane(&mut self, name: &str, plane: &[u8], layout: PlaneLayout) -> io::Result<()> {
+        let mut data = format!("P5\n{} {}\n255\n", layout.width, layout.height).into_bytes();
+        for y in 0..layout.height {
+            let start = layout.index(0, y);
+            data.extend_from_slice(&plane[start..start + layout.width]);
+        }
+        std::fs::write(self.dir.join(name), data)?;
+        self.entries.push(format!(
+            "{{\"file\": \"{}\", \"format\": \"pgm\", \"width\": {}, \"height\": {}}}",
+            name, layout.width, layout.height
+        ));
+        Ok(())
+    }
+
+    /// 第一步：填充后的 Y、U、V 平面。
+    pub fn yuv_image(&mut self, yuv_image: &MyYuvImage) -> io::Result<()> {
+        self.write_plane("step1_y.pgm", &yuv_image.y, yuv_image.y_layout())?;
+        self.write_plane("step1_u.pgm", &yuv_image.u, yuv_image.chroma_layout())?;
+        self.write_plane("step1_v.pgm", &yuv_image.v, yuv_image.chroma_layout())
+    }
+
+    /// 第二步及之后：按分量写出所有 DU，顺序与 `ComponentDus` 中相同。
+    pub fn dus<T: DumpDu>(&mut self, step: &str, dus: &ComponentDus<T>) -> io::Result<()> {
+        for (component, dus) in [("y", &dus.y), ("cb", &dus.cb), ("cr", &dus.cr)] {
+            let name = format!("{}_{}.bin", step, component);
+            let mut data = vec![];
+            for du in dus.iter() {
+                du.write_to(&mut data);
+            }
+            std::fs::write(self.dir.join(&name), data)?;
+            self.entries.push(format!(
+                "{{\"file\": \"{}\", \"format\": \"du\", \"dtype\": \"{}\", \"order\": \"{}\", \"count\": {}}}",
+                name,
+                T::DTYPE,
+                T::ORDER,
+                dus.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// 写出 steps.json。
+    pub fn finish(self) -> io::Result<()> {
+        let json = format!("[\n  {}\n]\n", self.entries.join(",\n  "));
+        std::fs::write(self.dir.join("steps.json"), json)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::arena::ScratchArena;
+    use super::super::encode_step1::encode_step1;
+    use super::super::encode_step2::encode_step2;
+    use super::super::encode_step3::encode_step3;
+    use super::super::encode_step4::encode_step4;
+    use super::super::encode_step5::encode_step5;
+    use super::super::options::EncodeOptions;
+
+    #[test]
+    fn test_dump_steps() {
+        let dir = std::env::temp_dir().join(format!("jpeglab_dump_{}", std::process::id()));
+        let image = image::RgbImage::from_fn(37, 21, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        let arena = &mut ScratchArena::default();
+        let yuv_image = encode_step1(&image, &Default::default(), Default::default()).unwrap();
+        let mcu_collection = encode_step2(&yuv_image, arena).unwrap();
+        let dct_mcu_collection = encode_step3(&mcu_collection, Default::default(), arena).unwrap();
+        let quantized_mcu_collection = encode_step4(
+            &dct_mcu_collection,
+            &EncodeOptions::default().quantization_tables(),
+            arena,
+        )
+        .unwrap();
+        let zigzag_mcu_collection = encode_step5(&quantized_mcu_collection, arena).unwrap();
+
+        let mut dumper = StepDumper::new(&dir).unwrap();
+        dumper.yuv_image(&yuv_image).unwrap();
+        dumper
+            .dus("step4", &quantized_mcu_collection.quantized_dus)
+            .unwrap();
+        dumper
+            .dus("step5", &zigzag_mcu_collection.zigzag_dus)
+            .unwrap();
+        dumper.finish().unwrap();
+
+        // 填充到 48x24，色度平面宽度减半。
+        let header = "P5\n24 24\n255\n";
+        let pgm = std::fs::read(dir.join("step1_u.pgm")).unwrap();
+        assert!(pgm.starts_with(header.as_bytes()));
+        assert_eq!(pgm.len(), header.len() + 24 * 24);
+        // 3x3 个 MCU，每个 MCU 两个 Y 的 DU。
+        let step4_y = std::fs::read(dir.join("step4_y.bin")).unwrap();
+        assert_eq!(step4_y.len(), 18 * 64 * 2);
+        let steps = std::fs::read_to_string(dir.join("steps.json")).unwrap();
+        assert!(steps.contains(
+            "{\"file\": \"step5_cr.bin\", \"format\": \"du\", \"dtype\": \"i16le\", \"order\": \"zigzag\", \"count\": 9}"
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,196 +1,681 @@
-use std::cmp::min;
 use std::io;
 
-use image::GrayImage;
-use image::ImageBuffer;
-use image::ImageFormat;
 use image::RgbImage;
+use rayon::prelude::*;
 
-/// 我的 YUV 格式，总是使用 YUV422。
-/// 图像已被填充为可被 MCU 整除（宽度为 16 的倍数，高度为 8 的倍数）。
+use super::decode16::Rgb16Image;
+
+/// 一个分量平面在内存中的布局。平面按行存储，每行占 `stride` 个元素。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaneLayout {
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+}
+
+impl PlaneLayout {
+    /// 平面共占多少个元素。
+    pub fn size(&self) -> usize {
+        self.height * self.stride
+    }
+
+    /// 平面中第 `y` 行第 `x` 列的下标。
+    pub fn index(&self, x: usize, y: usize) -> usize {
+        debug_assert!(x < self.width && y < self.height);
+        y * self.stride + x
+    }
+}
+
+/// 色度的子采样方式。Cb 和 Cr 的采样因子总是 (1, 1)，由 Y 的采样因子决定色度的分辨率。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Subsampling {
+    /// 色度横向减半，MCU 为 16x8，含两个 Y。
+    #[default]
+    #[value(name = "422", alias = "4:2:2")]
+    Yuv422,
+    /// 色度横向为四分之一，MCU 为 32x8，含四个 Y。
+    #[value(name = "411", alias = "4:1:1")]
+    Yuv411,
+    /// 色度纵向减半，MCU 为 8x16，含上下两个 Y。
+    #[value(name = "440", alias = "4:4:0")]
+    Yuv440,
+}
+
+impl Subsampling {
+    /// Y 的 (水平, 垂直) 采样因子，也是一个色度样本在两个方向上覆盖的像素数。
+    pub fn luma_factors(self) -> (usize, usize) {
+        match self {
+            Subsampling::Yuv422 => (2, 1),
+            Subsampling::Yuv411 => (4, 1),
+            Subsampling::Yuv440 => (1, 2),
+        }
+    }
+
+    /// Y、Cb、Cr 的 (水平, 垂直) 采样因子，写入 SOF0。
+    pub fn sampling_factors(self) -> [(u8, u8); 3] {
+        let (h, v) = self.luma_factors();
+        [(h as u8, v as u8), (1, 1), (1, 1)]
+    }
+
+    /// MCU 的宽和高（像素）。
+    pub fn mcu_size(self) -> (usize, usize) {
+        let (h, v) = self.luma_factors();
+        (8 * h, 8 * v)
+    }
+}
+
+/// 每个样本的位数。12 位的样本用于扩展的顺序编码（SOF1），只能由 16 位的图像编码。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SamplePrecision {
+    /// 8 位，基线编码。
+    #[default]
+    #[value(name = "8")]
+    Eight,
+    /// 12 位。
+    #[value(name = "12")]
+    Twelve,
+}
+
+impl SamplePrecision {
+    /// 写入 SOF 的精度。
+    pub fn bits(self) -> u8 {
+        match self {
+            SamplePrecision::Eight => 8,
+            SamplePrecision::Twelve => 12,
+        }
+    }
+}
+
+/// YUV 平面中的样本：8 位为 `u8`，12 位为 `u16`。
+pub trait Sample: Copy + Default + Send + Sync {
+    /// 无符号数转有符号数时减去的值，即 2 的 (精度 - 1) 次方。
+    const CENTER: i16;
+
+    fn to_i16(self) -> i16;
+}
+
+impl Sample for u8 {
+    const CENTER: i16 = 128;
+
+    fn to_i16(self) -> i16 {
+        self as i16
+    }
+}
+
+impl Sample for u16 {
+    const CENTER: i16 = 2048;
+
+    fn to_i16(self) -> i16 {
+        self as i16
+    }
+}
+
+/// 我的 YUV 格式，色度按 `subsampling` 子采样。
+/// 图像已被填充为可被 MCU 整除（YUV422 时宽度为 16 的倍数，高度为 8 的倍数）。
 /// 用 `self.padded_width()` 和 `self.padded_height()` 获取填充后的大小。
+/// 用 `self.y_layout()` 和 `self.chroma_layout()` 获取各个平面的布局。
+/// 样本默认为 8 位，12 位时为 `MyYuvImage<u16>`。
 #[derive(Debug)]
-pub struct MyYuvImage {
+pub struct MyYuvImage<T = u8> {
     pub original_width: usize,
     pub original_height: usize,
-    /// `self.padded_height() * self.padded_width()`
-    pub y: Vec<u8>,
-    /// `self.padded_height() * (self.padded_width() / 2)`
-    pub u: Vec<u8>,
-    /// `self.padded_height() * (self.padded_width() / 2)`
-    pub v: Vec<u8>,
+    pub subsampling: Subsampling,
+    /// 布局为 `self.y_layout()`。
+    pub y: Vec<T>,
+    /// 布局为 `self.chroma_layout()`。
+    pub u: Vec<T>,
+    /// 布局为 `self.chroma_layout()`。
+    pub v: Vec<T>,
+    /// 由 RGB 转换而来时使用的参数。
+    pub color_conversion: ColorConversion,
 }
 
-impl MyYuvImage {
+impl<T: Sample> MyYuvImage<T> {
     pub fn padded_width(&self) -> usize {
-        (self.original_width + 15) / 16 * 16
+        let (mcu_width, _) = self.subsampling.mcu_size();
+        self.original_width.div_ceil(mcu_width) * mcu_width
     }
 
     pub fn padded_height(&self) -> usize {
-        (self.original_height + 7) / 8 * 8
+        let (_, mcu_height) = self.subsampling.mcu_size();
+        self.original_height.div_ceil(mcu_height) * mcu_height
     }
 
-    pub fn new(width: usize, height: usize) -> Self {
-        let mut ret = MyYuvImage {
+    /// Y 平面的布局，与填充后的图像一样大。
+    pub fn y_layout(&self) -> PlaneLayout {
+        PlaneLayout {
+            width: self.padded_width(),
+            height: self.padded_height(),
+            stride: self.padded_width(),
+        }
+    }
+
+    /// U 和 V 平面的布局。例如 YUV422 时水平方向 2 个像素共用一个色度采样。
+    pub fn chroma_layout(&self) -> PlaneLayout {
+        let (h, v) = self.subsampling.luma_factors();
+        PlaneLayout {
+            width: self.padded_width() / h,
+            height: self.padded_height() / v,
+            stride: self.padded_width() / h,
+        }
+    }
+
+    pub fn new(width: usize, height: usize, subsampling: Subsampling) -> Self {
+        let mut ret = Self {
             original_width: width,
             original_height: height,
+            subsampling,
             y: vec![],
             u: vec![],
             v: vec![],
+            color_conversion: ColorConversion::default(),
         };
 
-        let y_size = ret.padded_height() * ret.padded_width();
-        ret.y.resize(y_size, u8::default());
-        let uv_size = ret.padded_height() * (ret.padded_width() / 2);
-        ret.u.resize(uv_size, u8::default());
-        ret.v.resize(uv_size, u8::default());
+        // 直接按填充后的尺寸分配。
+        ret.y = vec![T::default(); ret.y_layout().size()];
+        ret.u = vec![T::default(); ret.chroma_layout().size()];
+        ret.v = vec![T::default(); ret.chroma_layout().size()];
 
         ret
     }
 }
 
-/// Generated by ChatGPT 4.
-pub fn rgb_to_yuv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
-    let r = r as f32;
-    let g = g as f32;
-    let b = b as f32;
+/// YCbCr 与 RGB 之间的转换矩阵。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorMatrix {
+    /// ITU-R BT.601，JFIF 规定使用的矩阵。
+    #[default]
+    Bt601,
+    /// ITU-R BT.709，高清视频使用的矩阵。JFIF 解码器并不知道使用了这个矩阵，解码时需要同样指定。
+    Bt709,
+}
+
+impl ColorMatrix {
+    /// RGB 到 YCbCr 的系数，依次为 Y、Cb、Cr 对 R、G、B 的系数。
+    fn forward(&self) -> [[f32; 3]; 3] {
+        match self {
+            ColorMatrix::Bt601 => [
+                [0.299, 0.587, 0.114],
+                [-0.1687, -0.3313, 0.5],
+                [0.5, -0.4187, -0.0813],
+            ],
+            ColorMatrix::Bt709 => [
+                [0.2126, 0.7152, 0.0722],
+                [-0.1146, -0.3854, 0.5],
+                [0.5, -0.4542, -0.0458],
+            ],
+        }
+    }
 
-    let y = 0.299 * r + 0.587 * g + 0.114 * b;
-    let cb = -0.1687 * r - 0.3313 * g + 0.5 * b + 128.0;
-    let cr = 0.5 * r - 0.4187 * g - 0.0813 * b + 128.0;
+    /// YCbCr 到 RGB 的系数，依次为 Cr 对 R、Cb 对 G、Cr 对 G、Cb 对 B 的系数。
+    fn backward(&self) -> [f32; 4] {
+        match self {
+            ColorMatrix::Bt601 => [1.402, 0.344136, 0.714136, 1.772],
+            ColorMatrix::Bt709 => [1.5748, 0.1873, 0.4681, 1.8556],
+        }
+    }
+}
 
-    (
-        y.round().clamp(0.0, 255.0) as u8,
-        cb.round().clamp(0.0, 255.0) as u8,
-        cr.round().clamp(0.0, 255.0) as u8,
-    )
+/// YCbCr 的取值范围。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorRange {
+    /// 全范围，Y、Cb、Cr 都取 0~255，JFIF 规定使用的范围。
+    #[default]
+    Full,
+    /// 有限范围（studio swing），Y 取 16~235，Cb、Cr 取 16~240，视频中常用。
+    Limited,
 }
 
-/// Generated by ChatGPT 4.
-pub fn yuv_to_rgb(y: u8, cb: u8, cr: u8) -> (u8, u8, u8) {
-    let y = y as f32;
-    let cb = cb as f32 - 128.0;
-    let cr = cr as f32 - 128.0;
+impl ColorRange {
+    /// 依次为 Y 和 Cb、Cr 的取值范围。
+    fn bounds(&self) -> ((f32, f32), (f32, f32)) {
+        match self {
+            ColorRange::Full => ((0.0, 255.0), (0.0, 255.0)),
+            ColorRange::Limited => ((16.0, 235.0), (16.0, 240.0)),
+        }
+    }
+
+    /// 12 位时的取值范围。有限范围的边界是 8 位时的 16 倍，与色度的偏移 2048 一致。
+    fn bounds12(&self) -> ((f32, f32), (f32, f32)) {
+        match self {
+            ColorRange::Full => ((0.0, 4095.0), (0.0, 4095.0)),
+            ColorRange::Limited => ((256.0, 3760.0), (256.0, 3840.0)),
+        }
+    }
+}
+
+/// YCbCr 与 RGB 互相转换的参数。编码和解码应当使用相同的参数。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ColorConversion {
+    pub matrix: ColorMatrix,
+    pub range: ColorRange,
+}
+
+impl ColorConversion {
+    /// RGB 转换为 YCbCr：按矩阵的系数求出亮度和色差，再缩放到取值范围内，色度的偏移为 128。
+    pub fn rgb_to_yuv(&self, r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+        let r = r as f32;
+        let g = g as f32;
+        let b = b as f32;
+        let [ky, kcb, kcr] = self.matrix.forward();
+        let ((y_min, y_max), (c_min, c_max)) = self.range.bounds();
+        let y_scale = (y_max - y_min) / 255.0;
+        let c_scale = (c_max - c_min) / 255.0;
+
+        let y = (ky[0] * r + ky[1] * g + ky[2] * b) * y_scale + y_min;
+        let cb = (kcb[0] * r + kcb[1] * g + kcb[2] * b) * c_scale + 128.0;
+        let cr = (kcr[0] * r + kcr[1] * g + kcr[2] * b) * c_scale + 128.0;
+
+        (
+            y.round().clamp(y_min, y_max) as u8,
+            cb.round().clamp(c_min, c_max) as u8,
+            cr.round().clamp(c_min, c_max) as u8,
+        )
+    }
+
+    /// 16 位的 RGB 转换为 12 位的 YCbCr。RGB 先不舍入地缩放到 0~4095，色度的偏移为 2048。
+    pub fn rgb16_to_yuv12(&self, r: u16, g: u16, b: u16) -> (u16, u16, u16) {
+        let scale = 4095.0 / 65535.0;
+        let r = r as f32 * scale;
+        let g = g as f32 * scale;
+        let b = b as f32 * scale;
+        let [ky, kcb, kcr] = self.matrix.forward();
+        let ((y_min, y_max), (c_min, c_max)) = self.range.bounds12();
+        let y_scale = (y_max - y_min) / 4095.0;
+        let c_scale = (c_max - c_min) / 4095.0;
+
+        let y = (ky[0] * r + ky[1] * g + ky[2] * b) * y_scale + y_min;
+        let cb = (kcb[0] * r + kcb[1] * g + kcb[2] * b) * c_scale + 2048.0;
+        let cr = (kcr[0] * r + kcr[1] * g + kcr[2] * b) * c_scale + 2048.0;
+
+        (
+            y.round().clamp(y_min, y_max) as u16,
+            cb.round().clamp(c_min, c_max) as u16,
+            cr.round().clamp(c_min, c_max) as u16,
+        )
+    }
+
+    /// YCbCr 转换为 RGB，是 `rgb_to_yuv` 的逆变换，结果舍入并截断到 0~255。
+    pub fn yuv_to_rgb(&self, y: u8, cb: u8, cr: u8) -> (u8, u8, u8) {
+        let [r, g, b] = self.yuv_to_rgb_f32(y as f32, cb as f32, cr as f32);
+        (
+            r.round().clamp(0.0, 255.0) as u8,
+            g.round().clamp(0.0, 255.0) as u8,
+            b.round().clamp(0.0, 255.0) as u8,
+        )
+    }
+
+    /// 与 `yuv_to_rgb` 相同，但输入和输出都不舍入，输出也不截断到 0~255。
+    pub fn yuv_to_rgb_f32(&self, y: f32, cb: f32, cr: f32) -> [f32; 3] {
+        let [r_cr, g_cb, g_cr, b_cb] = self.matrix.backward();
+        // 超出范围的输入先截断，再拉伸到 0~255。
+        let ((y_min, y_max), (c_min, c_max)) = self.range.bounds();
+        let y_scale = (y_max - y_min) / 255.0;
+        let c_scale = (c_max - c_min) / 255.0;
+        let y = (y.clamp(y_min, y_max) - y_min) / y_scale;
+        let cb = (cb.clamp(c_min, c_max) - 128.0) / c_scale;
+        let cr = (cr.clamp(c_min, c_max) - 128.0) / c_scale;
+
+        [y + r_cr * cr, y - g_cb * cb - g_cr * cr, y + b_cb * cb]
+    }
+}
 
-    let r = y + 1.402 * cr;
-    let g = y - 0.344136 * cb - 0.714136 * cr;
-    let b = y + 1.772 * cb;
+/// 查找表中定点数的小数位数。
+const LUT_FRACTION_BITS: u32 = 16;
 
-    (
-        r.round().clamp(0.0, 255.0) as u8,
-        g.round().clamp(0.0, 255.0) as u8,
-        b.round().clamp(0.0, 255.0) as u8,
-    )
+/// 将实数转换为定点数。
+fn to_fixed(value: f32) -> i32 {
+    (value * (1 << LUT_FRACTION_BITS) as f32).round() as i32
 }
 
-/// 第一步：输入 RGB 的图像，输出 YUV422 的图像。
-/// YUV 的公式基于 ITU-R BT.601 标准。
-pub fn encode_step1(image: &RgbImage) -> io::Result<MyYuvImage> {
-    let (width, height) = image.dimensions();
+/// 将定点数四舍五入为整数。
+fn from_fixed(value: i32) -> i32 {
+    (value + (1 << (LUT_FRACTION_BITS - 1))) >> LUT_FRACTION_BITS
+}
+
+/// RGB 转换为 YCbCr 的查找表。每个系数对每个取值的贡献都预先算好，
+/// 逐像素的转换只需要查表、相加和移位。结果与 `ColorConversion::rgb_to_yuv` 最多相差 1。
+pub struct RgbToYuvTable {
+    /// 依次为 Y、Cb、Cr 中 R、G、B 的贡献，偏移量计入 B 的贡献中。
+    tables: [[[i32; 256]; 3]; 3],
+    y_bounds: (i32, i32),
+    c_bounds: (i32, i32),
+}
+
+impl RgbToYuvTable {
+    pub fn new(color_conversion: &ColorConversion) -> Self {
+        let forward = color_conversion.matrix.forward();
+        let ((y_min, y_max), (c_min, c_max)) = color_conversion.range.bounds();
+        let scales = [
+            (y_max - y_min) / 255.0,
+            (c_max - c_min) / 255.0,
+            (c_max - c_min) / 255.0,
+        ];
+        let offsets = [y_min, 128.0, 128.0];
+
+        let mut tables = [[[0; 256]; 3]; 3];
+        for (i, table) in tables.iter_mut().enumerate() {
+            for (j, channel) in table.iter_mut().enumerate() {
+                for (value, entry) in channel.iter_mut().enumerate() {
+                    let mut contribution = forward[i][j] * scales[i] * value as f32;
+                    if j == 2 {
+                        contribution += offsets[i];
+                    }
+                    *entry = to_fixed(contribution);
+                }
+            }
+        }
+
+        Self {
+            tables,
+            y_bounds: (y_min as i32, y_max as i32),
+            c_bounds: (c_min as i32, c_max as i32),
+        }
+    }
+
+    pub fn convert(&self, r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+        let [r, g, b] = [r as usize, g as usize, b as usize];
+        let sum = |t: &[[i32; 256]; 3]| from_fixed(t[0][r] + t[1][g] + t[2][b]);
+        let (y_min, y_max) = self.y_bounds;
+        let (c_min, c_max) = self.c_bounds;
+
+        (
+            sum(&self.tables[0]).clamp(y_min, y_max) as u8,
+            sum(&self.tables[1]).clamp(c_min, c_max) as u8,
+            sum(&self.tables[2]).clamp(c_min, c_max) as u8,
+        )
+    }
+}
+
+/// YCbCr 转换为 RGB 的查找表。超出范围的截断也计入表中。
+/// 结果与 `ColorConversion::yuv_to_rgb` 最多相差 1。
+pub struct YuvToRgbTable {
+    y: [i32; 256],
+    r_cr: [i32; 256],
+    g_cb: [i32; 256],
+    g_cr: [i32; 256],
+    b_cb: [i32; 256],
+}
+
+impl YuvToRgbTable {
+    pub fn new(color_conversion: &ColorConversion) -> Self {
+        let [r_cr, g_cb, g_cr, b_cb] = color_conversion.matrix.backward();
+        let ((y_min, y_max), (c_min, c_max)) = color_conversion.range.bounds();
+        let y_scale = (y_max - y_min) / 255.0;
+        let c_scale = (c_max - c_min) / 255.0;
+
+        let mut ret = Self {
+            y: [0; 256],
+            r_cr: [0; 256],
+            g_cb: [0; 256],
+            g_cr: [0; 256],
+            b_cb: [0; 256],
+        };
+        for value in 0..256 {
+            let y = ((value as f32).clamp(y_min, y_max) - y_min) / y_scale;
+            let c = ((value as f32).clamp(c_min, c_max) - 128.0) / c_scale;
+            ret.y[value] = to_fixed(y);
+            ret.r_cr[value] = to_fixed(r_cr * c);
+            ret.g_cb[value] = to_fixed(-g_cb * c);
+            ret.g_cr[value] = to_fixed(-g_cr * c);
+            ret.b_cb[value] = to_fixed(b_cb * c);
+        }
+        ret
+    }
+
+    pub fn convert(&self, y: u8, cb: u8, cr: u8) -> (u8, u8, u8) {
+        let [y, cb, cr] = [y as usize, cb as usize, cr as usize];
+        let y = self.y[y];
+
+        (
+            from_fixed(y + self.r_cr[cr]).clamp(0, 255) as u8,
+            from_fixed(y + self.g_cb[cb] + self.g_cr[cr]).clamp(0, 255) as u8,
+            from_fixed(y + self.b_cb[cb]).clamp(0, 255) as u8,
+        )
+    }
+}
+
+/// JPEG 图像的最大宽度和高度。
+pub const MAX_DIMENSION: u32 = u16::MAX as u32;
+
+/// 检查图像的尺寸能否编码为 JPEG。
+pub fn check_dimensions(width: u32, height: u32) -> io::Result<()> {
     if width == 0 || height == 0 {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
             "The image is empty",
         ));
     }
+    // SOF0 用 16 位存储宽和高。
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "The image is {}x{}, but JPEG supports at most {}x{}",
+                width, height, MAX_DIMENSION, MAX_DIMENSION
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// 第一步：输入 RGB 的图像，输出按 `subsampling` 子采样的 YUV 图像。
+/// YUV 的公式默认基于 ITU-R BT.601 标准，见 `ColorConversion`。
+#[tracing::instrument(skip_all, fields(width = image.width(), height = image.height(), ?subsampling))]
+pub fn encode_step1(
+    image: &RgbImage,
+    color_conversion: &ColorConversion,
+    subsampling: Subsampling,
+) -> io::Result<MyYuvImage> {
+    let table = RgbToYuvTable::new(color_conversion);
+    to_yuv_image(image.dimensions(), color_conversion, subsampling, |x, y| {
+        let pixel = image.get_pixel(x, y);
+        table.convert(pixel[0], pixel[1], pixel[2])
+    })
+}
 
-    let mut ret = MyYuvImage::new(width as usize, height as usize);
-
-    let mut y_idx: usize = 0;
-    let mut uv_idx: usize = 0;
-    for y in 0..ret.padded_height() {
-        for x in 0..ret.padded_width() {
-            let ox = min(x, ret.original_width - 1);
-            let oy = min(y, ret.original_height - 1);
-
-            // 使用边缘像素填充。
-            let pixel = image.get_pixel(ox as u32, oy as u32);
-            let r = pixel[0];
-            let g = pixel[1];
-            let b = pixel[2];
-
-            let (y, u, v) = rgb_to_yuv(r, g, b);
-            ret.y[y_idx] = y;
-            y_idx += 1;
-            if x % 2 == 0 {
-                ret.u[uv_idx] = u;
-                ret.v[uv_idx] = v;
-                uv_idx += 1;
+/// 与 `encode_step1` 相同，但输入 16 位的图像，输出 12 位的 YUV 图像，见 `ColorConversion::rgb16_to_yuv12`。
+#[tracing::instrument(skip_all, fields(width = image.width(), height = image.height(), ?subsampling))]
+pub fn encode_step1_12(
+    image: &Rgb16Image,
+    color_conversion: &ColorConversion,
+    subsampling: Subsampling,
+) -> io::Result<MyYuvImage<u16>> {
+    to_yuv_image(image.dimensions(), color_conversion, subsampling, |x, y| {
+        let pixel = image.get_pixel(x, y);
+        color_conversion.rgb16_to_yuv12(pixel[0], pixel[1], pixel[2])
+    })
+}
+
+/// 用 `convert` 把 (x, y) 处的像素转换为 YUV，子采样并填充。
+fn to_yuv_image<T: Sample>(
+    (width, height): (u32, u32),
+    color_conversion: &ColorConversion,
+    subsampling: Subsampling,
+    convert: impl Fn(u32, u32) -> (T, T, T) + Sync,
+) -> io::Result<MyYuvImage<T>> {
+    check_dimensions(width, height)?;
+
+    let mut ret = MyYuvImage::<T>::new(width as usize, height as usize, subsampling);
+    ret.color_conversion = *color_conversion;
+    let y_layout = ret.y_layout();
+    let chroma_layout = ret.chroma_layout();
+    let (h, v) = subsampling.luma_factors();
+
+    let (width, height) = (ret.original_width, ret.original_height);
+    let (chroma_width, chroma_height) = (width.div_ceil(h), height.div_ceil(v));
+    // 只转换原图的像素，填充部分直接复制边缘像素转换后的值。
+    // 每 v 行亮度对应一行色度，各组互不相关，在 rayon 的线程池中按组并行转换。
+    ret.y
+        .par_chunks_mut(y_layout.stride * v)
+        .zip(ret.u.par_chunks_mut(chroma_layout.stride))
+        .zip(ret.v.par_chunks_mut(chroma_layout.stride))
+        .take(chroma_height)
+        .enumerate()
+        .for_each(|(group, ((y_rows, u_row), v_row))| {
+            for (row, y_row) in y_rows.chunks_mut(y_layout.stride).enumerate() {
+                let y = group * v + row;
+                if y >= height {
+                    break;
+                }
+                let mut last = Default::default();
+                for x in 0..width {
+                    last = convert(x as u32, y as u32);
+                    let (luma, cb, cr) = last;
+                    y_row[x] = luma;
+                    // 色度取每 h x v 个像素中左上角的一个。
+                    if row == 0 && x % h == 0 {
+                        u_row[x / h] = cb;
+                        v_row[x / h] = cr;
+                    }
+                }
+
+                // 右侧使用最右边的像素填充。
+                let (luma, cb, cr) = last;
+                y_row[width..y_layout.width].fill(luma);
+                if row == 0 {
+                    u_row[chroma_width..chroma_layout.width].fill(cb);
+                    v_row[chroma_width..chroma_layout.width].fill(cr);
+                }
             }
-        }
-    }
+        });
 
-    assert_eq!(y_idx, ret.y.len());
-    assert_eq!(uv_idx, ret.u.len());
-    assert_eq!(uv_idx, ret.v.len());
+    // 下方使用最下面一行填充。
+    for y in height..y_layout.height {
+        let src = y_layout.index(0, height - 1);
+        ret.y
+            .copy_within(src..src + y_layout.width, y_layout.index(0, y));
+    }
+    for y in chroma_height..chroma_layout.height {
+        let src = chroma_layout.index(0, chroma_height - 1);
+        let dest = chroma_layout.index(0, y);
+        ret.u.copy_within(src..src + chroma_layout.width, dest);
+        ret.v.copy_within(src..src + chroma_layout.width, dest);
+    }
 
     Ok(ret)
 }
 
 pub fn show_step1(result: &MyYuvImage) {
+    let (h, v) = result.subsampling.luma_factors();
     println!(
-        "[INFO] 将图片转换为 YUV422 格式，并填充为 MCU 的倍数，尺寸变为 {}x{}",
+        "[INFO] 将图片转换为 YUV 格式，色度每 {}x{} 个像素采样一次，并填充为 MCU 的倍数，尺寸变为 {}x{}",
+        h,
+        v,
         result.padded_width(),
         result.padded_height()
     );
-    let y_img = GrayImage::from_raw(
-        result.padded_width() as u32,
-        result.padded_height() as u32,
-        result.y.clone(),
-    )
-    .unwrap();
-    let u_img = ImageBuffer::from_fn(
-        (result.padded_width() / 2) as u32,
-        result.padded_height() as u32,
-        |x, y| {
-            let u_val = result.u[(y * (result.padded_width() as u32 / 2) + x) as usize];
-            image::Rgb([0, u_val, 255 - u_val]) // 伪彩色
-        },
-    );
-    let v_img = ImageBuffer::from_fn(
-        (result.padded_width() / 2) as u32,
-        result.padded_height() as u32,
-        |x, y| {
-            let v_val = result.v[(y * (result.padded_width() as u32 / 2) + x) as usize];
-            image::Rgb([v_val, 0, 255 - v_val]) // 伪彩色
-        },
-    );
-    let rgb_img = ImageBuffer::from_fn(
-        result.original_width as u32,
-        result.original_height as u32,
-        |x, y| {
-            let y_idx = (y * result.padded_width() as u32 + x) as usize;
-            let u_idx = (y * (result.padded_width() as u32 / 2) + x / 2) as usize;
-            let v_idx = (y * (result.padded_width() as u32 / 2) + x / 2) as usize;
-
-            let y = result.y[y_idx];
-            let u = result.u[u_idx];
-            let v = result.v[v_idx];
-
-            let (r, g, b) = yuv_to_rgb(y, u, v);
-
-            image::Rgb([r, g, b])
-        },
-    );
+}
 
-    y_img
-        .save_with_format("output/y.png", ImageFormat::Png)
-        .unwrap_or_else(|_| {
-            println!("[ERROR] 保存 Y 图像失败，考虑手动新建一个名为 output 的子文件夹");
-        });
-    u_img
-        .save_with_format("output/u.png", ImageFormat::Png)
-        .unwrap_or_else(|_| {
-            println!("[ERROR] 保存 U 图像失败，考虑手动新建一个名为 output 的子文件夹");
-        });
-    v_img
-        .save_with_format("output/v.png", ImageFormat::Png)
-        .unwrap_or_else(|_| {
-            println!("[ERROR] 保存 V 图像失败，考虑手动新建一个名为 output 的子文件夹");
-        });
-    rgb_img
-        .save_with_format("output/initial_yuv_to_rgb.png", ImageFormat::Png)
-        .unwrap_or_else(|_| {
-            println!("[ERROR] 保存 RGB 图像失败，考虑手动新建一个名为 output 的子文件夹");
-        });
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dimension_limit() {
+        let conversion = ColorConversion::default();
+        let step1 = |width, height| {
+            encode_step1(
+                &RgbImage::new(width, height),
+                &conversion,
+                Default::default(),
+            )
+        };
+        assert!(step1(MAX_DIMENSION, 1).is_ok());
+        assert!(step1(MAX_DIMENSION + 1, 1).is_err());
+        assert!(step1(1, MAX_DIMENSION + 1).is_err());
+    }
+
+    #[test]
+    fn test_color_matrix() {
+        for matrix in [ColorMatrix::Bt601, ColorMatrix::Bt709] {
+            let conversion = ColorConversion {
+                matrix,
+                ..Default::default()
+            };
+            // 灰色的色度为 0（偏移 128），并且能转换回来。
+            assert_eq!(conversion.rgb_to_yuv(90, 90, 90), (90, 128, 128));
+            for rgb in [(255, 0, 0), (0, 255, 0), (0, 0, 255), (12, 200, 99)] {
+                let (y, cb, cr) = conversion.rgb_to_yuv(rgb.0, rgb.1, rgb.2);
+                let (r, g, b) = conversion.yuv_to_rgb(y, cb, cr);
+                assert!(r.abs_diff(rgb.0) <= 2, "{:?} {:?}", matrix, rgb);
+                assert!(g.abs_diff(rgb.1) <= 2, "{:?} {:?}", matrix, rgb);
+                assert!(b.abs_diff(rgb.2) <= 2, "{:?} {:?}", matrix, rgb);
+            }
+        }
+        // 两个矩阵的亮度系数不同。
+        let bt709 = ColorConversion {
+            matrix: ColorMatrix::Bt709,
+            ..Default::default()
+        };
+        assert_eq!(ColorConversion::default().rgb_to_yuv(0, 255, 0).0, 150);
+        assert_eq!(bt709.rgb_to_yuv(0, 255, 0).0, 182);
+    }
+
+    #[test]
+    fn test_color_range() {
+        for matrix in [ColorMatrix::Bt601, ColorMatrix::Bt709] {
+            let conversion = ColorConversion {
+                matrix,
+                range: ColorRange::Limited,
+            };
+            assert_eq!(conversion.rgb_to_yuv(0, 0, 0), (16, 128, 128));
+            assert_eq!(conversion.rgb_to_yuv(255, 255, 255), (235, 128, 128));
+            assert_eq!(conversion.yuv_to_rgb(16, 128, 128), (0, 0, 0));
+            assert_eq!(conversion.yuv_to_rgb(235, 128, 128), (255, 255, 255));
+            // 超出有限范围的值被截断。
+            assert_eq!(conversion.yuv_to_rgb(0, 128, 128), (0, 0, 0));
+            assert_eq!(conversion.yuv_to_rgb(255, 128, 128), (255, 255, 255));
+            assert_eq!(
+                conversion.yuv_to_rgb(128, 0, 0),
+                conversion.yuv_to_rgb(128, 16, 16)
+            );
+            for rgb in [(255, 0, 0), (0, 255, 0), (0, 0, 255), (12, 200, 99)] {
+                let (y, cb, cr) = conversion.rgb_to_yuv(rgb.0, rgb.1, rgb.2);
+                assert!((16..=235).contains(&y), "{:?} {:?}", matrix, rgb);
+                assert!((16..=240).contains(&cb), "{:?} {:?}", matrix, rgb);
+                assert!((16..=240).contains(&cr), "{:?} {:?}", matrix, rgb);
+                let (r, g, b) = conversion.yuv_to_rgb(y, cb, cr);
+                assert!(r.abs_diff(rgb.0) <= 3, "{:?} {:?}", matrix, rgb);
+                assert!(g.abs_diff(rgb.1) <= 3, "{:?} {:?}", matrix, rgb);
+                assert!(b.abs_diff(rgb.2) <= 3, "{:?} {:?}", matrix, rgb);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lookup_table() {
+        for matrix in [ColorMatrix::Bt601, ColorMatrix::Bt709] {
+            for range in [ColorRange::Full, ColorRange::Limited] {
+                let conversion = ColorConversion { matrix, range };
+                let forward = RgbToYuvTable::new(&conversion);
+                let backward = YuvToRgbTable::new(&conversion);
+                let close = |a: (u8, u8, u8), b: (u8, u8, u8)| {
+                    a.0.abs_diff(b.0) <= 1 && a.1.abs_diff(b.1) <= 1 && a.2.abs_diff(b.2) <= 1
+                };
+                for i in (0..=255).step_by(5) {
+                    for j in (0..=255).step_by(5) {
+                        for k in (0..=255).step_by(5) {
+                            assert!(
+                                close(forward.convert(i, j, k), conversion.rgb_to_yuv(i, j, k)),
+                                "{:?} rgb {} {} {}",
+                                conversion,
+                                i,
+                                j,
+                                k
+                            );
+                            assert!(
+                                close(backward.convert(i, j, k), conversion.yuv_to_rgb(i, j, k)),
+                                "{:?} yuv {} {} {}",
+                                conversion,
+                                i,
+                                j,
+                                k
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
@@ -1,66 +1,131 @@
 use std::io;
 
+use rayon::prelude::*;
+
+use super::arena::BufferPool;
+use super::arena::ScratchArena;
 use super::encode_step1::MyYuvImage;
+use super::encode_step1::PlaneLayout;
+use super::encode_step1::Sample;
 
-/// DU 是 8x8 的有符号数。
+/// DU 是 8x8 的有符号数。8 位的样本为 -128~127，12 位的样本为 -2048~2047。
 #[derive(Debug)]
-pub struct Du(pub [[i8; 8]; 8]);
+pub struct Du(pub [[i16; 8]; 8]);
 
-/// YUV422 的 MCU，对应原始图像的 16x8 区域。
+/// 按分量连续存储的 DU，同一分量的 DU 在内存中相邻，之后各步可以成批处理同一分量。
+/// 每个 MCU 在 `cb` 和 `cr` 中各有一个 DU，在 `y` 中按从左到右、从上到下的顺序有 h x v 个 DU，
+/// h 和 v 为 Y 的采样因子。例如 YUV422 的 MCU 对应原始图像的 16x8 区域，依次有 Y0、Y1 两个 DU。
 #[derive(Debug)]
-pub struct Mcu {
-    pub y0: Du,
-    pub y1: Du,
-    pub cb: Du,
-    pub cr: Du,
+pub struct ComponentDus<T> {
+    pub y: Vec<T>,
+    pub cb: Vec<T>,
+    pub cr: Vec<T>,
+}
+
+impl<T> ComponentDus<T> {
+    pub fn mcu_count(&self) -> usize {
+        self.cb.len()
+    }
+
+    /// 每个 MCU 中 Y 的 DU 数。
+    pub fn luma_per_mcu(&self) -> usize {
+        self.y.len() / self.cb.len().max(1)
+    }
+
+    /// 第 `i` 个 MCU 的所有 Y，以及 Cb、Cr。
+    pub fn mcu(&self, i: usize) -> (&[T], &T, &T) {
+        let n = self.luma_per_mcu();
+        (&self.y[n * i..n * (i + 1)], &self.cb[i], &self.cr[i])
+    }
+
+    /// 从缓冲池中取出足够容纳 `mcu_count` 个 MCU 的空缓冲区，每个 MCU 中有 `luma_per_mcu` 个 Y。
+    pub fn with_pool(pool: &mut BufferPool<T>, mcu_count: usize, luma_per_mcu: usize) -> Self {
+        Self {
+            y: pool.take(luma_per_mcu * mcu_count),
+            cb: pool.take(mcu_count),
+            cr: pool.take(mcu_count),
+        }
+    }
+
+    /// 对每个 DU 分别做变换，亮度和色度可以使用不同的变换。结果存放在从缓冲池中取出的缓冲区中。
+    /// 各个 DU 在 rayon 线程池中并行变换，结果仍按原来的顺序存放，与线程数无关。
+    pub fn map_in<U: Send>(
+        &self,
+        pool: &mut BufferPool<U>,
+        luma: impl Fn(&T) -> U + Sync + Send,
+        chroma: impl Fn(&T) -> U + Sync + Send,
+    ) -> ComponentDus<U>
+    where
+        T: Sync,
+    {
+        let mut ret = ComponentDus::with_pool(pool, self.mcu_count(), self.luma_per_mcu());
+        ret.y.par_extend(self.y.par_iter().map(luma));
+        ret.cb.par_extend(self.cb.par_iter().map(&chroma));
+        ret.cr.par_extend(self.cr.par_iter().map(&chroma));
+        ret
+    }
+
+    /// 把缓冲区归还到缓冲池。
+    pub fn recycle(self, pool: &mut BufferPool<T>) {
+        pool.recycle(self.y);
+        pool.recycle(self.cb);
+        pool.recycle(self.cr);
+    }
 }
 
 #[derive(Debug)]
 pub struct McuCollection {
     pub original_width: usize,
     pub original_height: usize,
-    pub mcus: Vec<Mcu>,
+    pub dus: ComponentDus<Du>,
 }
 
-/// 第二步：输入 YUV422 图像，输出所有 MCU。
-/// Y0 在 Y1 的左边。
-/// 无符号数转有符号数需要减去 128。
-pub fn encode_step2(yuv_image: &MyYuvImage) -> io::Result<McuCollection> {
-    let padded_width = yuv_image.padded_width();
-    let padded_height = yuv_image.padded_height();
-    let mut mcus = Vec::new();
-
-    for y in (0..padded_height).step_by(8) {
-        for x in (0..padded_width).step_by(16) {
-            let mut y0 = Du([[0; 8]; 8]);
-            let mut y1 = Du([[0; 8]; 8]);
-            let mut cb = Du([[0; 8]; 8]);
-            let mut cr = Du([[0; 8]; 8]);
-
-            for row in 0..8 {
-                for col in 0..8 {
-                    let y_index = (y + row) * padded_width + (x + col);
-                    y0.0[row][col] = (yuv_image.y[y_index] as i8).wrapping_add(-128);
-
-                    let y_index = (y + row) * padded_width + (x + col + 8);
-                    y1.0[row][col] = (yuv_image.y[y_index] as i8).wrapping_add(-128);
-
-                    let u_index = (y + row) * (padded_width / 2) + (x / 2 + col);
-                    cb.0[row][col] = (yuv_image.u[u_index] as i8).wrapping_add(-128);
-
-                    let v_index = (y + row) * (padded_width / 2) + (x / 2 + col);
-                    cr.0[row][col] = (yuv_image.v[v_index] as i8).wrapping_add(-128);
-                }
+/// 第二步：输入 YUV 图像，输出所有 MCU。
+/// 每个 MCU 中的 Y 按采样因子排列，例如 YUV422 的 Y0 在 Y1 的左边，YUV440 的 Y0 在 Y1 的上边。
+/// 无符号数转有符号数需要减去 128，12 位时减去 2048。
+#[tracing::instrument(skip_all, fields(width = yuv_image.original_width, height = yuv_image.original_height, mcu_count))]
+pub fn encode_step2<T: Sample>(
+    yuv_image: &MyYuvImage<T>,
+    arena: &mut ScratchArena,
+) -> io::Result<McuCollection> {
+    let y_layout = yuv_image.y_layout();
+    let chroma_layout = yuv_image.chroma_layout();
+    let (h, v) = yuv_image.subsampling.luma_factors();
+    let (mcu_width, mcu_height) = yuv_image.subsampling.mcu_size();
+    let mcu_count = y_layout.width / mcu_width * (y_layout.height / mcu_height);
+    tracing::Span::current().record("mcu_count", mcu_count);
+    let mut dus = ComponentDus::with_pool(&mut arena.du, mcu_count, h * v);
+
+    // 从平面中取出左上角位于 (x, y) 的 DU。
+    fn extract_du<T: Sample>(plane: &[T], layout: &PlaneLayout, x: usize, y: usize) -> Du {
+        let mut du = Du([[0; 8]; 8]);
+        for row in 0..8 {
+            for col in 0..8 {
+                let index = layout.index(x + col, y + row);
+                du.0[row][col] = plane[index].to_i16() - T::CENTER;
             }
+        }
+        du
+    }
 
-            mcus.push(Mcu { y0, y1, cb, cr });
+    // (x, y) 是 MCU 在亮度平面中的左上角。色度平面的分辨率在两个方向上分别是亮度的 1/h 和 1/v。
+    for y in (0..y_layout.height).step_by(mcu_height) {
+        for x in (0..y_layout.width).step_by(mcu_width) {
+            for j in 0..h * v {
+                let (du_x, du_y) = (x + j % h * 8, y + j / h * 8);
+                dus.y.push(extract_du(&yuv_image.y, &y_layout, du_x, du_y));
+            }
+            dus.cb
+                .push(extract_du(&yuv_image.u, &chroma_layout, x / h, y / v));
+            dus.cr
+                .push(extract_du(&yuv_image.v, &chroma_layout, x / h, y / v));
         }
     }
 
     Ok(McuCollection {
         original_width: yuv_image.original_width,
         original_height: yuv_image.original_height,
-        mcus,
+        dus,
     })
 }
 
@@ -69,8 +134,146 @@ pub fn show_step2(result: &McuCollection) {
         "[INFO] 大小为 {}x{} 的 RGB 图像编码出 {} 个 MCU，共 {} 个 DU",
         result.original_width,
         result.original_height,
-        result.mcus.len(),
-        result.mcus.len() * 4,
+        result.dus.mcu_count(),
+        result.dus.y.len() + result.dus.cb.len() + result.dus.cr.len(),
     );
-    println!("[VERBOSE] MCU 的例子：\n{:?}", &result.mcus[0]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use clap::ValueEnum;
+    use image::RgbImage;
+
+    use super::super::decode16::Rgb16Image;
+    use super::super::encode_step1::encode_step1;
+    use super::super::encode_step1::encode_step1_12;
+    use super::super::encode_step1::ColorConversion;
+    use super::super::encode_step1::Subsampling;
+
+    #[test]
+    fn test_mcu_count() {
+        for (subsampling, width, height, count) in [
+            (Subsampling::Yuv422, 1, 1, 1),
+            (Subsampling::Yuv422, 15, 7, 1),
+            (Subsampling::Yuv422, 16, 8, 1),
+            (Subsampling::Yuv422, 17, 8, 2),
+            (Subsampling::Yuv422, 16, 9, 2),
+            (Subsampling::Yuv411, 32, 8, 1),
+            (Subsampling::Yuv411, 33, 9, 4),
+            (Subsampling::Yuv440, 8, 16, 1),
+            (Subsampling::Yuv440, 9, 17, 4),
+        ] {
+            let yuv_image = encode_step1(
+                &RgbImage::new(width, height),
+                &ColorConversion::default(),
+                subsampling,
+            )
+            .unwrap();
+            let mcu_collection = encode_step2(&yuv_image, &mut Default::default()).unwrap();
+            let context = (subsampling, width, height);
+            assert_eq!(mcu_collection.dus.mcu_count(), count, "{:?}", context);
+            assert_eq!(mcu_collection.original_width, width as usize);
+            assert_eq!(mcu_collection.original_height, height as usize);
+        }
+    }
+
+    #[test]
+    fn test_flat_padding() {
+        // 纯色图像的填充复制边缘，每个数据单元（包括填充的）都是纯色的，DCT 后没有交流分量。
+        fn assert_flat<'a>(dus: impl Iterator<Item = &'a Du>, context: impl std::fmt::Debug) {
+            for du in dus {
+                let value = du.0[0][0];
+                assert!(du.0.iter().flatten().all(|&v| v == value), "{:?}", context);
+            }
+        }
+
+        for &subsampling in Subsampling::value_variants() {
+            for (width, height) in [(37, 21), (1, 1), (33, 9)] {
+                let context = (subsampling, width, height);
+                let image = RgbImage::from_pixel(width, height, image::Rgb([200, 30, 90]));
+                let yuv_image =
+                    encode_step1(&image, &ColorConversion::default(), subsampling).unwrap();
+                let dus = encode_step2(&yuv_image, &mut Default::default())
+                    .unwrap()
+                    .dus;
+                for i in 0..dus.mcu_count() {
+                    let (y_dus, cb_du, cr_du) = dus.mcu(i);
+                    assert_flat(y_dus.iter().chain([cb_du, cr_du]), context);
+                }
+
+                let image = Rgb16Image::from_pixel(width, height, image::Rgb([51400, 7710, 23130]));
+                let yuv_image =
+                    encode_step1_12(&image, &ColorConversion::default(), subsampling).unwrap();
+                let dus = encode_step2(&yuv_image, &mut Default::default())
+                    .unwrap()
+                    .dus;
+                for i in 0..dus.mcu_count() {
+                    let (y_dus, cb_du, cr_du) = dus.mcu(i);
+                    assert_flat(y_dus.iter().chain([cb_du, cr_du]), context);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_plane_addressing() {
+        fn pixel(x: u32, y: u32) -> [u8; 3] {
+            [(x * 3 + y) as u8, (255 - x * 2) as u8, (y * 7 + x) as u8]
+        }
+        fn shifted(value: u8) -> i16 {
+            value as i16 - 128
+        }
+
+        for &subsampling in Subsampling::value_variants() {
+            let (h, v) = subsampling.luma_factors();
+            let (mcu_width, mcu_height) = subsampling.mcu_size();
+            for height in [1, 8, 9, 17] {
+                for width in 1..=64 {
+                    let image = RgbImage::from_fn(width, height, |x, y| image::Rgb(pixel(x, y)));
+                    let yuv_image =
+                        encode_step1(&image, &ColorConversion::default(), subsampling).unwrap();
+                    let mcu_collection = encode_step2(&yuv_image, &mut Default::default()).unwrap();
+                    let mcus_per_row = (width as usize).div_ceil(mcu_width);
+
+                    // 填充区域复制边缘像素，色度取每 h x v 个像素中左上角的一个。
+                    let expected = |x: usize, y: usize| {
+                        let x = x.min(width as usize - 1) as u32;
+                        let y = y.min(height as usize - 1) as u32;
+                        let [r, g, b] = pixel(x, y);
+                        ColorConversion::default().rgb_to_yuv(r, g, b)
+                    };
+                    // 色度平面右侧的填充取最右边的像素，下方的填充复制最下面一行色度。
+                    let expected_chroma = |x: usize, y: usize| {
+                        let y = y.min((height as usize).div_ceil(v) - 1);
+                        expected(x * h, y * v)
+                    };
+                    for i in 0..mcu_collection.dus.mcu_count() {
+                        let (y_dus, cb_du, cr_du) = mcu_collection.dus.mcu(i);
+                        assert_eq!(y_dus.len(), h * v);
+                        let x0 = i % mcus_per_row * mcu_width;
+                        let y0 = i / mcus_per_row * mcu_height;
+                        for row in 0..8 {
+                            for col in 0..8 {
+                                let context = (subsampling, width, height, i, row, col);
+                                for (j, y_du) in y_dus.iter().enumerate() {
+                                    let (x, y) = (x0 + j % h * 8 + col, y0 + j / h * 8 + row);
+                                    assert_eq!(
+                                        y_du.0[row][col],
+                                        shifted(expected(x, y).0),
+                                        "{:?}",
+                                        context
+                                    );
+                                }
+                                let (_, cb, cr) = expected_chroma(x0 / h + col, y0 / v + row);
+                                assert_eq!(cb_du.0[row][col], shifted(cb), "{:?}", context);
+                                assert_eq!(cr_du.0[row][col], shifted(cr), "{:?}", context);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
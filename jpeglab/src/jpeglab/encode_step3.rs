@@ -1,44 +1,103 @@
 use std::f64::consts::PI;
 use std::io;
 
+use lazy_static::lazy_static;
+
+use super::arena::ScratchArena;
+use super::encode_step2::ComponentDus;
 use super::encode_step2::Du;
 use super::encode_step2::McuCollection;
+use super::int_dct::fdct_ifast;
+use super::int_dct::fdct_islow;
 
 /// DCT 后的 DU。
 #[derive(Debug)]
 pub struct DctDu(pub [[f64; 8]; 8]);
 
-/// DCT 后的 MCU。
-#[derive(Debug)]
-pub struct DctMcu {
-    pub y0: DctDu,
-    pub y1: DctDu,
-    pub cb: DctDu,
-    pub cr: DctDu,
-}
-
 #[derive(Debug)]
 pub struct DctMcuCollection {
     pub original_width: usize,
     pub original_height: usize,
-    pub dct_mcus: Vec<DctMcu>,
+    pub dct_dus: ComponentDus<DctDu>,
 }
 
-pub(super) fn dct(du: &Du) -> DctDu {
+/// DCT 和 IDCT 的实现和计算精度。后两种与 libjpeg 的 `JDCT_IFAST` 和 `JDCT_ISLOW` 相同，见 `int_dct`。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DctPrecision {
+    /// 按定义以 f64 计算，作为参考。
+    #[default]
+    #[value(alias = "float")]
+    F64,
+    /// 按定义以 f32 计算。在很多平台上更快，量化后精度的差别可以忽略。
+    F32,
+    /// libjpeg 的快速整数算法（AAN），乘法最少，精度最差。
+    Fast,
+    /// libjpeg 的精确整数算法（LLM），与浮点实现只差舍入。
+    Accurate,
+}
+
+/// DCT 和 IDCT 可以使用的浮点类型。
+pub(super) trait DctFloat:
+    'static + Copy + Default + std::ops::AddAssign + std::ops::Mul<Output = Self> + std::ops::MulAssign
+{
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+    /// 预先计算的余弦表，见 `COS_TABLE`。
+    fn cos_table() -> &'static [[Self; 8]; 8];
+}
+
+/// 余弦表，`[k][n]` 为 cos((2n + 1)kπ / 16)。DCT 和 IDCT 的三重循环中只需要查表。
+fn make_cos_table() -> [[f64; 8]; 8] {
+    const N: usize = 8;
+    std::array::from_fn(|k| {
+        std::array::from_fn(|n| (((2 * n + 1) * k) as f64 * PI / ((2 * N) as f64)).cos())
+    })
+}
+
+lazy_static! {
+    pub(super) static ref COS_TABLE: [[f64; 8]; 8] = make_cos_table();
+    static ref COS_TABLE_F32: [[f32; 8]; 8] = COS_TABLE.map(|row| row.map(|it| it as f32));
+}
+
+impl DctFloat for f64 {
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+    fn cos_table() -> &'static [[Self; 8]; 8] {
+        &COS_TABLE
+    }
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl DctFloat for f32 {
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+    fn cos_table() -> &'static [[Self; 8]; 8] {
+        &COS_TABLE_F32
+    }
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+fn dct_generic<T: DctFloat>(du: &Du) -> DctDu {
     const N: usize = 8;
 
-    let first_factor = (1.0 / N as f64).sqrt();
-    let others_factor = (2.0 / N as f64).sqrt();
+    let first_factor = T::from_f64((1.0 / N as f64).sqrt());
+    let others_factor = T::from_f64((2.0 / N as f64).sqrt());
+    let cos = T::cos_table();
 
     let input = &du.0;
-    let mut one = [[0f64; N]; N];
-    let mut ret = [[0f64; N]; N];
+    let mut one = [[T::default(); N]; N];
+    let mut ret = [[T::default(); N]; N];
 
     for u in 0..N {
         for v in 0..N {
             for y in 0..N {
-                one[u][v] +=
-                    input[u][y] as f64 * (((2 * y + 1) * v) as f64 * PI / ((2 * N) as f64)).cos();
+                one[u][v] += T::from_f64(input[u][y] as f64) * cos[v][y];
             }
             one[u][v] *= if v == 0 { first_factor } else { others_factor };
         }
@@ -47,40 +106,49 @@ pub(super) fn dct(du: &Du) -> DctDu {
     for v in 0..N {
         for u in 0..N {
             for x in 0..N {
-                ret[u][v] +=
-                    one[x][v] as f64 * (((2 * x + 1) * u) as f64 * PI / ((2 * N) as f64)).cos();
+                ret[u][v] += one[x][v] * cos[u][x];
             }
             ret[u][v] *= if u == 0 { first_factor } else { others_factor };
         }
     }
 
-    DctDu(ret)
+    DctDu(ret.map(|inner| inner.map(T::to_f64)))
 }
 
-/// 第三步：离散余弦变换。
-pub fn encode_step3(yuv_image: &McuCollection) -> io::Result<DctMcuCollection> {
-    let mut dct_mcus = Vec::new();
-
-    for mcu in &yuv_image.mcus {
-        dct_mcus.push(DctMcu {
-            y0: dct(&mcu.y0),
-            y1: dct(&mcu.y1),
-            cb: dct(&mcu.cb),
-            cr: dct(&mcu.cr),
-        });
+/// 以 f64 计算 DCT，供测试使用。
+#[cfg(test)]
+pub(super) fn dct(du: &Du) -> DctDu {
+    dct_with(du, DctPrecision::F64)
+}
+
+/// 以指定的精度计算 DCT。结果总是以 f64 存储。
+pub(super) fn dct_with(du: &Du, precision: DctPrecision) -> DctDu {
+    match precision {
+        DctPrecision::F64 => dct_generic::<f64>(du),
+        DctPrecision::F32 => dct_generic::<f32>(du),
+        DctPrecision::Fast => fdct_ifast(du),
+        DctPrecision::Accurate => fdct_islow(du),
     }
+}
+
+/// 第三步：离散余弦变换。
+#[tracing::instrument(skip_all, fields(mcu_count = yuv_image.dus.mcu_count(), ?precision))]
+pub fn encode_step3(
+    yuv_image: &McuCollection,
+    precision: DctPrecision,
+    arena: &mut ScratchArena,
+) -> io::Result<DctMcuCollection> {
+    let transform = |du: &Du| dct_with(du, precision);
 
     Ok(DctMcuCollection {
         original_width: yuv_image.original_width,
         original_height: yuv_image.original_height,
-        dct_mcus,
+        dct_dus: yuv_image
+            .dus
+            .map_in(&mut arena.dct_du, transform, transform),
     })
 }
 
-pub fn show_step3(result: &DctMcuCollection) {
-    println!("[VERBOSE] MCU 计算 DCT 的例子：\n{:?}", &result.dct_mcus[0]);
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -90,7 +158,7 @@ mod test {
     #[test]
     fn test_dct() {
         // https://blog.csdn.net/weixin_44874766/article/details/117444843
-        const DU_TABLE: [[i8; 8]; 8] = [
+        const DU_TABLE: [[i16; 8]; 8] = [
             [-76, -73, -67, -62, -58, -67, -64, -55],
             [-65, -69, -73, -38, -19, -43, -59, -56],
             [-66, -69, -60, -15, 16, -24, -62, -55],
@@ -121,4 +189,26 @@ mod test {
 
         assert_eq!(output, DCT_DU_TABLE);
     }
+
+    #[test]
+    fn test_dct_precision() {
+        let du = Du(std::array::from_fn(|i| {
+            std::array::from_fn(|j| ((i * 37 + j * 91) % 256) as i8 as i16)
+        }));
+        let f64_du = dct_with(&du, DctPrecision::F64);
+        let f32_du = dct_with(&du, DctPrecision::F32);
+        let accurate_du = dct_with(&du, DctPrecision::Accurate);
+        let fast_du = dct_with(&du, DctPrecision::Fast);
+        let mut fast_error = 0.0_f64;
+        for i in 0..8 {
+            for j in 0..8 {
+                assert!((f64_du.0[i][j] - f32_du.0[i][j]).abs() < 1e-3);
+                // 两遍各有一次舍入，第一遍的误差放大后也不超过 1。
+                assert!((f64_du.0[i][j] - accurate_du.0[i][j]).abs() <= 1.0);
+                fast_error = fast_error.max((f64_du.0[i][j] - fast_du.0[i][j]).abs());
+            }
+        }
+        assert!(fast_error < 8.0, "{}", fast_error);
+        assert_eq!(accurate_du.0[0][0], f64_du.0[0][0].round());
+    }
 }
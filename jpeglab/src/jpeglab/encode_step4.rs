@@ -1,7 +1,11 @@
 use std::io;
 
+use super::arena::ScratchArena;
+use super::encode_step2::ComponentDus;
 use super::encode_step3::DctDu;
 use super::encode_step3::DctMcuCollection;
+use super::hvs::perceptual_tables;
+use super::hvs::ViewingConditions;
 
 /// 量化后的 DU。
 /// 根据系数的编码表，设定为 16 位有符号整数。
@@ -10,7 +14,7 @@ pub struct QuantizedDu(pub [[i16; 8]; 8]);
 
 /// 量化表。
 /// 根据量化后的 DU，设定为 16 位无符号整数。
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct QuantizationTable(pub [[u16; 8]; 8]);
 
 /// 亮度量化表。
@@ -37,6 +41,104 @@ pub const CHROMINANCE_QUANTIZATION_TABLE: QuantizationTable = QuantizationTable(
     [99, 99, 99, 99, 99, 99, 99, 99],
 ]);
 
+/// 量化表的预设。表都按自然顺序（行为垂直频率，列为水平频率）给出。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum QuantizationPreset {
+    /// JPEG 标准附录 K.1 的示例表，即上面的两个表。亮度表按人眼对各频率的可见阈值得到，
+    /// 色度表在中高频几乎全是 99。
+    #[default]
+    #[value(name = "jpeg-annexk")]
+    AnnexK,
+    /// 所有系数都是 16，亮度和色度相同。不利用人眼对高频不敏感的特性，
+    /// 高频保留得多，文件也大得多，适合作为对照。
+    Flat,
+    /// mozjpeg 的默认表，由 N. Robidoux 为 ImageMagick 调整，亮度和色度相同。低频接近平坦，
+    /// 高频增长平滑，块效应和振铃比附录 K 少。大于 255 的值截断为 255，以便使用 8 位精度。
+    Mozjpeg,
+    /// mozjpeg 中针对 PSNR-HVS 调整的表。亮度的低频比附录 K 更细，色度除最低的几个频率外都很粗，
+    /// 把码率集中到人眼敏感的亮度细节上。
+    Hvs,
+    /// 按观看条件由人眼视觉模型生成，见 `hvs::perceptual_tables`。固定的表按某种观看条件设计，
+    /// 这里可以按实际的距离和像素密度调整：看得越远、像素越密，高频量化得越粗。
+    Perceptual,
+}
+
+const MOZJPEG_QUANTIZATION_TABLE: [[u16; 8]; 8] = [
+    [16, 16, 16, 18, 25, 37, 56, 85],
+    [16, 17, 20, 27, 34, 40, 53, 75],
+    [16, 20, 24, 31, 43, 62, 91, 135],
+    [18, 27, 31, 40, 53, 74, 106, 156],
+    [25, 34, 43, 53, 69, 94, 131, 189],
+    [37, 40, 62, 74, 94, 124, 169, 238],
+    [56, 53, 91, 106, 131, 169, 226, 311],
+    [85, 75, 135, 156, 189, 238, 311, 418],
+];
+
+const HVS_LUMINANCE_QUANTIZATION_TABLE: QuantizationTable = QuantizationTable([
+    [9, 10, 12, 14, 27, 32, 51, 62],
+    [11, 12, 14, 19, 27, 44, 59, 73],
+    [12, 14, 18, 25, 42, 59, 79, 78],
+    [17, 18, 25, 42, 61, 92, 87, 92],
+    [23, 28, 42, 75, 79, 112, 112, 99],
+    [40, 42, 59, 84, 88, 124, 132, 111],
+    [42, 64, 78, 95, 105, 126, 125, 99],
+    [70, 75, 100, 102, 116, 100, 107, 98],
+]);
+
+const HVS_CHROMINANCE_QUANTIZATION_TABLE: QuantizationTable = QuantizationTable([
+    [9, 10, 17, 19, 62, 89, 91, 97],
+    [12, 13, 18, 29, 84, 91, 88, 98],
+    [14, 19, 29, 93, 95, 95, 98, 97],
+    [20, 26, 84, 88, 95, 95, 98, 94],
+    [26, 86, 91, 93, 97, 99, 98, 99],
+    [99, 100, 98, 99, 99, 99, 99, 99],
+    [99, 99, 99, 99, 99, 99, 99, 99],
+    [97, 97, 99, 99, 99, 99, 97, 99],
+]);
+
+impl QuantizationPreset {
+    /// 亮度和色度的量化表。`viewing` 只用于 `Perceptual`。
+    pub fn tables(self, viewing: &ViewingConditions) -> [QuantizationTable; 2] {
+        match self {
+            QuantizationPreset::AnnexK => {
+                [LUMINANCE_QUANTIZATION_TABLE, CHROMINANCE_QUANTIZATION_TABLE]
+            }
+            QuantizationPreset::Flat => {
+                let flat = QuantizationTable([[16; 8]; 8]);
+                [flat.clone(), flat]
+            }
+            QuantizationPreset::Mozjpeg => {
+                let table = QuantizationTable(
+                    MOZJPEG_QUANTIZATION_TABLE.map(|row| row.map(|q| q.min(255))),
+                );
+                [table.clone(), table]
+            }
+            QuantizationPreset::Hvs => [
+                HVS_LUMINANCE_QUANTIZATION_TABLE,
+                HVS_CHROMINANCE_QUANTIZATION_TABLE,
+            ],
+            QuantizationPreset::Perceptual => perceptual_tables(viewing),
+        }
+    }
+}
+
+impl QuantizationTable {
+    /// 按 IJG 的做法以质量因子缩放量化表，质量的范围是 1 到 100。质量为 50 时不变，越大量化越细。
+    /// 结果限制在 1 到 255 之间，总是可以用 8 位精度存储。
+    pub fn scaled(&self, quality: u8) -> QuantizationTable {
+        let quality = quality.clamp(1, 100) as u32;
+        let scale = if quality < 50 {
+            5000 / quality
+        } else {
+            200 - 2 * quality
+        };
+        QuantizationTable(
+            self.0
+                .map(|row| row.map(|q| ((q as u32 * scale + 50) / 100).clamp(1, 255) as u16)),
+        )
+    }
+}
+
 impl DctDu {
     pub fn quantize(&self, table: &QuantizationTable) -> QuantizedDu {
         let mut ret = [[0_i16; 8]; 8];
@@ -51,57 +153,45 @@ impl DctDu {
     }
 }
 
-/// 量化后的 MCU。
-#[derive(Debug)]
-pub struct QuantizedMcu {
-    pub y0: QuantizedDu,
-    pub y1: QuantizedDu,
-    pub cb: QuantizedDu,
-    pub cr: QuantizedDu,
-}
-
 #[derive(Debug)]
 pub struct QuantizedMcuCollection {
     pub original_width: usize,
     pub original_height: usize,
-    pub quantized_mcus: Vec<QuantizedMcu>,
+    pub quantized_dus: ComponentDus<QuantizedDu>,
 }
 
 /// 第四步：量化。
-pub fn encode_step4(dct_mcu_collection: &DctMcuCollection) -> io::Result<QuantizedMcuCollection> {
-    let mut quantized_mcus = Vec::new();
-
-    for mcu in &dct_mcu_collection.dct_mcus {
-        quantized_mcus.push(QuantizedMcu {
-            y0: mcu.y0.quantize(&LUMINANCE_QUANTIZATION_TABLE),
-            y1: mcu.y1.quantize(&LUMINANCE_QUANTIZATION_TABLE),
-            cb: mcu.cb.quantize(&CHROMINANCE_QUANTIZATION_TABLE),
-            cr: mcu.cr.quantize(&CHROMINANCE_QUANTIZATION_TABLE),
-        });
-    }
-
+#[tracing::instrument(skip_all, fields(mcu_count = dct_mcu_collection.dct_dus.mcu_count()))]
+pub fn encode_step4(
+    dct_mcu_collection: &DctMcuCollection,
+    quantization_tables: &[QuantizationTable; 2],
+    arena: &mut ScratchArena,
+) -> io::Result<QuantizedMcuCollection> {
+    let [luminance_table, chrominance_table] = quantization_tables;
     Ok(QuantizedMcuCollection {
         original_width: dct_mcu_collection.original_width,
         original_height: dct_mcu_collection.original_height,
-        quantized_mcus,
+        quantized_dus: dct_mcu_collection.dct_dus.map_in(
+            &mut arena.quantized_du,
+            |du| du.quantize(luminance_table),
+            |du| du.quantize(chrominance_table),
+        ),
     })
 }
 
-pub fn show_step4(result: &QuantizedMcuCollection) {
-    println!("[VERBOSE] 量化的例子：\n{:?}", &result.quantized_mcus[0]);
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
 
+    use clap::ValueEnum;
+
     use super::super::encode_step2::Du;
     use super::super::encode_step3::dct;
 
     #[test]
     fn test_quantize() {
         // https://blog.csdn.net/weixin_44874766/article/details/117444843
-        const DU_TABLE: [[i8; 8]; 8] = [
+        const DU_TABLE: [[i16; 8]; 8] = [
             [-76, -73, -67, -62, -58, -67, -64, -55],
             [-65, -69, -73, -38, -19, -43, -59, -56],
             [-66, -69, -60, -15, 16, -24, -62, -55],
@@ -127,4 +217,31 @@ mod test {
 
         assert_eq!(quantized_du.0, QUANTIZED_DU_TABLE);
     }
+
+    #[test]
+    fn test_scaled() {
+        let table = &LUMINANCE_QUANTIZATION_TABLE;
+        assert_eq!(table.scaled(50).0, table.0);
+        assert_eq!(table.scaled(100).0, [[1; 8]; 8]);
+        assert_eq!(table.scaled(75).0[0][0], 8);
+        assert_eq!(table.scaled(10).0[0][0], 80);
+        assert_eq!(table.scaled(1).0[7][7], 255);
+    }
+
+    #[test]
+    fn test_presets() {
+        let viewing = ViewingConditions::default();
+        let [luminance, chrominance] = QuantizationPreset::AnnexK.tables(&viewing);
+        assert_eq!(luminance.0, LUMINANCE_QUANTIZATION_TABLE.0);
+        assert_eq!(chrominance.0, CHROMINANCE_QUANTIZATION_TABLE.0);
+        assert_eq!(QuantizationPreset::Flat.tables(&viewing)[1].0, [[16; 8]; 8]);
+        let [luminance, chrominance] = QuantizationPreset::Mozjpeg.tables(&viewing);
+        assert_eq!(luminance.0, chrominance.0);
+        assert_eq!(luminance.0[7][7], 255);
+        for preset in QuantizationPreset::value_variants() {
+            for table in preset.tables(&viewing) {
+                assert!(table.0.iter().flatten().all(|&q| (1..=255).contains(&q)));
+            }
+        }
+    }
 }
@@ -1,100 +1,45 @@
 use std::io;
 
+use super::arena::ScratchArena;
+use super::encode_step2::ComponentDus;
 use super::encode_step4::QuantizedDu;
 use super::encode_step4::QuantizedMcuCollection;
+use super::zigzag::to_zigzag;
 
 /// Zigzag 后的 DU。
 #[derive(Debug)]
 pub struct ZigzagDu(pub [i16; 64]);
 
-/// Zigzag 后的 MCU。
-#[derive(Debug)]
-pub struct ZigzagMcu {
-    pub y0: ZigzagDu,
-    pub y1: ZigzagDu,
-    pub cb: ZigzagDu,
-    pub cr: ZigzagDu,
-}
-
 #[derive(Debug)]
 pub struct ZigzagMcuCollection {
     pub original_width: usize,
     pub original_height: usize,
-    pub zigzag_mcus: Vec<ZigzagMcu>,
+    pub zigzag_dus: ComponentDus<ZigzagDu>,
 }
 
 impl QuantizedDu {
     pub fn zigzag(&self) -> ZigzagDu {
-        let input = &self.0;
-        let mut ret = [0_i16; 64];
-
-        let mut x = 0;
-        let mut y = 0;
-        let mut idx = 0;
-
-        while idx < ret.len() {
-            while idx < ret.len() {
-                ret[idx] = input[x][y];
-                idx += 1;
-                // 优先处理 y == 7，因为有对角线。
-                if y == 7 {
-                    x += 1;
-                    break;
-                } else if x == 0 {
-                    y += 1;
-                    break;
-                } else {
-                    x -= 1;
-                    y += 1;
-                }
-            }
-            while idx < ret.len() {
-                ret[idx] = input[x][y];
-                idx += 1;
-                // 优先处理 x == 7，因为有对角线。
-                if x == 7 {
-                    y += 1;
-                    break;
-                } else if y == 0 {
-                    x += 1;
-                    break;
-                } else {
-                    x += 1;
-                    y -= 1;
-                }
-            }
-        }
-
-        ZigzagDu(ret)
+        ZigzagDu(to_zigzag(&self.0))
     }
 }
 
 /// 第五步：Zigzag。
+#[tracing::instrument(skip_all, fields(mcu_count = quantized_mcu_collection.quantized_dus.mcu_count()))]
 pub fn encode_step5(
     quantized_mcu_collection: &QuantizedMcuCollection,
+    arena: &mut ScratchArena,
 ) -> io::Result<ZigzagMcuCollection> {
-    let mut zigzag_mcus = Vec::new();
-
-    for mcu in &quantized_mcu_collection.quantized_mcus {
-        zigzag_mcus.push(ZigzagMcu {
-            y0: mcu.y0.zigzag(),
-            y1: mcu.y1.zigzag(),
-            cb: mcu.cb.zigzag(),
-            cr: mcu.cr.zigzag(),
-        });
-    }
-
     Ok(ZigzagMcuCollection {
         original_width: quantized_mcu_collection.original_width,
         original_height: quantized_mcu_collection.original_height,
-        zigzag_mcus,
+        zigzag_dus: quantized_mcu_collection.quantized_dus.map_in(
+            &mut arena.zigzag_du,
+            QuantizedDu::zigzag,
+            QuantizedDu::zigzag,
+        ),
     })
 }
 
-pub fn show_step5(result: &ZigzagMcuCollection) {
-    println!("[VERBOSE] Zigzag 的例子：\n{:?}", &result.zigzag_mcus[0]);
-}
-
 #[cfg(test)]
 mod test {
     use super::super::encode_step4::QuantizedDu;
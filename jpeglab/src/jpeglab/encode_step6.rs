@@ -3,15 +3,24 @@ use std::io;
 
 use bitvec::mem::bits_of;
 use bitvec::prelude::*;
+use image::RgbImage;
 use lazy_static::lazy_static;
 
+use super::arithmetic::encode_arithmetic;
+use super::arithmetic::ArithmeticScan;
+use super::bit_trace::CodedSymbol;
+use super::bit_trace::TraceEntry;
+use super::encode_step1::SamplePrecision;
+use super::encode_step1::Subsampling;
+use super::encode_step2::ComponentDus;
 use super::encode_step5::ZigzagDu;
 use super::encode_step5::ZigzagMcuCollection;
+use super::options::EncodeOptions;
 
 /// 按 JPEG 标准定义霍夫曼码表结构体，由长度表和符号表组成，描述了一棵霍夫曼树。
 /// 编码 DC 的数字时，会根据数字的大小分为至多 16 个符号，这些符号用这里定义的霍夫曼码表编码。见课件表 8.17, 8.18。
 /// 编码 AC 的数字时，会根据数字的大小或者行程编码 0 的数量分为很多符号。见课件表 8.17, 8.19。
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct JpegHuffmanTable {
     /// 长度为 (n + 1) 的霍夫曼码字有 `codes[n]` 个。
     /// 共有 `self.codes.iter().map(|&x| x as usize).sum::<usize>()` 个霍夫曼码字。
@@ -28,10 +37,92 @@ impl JpegHuffmanTable {
             values: vec![],
         }
     }
+
+    /// 按 JPEG 标准附录 K.2 的方法，由每个符号出现的次数生成最优的霍夫曼码表。
+    /// 码长不超过 16，且不使用全 1 的码字。没有任何符号时返回空表。
+    pub fn optimal(counts: &[usize; 256]) -> Self {
+        // 下标 256 为保留的符号，出现 1 次，保证不会分配全 1 的码字。
+        let mut freq = [0_usize; 257];
+        freq[..256].copy_from_slice(counts);
+        freq[256] = 1;
+        let mut code_size = [0_usize; 257];
+        let mut others = [None::<usize>; 257];
+
+        // 频率最小的非零项，相同时取下标大的。
+        let least = |freq: &[usize; 257], except: Option<usize>| {
+            (0..257)
+                .filter(|&i| freq[i] != 0 && Some(i) != except)
+                .min_by_key(|&i| (freq[i], std::cmp::Reverse(i)))
+        };
+        while let Some(v1) = least(&freq, None) {
+            let Some(v2) = least(&freq, Some(v1)) else {
+                break;
+            };
+            freq[v1] += freq[v2];
+            freq[v2] = 0;
+
+            let mut v = v1;
+            code_size[v] += 1;
+            while let Some(next) = others[v] {
+                v = next;
+                code_size[v] += 1;
+            }
+            others[v] = Some(v2);
+            let mut v = v2;
+            code_size[v] += 1;
+            while let Some(next) = others[v] {
+                v = next;
+                code_size[v] += 1;
+            }
+        }
+
+        // 下标为码长。最长可能有 256 位，先统计，再把超过 16 位的码字移到更短的长度上。
+        let mut bits = [0_usize; 258];
+        for &size in &code_size {
+            if size != 0 {
+                bits[size] += 1;
+            }
+        }
+        for i in (17..bits.len()).rev() {
+            while bits[i] > 0 {
+                let mut j = i - 2;
+                while bits[j] == 0 {
+                    j -= 1;
+                }
+                bits[i] -= 2;
+                bits[i - 1] += 1;
+                bits[j + 1] += 2;
+                bits[j] -= 1;
+            }
+        }
+        // 去掉保留的符号，它总在最长的码字中。
+        match (1..=16).rev().find(|&i| bits[i] != 0) {
+            Some(i) => bits[i] -= 1,
+            None => return Self::new(),
+        }
+
+        let mut ret = Self::new();
+        for (code, &count) in ret.codes.iter_mut().zip(&bits[1..=16]) {
+            *code = count as u8;
+        }
+        for size in 1..=32 {
+            ret.values
+                .extend((0..256).filter(|&i| code_size[i] == size).map(|i| i as u8));
+        }
+        ret
+    }
 }
 
+/// 按位存储的码流。统一使用 MSB 优先的顺序，与 JPEG 文件中的位序一致，可以直接作为字节输出，不需要反转。
+pub type JpegBits = BitVec<u8, Msb0>;
+
 #[derive(Debug)]
-pub struct CachedHuffmanTable(pub HashMap<u8, BitVec>);
+pub struct CachedHuffmanTable(pub HashMap<u8, JpegBits>);
+
+/// 编码用的霍夫曼码表，以符号为下标，存放 (码字, 码长)，码字的高位在前。码长为 0 表示没有该符号。
+/// 编码时直接查表写入，不需要分配内存。
+#[derive(Debug)]
+pub struct HuffmanCodeTable(pub [(u16, u8); 256]);
 
 // 完整的亮度直流、亮度交流、色度直流、色度交流的默认霍夫曼码表参见：
 // https://blog.csdn.net/xiaoyafang123/article/details/120370880
@@ -390,7 +481,7 @@ FA	16	1111111111111110"#;
 
 /// 根据参考网址的默认霍夫曼码表生成我的霍夫曼码表结构体。
 /// 返回的元组的第二个是用于验证的码字，按码表的 `values` 排序。
-fn generate_huffman_table(content: &str) -> (JpegHuffmanTable, Vec<BitVec>) {
+fn generate_huffman_table(content: &str) -> (JpegHuffmanTable, Vec<JpegBits>) {
     let mut codes = [0_u8; 16];
     let mut values = vec![];
     let mut bits_vec = vec![];
@@ -398,7 +489,7 @@ fn generate_huffman_table(content: &str) -> (JpegHuffmanTable, Vec<BitVec>) {
     for line in content.split('\n') {
         let mut symbol = u8::default();
         let mut length = u8::default();
-        let mut bits = bitvec![];
+        let mut bits = JpegBits::new();
         let mut idx = 0;
         for element in line.split('\t') {
             match idx {
@@ -448,16 +539,14 @@ fn generate_huffman_table(content: &str) -> (JpegHuffmanTable, Vec<BitVec>) {
 
 impl JpegHuffmanTable {
     /// 根据霍夫曼码表的 `codes` 字段生成霍夫曼码，`values[i]` 的霍夫曼码为 `ret[i]`。
-    pub fn generate_bits(&self) -> Vec<BitVec> {
+    pub fn generate_bits(&self) -> Vec<JpegBits> {
         let mut ret = vec![];
         let mut c = 0_usize;
         for i in 0..self.codes.len() {
             let length = i + 1;
             for _ in 0..self.codes[i] {
-                let mut bits = c.view_bits::<Lsb0>().to_owned();
-                bits.resize(length, false);
-                bits.reverse();
-                ret.push(bits);
+                // 码字的高位在前。
+                ret.push((0..length).rev().map(|j| (c >> j) & 1 == 1).collect());
                 c += 1;
             }
             c *= 2;
@@ -473,6 +562,70 @@ impl JpegHuffmanTable {
         }
         CachedHuffmanTable(ret)
     }
+
+    pub fn to_code_table(&self) -> HuffmanCodeTable {
+        let mut ret = [(0, 0); 256];
+        let mut c = 0_u16;
+        let mut values = self.values.iter();
+        for i in 0..self.codes.len() {
+            for _ in 0..self.codes[i] {
+                if let Some(&symbol) = values.next() {
+                    ret[symbol as usize] = (c, (i + 1) as u8);
+                }
+                c = c.wrapping_add(1);
+            }
+            c = c.wrapping_mul(2);
+        }
+        HuffmanCodeTable(ret)
+    }
+}
+
+/// 熵编码使用的霍夫曼表。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HuffmanMode {
+    /// JPEG 标准附录 K.3 中的表。
+    #[default]
+    Default,
+    /// 先统计每个符号出现的次数，再为这幅图像生成最优的表，需要编码两遍。
+    Optimized,
+}
+
+/// 熵编码的方式。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EntropyCoding {
+    /// 霍夫曼编码，写入 SOF0 和 DHT。
+    #[default]
+    Huffman,
+    /// 算术编码，写入 SOF9 和 DAC，见 `arithmetic`。
+    Arithmetic,
+}
+
+/// 一次编码使用的四个霍夫曼表，顺序为亮度 DC、亮度 AC、色度 DC、色度 AC，与 DHT 段中的顺序相同。
+#[derive(Debug, Clone)]
+pub struct HuffmanTables(pub [JpegHuffmanTable; 4]);
+
+impl Default for HuffmanTables {
+    fn default() -> Self {
+        Self([
+            DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE.clone(),
+            DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE.clone(),
+            DEFAULT_CHROMA_DC_HUFFMAN_TABLE.clone(),
+            DEFAULT_CHROMA_AC_HUFFMAN_TABLE.clone(),
+        ])
+    }
+}
+
+impl HuffmanTables {
+    /// 由 `ScanEncoder::symbol_counts` 生成最优的表。
+    pub fn optimal(counts: &[[usize; 256]; 4]) -> Self {
+        Self(counts.each_ref().map(JpegHuffmanTable::optimal))
+    }
+
+    /// 包含所有符号的表。12 位的样本有更大的类别，标准中的表没有这些符号，先用这个表编码一遍统计符号。
+    pub fn complete() -> Self {
+        let table = JpegHuffmanTable::optimal(&[1; 256]);
+        Self(std::array::from_fn(|_| table.clone()))
+    }
 }
 
 lazy_static! {
@@ -486,19 +639,21 @@ lazy_static! {
         generate_huffman_table(CHROMA_AC).0;
 }
 
-/// DC 编码器的差分性质由相邻 MCU 之间的同种类 DU 使用，YUV422 共需要 3 个 DC 编码器状态。
+/// DC 编码器的差分性质由相邻 MCU 之间的同种类 DU 使用，Y、Cb、Cr 共需要 3 个 DC 编码器状态。
 struct DcEncoder<'a> {
     pub pred: i16,
-    pub huffman_table: &'a CachedHuffmanTable,
+    pub huffman_table: &'a HuffmanCodeTable,
 }
 
 /// AC 编码器的行程编码性质在单个 DU 内部使用，有多少个 DU 就需要新建多少个 AC 编码器状态。
 struct AcEncoder<'a> {
     pub zero_run_length: usize,
-    pub huffman_table: &'a CachedHuffmanTable,
+    /// 下一个输入的系数在 Zigzag 顺序中的位置。
+    pub position: usize,
+    pub huffman_table: &'a HuffmanCodeTable,
 }
 
-fn get_category(abs_value: u16) -> u8 {
+pub fn get_category(abs_value: u16) -> u8 {
     // 根据表 8.17 将值分类。
     abs_value
         .view_bits::<Msb0>()
@@ -506,37 +661,33 @@ fn get_category(abs_value: u16) -> u8 {
         .map_or(0, |v| (bits_of::<u16>() - 1 - v) + 1) as u8
 }
 
+/// 将一个值按类别编码，追加到 `out` 的末尾，返回编码的符号。
+/// 霍夫曼码字和值的各位拼成一个整数后一次写入，不分配内存。
 fn entropy_encode_category(
-    huffman_table: &CachedHuffmanTable,
+    huffman_table: &HuffmanCodeTable,
     value: i16,
     zrl: Option<u8>,
-) -> BitVec {
-    let mut ret = bitvec![];
-
-    let abs_value = value.abs() as u16;
+    out: &mut JpegBits,
+) -> u8 {
+    let abs_value = value.unsigned_abs();
     let category = get_category(abs_value);
     // 符号的高四位表示 0 的行程编码（如果是 AC），符号的低四位表示类别。
     let symbol = (zrl.unwrap_or(0) << 4) | category;
 
-    let prefix = huffman_table.0.get(&symbol).unwrap();
-    ret.append(&mut prefix.to_owned());
-    if category != 0 {
-        // 不需要减去最高位。此时，最高位为 1 表示正数，最高位为 0 表示负数。
-        let mut abs_bits = abs_value.view_bits::<Lsb0>()[..category as usize].to_owned();
-        abs_bits.reverse(); // LSB0 to MSB0.
-        let mut bits = if value > 0 {
-            abs_bits
-        } else {
-            abs_bits.iter().map(|v| !v).collect()
-        };
-        ret.append(&mut bits);
-    }
-
-    ret
+    let (code, length) = huffman_table.0[symbol as usize];
+    debug_assert!(length != 0, "symbol 0x{:02X} is not in the table", symbol);
+    // 不需要减去最高位。此时，最高位为 1 表示正数，最高位为 0 表示负数。
+    let bits = if value > 0 { abs_value } else { !abs_value };
+    let mask = (1_u32 << category) - 1;
+    // 码字和值都至多 16 位，拼起来不超过 32 位。
+    let word = ((code as u32) << category) | (bits as u32 & mask);
+    let total = (length + category) as usize;
+    out.extend_from_bitslice(&word.view_bits::<Msb0>()[32 - total..]);
+    symbol
 }
 
 impl<'a> DcEncoder<'a> {
-    pub fn new(huffman_table: &'a CachedHuffmanTable) -> Self {
+    pub fn new(huffman_table: &'a HuffmanCodeTable) -> Self {
         Self {
             pred: 0,
             huffman_table,
@@ -545,42 +696,52 @@ impl<'a> DcEncoder<'a> {
 }
 
 impl<'a> AcEncoder<'a> {
-    pub fn new(huffman_table: &'a CachedHuffmanTable) -> Self {
+    pub fn new(huffman_table: &'a HuffmanCodeTable) -> Self {
         Self {
             zero_run_length: 0,
+            position: 1,
             huffman_table,
         }
     }
 }
 
+/// 编码结果直接追加到 `out` 的末尾，避免每个值都分配新的 `BitVec`。
+/// 每编码一个符号，以 `SymbolSink` 的参数调用 `sink`，不跟踪时传入空的闭包。
 trait JpegScanEncode {
-    fn next(&mut self, value: i16) -> BitVec;
+    fn next(&mut self, value: i16, out: &mut JpegBits, sink: &mut impl SymbolSink);
 }
 
+/// 接收编码或解码的每个符号：符号覆盖的第一个系数在 Zigzag 顺序中的位置、符号，
+/// 以及附加的值（DC 为差分值，EOB 和 ZRL 为 0）。见 `bit_trace`。
+pub trait SymbolSink: FnMut(u8, u8, i16) {}
+
+impl<F: FnMut(u8, u8, i16)> SymbolSink for F {}
+
 impl<'a> JpegScanEncode for DcEncoder<'a> {
-    fn next(&mut self, value: i16) -> BitVec {
+    fn next(&mut self, value: i16, out: &mut JpegBits, sink: &mut impl SymbolSink) {
         let diff = value - self.pred;
-        let ret = entropy_encode_category(&self.huffman_table, diff, None);
+        let symbol = entropy_encode_category(self.huffman_table, diff, None, out);
+        sink(0, symbol, diff);
         self.pred = value;
-        ret
     }
 }
 
 impl<'a> JpegScanEncode for AcEncoder<'a> {
-    fn next(&mut self, value: i16) -> BitVec {
+    fn next(&mut self, value: i16, out: &mut JpegBits, sink: &mut impl SymbolSink) {
         if value == 0 {
             self.zero_run_length += 1;
-            bitvec![]
         } else {
-            let mut ret = self.flush(false);
-            ret.append(&mut entropy_encode_category(
-                &self.huffman_table,
+            self.flush(false, out, sink);
+            let symbol = entropy_encode_category(
+                self.huffman_table,
                 value,
                 Some(self.zero_run_length as u8),
-            ));
+                out,
+            );
+            sink((self.position - self.zero_run_length) as u8, symbol, value);
             self.zero_run_length = 0;
-            ret
         }
+        self.position += 1;
     }
 }
 
@@ -588,94 +749,571 @@ impl<'a> AcEncoder<'a> {
     /// 将当前的零游程单独编码。
     /// 如果 `is_end_of_block` 为 `true`，则根据是否有零游程输出 EOB。
     /// 如果 `is_end_of_block` 为 `false`，则编码超过 16 个的 0，直到零游程小于 16。
-    fn flush(&mut self, is_end_of_block: bool) -> BitVec {
-        if is_end_of_block == true {
+    fn flush(&mut self, is_end_of_block: bool, out: &mut JpegBits, sink: &mut impl SymbolSink) {
+        let start = (self.position - self.zero_run_length) as u8;
+        if is_end_of_block {
             if self.zero_run_length != 0 {
-                entropy_encode_category(&self.huffman_table, 0, None) // EOB: 0/0
-            } else {
-                bitvec![]
+                let symbol = entropy_encode_category(self.huffman_table, 0, None, out); // EOB: 0/0
+                sink(start, symbol, 0);
             }
         } else {
-            let mut ret = bitvec![];
             while self.zero_run_length >= 16 {
-                let mut f0 = entropy_encode_category(&self.huffman_table, 0, Some(15));
-                ret.append(&mut f0);
+                let start = (self.position - self.zero_run_length) as u8;
+                let symbol = entropy_encode_category(self.huffman_table, 0, Some(15), out);
+                sink(start, symbol, 0);
                 self.zero_run_length -= 16;
             }
-            ret
         }
     }
 }
 
 /// 最基本的 JPEG 编码结果，可以据此生成 JPEG 文件。
-/// 但是注意，假设使用 YUV422 采样，使用了默认量化表，使用了默认霍夫曼码表，这些都不在此提及。
+/// 但是注意，采样因子和量化表都不在此提及。
 pub struct JpegOutputData {
     pub original_width: usize,
     pub original_height: usize,
     /// 熵编码的最终结果。
-    pub scan: BitVec,
+    pub scan: JpegBits,
+    /// 编码时的统计信息，用于自检。
+    pub summary: ScanSummary,
+    /// 编码使用的霍夫曼表，写入 DHT 段。
+    pub huffman_tables: HuffmanTables,
+    /// 使用优化的霍夫曼表时，用默认的表编码时 Y、Cb、Cr 各自的位数，用于比较。
+    pub default_table_bits: Option<[usize; 3]>,
+    /// 重新同步间隔，写入 DRI 段。为 `None` 时没有 DRI 和 RSTn。
+    pub restart_interval: Option<u16>,
+    /// 每个 RSTn 在 `scan` 中的字节位置，已按字节对齐。第 k 个写为 RST(k mod 8)。
+    pub restarts: Vec<usize>,
+    /// 渐进式编码的各个扫描，按 `PROGRESSIVE_SCRIPT` 的顺序。为空时是顺序编码。
+    /// 渐进式编码时 `scan` 和 `summary` 仍为顺序编码的结果，只用于统计，不写入文件。
+    pub progressive_scans: Vec<ProgressiveScan>,
+    /// 非交织的顺序编码的各个扫描，依次为 Y、Cb、Cr。为空时所有分量在一个扫描中交织。
+    /// 与渐进式编码时相同，`scan` 和 `summary` 仍为交织编码的结果，只用于统计，不写入文件。
+    pub component_scans: Vec<ProgressiveScan>,
+    /// 算术编码的扫描数据。不为 `None` 时写入文件的是它，`scan` 和 `summary` 仍为霍夫曼编码的结果，只用于统计和比较。
+    pub arithmetic_scan: Option<ArithmeticScan>,
+    /// 写入 APP0 的 JFIF 缩略图。
+    pub jfif_thumbnail: Option<RgbImage>,
 }
 
-/// 第六步：编码。
-/// 分为直流和交流。
-/// 为了方便，熵编码使用默认的霍夫曼编码。
-/// 尽管 DC 分量有差分编码，仍然是以 DU 为单位进行编码的。
-pub fn encode_step6(zigzag_mcu_collection: &ZigzagMcuCollection) -> io::Result<JpegOutputData> {
-    let mut scan = bitvec![];
-    let mcus = &zigzag_mcu_collection.zigzag_mcus;
-
-    fn encode_du(
-        du: &ZigzagDu,
-        dc_encoder: &mut DcEncoder,
-        ac_huffman_table: &CachedHuffmanTable,
-    ) -> BitVec {
-        let mut ret = bitvec![];
-
-        let mut ac_encoder = AcEncoder::new(&ac_huffman_table);
-        ret.append(&mut dc_encoder.next(du.0[0]));
-        for i in 1..du.0.len() {
-            ret.append(&mut ac_encoder.next(du.0[i]));
+/// 渐进式编码中的一个扫描。只使用频谱选择，不使用逐次逼近。
+/// 非交织的顺序编码中每个分量的扫描也用它表示，此时 Ss 为 0，Se 为 63。
+#[derive(Debug, Clone)]
+pub struct ProgressiveScan {
+    /// 扫描包含的分量，0 为 Y，1 为 Cb，2 为 Cr。多于一个分量时交织。
+    pub components: Vec<usize>,
+    /// 频谱选择的开始和结束，为 Zigzag 顺序中的位置。
+    pub ss: u8,
+    pub se: u8,
+    /// 熵编码的结果。
+    pub scan: JpegBits,
+    /// 每个 RSTn 在 `scan` 中的字节位置。
+    pub restarts: Vec<usize>,
+}
+
+/// 熵编码的统计信息。解码后重新统计并比较，可以发现码流的错误。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanSummary {
+    /// 编码的 MCU 数。
+    pub mcu_count: usize,
+    /// Y、Cb、Cr 所有 DU 的 DC 系数之和。
+    pub dc_sums: [i64; 3],
+    /// 按编码顺序，每个 MCU 中 Y、Cb、Cr 占用的位数。
+    pub mcu_bits: Vec<[u32; 3]>,
+}
+
+impl ScanSummary {
+    /// Y、Cb、Cr 各自的总位数。
+    pub fn component_bits(&self) -> [usize; 3] {
+        self.mcu_bits.iter().fold([0; 3], |sums, bits| {
+            std::array::from_fn(|i| sums[i] + bits[i] as usize)
+        })
+    }
+}
+
+fn encode_du(
+    du: &ZigzagDu,
+    dc_encoder: &mut DcEncoder,
+    ac_huffman_table: &HuffmanCodeTable,
+    out: &mut JpegBits,
+    sink: &mut impl SymbolSink,
+) {
+    let mut ac_encoder = AcEncoder::new(ac_huffman_table);
+    dc_encoder.next(du.0[0], out, sink);
+    for &value in &du.0[1..] {
+        ac_encoder.next(value, out, sink);
+    }
+    ac_encoder.flush(true, out, sink);
+}
+
+/// 统计一个 DU 的每个符号出现的次数，`counts` 为 (DC 的次数, AC 的次数)。
+/// 跟踪时还记录每个符号，码字从 (DC 码表, AC 码表) 中查出。
+fn trace_sink<'t>(
+    trace: &'t mut Option<Vec<TraceEntry>>,
+    counts: &'t mut [[usize; 256]],
+    mcu: usize,
+    component: usize,
+    du: usize,
+    (dc_table, ac_table): (&'t HuffmanCodeTable, &'t HuffmanCodeTable),
+) -> impl SymbolSink + 't {
+    move |position, symbol, value| {
+        counts[(position != 0) as usize][symbol as usize] += 1;
+        if let Some(trace) = trace {
+            let table = if position == 0 { dc_table } else { ac_table };
+            let (code, code_length) = table.0[symbol as usize];
+            trace.push(TraceEntry {
+                mcu,
+                component,
+                du,
+                symbol: CodedSymbol {
+                    position,
+                    value,
+                    symbol,
+                    code,
+                    code_length,
+                },
+            });
         }
-        ret.append(&mut ac_encoder.flush(true));
+    }
+}
 
-        ret
+/// 熵编码器，可以分多次输入 MCU，例如按条带编码时。DC 的差分预测值在多次输入之间保留。
+pub struct ScanEncoder {
+    luminance_dc_huffman_table: HuffmanCodeTable,
+    chroma_dc_huffman_table: HuffmanCodeTable,
+    luminance_ac_huffman_table: HuffmanCodeTable,
+    chroma_ac_huffman_table: HuffmanCodeTable,
+    /// Y、Cb、Cr 的 DC 预测值。
+    dc_preds: [i16; 3],
+    scan: JpegBits,
+    summary: ScanSummary,
+    huffman_tables: HuffmanTables,
+    /// 每个符号出现的次数，顺序与 `HuffmanTables` 相同。
+    symbol_counts: [[usize; 256]; 4],
+    /// 调用 `enable_trace` 后记录每个符号。
+    trace: Option<Vec<TraceEntry>>,
+    /// 每隔多少个 MCU 插入一个 RSTn。
+    restart_interval: Option<u16>,
+    /// 每个 RSTn 在 `scan` 中的字节位置。
+    restarts: Vec<usize>,
+}
+
+impl ScanEncoder {
+    /// 使用默认的霍夫曼表。`mcu_count` 为预计的 MCU 总数，用于预估扫描数据的大小。
+    pub fn new(mcu_count: usize) -> Self {
+        Self::with_tables(mcu_count, HuffmanTables::default())
+    }
+
+    /// 使用指定的霍夫曼表。表中必须有编码时用到的所有符号。
+    pub fn with_tables(mcu_count: usize, huffman_tables: HuffmanTables) -> Self {
+        let [luminance_dc, luminance_ac, chroma_dc, chroma_ac] = huffman_tables
+            .0
+            .each_ref()
+            .map(JpegHuffmanTable::to_code_table);
+        Self {
+            luminance_dc_huffman_table: luminance_dc,
+            chroma_dc_huffman_table: chroma_dc,
+            luminance_ac_huffman_table: luminance_ac,
+            chroma_ac_huffman_table: chroma_ac,
+            dc_preds: [0; 3],
+            // 默认量化表下平均每个系数大约不到 1 位。
+            scan: JpegBits::with_capacity(mcu_count * 4 * 64),
+            summary: ScanSummary::default(),
+            huffman_tables,
+            symbol_counts: [[0; 256]; 4],
+            trace: None,
+            restart_interval: None,
+            restarts: vec![],
+        }
+    }
+
+    /// 到目前为止每个符号出现的次数，顺序与 `HuffmanTables` 相同。
+    pub fn symbol_counts(&self) -> &[[usize; 256]; 4] {
+        &self.symbol_counts
     }
 
-    let luminance_dc_huffman_table = DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE.to_cached();
-    let chroma_dc_huffman_table = DEFAULT_CHROMA_DC_HUFFMAN_TABLE.to_cached();
-    let luminance_ac_huffman_table = DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE.to_cached();
-    let chroma_ac_huffman_table = DEFAULT_CHROMA_AC_HUFFMAN_TABLE.to_cached();
+    /// 之后编码的每个符号都记录下来，由 `take_trace` 取出。只适合很小的图像。
+    pub fn enable_trace(&mut self) {
+        self.trace.get_or_insert_with(Vec::new);
+    }
+
+    /// 每隔 `interval` 个 MCU 插入一个 RSTn，必须在编码第一个 MCU 之前设置。为 `None` 或 0 时不插入。
+    pub fn set_restart_interval(&mut self, interval: Option<u16>) {
+        self.restart_interval = interval.filter(|&n| n != 0);
+    }
+
+    /// 取出已记录的符号。
+    pub fn take_trace(&mut self) -> Vec<TraceEntry> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
 
-    let mut dc_encoder_y = DcEncoder::new(&luminance_dc_huffman_table);
-    let mut dc_encoder_u = DcEncoder::new(&chroma_dc_huffman_table);
-    let mut dc_encoder_v = DcEncoder::new(&chroma_dc_huffman_table);
-    for mcu in mcus {
-        scan.append(&mut encode_du(
-            &mcu.y0,
-            &mut dc_encoder_y,
-            &luminance_ac_huffman_table,
+    /// 按顺序编码一批 MCU，追加到扫描数据的末尾。
+    pub fn encode_mcus(&mut self, dus: &ComponentDus<ZigzagDu>) {
+        let mut dc_encoder_y = DcEncoder::new(&self.luminance_dc_huffman_table);
+        let mut dc_encoder_u = DcEncoder::new(&self.chroma_dc_huffman_table);
+        let mut dc_encoder_v = DcEncoder::new(&self.chroma_dc_huffman_table);
+        dc_encoder_y.pred = self.dc_preds[0];
+        dc_encoder_u.pred = self.dc_preds[1];
+        dc_encoder_v.pred = self.dc_preds[2];
+
+        let luminance_dc_huffman_table = &self.luminance_dc_huffman_table;
+        let chroma_dc_huffman_table = &self.chroma_dc_huffman_table;
+        let luminance_ac_huffman_table = &self.luminance_ac_huffman_table;
+        let chroma_ac_huffman_table = &self.chroma_ac_huffman_table;
+        let scan = &mut self.scan;
+        let trace = &mut self.trace;
+        let (luminance_counts, chroma_counts) = self.symbol_counts.split_at_mut(2);
+        let first_mcu = self.summary.mcu_count;
+        self.summary.mcu_bits.reserve(dus.mcu_count());
+        for i in 0..dus.mcu_count() {
+            let (ys, cb, cr) = dus.mcu(i);
+            let mcu = first_mcu + i;
+            // 重新同步：用 1 补齐到字节边界，记录 RSTn 的位置，DC 的预测值清零。
+            if is_restart(self.restart_interval, mcu) {
+                restart(scan, &mut self.restarts);
+                dc_encoder_y.pred = 0;
+                dc_encoder_u.pred = 0;
+                dc_encoder_v.pred = 0;
+            }
+            let start = scan.len();
+            let luminance = (luminance_dc_huffman_table, luminance_ac_huffman_table);
+            let chroma = (chroma_dc_huffman_table, chroma_ac_huffman_table);
+            for (j, y) in ys.iter().enumerate() {
+                encode_du(
+                    y,
+                    &mut dc_encoder_y,
+                    luminance_ac_huffman_table,
+                    scan,
+                    &mut trace_sink(trace, luminance_counts, mcu, 0, j, luminance),
+                );
+            }
+            let y_end = scan.len();
+            encode_du(
+                cb,
+                &mut dc_encoder_u,
+                chroma_ac_huffman_table,
+                scan,
+                &mut trace_sink(trace, chroma_counts, mcu, 1, 0, chroma),
+            );
+            let cb_end = scan.len();
+            encode_du(
+                cr,
+                &mut dc_encoder_v,
+                chroma_ac_huffman_table,
+                scan,
+                &mut trace_sink(trace, chroma_counts, mcu, 2, 0, chroma),
+            );
+            self.summary.mcu_bits.push([
+                (y_end - start) as u32,
+                (cb_end - y_end) as u32,
+                (scan.len() - cb_end) as u32,
+            ]);
+
+            let dc_sums = &mut self.summary.dc_sums;
+            dc_sums[0] += ys.iter().map(|y| y.0[0] as i64).sum::<i64>();
+            dc_sums[1] += cb.0[0] as i64;
+            dc_sums[2] += cr.0[0] as i64;
+        }
+        self.summary.mcu_count += dus.mcu_count();
+
+        self.dc_preds = [dc_encoder_y.pred, dc_encoder_u.pred, dc_encoder_v.pred];
+    }
+
+    /// 结束编码，返回原始尺寸为 `original_width` x `original_height` 的编码结果。
+    pub fn finish(self, original_width: usize, original_height: usize) -> JpegOutputData {
+        JpegOutputData {
+            original_width,
+            original_height,
+            scan: self.scan,
+            summary: self.summary,
+            huffman_tables: self.huffman_tables,
+            default_table_bits: None,
+            restart_interval: self.restart_interval,
+            restarts: self.restarts,
+            progressive_scans: vec![],
+            component_scans: vec![],
+            arithmetic_scan: None,
+            jfif_thumbnail: None,
+        }
+    }
+}
+
+/// 是否在第 `unit` 个 MCU（非交织的扫描中为 DU）之前插入 RSTn。
+fn is_restart(interval: Option<u16>, unit: usize) -> bool {
+    interval.is_some_and(|n| unit != 0 && unit.is_multiple_of(n as usize))
+}
+
+/// 用 1 补齐到字节边界，记录 RSTn 的位置。
+fn restart(scan: &mut JpegBits, restarts: &mut Vec<usize>) {
+    scan.resize(scan.len().next_multiple_of(8), true);
+    restarts.push(scan.len() / 8);
+}
+
+/// 渐进式编码的扫描顺序：(分量, Ss, Se)。先是所有分量的 DC，再是 Y 的低频 AC 和色度的 AC，最后是 Y 其余的 AC。
+/// 与 libjpeg 的 `jpeg_simple_progression` 去掉逐次逼近后相同。标准规定 AC 的扫描只能有一个分量。
+pub const PROGRESSIVE_SCRIPT: [(&[usize], u8, u8); 5] = [
+    (&[0, 1, 2], 0, 0),
+    (&[0], 1, 5),
+    (&[1], 1, 63),
+    (&[2], 1, 63),
+    (&[0], 6, 63),
+];
+
+/// 非交织的扫描中分量 `component` 的 DU，按从左到右、从上到下的顺序。
+/// 只包括与图像重叠的 DU，为补齐 MCU 而填充的 DU 不编码（标准 A.2.2）。
+fn component_dus(
+    dus: &ComponentDus<ZigzagDu>,
+    component: usize,
+    subsampling: Subsampling,
+    (width, height): (usize, usize),
+) -> Vec<&ZigzagDu> {
+    let (h, v) = subsampling.luma_factors();
+    let mcus_per_row = width.div_ceil(8 * h);
+    let mut ret = vec![];
+    if component == 0 {
+        for y in 0..height.div_ceil(8) {
+            for x in 0..width.div_ceil(8) {
+                let mcu = y / v * mcus_per_row + x / h;
+                ret.push(&dus.y[mcu * h * v + y % v * h + x % h]);
+            }
+        }
+    } else {
+        let plane = if component == 1 { &dus.cb } else { &dus.cr };
+        for y in 0..height.div_ceil(v).div_ceil(8) {
+            for x in 0..width.div_ceil(h).div_ceil(8) {
+                ret.push(&plane[y * mcus_per_row + x]);
+            }
+        }
+    }
+    ret
+}
+
+/// 按 `PROGRESSIVE_SCRIPT` 渐进式编码。`restart_interval` 在 DC 的扫描中以 MCU 计，在 AC 的扫描中以 DU 计。
+/// `huffman_tables` 中必须有用到的所有符号。EOB 只表示一个 DU 结束，不使用 EOB 行程，因此标准的 AC 表就足够。
+pub fn encode_progressive(
+    dus: &ComponentDus<ZigzagDu>,
+    subsampling: Subsampling,
+    dimensions: (usize, usize),
+    huffman_tables: &HuffmanTables,
+    restart_interval: Option<u16>,
+) -> Vec<ProgressiveScan> {
+    let [luminance_dc, luminance_ac, chroma_dc, chroma_ac] = huffman_tables
+        .0
+        .each_ref()
+        .map(JpegHuffmanTable::to_code_table);
+    let dc_tables = [&luminance_dc, &chroma_dc, &chroma_dc];
+    let ac_tables = [&luminance_ac, &chroma_ac, &chroma_ac];
+    let restart_interval = restart_interval.filter(|&n| n != 0);
+    let mut sink = |_: u8, _: u8, _: i16| {};
+
+    let mut ret = vec![];
+    for (components, ss, se) in PROGRESSIVE_SCRIPT {
+        let mut scan = JpegBits::new();
+        let mut restarts = vec![];
+        if ss == 0 {
+            // DC 的扫描交织，顺序与顺序编码相同。
+            let mut encoders = dc_tables.map(DcEncoder::new);
+            for i in 0..dus.mcu_count() {
+                if is_restart(restart_interval, i) {
+                    restart(&mut scan, &mut restarts);
+                    encoders.iter_mut().for_each(|encoder| encoder.pred = 0);
+                }
+                let (ys, cb, cr) = dus.mcu(i);
+                for y in ys {
+                    encoders[0].next(y.0[0], &mut scan, &mut sink);
+                }
+                encoders[1].next(cb.0[0], &mut scan, &mut sink);
+                encoders[2].next(cr.0[0], &mut scan, &mut sink);
+            }
+        } else {
+            let component = components[0];
+            for (i, du) in component_dus(dus, component, subsampling, dimensions)
+                .into_iter()
+                .enumerate()
+            {
+                if is_restart(restart_interval, i) {
+                    restart(&mut scan, &mut restarts);
+                }
+                let mut encoder = AcEncoder::new(ac_tables[component]);
+                encoder.position = ss as usize;
+                for &value in &du.0[ss as usize..=se as usize] {
+                    encoder.next(value, &mut scan, &mut sink);
+                }
+                encoder.flush(true, &mut scan, &mut sink);
+            }
+        }
+        ret.push(ProgressiveScan {
+            components: components.to_vec(),
+            ss,
+            se,
+            scan,
+            restarts,
+        });
+    }
+    ret
+}
+
+/// 非交织的顺序编码：每个分量一个扫描，依次为 Y、Cb、Cr，每个 DU 编码全部 64 个系数。
+/// 每个扫描只有一个分量，DU 的顺序见 `component_dus`，`restart_interval` 以 DU 计。
+/// 每个符号出现的次数累加到 `counts`，顺序与 `HuffmanTables` 相同。
+pub fn encode_non_interleaved(
+    dus: &ComponentDus<ZigzagDu>,
+    subsampling: Subsampling,
+    dimensions: (usize, usize),
+    huffman_tables: &HuffmanTables,
+    restart_interval: Option<u16>,
+    counts: &mut [[usize; 256]; 4],
+) -> Vec<ProgressiveScan> {
+    let code_tables = huffman_tables
+        .0
+        .each_ref()
+        .map(JpegHuffmanTable::to_code_table);
+    let restart_interval = restart_interval.filter(|&n| n != 0);
+
+    let mut ret = vec![];
+    for component in 0..3 {
+        // 亮度使用 0 号和 1 号表，色度使用 2 号和 3 号表。
+        let table = if component == 0 { 0 } else { 2 };
+        let counts = &mut counts[table..table + 2];
+        let mut sink = |position: u8, symbol: u8, _: i16| {
+            counts[(position != 0) as usize][symbol as usize] += 1;
+        };
+        let mut scan = JpegBits::new();
+        let mut restarts = vec![];
+        let mut dc_encoder = DcEncoder::new(&code_tables[table]);
+        for (i, du) in component_dus(dus, component, subsampling, dimensions)
+            .into_iter()
+            .enumerate()
+        {
+            if is_restart(restart_interval, i) {
+                restart(&mut scan, &mut restarts);
+                dc_encoder.pred = 0;
+            }
+            encode_du(
+                du,
+                &mut dc_encoder,
+                &code_tables[table + 1],
+                &mut scan,
+                &mut sink,
+            );
+        }
+        ret.push(ProgressiveScan {
+            components: vec![component],
+            ss: 0,
+            se: 63,
+            scan,
+            restarts,
+        });
+    }
+    ret
+}
+
+/// 第六步：编码。
+/// 分为直流和交流。
+/// 默认使用标准中的霍夫曼表；要求优化时先用默认的表编码一遍统计符号，再用生成的表重新编码。
+/// 尽管 DC 分量有差分编码，仍然是以 DU 为单位进行编码的。
+/// 12 位的样本总是使用优化的霍夫曼表，第一遍用 `HuffmanTables::complete` 统计符号。
+/// 指定重新同步间隔时每隔这么多个 MCU 插入 RSTn，DC 的差分重新开始。
+/// 渐进式编码时另外按 `PROGRESSIVE_SCRIPT` 编码各个扫描，不能与优化的霍夫曼表同时使用。
+/// 非交织时另外每个分量编码一个扫描，见 `encode_non_interleaved`。各个分量的 DC 按不同的顺序差分，
+/// 优化的霍夫曼表由交织和非交织时的符号一起统计，两种编码都能使用。
+/// 算术编码时另外用算术编码，只能顺序编码，也不使用霍夫曼表。
+#[tracing::instrument(skip_all, fields(mcu_count = zigzag_mcu_collection.zigzag_dus.mcu_count(), huffman = ?options.huffman, scan_bytes))]
+pub fn encode_step6(
+    zigzag_mcu_collection: &ZigzagMcuCollection,
+    options: &EncodeOptions,
+) -> io::Result<JpegOutputData> {
+    if options.progressive && options.huffman == HuffmanMode::Optimized {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Optimized Huffman tables cannot be used for progressive encoding",
         ));
-        scan.append(&mut encode_du(
-            &mcu.y1,
-            &mut dc_encoder_y,
-            &luminance_ac_huffman_table,
+    }
+    let arithmetic = options.entropy_coding == EntropyCoding::Arithmetic;
+    if arithmetic && (options.progressive || options.huffman == HuffmanMode::Optimized) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Arithmetic coding cannot be used for progressive encoding or with optimized Huffman tables",
         ));
-        scan.append(&mut encode_du(
-            &mcu.cb,
-            &mut dc_encoder_u,
-            &chroma_ac_huffman_table,
+    }
+    let twelve_bit = options.sample_precision == SamplePrecision::Twelve;
+    if twelve_bit && options.progressive {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "12-bit samples cannot be used for progressive encoding",
         ));
-        scan.append(&mut encode_du(
-            &mcu.cr,
-            &mut dc_encoder_v,
-            &chroma_ac_huffman_table,
+    }
+    if options.non_interleaved && (options.progressive || arithmetic) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Non-interleaved scans cannot be used for progressive encoding or with arithmetic coding",
         ));
     }
+    let dimensions = (
+        zigzag_mcu_collection.original_width,
+        zigzag_mcu_collection.original_height,
+    );
+    let dus = &zigzag_mcu_collection.zigzag_dus;
+    let mut encoder = if twelve_bit {
+        ScanEncoder::with_tables(dus.mcu_count(), HuffmanTables::complete())
+    } else {
+        ScanEncoder::new(dus.mcu_count())
+    };
+    encoder.set_restart_interval(options.restart_interval);
+    encoder.encode_mcus(dus);
+    let mut default_table_bits = None;
+    if options.huffman == HuffmanMode::Optimized || (twelve_bit && !arithmetic) {
+        if !twelve_bit {
+            default_table_bits = Some(encoder.summary.component_bits());
+        }
+        let mut counts = *encoder.symbol_counts();
+        if options.non_interleaved {
+            encode_non_interleaved(
+                dus,
+                options.subsampling,
+                dimensions,
+                &encoder.huffman_tables,
+                options.restart_interval,
+                &mut counts,
+            );
+        }
+        let tables = HuffmanTables::optimal(&counts);
+        encoder = ScanEncoder::with_tables(dus.mcu_count(), tables);
+        encoder.set_restart_interval(options.restart_interval);
+        encoder.encode_mcus(dus);
+    }
+    tracing::Span::current().record("scan_bytes", encoder.scan.len().div_ceil(8));
 
+    let progressive_scans = if options.progressive {
+        encode_progressive(
+            dus,
+            options.subsampling,
+            dimensions,
+            &encoder.huffman_tables,
+            options.restart_interval,
+        )
+    } else {
+        vec![]
+    };
+    let component_scans = if options.non_interleaved {
+        encode_non_interleaved(
+            dus,
+            options.subsampling,
+            dimensions,
+            &encoder.huffman_tables,
+            options.restart_interval,
+            &mut [[0; 256]; 4],
+        )
+    } else {
+        vec![]
+    };
+    let arithmetic_scan = arithmetic.then(|| encode_arithmetic(dus, options.restart_interval));
     Ok(JpegOutputData {
-        original_width: zigzag_mcu_collection.original_width,
-        original_height: zigzag_mcu_collection.original_height,
-        scan,
+        default_table_bits,
+        progressive_scans,
+        component_scans,
+        arithmetic_scan,
+        ..encoder.finish(dimensions.0, dimensions.1)
     })
 }
 
@@ -711,10 +1349,11 @@ mod test {
 
     #[test]
     fn test_dc_encoder() {
-        let table = DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE.to_cached();
+        let table = DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE.to_code_table();
         let mut encoder = DcEncoder::new(&table);
+        let mut result = JpegBits::new();
 
-        let result = encoder.next(14); // Category 4.
+        encoder.next(14, &mut result, &mut |_, _, _| {}); // Category 4.
         assert_eq!(
             result,
             bits!(
@@ -723,7 +1362,8 @@ mod test {
             )
         );
 
-        let result = encoder.next(114); // 100, Category 7.
+        result.clear();
+        encoder.next(114, &mut result, &mut |_, _, _| {}); // 100, Category 7.
         assert_eq!(
             result,
             bits!(
@@ -732,7 +1372,8 @@ mod test {
             )
         );
 
-        let result = encoder.next(-514); // -628, Category A, 1's complement.
+        result.clear();
+        encoder.next(-514, &mut result, &mut |_, _, _| {}); // -628, Category A, 1's complement.
         assert_eq!(
             result,
             bits!(
@@ -742,9 +1383,43 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_optimal_huffman_table() {
+        let mut counts = [0; 256];
+        counts[0x00] = 100;
+        counts[0x01] = 50;
+        counts[0x11] = 25;
+        counts[0xF0] = 25;
+        let table = JpegHuffmanTable::optimal(&counts);
+        assert_eq!(table.codes[..5], [1, 1, 1, 1, 0]);
+        assert_eq!(table.values, [0x00, 0x01, 0x11, 0xF0]);
+
+        // 按斐波那契数列出现的符号会生成很长的码字，需要限制在 16 位以内。
+        let mut counts = [0; 256];
+        let (mut a, mut b) = (1, 1);
+        for count in counts.iter_mut().take(40) {
+            *count = a;
+            (a, b) = (b, a + b);
+        }
+        let table = JpegHuffmanTable::optimal(&counts);
+        assert_eq!(table.values.len(), 40);
+        assert_eq!(table.codes.iter().map(|&n| n as usize).sum::<usize>(), 40);
+        assert_ne!(table.codes[15], 0);
+        let kraft: f64 = (1..=16)
+            .zip(table.codes)
+            .map(|(length, n)| n as f64 / (1 << length) as f64)
+            .sum();
+        // 不使用全 1 的码字。
+        assert!(kraft < 1.0);
+        // 出现最多的符号码字最短。
+        assert_eq!(table.values[..2], [38, 39]);
+
+        assert!(JpegHuffmanTable::optimal(&[0; 256]).values.is_empty());
+    }
+
     #[test]
     fn test_ac_encoder() {
-        let table = DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE.to_cached();
+        let table = DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE.to_code_table();
         // 课件上的例子。注意表不一样。
         let ac = [
             5, -2, 0, 2, 0, 0, 0, //
@@ -758,11 +1433,25 @@ mod test {
         ];
 
         let mut encoder = AcEncoder::new(&table);
-        let mut result = bitvec![];
+        let mut result = JpegBits::new();
+        let mut symbols = vec![];
+        let mut sink = |position, symbol, value| symbols.push((position, symbol, value));
         for v in ac {
-            result.append(&mut encoder.next(v));
+            encoder.next(v, &mut result, &mut sink);
         }
-        result.append(&mut encoder.flush(true));
+        encoder.flush(true, &mut result, &mut sink);
+        assert_eq!(
+            symbols,
+            [
+                (1, 0x03, 5),
+                (2, 0x02, -2),
+                (3, 0x12, 2),
+                (5, 0x31, 1),
+                (9, 0xF0, 0),
+                (25, 0x61, -1),
+                (32, 0x00, 0),
+            ]
+        );
 
         let truth = bits![
             1, 0, 0, // 0/3
@@ -1,21 +1,28 @@
 use std::io;
 use std::path::Path;
 
-use bitvec::field::BitField;
-use bitvec::order::Lsb0;
-use bitvec::view::BitView;
 use bytebuffer::ByteBuffer;
 use bytebuffer::Endian;
+use image::RgbImage;
 
+use super::analysis::entropy_report;
+use super::analysis::print_huffman_savings;
+use super::analysis::print_huffman_statistics;
+use super::analysis::save_bit_cost;
+use super::arithmetic::AC_CONDITIONING;
+use super::arithmetic::DC_CONDITIONING;
+use super::encode_step1::SamplePrecision;
+use super::encode_step1::Subsampling;
 use super::encode_step4::QuantizationTable;
 use super::encode_step4::CHROMINANCE_QUANTIZATION_TABLE;
 use super::encode_step4::LUMINANCE_QUANTIZATION_TABLE;
+use super::encode_step6::JpegBits;
 use super::encode_step6::JpegHuffmanTable;
 use super::encode_step6::JpegOutputData;
-use super::encode_step6::DEFAULT_CHROMA_AC_HUFFMAN_TABLE;
-use super::encode_step6::DEFAULT_CHROMA_DC_HUFFMAN_TABLE;
-use super::encode_step6::DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE;
-use super::encode_step6::DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE;
+use super::encode_step6::ProgressiveScan;
+use super::options::EncodeOptions;
+use super::verify::verify_jpeg;
+use super::zigzag::to_zigzag;
 
 /// 图像开始。
 /// FF D8
@@ -26,7 +33,7 @@ pub struct SOI;
 /// FF E0
 #[derive(Debug)]
 pub struct APP0 {
-    /// 块长度（不含起始符号 FF E0）。总是为 16。
+    /// 块长度（不含起始符号 FF E0）。没有缩略图时为 16。
     pub length: u16,
     pub identifier: [u8; 5],
     pub major_version: u8,
@@ -36,6 +43,8 @@ pub struct APP0 {
     pub y_density: u16,
     pub x_thumbnail: u8,
     pub y_thumbnail: u8,
+    /// 缩略图的 RGB 像素，逐行存储，共 3 × x_thumbnail × y_thumbnail 个字节。
+    pub thumbnail: Vec<u8>,
 }
 
 impl Default for APP0 {
@@ -50,16 +59,125 @@ impl Default for APP0 {
             y_density: 1,
             x_thumbnail: 0,
             y_thumbnail: 0,
+            thumbnail: vec![],
         }
     }
 }
 
-/// 量化表。
-/// FF DB
+/// JFIF 像素密度的单位，写入 APP0。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DensityUnits {
+    /// 没有单位，密度只表示像素的宽高比。
+    #[default]
+    None,
+    /// 每英寸的像素数（DPI）。
+    Inch,
+    /// 每厘米的像素数。
+    Cm,
+}
+
+impl DensityUnits {
+    /// APP0 中的取值，未知的值返回 `None`。
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(DensityUnits::None),
+            1 => Some(DensityUnits::Inch),
+            2 => Some(DensityUnits::Cm),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            DensityUnits::None => 0,
+            DensityUnits::Inch => 1,
+            DensityUnits::Cm => 2,
+        }
+    }
+}
+
+/// APP0 中的像素密度。默认没有单位，宽高比为 1:1。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Density {
+    pub units: DensityUnits,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl Default for Density {
+    fn default() -> Self {
+        Self {
+            units: DensityUnits::None,
+            x: 1,
+            y: 1,
+        }
+    }
+}
+
+impl APP0 {
+    /// 设置像素密度。
+    pub fn with_density(self, density: Density) -> Self {
+        Self {
+            units: density.units.to_u8(),
+            x_density: density.x,
+            y_density: density.y,
+            ..self
+        }
+    }
+
+    /// 像素密度。单位未知时返回 `None`。
+    pub fn density(&self) -> Option<Density> {
+        Some(Density {
+            units: DensityUnits::from_u8(self.units)?,
+            x: self.x_density,
+            y: self.y_density,
+        })
+    }
+
+    /// 带有缩略图的 APP0。缩略图不能超过一个段的上限，见 `thumbnail::make_jfif_thumbnail`。
+    pub fn with_thumbnail(thumbnail: &RgbImage) -> Self {
+        let (width, height) = thumbnail.dimensions();
+        Self {
+            length: 16 + thumbnail.as_raw().len() as u16,
+            x_thumbnail: width as u8,
+            y_thumbnail: height as u8,
+            thumbnail: thumbnail.as_raw().clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Adobe 的应用程序标记，说明分量的颜色变换。有些解码器据此而不是 JFIF 判断颜色空间。
+/// FF EE
 #[derive(Debug)]
-pub struct DQT {
-    /// 块长度（不含起始符号 FF DB）。输出为 131。
+pub struct APP14 {
+    /// 块长度（不含起始符号 FF EE）。总是为 14。
     pub length: u16,
+    pub identifier: [u8; 5],
+    pub version: u16,
+    pub flags0: u16,
+    pub flags1: u16,
+    /// 0 表示没有变换（RGB 或 CMYK），1 表示 YCbCr，2 表示 YCCK。
+    pub transform: u8,
+}
+
+impl Default for APP14 {
+    /// 编码器的输出总是 YCbCr。
+    fn default() -> Self {
+        Self {
+            length: 14,
+            identifier: *b"Adobe",
+            version: 100,
+            flags0: 0,
+            flags1: 0,
+            transform: 1,
+        }
+    }
+}
+
+/// DQT 中的一个量化表。
+#[derive(Debug)]
+pub struct DqtTable {
     /// 量化表的精度，在原始结构中占 1 个字节的高 4 位。注意是完全大端。
     /// 1 表示 16 位，0 表示 8 位。
     pub is_precision_16: bool,
@@ -70,10 +188,9 @@ pub struct DQT {
     pub table: [u16; 64],
 }
 
-impl Default for DQT {
+impl Default for DqtTable {
     fn default() -> Self {
         Self {
-            length: 131,
             is_precision_16: true,
             id: 0,
             table: [0; 64],
@@ -81,6 +198,41 @@ impl Default for DQT {
     }
 }
 
+impl DqtTable {
+    /// 该表在 DQT 中占用的字节数。
+    fn size(&self) -> u16 {
+        1 + if self.is_precision_16 { 128 } else { 64 }
+    }
+}
+
+/// 量化表。一个 DQT 可以依次包含多个量化表。
+/// FF DB
+#[derive(Debug)]
+pub struct DQT {
+    /// 块长度（不含起始符号 FF DB）。只有一个 16 位精度的表时为 131，两个时为 260。
+    pub length: u16,
+    pub tables: Vec<DqtTable>,
+}
+
+impl DQT {
+    pub fn new(tables: Vec<DqtTable>) -> Self {
+        Self {
+            length: 2 + tables.iter().map(DqtTable::size).sum::<u16>(),
+            tables,
+        }
+    }
+}
+
+/// 量化表的组织方式。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DqtLayout {
+    /// 所有量化表放在同一个 DQT 中，这是大多数编码器的做法。
+    #[default]
+    Combined,
+    /// 每个量化表单独一个 DQT。
+    Separate,
+}
+
 /// SOF0 中用到的分量信息。
 #[derive(Debug)]
 pub struct SOF0Component {
@@ -96,13 +248,15 @@ pub struct SOF0Component {
     pub quantization_id: u8,
 }
 
-/// 帧图像开始标记 0。
+/// 帧图像开始标记 0。12 位的样本为 SOF1，渐进式编码时为 SOF2，结构相同。
 /// FF C0
 #[derive(Debug)]
 pub struct SOF0 {
+    /// 标记的第二个字节。SOF0 为 0xC0，SOF1 为 0xC1，SOF2 为 0xC2。
+    pub marker: u8,
     /// 块长度（不含起始符号 FF C0）。总是为 17。
     pub length: u16,
-    /// 每个颜色分量的位数。只支持 8。
+    /// 每个颜色分量的位数，8 或 12。
     pub precision: u8,
     /// 行数，高。
     pub lines: u16,
@@ -115,6 +269,7 @@ pub struct SOF0 {
 impl Default for SOF0 {
     fn default() -> Self {
         Self {
+            marker: 0xC0,
             length: 17,
             precision: 8,
             lines: 0,
@@ -143,12 +298,9 @@ impl Default for SOF0 {
     }
 }
 
-/// 霍夫曼表。
-/// FF C4
+/// DHT 中的一个霍夫曼表。
 #[derive(Debug)]
-pub struct DHT {
-    /// 块长度（不含起始符号 FF C4）。
-    pub length: u16,
+pub struct DhtTable {
     /// 霍夫曼表的类别，在原始结构中占 1 个字节的高 4 位。
     /// 0 表示 DC，1 表示 AC。
     pub table_class: u8,
@@ -161,6 +313,135 @@ pub struct DHT {
     pub values: Vec<u8>,
 }
 
+impl DhtTable {
+    /// 该表在 DHT 中占用的字节数。
+    fn size(&self) -> u16 {
+        1 + 16 + self.values.len() as u16
+    }
+}
+
+/// 霍夫曼表。一个 DHT 可以依次包含多个霍夫曼表。
+/// FF C4
+#[derive(Debug)]
+pub struct DHT {
+    /// 块长度（不含起始符号 FF C4）。
+    pub length: u16,
+    pub tables: Vec<DhtTable>,
+}
+
+impl DHT {
+    pub fn new(tables: Vec<DhtTable>) -> Self {
+        Self {
+            length: 2 + tables.iter().map(DhtTable::size).sum::<u16>(),
+            tables,
+        }
+    }
+}
+
+/// DAC 中的一个条件参数。
+#[derive(Debug)]
+pub struct DacTable {
+    /// 类别，在原始结构中占 1 个字节的高 4 位。0 表示 DC，1 表示 AC。
+    pub table_class: u8,
+    /// 编号，在原始结构中占 1 个字节的低 4 位，与霍夫曼表的编号相同。
+    pub id: u8,
+    /// DC 为 U << 4 | L，AC 为 Kx。
+    pub value: u8,
+}
+
+/// 算术编码的条件参数。没有 DAC 时使用默认值，这里仍然写出。
+/// FF CC
+#[derive(Debug)]
+pub struct DAC {
+    /// 块长度（不含起始符号 FF CC）。
+    pub length: u16,
+    pub tables: Vec<DacTable>,
+}
+
+impl Default for DAC {
+    /// 亮度和色度各一组 DC 和 AC 的参数，编号与霍夫曼表相同。
+    fn default() -> Self {
+        let (l, u) = DC_CONDITIONING;
+        let tables: Vec<_> = (0..2)
+            .flat_map(|id| {
+                [
+                    DacTable {
+                        table_class: 0,
+                        id,
+                        value: u << 4 | l,
+                    },
+                    DacTable {
+                        table_class: 1,
+                        id,
+                        value: AC_CONDITIONING,
+                    },
+                ]
+            })
+            .collect();
+        Self {
+            length: 2 + 2 * tables.len() as u16,
+            tables,
+        }
+    }
+}
+
+/// 量化表的精度。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DqtPrecision {
+    /// 所有值都不超过 255 时使用 8 位精度，否则使用 16 位精度。基线 JPEG 通常使用 8 位精度。
+    #[default]
+    Auto,
+    /// 总是使用 16 位精度。
+    Bits16,
+}
+
+/// 霍夫曼表的组织方式。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DhtLayout {
+    /// 每个霍夫曼表单独一个 DHT。
+    #[default]
+    Separate,
+    /// 所有霍夫曼表放在同一个 DHT 中，与 libjpeg 相同。
+    Combined,
+}
+
+/// 帧的采样因子和量化表。编码时由 `EncodeOptions::frame_layout` 决定，无损变换后可能不同。
+#[derive(Debug, Clone)]
+pub struct FrameLayout {
+    /// Y、Cb、Cr 的 (水平, 垂直) 采样因子。
+    pub sampling_factors: [(u8, u8); 3],
+    /// 亮度和色度的量化表，Cb 和 Cr 共用色度量化表。
+    pub quantization_tables: [QuantizationTable; 2],
+}
+
+impl Default for FrameLayout {
+    fn default() -> Self {
+        Self {
+            sampling_factors: Subsampling::default().sampling_factors(),
+            quantization_tables: [LUMINANCE_QUANTIZATION_TABLE, CHROMINANCE_QUANTIZATION_TABLE],
+        }
+    }
+}
+
+/// 定义重新同步间隔。
+/// FF DD
+#[derive(Debug)]
+pub struct DRI {
+    /// 块长度（不含起始符号 FF DD）。总是为 4。
+    pub length: u16,
+    /// 每隔多少个 MCU 插入一个 RSTn。
+    pub restart_interval: u16,
+}
+
+impl DRI {
+    pub fn new(restart_interval: u16) -> Self {
+        Self {
+            length: 4,
+            restart_interval,
+        }
+    }
+}
+
 /// SOS 中用到的分量信息。
 #[derive(Debug)]
 pub struct SOSComponent {
@@ -200,17 +481,17 @@ impl Default for SOS {
                 SOSComponent {
                     id: 1,
                     dc_huffman_id: 0,
-                    ac_huffman_id: 1,
+                    ac_huffman_id: 0,
                 },
                 SOSComponent {
                     id: 2,
-                    dc_huffman_id: 2,
-                    ac_huffman_id: 3,
+                    dc_huffman_id: 1,
+                    ac_huffman_id: 1,
                 },
                 SOSComponent {
                     id: 3,
-                    dc_huffman_id: 2,
-                    ac_huffman_id: 3,
+                    dc_huffman_id: 1,
+                    ac_huffman_id: 1,
                 },
             ],
             ss: 0,
@@ -262,6 +543,24 @@ impl ToVec for APP0 {
         ret.write_u16(self.y_density);
         ret.write_u8(self.x_thumbnail);
         ret.write_u8(self.y_thumbnail);
+        ret.write_bytes(&self.thumbnail);
+
+        ret.into_vec()
+    }
+}
+
+impl ToVec for APP14 {
+    fn to_vec(&self) -> Vec<u8> {
+        let mut ret = ByteBuffer::new();
+        ret.set_endian(Endian::BigEndian);
+        ret.write_bytes(&[0xFF, 0xEE]);
+
+        ret.write_u16(self.length);
+        ret.write_bytes(&self.identifier);
+        ret.write_u16(self.version);
+        ret.write_u16(self.flags0);
+        ret.write_u16(self.flags1);
+        ret.write_u8(self.transform);
 
         ret.into_vec()
     }
@@ -274,10 +573,16 @@ impl ToVec for DQT {
         ret.write_bytes(&[0xFF, 0xDB]);
 
         ret.write_u16(self.length);
-        let precision = if self.is_precision_16 { 1 } else { 0 };
-        ret.write_u8(precision << 4 | self.id);
-        for &value in self.table.iter() {
-            ret.write_u16(value);
+        for table in &self.tables {
+            let precision = if table.is_precision_16 { 1 } else { 0 };
+            ret.write_u8(precision << 4 | table.id);
+            for &value in table.table.iter() {
+                if table.is_precision_16 {
+                    ret.write_u16(value);
+                } else {
+                    ret.write_u8(value as u8);
+                }
+            }
         }
 
         ret.into_vec()
@@ -288,7 +593,7 @@ impl ToVec for SOF0 {
     fn to_vec(&self) -> Vec<u8> {
         let mut ret = ByteBuffer::new();
         ret.set_endian(Endian::BigEndian);
-        ret.write_bytes(&[0xFF, 0xC0]);
+        ret.write_bytes(&[0xFF, self.marker]);
 
         ret.write_u16(self.length);
         ret.write_u8(self.precision);
@@ -314,18 +619,49 @@ impl ToVec for DHT {
         ret.write_bytes(&[0xFF, 0xC4]);
 
         ret.write_u16(self.length);
-        ret.write_u8(self.table_class << 4 | self.id);
-        for &code in self.codes.iter() {
-            ret.write_u8(code);
+        for table in &self.tables {
+            ret.write_u8(table.table_class << 4 | table.id);
+            for &code in table.codes.iter() {
+                ret.write_u8(code);
+            }
+            for &value in table.values.iter() {
+                ret.write_u8(value);
+            }
         }
-        for &value in self.values.iter() {
-            ret.write_u8(value);
+
+        ret.into_vec()
+    }
+}
+
+impl ToVec for DAC {
+    fn to_vec(&self) -> Vec<u8> {
+        let mut ret = ByteBuffer::new();
+        ret.set_endian(Endian::BigEndian);
+        ret.write_bytes(&[0xFF, 0xCC]);
+
+        ret.write_u16(self.length);
+        for table in &self.tables {
+            ret.write_u8(table.table_class << 4 | table.id);
+            ret.write_u8(table.value);
         }
 
         ret.into_vec()
     }
 }
 
+impl ToVec for DRI {
+    fn to_vec(&self) -> Vec<u8> {
+        let mut ret = ByteBuffer::new();
+        ret.set_endian(Endian::BigEndian);
+        ret.write_bytes(&[0xFF, 0xDD]);
+
+        ret.write_u16(self.length);
+        ret.write_u16(self.restart_interval);
+
+        ret.into_vec()
+    }
+}
+
 impl ToVec for SOS {
     fn to_vec(&self) -> Vec<u8> {
         let mut ret = ByteBuffer::new();
@@ -360,59 +696,24 @@ impl ToVec for EOI {
 
 impl QuantizationTable {
     /// 量化表也是 Zigzag 形式存储的！！！
-    fn to_dqt(&self, id: u8) -> DQT {
-        // 默认 16 位精度。
-        let mut table = DQT::default();
-        table.id = id;
-
-        let input = &self.0;
-        let output = &mut table.table;
-
-        let mut x = 0;
-        let mut y = 0;
-        let mut idx = 0;
-
-        while idx < output.len() {
-            while idx < output.len() {
-                output[idx] = input[x][y];
-                idx += 1;
-                // 优先处理 y == 7，因为有对角线。
-                if y == 7 {
-                    x += 1;
-                    break;
-                } else if x == 0 {
-                    y += 1;
-                    break;
-                } else {
-                    x -= 1;
-                    y += 1;
-                }
-            }
-            while idx < output.len() {
-                output[idx] = input[x][y];
-                idx += 1;
-                // 优先处理 x == 7，因为有对角线。
-                if x == 7 {
-                    y += 1;
-                    break;
-                } else if y == 0 {
-                    x += 1;
-                    break;
-                } else {
-                    x += 1;
-                    y -= 1;
-                }
-            }
-        }
+    fn to_dqt_table(&self, id: u8, precision: DqtPrecision) -> DqtTable {
+        let table = to_zigzag(&self.0);
+        let is_precision_16 = match precision {
+            DqtPrecision::Auto => table.iter().any(|&value| value > 255),
+            DqtPrecision::Bits16 => true,
+        };
 
-        table
+        DqtTable {
+            is_precision_16,
+            id,
+            table,
+        }
     }
 }
 
 impl JpegHuffmanTable {
-    fn to_dht(&self, id: u8, table_class: u8) -> DHT {
-        DHT {
-            length: 2 + 1 + 16 + self.values.len() as u16,
+    fn to_dht_table(&self, id: u8, table_class: u8) -> DhtTable {
+        DhtTable {
             table_class,
             id,
             codes: self.codes,
@@ -421,74 +722,148 @@ impl JpegHuffmanTable {
     }
 }
 
+impl SOS {
+    /// 渐进式编码或非交织的顺序编码中的一个扫描。Y 使用 0 号霍夫曼表，色度使用 1 号。
+    fn progressive(scan: &ProgressiveScan) -> Self {
+        let components: Vec<_> = scan
+            .components
+            .iter()
+            .map(|&i| SOSComponent {
+                id: i as u8 + 1,
+                dc_huffman_id: (i != 0) as u8,
+                ac_huffman_id: (i != 0) as u8,
+            })
+            .collect();
+        Self {
+            length: 6 + 2 * components.len() as u16,
+            components,
+            ss: scan.ss,
+            se: scan.se,
+            ah: 0,
+            al: 0,
+        }
+    }
+}
+
 impl JpegOutputData {
     fn to_image_data(&self) -> ImageData {
-        let mut ret = ImageData::new();
-        let scan = &self.scan;
-
-        let mut raw_vec = vec![];
-        raw_vec.resize((scan.len() + 7) / 8, Default::default());
-        let usize_slice = scan.as_raw_slice();
-        let u8_slice;
-        unsafe {
-            let ptr = usize_slice.as_ptr() as *const u8;
-            let length = usize_slice.len() * std::mem::size_of::<usize>();
-            u8_slice = &std::slice::from_raw_parts(ptr, length)[..raw_vec.len()];
-        }
-
-        // To MSB.
-        for i in 0..raw_vec.len() {
-            let mut bits = u8_slice[i].view_bits::<Lsb0>().to_owned();
-            bits.reverse();
-            raw_vec[i] = bits.load();
-        }
-
-        // 防止出现 0xFF 0xxx 被当作标记，一旦出现 0xFF 就在后面补充 0x00。
-        for v in raw_vec {
-            ret.0.push(v);
-            if v == 0xFF {
-                ret.0.push(0);
-            }
+        match &self.arithmetic_scan {
+            Some(scan) => stuff_bytes(scan.data.clone(), &scan.restarts),
+            None => to_image_data(&self.scan, &self.restarts),
         }
+    }
+}
 
-        ret
+/// 把码流 `scan` 转换为图像数据，在 `restarts` 的位置插入 RSTn。
+fn to_image_data(scan: &JpegBits, restarts: &[usize]) -> ImageData {
+    // 码流已经是 MSB 优先的，直接按字节输出。最后一个字节不足的位补 0。
+    let mut raw_vec = scan.as_raw_slice().to_vec();
+    let tail = scan.len() % 8;
+    if tail != 0 {
+        *raw_vec.last_mut().unwrap() &= 0xFF << (8 - tail);
     }
+    stuff_bytes(raw_vec, restarts)
 }
 
-/// 第七步：输出 JPEG 文件。
-/// 文件名为 out.jpg。
-pub fn encode_step7(data: &JpegOutputData) -> io::Result<()> {
-    let out_path = Path::new("out.jpg");
+/// 在 0xFF 之后填充 0x00，在 `restarts` 的位置插入 RSTn。
+fn stuff_bytes(raw_vec: Vec<u8>, restarts: &[usize]) -> ImageData {
+    let mut ret = ImageData::new();
+
+    // 防止出现 0xFF 0xxx 被当作标记，一旦出现 0xFF 就在后面补充 0x00。
+    // 在记录的位置插入 RST0 到 RST7，循环使用。
+    let mut restarts = restarts.iter().enumerate().peekable();
+    for (i, v) in raw_vec.into_iter().enumerate() {
+        if let Some((k, _)) = restarts.next_if(|&(_, &position)| position == i) {
+            ret.0.extend([0xFF, 0xD0 + (k % 8) as u8]);
+        }
+        ret.0.push(v);
+        if v == 0xFF {
+            ret.0.push(0);
+        }
+    }
+
+    ret
+}
 
+/// 将编码结果组装为完整的 JPEG 文件内容。
+pub fn make_jpeg(data: &JpegOutputData, options: &EncodeOptions) -> Vec<u8> {
+    // EXIF 应当在 XMP 之前，APP2 的 ICC 配置文件在 APP1 之后。
+    let mut metadata = vec![];
+    if let Some(exif) = &options.exif {
+        metadata.extend(exif.to_vec());
+    }
+    if let Some(xmp) = &options.xmp {
+        metadata.extend(xmp.to_vec());
+    }
+    if let Some(icc_profile) = &options.icc_profile {
+        metadata.extend(icc_profile.to_vec());
+    }
+    make_jpeg_with_layout(data, &options.frame_layout(), &metadata, options)
+}
+
+/// 与 `make_jpeg` 相同，但使用指定的采样因子和量化表。
+/// `metadata` 为原样写在 APP0 之后的段，已包含标记和长度，例如转码时保留的 EXIF。
+#[tracing::instrument(skip_all, fields(width = data.original_width, height = data.original_height, bytes))]
+pub fn make_jpeg_with_layout(
+    data: &JpegOutputData,
+    layout: &FrameLayout,
+    metadata: &[u8],
+    options: &EncodeOptions,
+) -> Vec<u8> {
     let soi = SOI;
-    let app0 = APP0::default();
+    let app0 = match &data.jfif_thumbnail {
+        Some(thumbnail) => APP0::with_thumbnail(thumbnail),
+        None => APP0::default(),
+    }
+    .with_density(options.density);
     let mut dqts = Vec::<DQT>::new();
     let mut sof0 = SOF0::default();
     let mut dhts = Vec::<DHT>::new();
+    let dri = data.restart_interval.map(DRI::new);
     let sos = SOS::default();
     let image_data;
     let eoi = EOI;
 
     // DQT
-    const QUANTIZATION_TABLES: [QuantizationTable; 2] =
-        [LUMINANCE_QUANTIZATION_TABLE, CHROMINANCE_QUANTIZATION_TABLE];
-    for (i, q) in QUANTIZATION_TABLES.iter().enumerate() {
-        dqts.push(q.to_dqt(i as u8));
+    let dqt_tables = layout
+        .quantization_tables
+        .iter()
+        .enumerate()
+        .map(|(i, q)| q.to_dqt_table(i as u8, options.dqt_precision));
+    match options.dqt_layout {
+        DqtLayout::Combined => dqts.push(DQT::new(dqt_tables.collect())),
+        DqtLayout::Separate => dqts.extend(dqt_tables.map(|table| DQT::new(vec![table]))),
     }
 
-    // SOF0
+    // SOF0，12 位的样本为 SOF1，渐进式编码时为 SOF2，算术编码时为 SOF9
+    sof0.precision = options.sample_precision.bits();
+    if options.sample_precision == SamplePrecision::Twelve {
+        sof0.marker = 0xC1;
+    }
+    if !data.progressive_scans.is_empty() {
+        sof0.marker = 0xC2;
+    }
+    if data.arithmetic_scan.is_some() {
+        sof0.marker = 0xC9;
+    }
     sof0.lines = data.original_height as u16;
     sof0.samples_per_line = data.original_width as u16;
+    for (component, &(h, v)) in sof0.components.iter_mut().zip(&layout.sampling_factors) {
+        component.horizontal_sampling_factor = h;
+        component.vertical_sampling_factor = v;
+    }
 
     // DHT
-    let huffman_tables = [
-        DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE.clone(),
-        DEFAULT_LUMINANCE_AC_HUFFMAN_TABLE.clone(),
-        DEFAULT_CHROMA_DC_HUFFMAN_TABLE.clone(),
-        DEFAULT_CHROMA_AC_HUFFMAN_TABLE.clone(),
-    ];
-    for (i, h) in huffman_tables.iter().enumerate() {
-        dhts.push(h.to_dht(i as u8, if i % 2 == 0 { 0 } else { 1 }));
+    // 基线 JPEG 中每类最多两个表，亮度用 0 号表，色度用 1 号表。
+    let dht_tables = data
+        .huffman_tables
+        .0
+        .iter()
+        .enumerate()
+        .map(|(i, h)| h.to_dht_table((i / 2) as u8, (i % 2) as u8));
+    match options.dht_layout {
+        DhtLayout::Separate => dhts.extend(dht_tables.map(|table| DHT::new(vec![table]))),
+        DhtLayout::Combined => dhts.push(DHT::new(dht_tables.collect())),
     }
 
     // Image Data
@@ -497,24 +872,91 @@ pub fn encode_step7(data: &JpegOutputData) -> io::Result<()> {
     let mut output = ByteBuffer::new();
     output.write_bytes(&soi.to_vec());
     output.write_bytes(&app0.to_vec());
+    if options.adobe_app14 {
+        output.write_bytes(&APP14::default().to_vec());
+    }
+    output.write_bytes(metadata);
     for dqt in &dqts {
         output.write_bytes(&dqt.to_vec());
     }
     output.write_bytes(&sof0.to_vec());
-    for dht in &dhts {
-        output.write_bytes(&dht.to_vec());
+    // 算术编码时没有霍夫曼表，只有条件参数。
+    if data.arithmetic_scan.is_some() {
+        output.write_bytes(&DAC::default().to_vec());
+    } else {
+        for dht in &dhts {
+            output.write_bytes(&dht.to_vec());
+        }
+    }
+    if let Some(dri) = &dri {
+        output.write_bytes(&dri.to_vec());
+    }
+    if data.progressive_scans.is_empty() && data.component_scans.is_empty() {
+        output.write_bytes(&sos.to_vec());
+        output.write_bytes(&image_data.to_vec());
+    }
+    for scan in data.progressive_scans.iter().chain(&data.component_scans) {
+        output.write_bytes(&SOS::progressive(scan).to_vec());
+        output.write_bytes(&to_image_data(&scan.scan, &scan.restarts).to_vec());
     }
-    output.write_bytes(&sos.to_vec());
-    output.write_bytes(&image_data.to_vec());
     output.write_bytes(&eoi.to_vec());
 
-    std::fs::write(out_path, output.into_vec())
+    tracing::Span::current().record("bytes", output.len());
+    output.into_vec()
+}
+
+/// 第七步：输出 JPEG 文件。
+/// 文件名为 out.jpg。要求自检时，重新读取写入的文件并检查。要求时写出每个 MCU 占用的位数。
+#[tracing::instrument(skip_all)]
+pub fn encode_step7(data: &JpegOutputData, options: &EncodeOptions) -> io::Result<()> {
+    let out_path = Path::new("out.jpg");
+    std::fs::write(out_path, make_jpeg(data, options))?;
+
+    if options.verify {
+        verify_jpeg(&std::fs::read(out_path)?, data, &options.frame_layout())?;
+        println!("[INFO] 自检通过");
+    }
+    if let Some(default_bits) = data.default_table_bits {
+        print_huffman_savings(default_bits, data.summary.component_bits());
+    }
+    if let Some(scan) = &data.arithmetic_scan {
+        let huffman_bytes = data.scan.len().div_ceil(8);
+        println!(
+            "[INFO] 算术编码的扫描数据为 {} 字节，霍夫曼编码为 {} 字节，节省 {:.2}%",
+            scan.data.len(),
+            huffman_bytes,
+            (huffman_bytes as f64 - scan.data.len() as f64) / huffman_bytes.max(1) as f64 * 100.0
+        );
+    }
+    if options.huffman_stats {
+        print_huffman_statistics(&entropy_report(&std::fs::read(out_path)?)?);
+    }
+    if let Some(dir) = &options.bit_cost_dir {
+        save_bit_cost(data, options.subsampling, dir)?;
+        println!("[INFO] 每个 MCU 占用的位数写入 {}", dir.display());
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    use super::super::coefficients::CoefficientImage;
+    use super::super::component::decode_component;
+    use super::super::component::Component;
+    use super::super::decode16::Rgb16Image;
+    use super::super::decode_to_image;
+    use super::super::encode16_to_vec;
+    use super::super::encode_step6::EntropyCoding;
+    use super::super::encode_step6::HuffmanMode;
+    use super::super::encode_to_vec;
+    use super::super::metrics::psnr;
+    use super::super::tables::read_tables;
+    use super::super::test_util::test_image;
+    use super::super::test_util::test_image_of_size;
+    use super::super::thumbnail::make_jfif_thumbnail;
+
     #[test]
     fn test_app0() {
         let app0 = APP0::default().to_vec();
@@ -535,6 +977,190 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_jfif_thumbnail() {
+        let image = test_image();
+        let plain = encode_to_vec(&image, &Default::default()).unwrap();
+        let options = EncodeOptions {
+            jfif_thumbnail: Some(16),
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&image, &options).unwrap();
+        // 16x9 的缩略图，之后的段不变。
+        let length = 16 + 3 * 16 * 9;
+        assert_eq!(&jpeg[4..6], (length as u16).to_be_bytes());
+        assert_eq!(&jpeg[18..20], [16, 9]);
+        assert_eq!(jpeg[2 + 2 + length..], plain[2 + 18..]);
+        let thumbnail = &jpeg[20..2 + 2 + length];
+        assert_eq!(thumbnail, make_jfif_thumbnail(&image, 16).as_raw());
+        assert_eq!(
+            decode_to_image(&jpeg, &Default::default()).unwrap(),
+            decode_to_image(&plain, &Default::default()).unwrap()
+        );
+
+        // 最大的缩略图不超过段的上限，图像更小时不放大。
+        let large = make_jfif_thumbnail(&image::RgbImage::new(1000, 1000), 255);
+        assert_eq!(large.dimensions(), (85, 85));
+        assert!(APP0::with_thumbnail(&large).to_vec().len() - 2 <= u16::MAX as usize);
+        assert_eq!(make_jfif_thumbnail(&image, 80).dimensions(), (37, 21));
+    }
+
+    #[test]
+    fn test_app14() {
+        let app14 = APP14::default().to_vec();
+        assert_eq!(
+            app14,
+            [
+                0xFF, 0xEE, //
+                0x00, 0x0E, //
+                0x41, 0x64, 0x6F, 0x62, 0x65, //
+                0x00, 0x64, //
+                0x00, 0x00, //
+                0x00, 0x00, //
+                0x01, //
+            ]
+        );
+
+        let image = test_image();
+        let plain = encode_to_vec(&image, &Default::default()).unwrap();
+        let options = EncodeOptions {
+            adobe_app14: true,
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&image, &options).unwrap();
+        // 紧跟在 APP0 之后。
+        assert_eq!(jpeg[20..36], app14);
+        assert_eq!(
+            decode_to_image(&jpeg, &Default::default()).unwrap(),
+            decode_to_image(&plain, &Default::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_dqt_layout() {
+        let data = JpegOutputData {
+            original_width: 1,
+            original_height: 1,
+            scan: Default::default(),
+            summary: Default::default(),
+            huffman_tables: Default::default(),
+            default_table_bits: None,
+            restart_interval: None,
+            restarts: vec![],
+            progressive_scans: vec![],
+            component_scans: vec![],
+            arithmetic_scan: None,
+            jfif_thumbnail: None,
+        };
+        let count_dqt = |jpeg: &[u8]| jpeg.windows(2).filter(|w| w == &[0xFF, 0xDB]).count();
+
+        let options = |dqt_layout| EncodeOptions {
+            dqt_layout,
+            ..Default::default()
+        };
+
+        let combined = make_jpeg(&data, &options(DqtLayout::Combined));
+        assert_eq!(count_dqt(&combined), 1);
+        let start = combined.windows(2).position(|w| w == [0xFF, 0xDB]).unwrap();
+        assert_eq!(&combined[start + 2..start + 5], [0x00, 0x84, 0x00]);
+        // 第二个表紧接在第一个表之后。
+        assert_eq!(combined[start + 4 + 1 + 64], 0x01);
+
+        let separate = make_jpeg(&data, &options(DqtLayout::Separate));
+        assert_eq!(count_dqt(&separate), 2);
+        assert_eq!(separate.len(), combined.len() + 4);
+    }
+
+    #[test]
+    fn test_dqt_precision() {
+        let table = LUMINANCE_QUANTIZATION_TABLE.to_dqt_table(0, DqtPrecision::Auto);
+        assert!(!table.is_precision_16);
+        let dqt = DQT::new(vec![table]);
+        assert_eq!(dqt.length, 67);
+        assert_eq!(dqt.to_vec().len(), 2 + 67);
+
+        let table = LUMINANCE_QUANTIZATION_TABLE.to_dqt_table(0, DqtPrecision::Bits16);
+        assert!(table.is_precision_16);
+        assert_eq!(DQT::new(vec![table]).length, 131);
+
+        // 超过 255 的值只能以 16 位精度存储。
+        let mut large = LUMINANCE_QUANTIZATION_TABLE;
+        large.0[7][7] = 256;
+        assert!(large.to_dqt_table(0, DqtPrecision::Auto).is_precision_16);
+    }
+
+    #[test]
+    fn test_quality() {
+        let image = test_image();
+        let encode = |quality| {
+            let options = EncodeOptions {
+                quality,
+                ..Default::default()
+            };
+            encode_to_vec(&image, &options).unwrap()
+        };
+        let default = encode(None);
+        assert_eq!(encode(Some(50)), default);
+
+        // DQT 中是缩放后的表。
+        let high = encode(Some(90));
+        let tables = read_tables(&high).unwrap();
+        assert_eq!(
+            tables.quantization_tables[0].1 .0,
+            LUMINANCE_QUANTIZATION_TABLE.scaled(90).0
+        );
+        assert_eq!(
+            tables.quantization_tables[1].1 .0,
+            CHROMINANCE_QUANTIZATION_TABLE.scaled(90).0
+        );
+        assert!(high.len() > default.len());
+        assert!(encode(Some(10)).len() < default.len());
+
+        let error = |jpeg: &[u8]| {
+            let decoded = decode_to_image(jpeg, &Default::default()).unwrap();
+            psnr(&decoded, &image).unwrap()
+        };
+        assert!(error(&high) > error(&default));
+    }
+
+    #[test]
+    fn test_dht_layout() {
+        let data = JpegOutputData {
+            original_width: 1,
+            original_height: 1,
+            scan: Default::default(),
+            summary: Default::default(),
+            huffman_tables: Default::default(),
+            default_table_bits: None,
+            restart_interval: None,
+            restarts: vec![],
+            progressive_scans: vec![],
+            component_scans: vec![],
+            arithmetic_scan: None,
+            jfif_thumbnail: None,
+        };
+        let count_dht = |jpeg: &[u8]| jpeg.windows(2).filter(|w| w == &[0xFF, 0xC4]).count();
+
+        let options = |dht_layout| EncodeOptions {
+            dht_layout,
+            ..Default::default()
+        };
+
+        let separate = make_jpeg(&data, &options(DhtLayout::Separate));
+        assert_eq!(count_dht(&separate), 4);
+        let combined = make_jpeg(&data, &options(DhtLayout::Combined));
+        assert_eq!(count_dht(&combined), 1);
+        assert_eq!(combined.len(), separate.len() - 3 * 4);
+
+        // 解码器要能解析同一个 DHT 中的多个表。
+        let image =
+            image::RgbImage::from_fn(20, 10, |x, y| image::Rgb([x as u8 * 9, y as u8 * 17, 80]));
+        let jpeg = encode_to_vec(&image, &options(DhtLayout::Combined)).unwrap();
+        let expected = encode_to_vec(&image, &Default::default()).unwrap();
+        let decoded = decode_to_image(&jpeg, &Default::default()).unwrap();
+        assert!(decoded == decode_to_image(&expected, &Default::default()).unwrap());
+    }
+
     #[test]
     fn test_sof0() {
         let sof0 = SOF0::default().to_vec();
@@ -563,13 +1189,355 @@ mod test {
                 0xFF, 0xDA, //
                 0x00, 0x0C, //
                 0x03, //
-                0x01, 0x01, //
-                0x02, 0x23, //
-                0x03, 0x23, //
+                0x01, 0x00, //
+                0x02, 0x11, //
+                0x03, 0x11, //
                 0x00, //
                 0x3F, //
                 0x00, //
             ]
         );
     }
+
+    #[test]
+    fn test_optimized_huffman() {
+        let image = test_image_of_size(48, 24);
+        let options = EncodeOptions {
+            huffman: HuffmanMode::Optimized,
+            verify: true,
+            ..Default::default()
+        };
+        let optimized = encode_to_vec(&image, &options).unwrap();
+        let expected = encode_to_vec(&image, &Default::default()).unwrap();
+        // 熵编码是无损的，只有霍夫曼表不同。
+        let decoded = decode_to_image(&optimized, &Default::default()).unwrap();
+        assert!(decoded == decode_to_image(&expected, &Default::default()).unwrap());
+        assert!(optimized.len() < expected.len());
+    }
+
+    #[test]
+    fn test_restart_interval() {
+        // 4x6 = 24 个 MCU。
+        let image = test_image_of_size(64, 48);
+        let expected = encode_to_vec(&image, &Default::default()).unwrap();
+        for (huffman, striped) in [
+            (HuffmanMode::Default, false),
+            (HuffmanMode::Default, true),
+            (HuffmanMode::Optimized, false),
+        ] {
+            let options = EncodeOptions {
+                restart_interval: Some(2),
+                huffman,
+                striped,
+                ..Default::default()
+            };
+            let jpeg = encode_to_vec(&image, &options).unwrap();
+            assert!(jpeg
+                .windows(6)
+                .any(|w| w == [0xFF, 0xDD, 0x00, 0x04, 0x00, 0x02]));
+            // 除了第一个 MCU 之前，每隔 2 个 MCU 一个 RSTn，RST0 到 RST7 循环使用。
+            let scan_start = jpeg.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+            let markers: Vec<_> = jpeg[scan_start..]
+                .windows(2)
+                .filter(|w| w[0] == 0xFF && (0xD0..=0xD7).contains(&w[1]))
+                .map(|w| w[1] - 0xD0)
+                .collect();
+            assert_eq!(markers, [0, 1, 2, 3, 4, 5, 6, 7, 0, 1, 2]);
+
+            // 量化后的系数不变，其他解码器得到相同的图像。
+            let decode = |jpeg: &[u8]| {
+                image::load_from_memory_with_format(jpeg, image::ImageFormat::Jpeg)
+                    .unwrap()
+                    .to_rgb8()
+            };
+            assert!(decode(&jpeg) == decode(&expected), "{:?}", huffman);
+        }
+
+        let options = EncodeOptions {
+            restart_interval: Some(2),
+            verify: true,
+            ..Default::default()
+        };
+        encode_to_vec(&image, &options).unwrap();
+    }
+
+    #[test]
+    fn test_non_interleaved() {
+        let image = test_image();
+        // image 不支持多个扫描的顺序编码。把每个扫描单独组成一个灰度图像的文件解码，
+        // 与交织时解码出的分量平面比较，只差 IDCT 的误差。
+        let decode_scan = |jpeg: &[u8], component: usize, (width, height): (u16, u16)| {
+            let mut header = vec![0xFF, 0xD8];
+            let mut scans = vec![];
+            let mut i = 2;
+            while jpeg[i + 1] != 0xD9 {
+                let end = i + 2 + u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+                match jpeg[i + 1] {
+                    0xC0 => {}
+                    0xDA => {
+                        // 扫描数据一直到下一个不是 RSTn 或填充的标记。
+                        let mut data_end = end;
+                        while jpeg[data_end] != 0xFF
+                            || matches!(jpeg[data_end + 1], 0x00 | 0xD0..=0xD7)
+                        {
+                            data_end += 1;
+                        }
+                        scans.push(&jpeg[i..data_end]);
+                        i = data_end;
+                        continue;
+                    }
+                    _ => header.extend(&jpeg[i..end]),
+                }
+                i = end;
+            }
+            header.extend([0xFF, 0xC0, 0, 11, 8]);
+            header.extend(height.to_be_bytes());
+            header.extend(width.to_be_bytes());
+            header.extend([1, component as u8 + 1, 0x11, (component != 0) as u8]);
+            header.extend(scans[component]);
+            header.extend([0xFF, 0xD9]);
+            image::load_from_memory_with_format(&header, image::ImageFormat::Jpeg)
+                .unwrap()
+                .to_luma8()
+        };
+        for huffman in [HuffmanMode::Default, HuffmanMode::Optimized] {
+            let options = EncodeOptions {
+                huffman,
+                ..Default::default()
+            };
+            let expected = encode_to_vec(&image, &options).unwrap();
+            let non_interleaved = EncodeOptions {
+                non_interleaved: true,
+                ..options
+            };
+            let jpeg = encode_to_vec(&image, &non_interleaved).unwrap();
+            assert!(jpeg.windows(2).any(|w| w == [0xFF, 0xC0]));
+            let scans: Vec<_> = jpeg
+                .windows(3)
+                .enumerate()
+                .filter(|(_, w)| w[..2] == [0xFF, 0xDA])
+                .map(|(i, _)| i)
+                .collect();
+            // 每个扫描一个分量，ID 依次为 1 到 3。
+            assert_eq!(scans.len(), 3);
+            for (i, &start) in scans.iter().enumerate() {
+                assert_eq!(jpeg[start + 4], 1);
+                assert_eq!(jpeg[start + 5], i as u8 + 1);
+            }
+            // 系数相同，只是分成了多个扫描。
+            for (component, dimensions) in [
+                (Component::Y, (37, 21)),
+                (Component::Cb, (19, 21)),
+                (Component::Cr, (19, 21)),
+            ] {
+                let plane = decode_component(&expected, component, Default::default()).unwrap();
+                let decoded = decode_scan(&jpeg, component as usize, dimensions);
+                assert_eq!(decoded.dimensions(), plane.dimensions());
+                for (p, q) in decoded.pixels().zip(plane.pixels()) {
+                    assert!(p[0].abs_diff(q[0]) <= 2, "{:?} {:?}", huffman, component);
+                }
+            }
+        }
+
+        // Y 的扫描有 5x3 个 DU，色度的扫描各有 3x3 个 DU。
+        let options = EncodeOptions {
+            non_interleaved: true,
+            restart_interval: Some(2),
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&image, &options).unwrap();
+        let restarts = jpeg
+            .windows(2)
+            .filter(|w| w[0] == 0xFF && (0xD0..=0xD7).contains(&w[1]))
+            .count();
+        assert_eq!(restarts, 7 + 4 + 4);
+
+        for options in [
+            EncodeOptions {
+                progressive: true,
+                ..options.clone()
+            },
+            EncodeOptions {
+                entropy_coding: EntropyCoding::Arithmetic,
+                ..options.clone()
+            },
+            EncodeOptions {
+                striped: true,
+                ..options
+            },
+        ] {
+            assert!(encode_to_vec(&image, &options).is_err());
+        }
+    }
+
+    #[test]
+    fn test_progressive() {
+        let image = test_image();
+        let decode = |jpeg: &[u8]| {
+            image::load_from_memory_with_format(jpeg, image::ImageFormat::Jpeg)
+                .unwrap()
+                .to_rgb8()
+        };
+        // image 不支持 4:1:1。
+        for subsampling in [Subsampling::Yuv422, Subsampling::Yuv440] {
+            for restart_interval in [None, Some(2)] {
+                let options = EncodeOptions {
+                    subsampling,
+                    restart_interval,
+                    ..Default::default()
+                };
+                let expected = encode_to_vec(&image, &options).unwrap();
+                let progressive = EncodeOptions {
+                    progressive: true,
+                    ..options
+                };
+                let jpeg = encode_to_vec(&image, &progressive).unwrap();
+                assert!(jpeg.windows(2).any(|w| w == [0xFF, 0xC2]));
+                assert!(!jpeg.windows(2).any(|w| w == [0xFF, 0xC0]));
+                let scans = jpeg.windows(2).filter(|w| w == &[0xFF, 0xDA]).count();
+                assert_eq!(scans, 5);
+                if restart_interval.is_some() {
+                    // DC 的扫描有 9 或 10 个 MCU，Y 的扫描有 5x3 个 DU，色度的扫描有 3x3 或 5x2 个 DU。
+                    // image 在非交织的扫描中按 Y 的 DU 数计算色度的重新同步间隔，无法解码。
+                    let restarts = jpeg
+                        .windows(2)
+                        .filter(|w| w[0] == 0xFF && (0xD0..=0xD7).contains(&w[1]))
+                        .count();
+                    assert_eq!(restarts, 4 + 7 + 4 + 4 + 7);
+                    continue;
+                }
+                // 系数相同，只是分成了多个扫描。
+                assert!(decode(&jpeg) == decode(&expected), "{:?}", subsampling);
+            }
+        }
+
+        for options in [
+            EncodeOptions {
+                huffman: HuffmanMode::Optimized,
+                ..Default::default()
+            },
+            EncodeOptions {
+                striped: true,
+                ..Default::default()
+            },
+        ] {
+            let options = EncodeOptions {
+                progressive: true,
+                ..options
+            };
+            assert!(encode_to_vec(&image, &options).is_err());
+        }
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let image = test_image();
+        for restart_interval in [None, Some(2)] {
+            let options = EncodeOptions {
+                restart_interval,
+                ..Default::default()
+            };
+            let huffman = encode_to_vec(&image, &options).unwrap();
+            let arithmetic = EncodeOptions {
+                entropy_coding: EntropyCoding::Arithmetic,
+                ..options
+            };
+            let jpeg = encode_to_vec(&image, &arithmetic).unwrap();
+            assert!(jpeg.windows(2).any(|w| w == [0xFF, 0xC9]));
+            assert!(jpeg.windows(2).any(|w| w == [0xFF, 0xCC]));
+            assert!(!jpeg.windows(2).any(|w| w == [0xFF, 0xC4]));
+            assert!(jpeg.len() < huffman.len());
+            if restart_interval.is_some() {
+                let restarts = jpeg
+                    .windows(2)
+                    .filter(|w| w[0] == 0xFF && (0xD0..=0xD7).contains(&w[1]))
+                    .count();
+                assert_eq!(restarts, 4);
+            }
+
+            // 按条带编码时输出相同。
+            let striped = EncodeOptions {
+                striped: true,
+                ..arithmetic
+            };
+            assert_eq!(encode_to_vec(&image, &striped).unwrap(), jpeg);
+        }
+
+        for options in [
+            EncodeOptions {
+                huffman: HuffmanMode::Optimized,
+                ..Default::default()
+            },
+            EncodeOptions {
+                progressive: true,
+                ..Default::default()
+            },
+        ] {
+            let options = EncodeOptions {
+                entropy_coding: EntropyCoding::Arithmetic,
+                ..options
+            };
+            assert!(encode_to_vec(&image, &options).is_err());
+        }
+    }
+
+    #[test]
+    fn test_twelve_bit() {
+        // 75% 的灰色，Y 为 3071（8 位时为 191）。
+        let image = Rgb16Image::from_pixel(37, 21, image::Rgb([49151; 3]));
+        let options = EncodeOptions {
+            sample_precision: SamplePrecision::Twelve,
+            ..Default::default()
+        };
+        let jpeg = encode16_to_vec(&image, &options).unwrap();
+        let sof = jpeg.windows(2).position(|w| w == [0xFF, 0xC1]).unwrap();
+        assert_eq!(jpeg[sof + 4], 12);
+        assert!(!jpeg.windows(2).any(|w| w == [0xFF, 0xC0]));
+
+        // 自己的解码器只支持 8 位。把帧头改为 SOF0 和 8 位后，熵解码不受影响。
+        let mut patched = jpeg.clone();
+        patched[sof + 1] = 0xC0;
+        patched[sof + 4] = 8;
+        let coefficients = CoefficientImage::read(&patched).unwrap();
+        for (i, grid) in coefficients.grids.iter().enumerate() {
+            for y in 0..grid.height {
+                for x in 0..grid.width {
+                    let block = grid.get(x, y);
+                    // (3071 - 2048) * 8 / 16 = 511.5，是 8 位时的 16 倍；色度为 0。
+                    let dc = if i == 0 { 512 } else { 0 };
+                    assert!((block[0][0] - dc).abs() <= 1, "{} {:?}", i, block[0][0]);
+                    assert!(block.as_flattened()[1..].iter().all(|&v| v == 0));
+                }
+            }
+        }
+
+        // 8 位的输入也可以编码为 12 位；算术编码时为 SOF9。
+        let image8 = test_image();
+        let jpeg = encode_to_vec(&image8, &options).unwrap();
+        assert!(jpeg.windows(2).any(|w| w == [0xFF, 0xC1]));
+        let arithmetic = EncodeOptions {
+            entropy_coding: EntropyCoding::Arithmetic,
+            ..options.clone()
+        };
+        let jpeg = encode_to_vec(&image8, &arithmetic).unwrap();
+        let sof = jpeg.windows(2).position(|w| w == [0xFF, 0xC9]).unwrap();
+        assert_eq!(jpeg[sof + 4], 12);
+
+        for options in [
+            EncodeOptions {
+                striped: true,
+                ..options.clone()
+            },
+            EncodeOptions {
+                progressive: true,
+                ..options.clone()
+            },
+            EncodeOptions {
+                verify: true,
+                ..options
+            },
+        ] {
+            assert!(encode_to_vec(&image8, &options).is_err());
+        }
+    }
 }
@@ -0,0 +1,222 @@
+//! 从 JPEG 中取出元数据的原始内容，不解码图像，交给其他工具处理。
+//!
+//! EXIF 为 APP1 中标识之后的 TIFF 结构；ICC 配置文件可能分为多个 APP2，按序号拼接；
+//! XMP 为 APP1 中标识之后的 XML，有扩展 XMP 时合并进来，见 `XmpPacket::read`。
+
+use std::io;
+
+use super::metadata::XmpPacket;
+use super::metadata::EXIF_HEADER;
+use super::metadata::ICC_HEADER;
+use super::segments::read_segments;
+
+/// 各种元数据的原始内容，没有时为 `None`。同一种有多个时取第一个。
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MetadataPayloads<'a> {
+    /// TIFF 结构，以 "II" 或 "MM" 开头。
+    pub exif: Option<&'a [u8]>,
+    /// 拼接后的 ICC 配置文件。
+    pub icc_profile: Option<Vec<u8>>,
+    /// XMP 包，已合并扩展 XMP。
+    pub xmp: Option<Vec<u8>>,
+}
+
+/// 按序号拼接 ICC 配置文件的各块。块数不一致、序号重复或缺块时出错。
+fn assemble_icc_profile(chunks: &[(u8, u8, &[u8])]) -> io::Result<Option<Vec<u8>>> {
+    let Some(&(_, count, _)) = chunks.first() else {
+        return Ok(None);
+    };
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    if chunks.iter().any(|&(_, c, _)| c != count) {
+        return Err(invalid(
+            "The ICC profile chunks disagree on the chunk count".to_string(),
+        ));
+    }
+    let mut ret = vec![];
+    for sequence in 1..=count {
+        let mut found = chunks.iter().filter(|&&(s, _, _)| s == sequence);
+        match (found.next(), found.next()) {
+            (Some((_, _, data)), None) => ret.extend_from_slice(data),
+            (None, _) => {
+                return Err(invalid(format!(
+                    "ICC profile chunk {} of {} is missing",
+                    sequence, count
+                )))
+            }
+            (Some(_), Some(_)) => {
+                return Err(invalid(format!(
+                    "ICC profile chunk {} of {} appears more than once",
+                    sequence, count
+                )))
+            }
+        }
+    }
+    if chunks.len() != count as usize {
+        return Err(invalid(format!(
+            "Expected {} ICC profile chunks, found {}",
+            count,
+            chunks.len()
+        )));
+    }
+    Ok(Some(ret))
+}
+
+/// 找到 EXIF、ICC 配置文件和 XMP 的原始内容。
+pub fn extract_metadata(jpeg: &[u8]) -> io::Result<MetadataPayloads<'_>> {
+    let mut ret = MetadataPayloads::default();
+    let mut icc_chunks = vec![];
+    let mut app1s = vec![];
+    for segment in read_segments(jpeg)? {
+        match segment.marker {
+            // APP1
+            0xE1 => {
+                if let Some(tiff) = segment.data.strip_prefix(EXIF_HEADER) {
+                    ret.exif = ret.exif.or(Some(tiff));
+                } else {
+                    app1s.push(segment.data);
+                }
+            }
+            // APP2
+            0xE2 => {
+                if let Some([sequence, count, data @ ..]) = segment.data.strip_prefix(ICC_HEADER) {
+                    icc_chunks.push((*sequence, *count, data));
+                }
+            }
+            _ => {}
+        }
+    }
+    ret.icc_profile = assemble_icc_profile(&icc_chunks)?;
+    ret.xmp = XmpPacket::read(app1s)?.map(|xmp| xmp.as_str().as_bytes().to_vec());
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::RgbImage;
+
+    use super::super::encode_step7::ToVec;
+    use super::super::encode_to_vec;
+    use super::super::info::read_info;
+    use super::super::metadata::ExifData;
+    use super::super::metadata::EXTENDED_XMP_HEADER;
+    use super::super::options::EncodeOptions;
+    use super::super::segments::read_segments;
+
+    /// 在 SOI 之后依次插入若干个 APP2。
+    fn insert_app2(jpeg: &[u8], segments: &[Vec<u8>]) -> Vec<u8> {
+        let mut ret = jpeg[..2].to_vec();
+        for data in segments {
+            ret.extend_from_slice(&[0xFF, 0xE2]);
+            ret.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+            ret.extend_from_slice(data);
+        }
+        ret.extend_from_slice(&jpeg[2..]);
+        ret
+    }
+
+    fn icc_chunk(sequence: u8, count: u8, data: &[u8]) -> Vec<u8> {
+        [ICC_HEADER, &[sequence, count], data].concat()
+    }
+
+    #[test]
+    fn test_extract_metadata() {
+        let image = RgbImage::new(16, 8);
+        let plain = encode_to_vec(&image, &EncodeOptions::default()).unwrap();
+        assert_eq!(
+            extract_metadata(&plain).unwrap(),
+            MetadataPayloads::default()
+        );
+
+        let jpeg = encode_to_vec(
+            &image,
+            &EncodeOptions {
+                xmp: Some(XmpPacket::new("<x:xmpmeta/>".to_string()).unwrap()),
+                exif: Some(ExifData::new(None, Some("2024:01:02 03:04:05".to_string())).unwrap()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // 各块的顺序打乱。
+        let jpeg = insert_app2(
+            &jpeg,
+            &[icc_chunk(2, 2, b"world"), icc_chunk(1, 2, b"hello ")],
+        );
+        let payloads = extract_metadata(&jpeg).unwrap();
+        assert_eq!(payloads.xmp.as_deref(), Some(b"<x:xmpmeta/>".as_slice()));
+        assert_eq!(
+            payloads.icc_profile.as_deref(),
+            Some(b"hello world".as_slice())
+        );
+        let exif = payloads.exif.unwrap();
+        assert!(exif.starts_with(b"MM") || exif.starts_with(b"II"));
+        assert!(exif.windows(19).any(|w| w == b"2024:01:02 03:04:05"));
+
+        // 缺块或重复的块。
+        for chunks in [
+            vec![icc_chunk(1, 2, b"a")],
+            vec![icc_chunk(1, 2, b"a"), icc_chunk(1, 2, b"b")],
+            vec![icc_chunk(1, 1, b"a"), icc_chunk(2, 2, b"b")],
+        ] {
+            assert!(extract_metadata(&insert_app2(&plain, &chunks)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_extract_extended_xmp() {
+        let description = format!(
+            "<rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\
+             <dc:description>{}</dc:description></rdf:Description>",
+            "extended ".repeat(10000)
+        );
+        let xml = format!(
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+             <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">{}</rdf:RDF>\
+             </x:xmpmeta>",
+            description
+        );
+        assert!(xml.len() > 65536);
+        let options = EncodeOptions {
+            xmp: Some(XmpPacket::new(xml).unwrap()),
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&RgbImage::new(16, 8), &options).unwrap();
+
+        // 扩展 XMP 合并到标准的 XMP 中，去掉了 GUID。
+        let xmp = String::from_utf8(extract_metadata(&jpeg).unwrap().xmp.unwrap()).unwrap();
+        assert!(xmp.contains(&format!("{}</rdf:RDF>", description)));
+        assert!(!xmp.contains("HasExtendedXMP"));
+        assert_eq!(read_info(&jpeg).unwrap().xmp.unwrap().as_str(), xmp);
+
+        // 内容被修改、缺块或重复的块。
+        let chunks: Vec<_> = read_segments(&jpeg)
+            .unwrap()
+            .iter()
+            .filter(|segment| segment.data.starts_with(EXTENDED_XMP_HEADER))
+            .map(|segment| segment.to_vec())
+            .collect();
+        assert_eq!(chunks.len(), 2);
+        let start = jpeg
+            .windows(chunks[0].len())
+            .position(|w| w == chunks[0])
+            .unwrap();
+        let end = start + chunks[0].len() + chunks[1].len();
+        let mut modified = jpeg.clone();
+        modified[end - 20] ^= 1;
+        for jpeg in [
+            modified,
+            [&jpeg[..start], &chunks[1], &jpeg[end..]].concat(),
+            [
+                &jpeg[..start],
+                &chunks[0],
+                &chunks[0],
+                &chunks[1],
+                &jpeg[end..],
+            ]
+            .concat(),
+        ] {
+            assert!(extract_metadata(&jpeg).is_err());
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! 读取输入图像的每一帧。动画 GIF 的每一帧按处置方式（disposal）合成到画布上，
+//! 得到的是播放时看到的完整画面，而不是只有变化部分的子图。多页 TIFF 的每一页是一帧，
+//! 各页的颜色类型可以不同，之后都由 `convert::to_rgb8` 转换。其他格式只有一帧。
+//! WebP 需要启用 `webp` feature（默认启用），AVIF 需要启用 `avif` feature（默认不启用）。
+
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::codecs::gif::GifDecoder;
+use image::io::Reader as ImageReader;
+use image::AnimationDecoder;
+use image::DynamicImage;
+use image::ImageBuffer;
+use image::ImageFormat;
+use tiff::decoder::DecodingResult;
+
+fn decode_error(_: image::ImageError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Fail to decode the bitmap file")
+}
+
+fn tiff_error(e: tiff::TiffError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// 把 TIFF 的一页转换为对应颜色类型的图像。
+fn tiff_page(
+    width: u32,
+    height: u32,
+    color_type: tiff::ColorType,
+    data: DecodingResult,
+) -> Option<DynamicImage> {
+    use tiff::ColorType;
+
+    Some(match (color_type, data) {
+        (ColorType::Gray(8), DecodingResult::U8(v)) => {
+            DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, v)?)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(v)) => {
+            DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, v)?)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(v)) => {
+            DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, v)?)
+        }
+        (ColorType::GrayA(16), DecodingResult::U16(v)) => {
+            DynamicImage::ImageLumaA16(ImageBuffer::from_raw(width, height, v)?)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(v)) => {
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, v)?)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(v)) => {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, v)?)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(v)) => {
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, v)?)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(v)) => {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, v)?)
+        }
+        // 不做色彩管理，按 R = (1 - C)(1 - K) 的朴素公式转换。
+        (ColorType::CMYK(8), DecodingResult::U8(v)) => {
+            let rgb = v
+                .chunks_exact(4)
+                .flat_map(|p| {
+                    let k = 255 - p[3] as u32;
+                    [0, 1, 2].map(|i| ((255 - p[i] as u32) * k / 255) as u8)
+                })
+                .collect();
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, rgb)?)
+        }
+        _ => return None,
+    })
+}
+
+/// 读取多页 TIFF 的每一页。
+fn read_tiff_pages(path: &Path) -> io::Result<Vec<DynamicImage>> {
+    let mut decoder =
+        tiff::decoder::Decoder::new(BufReader::new(File::open(path)?)).map_err(tiff_error)?;
+    let mut ret = vec![];
+    loop {
+        let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+        let color_type = decoder.colortype().map_err(tiff_error)?;
+        let data = decoder.read_image().map_err(tiff_error)?;
+        let page = tiff_page(width, height, color_type, data).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Page {} of the TIFF file has an unsupported color type {:?}",
+                    ret.len(),
+                    color_type
+                ),
+            )
+        })?;
+        ret.push(page);
+        if !decoder.more_images() {
+            return Ok(ret);
+        }
+        decoder.next_image().map_err(tiff_error)?;
+    }
+}
+
+/// 没有编译进来的解码器，给出明确的错误，而不是笼统的解码失败。
+fn check_supported(format: Option<ImageFormat>) -> io::Result<()> {
+    let message = match format {
+        Some(ImageFormat::WebP) if !cfg!(feature = "webp") => {
+            "Decoding WebP input requires the webp feature of jpeglab"
+        }
+        Some(ImageFormat::Avif) if !cfg!(feature = "avif") => {
+            "Decoding AVIF input requires the avif feature of jpeglab"
+        }
+        _ => return Ok(()),
+    };
+    Err(io::Error::new(io::ErrorKind::Unsupported, message))
+}
+
+/// 按文件内容判断格式，读取所有帧。
+pub fn read_frames(path: &Path) -> io::Result<Vec<DynamicImage>> {
+    let reader = ImageReader::open(path)?.with_guessed_format()?;
+    check_supported(reader.format())?;
+    if reader.format() == Some(ImageFormat::Gif) {
+        // 合成由 image 的帧迭代器完成。
+        let decoder = GifDecoder::new(BufReader::new(File::open(path)?)).map_err(decode_error)?;
+        let frames = decoder
+            .into_frames()
+            .collect_frames()
+            .map_err(decode_error)?;
+        return Ok(frames
+            .into_iter()
+            .map(|frame| DynamicImage::ImageRgba8(frame.into_buffer()))
+            .collect());
+    }
+    if reader.format() == Some(ImageFormat::Tiff) {
+        return read_tiff_pages(path);
+    }
+    Ok(vec![reader.decode().map_err(decode_error)?])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use gif::DisposalMethod;
+    use image::Rgba;
+
+    /// 写入一帧：`color` 填充 `rect` = (left, top, width, height)。
+    fn write_frame(
+        encoder: &mut gif::Encoder<File>,
+        color: [u8; 4],
+        rect: (u16, u16, u16, u16),
+        dispose: DisposalMethod,
+    ) {
+        let (left, top, width, height) = rect;
+        let mut pixels = color.repeat(width as usize * height as usize);
+        let mut frame = gif::Frame::from_rgba_speed(width, height, &mut pixels, 10);
+        frame.left = left;
+        frame.top = top;
+        frame.dispose = dispose;
+        encoder.write_frame(&frame).unwrap();
+    }
+
+    #[test]
+    fn test_read_gif_frames() {
+        let path = std::env::temp_dir().join(format!("jpeglab_frames_{}.gif", std::process::id()));
+        let red = [255, 0, 0, 255];
+        let blue = [0, 0, 255, 255];
+        let green = [0, 255, 0, 255];
+        {
+            let mut encoder = gif::Encoder::new(File::create(&path).unwrap(), 6, 4, &[]).unwrap();
+            write_frame(&mut encoder, red, (0, 0, 6, 4), DisposalMethod::Keep);
+            // 只覆盖 (2, 1) 起的 2x2，显示后恢复为背景。
+            write_frame(&mut encoder, blue, (2, 1, 2, 2), DisposalMethod::Background);
+            write_frame(&mut encoder, green, (0, 0, 1, 1), DisposalMethod::Keep);
+        }
+        let frames = read_frames(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(frames.len(), 3);
+        let frames: Vec<_> = frames.iter().map(|frame| frame.to_rgba8()).collect();
+        assert!(frames.iter().all(|frame| frame.dimensions() == (6, 4)));
+        assert!(frames[0].pixels().all(|&p| p == Rgba(red)));
+        // 第二帧叠加在第一帧上。
+        assert_eq!(*frames[1].get_pixel(0, 0), Rgba(red));
+        assert_eq!(*frames[1].get_pixel(2, 1), Rgba(blue));
+        assert_eq!(*frames[1].get_pixel(3, 2), Rgba(blue));
+        assert_eq!(*frames[1].get_pixel(4, 2), Rgba(red));
+        // 第二帧的区域被清除为透明，其余保留。
+        assert_eq!(*frames[2].get_pixel(0, 0), Rgba(green));
+        assert_eq!(frames[2].get_pixel(2, 1)[3], 0);
+        assert_eq!(*frames[2].get_pixel(4, 2), Rgba(red));
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn test_read_webp() {
+        use image::codecs::webp::WebPEncoder;
+        use image::RgbImage;
+
+        let image = RgbImage::from_fn(9, 7, |x, y| {
+            image::Rgb([(x * 20) as u8, (y * 30) as u8, 50])
+        });
+        let path = std::env::temp_dir().join(format!("jpeglab_frames_{}.webp", std::process::id()));
+        image
+            .write_with_encoder(WebPEncoder::new_lossless(File::create(&path).unwrap()))
+            .unwrap();
+        let frames = read_frames(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].to_rgb8(), image);
+    }
+
+    #[test]
+    fn test_read_tiff_pages() {
+        use tiff::encoder::colortype;
+        use tiff::encoder::TiffEncoder;
+
+        let path = std::env::temp_dir().join(format!("jpeglab_pages_{}.tif", std::process::id()));
+        let rgb: Vec<u8> = (0..5 * 3 * 3).map(|i| (i * 5) as u8).collect();
+        let gray: Vec<u16> = (0..4 * 2).map(|i| i * 8000).collect();
+        let cmyk = [0, 255, 255, 0, 0, 0, 0, 255];
+        {
+            let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+            encoder.write_image::<colortype::RGB8>(5, 3, &rgb).unwrap();
+            encoder
+                .write_image::<colortype::Gray16>(4, 2, &gray)
+                .unwrap();
+            encoder
+                .write_image::<colortype::CMYK8>(2, 1, &cmyk)
+                .unwrap();
+        }
+        let pages = read_frames(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].to_rgb8().into_raw(), rgb);
+        assert_eq!(pages[1].color(), image::ColorType::L16);
+        assert_eq!(pages[1].as_luma16().unwrap().as_raw(), &gray);
+        assert_eq!(pages[2].to_rgb8().into_raw(), [255, 0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,158 @@
+//! 把编码的各个有损步骤的效果拼成一幅图，便于直观比较。按行排列六格：
+//! 原图、Y、Cb、Cr（色度为下采样后的平面，横向放大回原尺寸），
+//! 只量化的重建（色度不下采样，DCT、量化、反量化、IDCT），最终解码的结果。
+//! 后两格的差别就是色度下采样的影响。
+
+use std::io;
+
+use image::RgbImage;
+
+use super::decode_to_image;
+use super::encode_step1::encode_step1;
+use super::encode_step1::ColorConversion;
+use super::encode_step1::MyYuvImage;
+use super::encode_step2::Du;
+use super::encode_step3::dct_with;
+use super::encode_step3::DctPrecision;
+use super::encode_step4::QuantizationTable;
+use super::encode_to_vec;
+use super::options::DecodeOptions;
+use super::options::EncodeOptions;
+
+/// 每行的格数。
+const GRID_COLUMNS: u32 = 3;
+/// 格与格之间、格与边缘之间的间隔（像素）。
+const GRID_GAP: u32 = 4;
+const GRID_BACKGROUND: image::Rgb<u8> = image::Rgb([128, 128, 128]);
+
+/// 第一步得到的平面中 (x, y) 处的样本，色度按子采样的倍数复制。
+fn plane_sample(yuv_image: &MyYuvImage, plane: usize, x: usize, y: usize) -> u8 {
+    let (h, v) = yuv_image.subsampling.luma_factors();
+    match plane {
+        0 => yuv_image.y[yuv_image.y_layout().index(x, y)],
+        1 => yuv_image.u[yuv_image.chroma_layout().index(x / h, y / v)],
+        _ => yuv_image.v[yuv_image.chroma_layout().index(x / h, y / v)],
+    }
+}
+
+/// 对一个全分辨率的平面按 8x8 的块做 DCT、量化、反量化和 IDCT。边缘不足一块时复制最后一行和最后一列。
+fn quantize_plane(
+    plane: &[u8],
+    width: usize,
+    height: usize,
+    table: &QuantizationTable,
+    precision: DctPrecision,
+) -> Vec<u8> {
+    let mut ret = vec![0; width * height];
+    for block_y in (0..height).step_by(8) {
+        for block_x in (0..width).step_by(8) {
+            let du = Du(std::array::from_fn(|y| {
+                std::array::from_fn(|x| {
+                    let (x, y) = ((block_x + x).min(width - 1), (block_y + y).min(height - 1));
+                    plane[y * width + x] as i16 - 128
+                })
+            }));
+            let du = dct_with(&du, precision);
+            let du = du.quantize(table).to_dct_du(table).idct_with(precision);
+            for (y, row) in du.0.iter().enumerate().take(height - block_y) {
+                for (x, &value) in row.iter().enumerate().take(width - block_x) {
+                    ret[(block_y + y) * width + block_x + x] = (value + 128) as u8;
+                }
+            }
+        }
+    }
+    ret
+}
+
+/// 不下采样色度，只经过量化的重建。
+fn quantize_only(
+    image: &RgbImage,
+    color_conversion: &ColorConversion,
+    quantization_tables: &[QuantizationTable; 2],
+    precision: DctPrecision,
+) -> RgbImage {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut planes = [vec![], vec![], vec![]];
+    for pixel in image.pixels() {
+        let (y, u, v) = color_conversion.rgb_to_yuv(pixel[0], pixel[1], pixel[2]);
+        planes[0].push(y);
+        planes[1].push(u);
+        planes[2].push(v);
+    }
+    let [luminance_table, chrominance_table] = quantization_tables;
+    let tables = [luminance_table, chrominance_table, chrominance_table];
+    let [y, u, v] =
+        std::array::from_fn(|i| quantize_plane(&planes[i], width, height, tables[i], precision));
+    RgbImage::from_fn(image.width(), image.height(), |x, y_| {
+        let i = y_ as usize * width + x as usize;
+        let (r, g, b) = color_conversion.yuv_to_rgb(y[i], u[i], v[i]);
+        image::Rgb([r, g, b])
+    })
+}
+
+/// 生成对比图，各格的顺序见模块的说明。
+pub fn render_grid(image: &RgbImage, options: &EncodeOptions) -> io::Result<RgbImage> {
+    let (width, height) = image.dimensions();
+    let yuv_image = encode_step1(image, &options.color_conversion, options.subsampling)?;
+    let planes: [RgbImage; 3] = std::array::from_fn(|plane| {
+        RgbImage::from_fn(width, height, |x, y| {
+            let value = plane_sample(&yuv_image, plane, x as usize, y as usize);
+            image::Rgb([value; 3])
+        })
+    });
+    let decode_options = DecodeOptions {
+        color_conversion: options.color_conversion,
+        dct_precision: options.dct_precision,
+        ..Default::default()
+    };
+    let decoded = decode_to_image(&encode_to_vec(image, options)?, &decode_options)?;
+    let quantized = quantize_only(
+        image,
+        &options.color_conversion,
+        &options.quantization_tables(),
+        options.dct_precision,
+    );
+
+    let [y, cb, cr] = planes;
+    let tiles = [image.clone(), y, cb, cr, quantized, decoded];
+    let rows = (tiles.len() as u32).div_ceil(GRID_COLUMNS);
+    let mut ret = RgbImage::from_pixel(
+        GRID_COLUMNS * (width + GRID_GAP) + GRID_GAP,
+        rows * (height + GRID_GAP) + GRID_GAP,
+        GRID_BACKGROUND,
+    );
+    for (i, tile) in tiles.iter().enumerate() {
+        let i = i as u32;
+        let x = GRID_GAP + i % GRID_COLUMNS * (width + GRID_GAP);
+        let y = GRID_GAP + i / GRID_COLUMNS * (height + GRID_GAP);
+        image::imageops::replace(&mut ret, tile, x as i64, y as i64);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::metrics::psnr;
+    use super::super::test_util::test_image;
+
+    #[test]
+    fn test_render_grid() {
+        let image = test_image();
+        let grid = render_grid(&image, &Default::default()).unwrap();
+        assert_eq!(grid.dimensions(), (3 * 41 + 4, 2 * 25 + 4));
+        let tile = |i: u32| {
+            let (x, y) = (4 + i % 3 * 41, 4 + i / 3 * 25);
+            image::imageops::crop_imm(&grid, x, y, 37, 21).to_image()
+        };
+        assert_eq!(tile(0), image);
+        assert_eq!(*grid.get_pixel(0, 0), GRID_BACKGROUND);
+
+        // 只量化的重建比下采样后再量化更接近原图。
+        let quantized = psnr(&image, &tile(4)).unwrap();
+        let decoded = psnr(&image, &tile(5)).unwrap();
+        assert!(quantized > decoded, "{} <= {}", quantized, decoded);
+        assert!(decoded > 20.0);
+    }
+}
@@ -0,0 +1,126 @@
+//! 由人眼的对比敏感度函数（CSF）生成量化表。
+//!
+//! DCT 系数 (u, v) 的空间频率为 sqrt(u² + v²) / 16 周/像素，按观看条件换算为周/度后，
+//! 量化步长与该频率上的敏感度成反比：人眼越不敏感的频率量化得越粗。
+//! CSF 采用 Mannos 和 Sakrison 的模型，在峰值频率以下取峰值，以免低频（包括 DC）被粗量化。
+//! 色度的敏感度下降得比亮度快，近似为亮度的 CSF 在两倍频率处的值；YUV422 的色度横向每个样本覆盖两个像素。
+
+use std::f64::consts::PI;
+
+use super::encode_step4::QuantizationTable;
+
+/// 观看条件。观看的距离越远、屏幕的像素越密，每度视角中的像素越多，同一个 DCT 频率看起来越高。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewingConditions {
+    /// 眼睛到屏幕的距离，单位为英寸。
+    pub distance: f64,
+    /// 屏幕的像素密度，单位为每英寸的像素数。
+    pub dpi: f64,
+}
+
+impl Default for ViewingConditions {
+    /// 在约 60 厘米外看 96 DPI 的显示器。
+    fn default() -> Self {
+        Self {
+            distance: 24.0,
+            dpi: 96.0,
+        }
+    }
+}
+
+impl ViewingConditions {
+    /// 每度视角中的像素数。
+    pub fn pixels_per_degree(&self) -> f64 {
+        self.dpi * self.distance * (PI / 180.0).tan()
+    }
+}
+
+/// CSF 取最大值的频率，单位为周/度。
+const PEAK_FREQUENCY: f64 = 8.0;
+/// 亮度和色度的 DC 量化步长，与附录 K 的表相同。
+const LUMINANCE_BASE: f64 = 16.0;
+const CHROMINANCE_BASE: f64 = 17.0;
+/// 色度的 CSF 近似为亮度的 CSF 在多少倍频率处的值。
+const CHROMINANCE_FREQUENCY_SCALE: f64 = 2.0;
+
+/// Mannos 和 Sakrison 的 CSF，`frequency` 的单位为周/度。
+fn csf(frequency: f64) -> f64 {
+    2.6 * (0.0192 + 0.114 * frequency) * (-(0.114 * frequency).powf(1.1)).exp()
+}
+
+/// 相对于峰值的敏感度，峰值频率以下为 1。
+fn sensitivity(frequency: f64) -> f64 {
+    if frequency <= PEAK_FREQUENCY {
+        1.0
+    } else {
+        csf(frequency) / csf(PEAK_FREQUENCY)
+    }
+}
+
+/// 生成一个量化表。`horizontal_subsampling` 为一个样本横向覆盖的像素数。
+fn table(
+    viewing: &ViewingConditions,
+    base: f64,
+    horizontal_subsampling: f64,
+    frequency_scale: f64,
+) -> QuantizationTable {
+    let pixels_per_degree = viewing.pixels_per_degree();
+    QuantizationTable(std::array::from_fn(|v| {
+        std::array::from_fn(|u| {
+            // 周/像素。
+            let horizontal = u as f64 / 16.0 / horizontal_subsampling;
+            let vertical = v as f64 / 16.0;
+            let frequency = horizontal.hypot(vertical) * pixels_per_degree * frequency_scale;
+            (base / sensitivity(frequency)).round().clamp(1.0, 255.0) as u16
+        })
+    }))
+}
+
+/// 按观看条件生成亮度和色度的量化表，色度按 YUV422 计算。
+pub fn perceptual_tables(viewing: &ViewingConditions) -> [QuantizationTable; 2] {
+    [
+        table(viewing, LUMINANCE_BASE, 1.0, 1.0),
+        table(viewing, CHROMINANCE_BASE, 2.0, CHROMINANCE_FREQUENCY_SCALE),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sum(table: &QuantizationTable) -> u32 {
+        table.0.iter().flatten().map(|&q| q as u32).sum()
+    }
+
+    #[test]
+    fn test_perceptual_tables() {
+        let viewing = ViewingConditions::default();
+        assert!((viewing.pixels_per_degree() - 40.2).abs() < 0.1);
+
+        let [luminance, chrominance] = perceptual_tables(&viewing);
+        assert_eq!((luminance.0[0][0], chrominance.0[0][0]), (16, 17));
+        // 沿对角线越来越粗。
+        for i in 1..8 {
+            assert!(luminance.0[i][i] >= luminance.0[i - 1][i - 1]);
+            assert!(chrominance.0[i][i] >= chrominance.0[i - 1][i - 1]);
+        }
+        assert!(luminance.0[7][7] > 16);
+        assert!(sum(&chrominance) > sum(&luminance));
+        // 色度横向下采样，同样的 (u, v) 横向的频率较低。
+        assert!(chrominance.0[0][7] < chrominance.0[7][0]);
+
+        // 离得越远，量化得越粗。
+        let far = ViewingConditions {
+            distance: 48.0,
+            ..viewing
+        };
+        let [far_luminance, _] = perceptual_tables(&far);
+        assert!(sum(&far_luminance) > sum(&luminance));
+        // 足够近时所有频率都在峰值以下，量化表是平坦的。
+        let near = ViewingConditions {
+            distance: 2.0,
+            ..viewing
+        };
+        assert_eq!(perceptual_tables(&near)[0].0, [[16; 8]; 8]);
+    }
+}
@@ -0,0 +1,170 @@
+//! 读取 JPEG 文件的基本信息和元数据，只解析标记段，不解码图像。
+
+use std::io;
+
+use super::decode_step1::parse_app0;
+use super::encode_step6::EntropyCoding;
+use super::encode_step7::Density;
+use super::metadata::IptcInfo;
+use super::metadata::XmpPacket;
+use super::segments::read_segments;
+
+/// JPEG 文件的基本信息。
+#[derive(Debug, Clone, Default)]
+pub struct JpegInfo {
+    pub width: usize,
+    pub height: usize,
+    /// 各分量的 (水平, 垂直) 采样因子。
+    pub sampling_factors: Vec<(u8, u8)>,
+    /// 熵编码的方式，SOF9 和 SOF10 为算术编码。
+    pub entropy_coding: EntropyCoding,
+    /// JFIF 的 APP0 中的像素密度。没有 APP0 或单位未知时为 `None`。
+    pub density: Option<Density>,
+    /// APP1 中的 XMP。
+    pub xmp: Option<XmpPacket>,
+    /// Photoshop 的 APP13 中的 IPTC。
+    pub iptc: Option<IptcInfo>,
+}
+
+/// 读取 JPEG 文件的基本信息。
+pub fn read_info(jpeg: &[u8]) -> io::Result<JpegInfo> {
+    let mut ret = JpegInfo::default();
+    let mut has_frame = false;
+    let mut app1s = vec![];
+
+    for segment in read_segments(jpeg)? {
+        match segment.marker {
+            // SOF0 到 SOF2 和 SOF9 到 SOF10：精度、高、宽、分量数，之后每个分量 3 个字节。
+            0xC0..=0xC2 | 0xC9..=0xCA => {
+                let data = segment.data;
+                let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid SOF block");
+                let header = data.get(..6).ok_or_else(invalid)?;
+                ret.height = u16::from_be_bytes([header[1], header[2]]) as usize;
+                ret.width = u16::from_be_bytes([header[3], header[4]]) as usize;
+                let n_components = header[5] as usize;
+                let components = data.get(6..6 + 3 * n_components).ok_or_else(invalid)?;
+                ret.sampling_factors = components
+                    .chunks(3)
+                    .map(|c| (c[1] >> 4, c[1] & 0x0F))
+                    .collect();
+                ret.entropy_coding = if segment.marker >= 0xC9 {
+                    EntropyCoding::Arithmetic
+                } else {
+                    EntropyCoding::Huffman
+                };
+                has_frame = true;
+            }
+            // APP0
+            0xE0 if ret.density.is_none() => {
+                ret.density = parse_app0(segment.data)
+                    .ok()
+                    .filter(|app0| &app0.identifier == b"JFIF\0")
+                    .and_then(|app0| app0.density())
+            }
+            // APP1
+            0xE1 => app1s.push(segment.data),
+            // APP13
+            0xED if ret.iptc.is_none() => ret.iptc = IptcInfo::parse(segment.data),
+            _ => {}
+        }
+    }
+
+    if !has_frame {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No frame header found",
+        ));
+    }
+    ret.xmp = XmpPacket::read(app1s)?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::decode_to_image;
+    use super::super::encode_step7::DensityUnits;
+    use super::super::encode_step7::ToVec;
+    use super::super::encode_to_vec;
+    use super::super::metadata::test::make_app13;
+    use super::super::segments::Segment;
+    use super::super::transform;
+    use super::super::transform::Operation;
+    use super::super::transform::Rotation;
+    use super::super::EncodeOptions;
+    use super::super::TransformOptions;
+
+    #[test]
+    fn test_read_info() {
+        let image = image::RgbImage::from_fn(20, 10, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        let info = read_info(&jpeg).unwrap();
+        assert_eq!((info.width, info.height), (20, 10));
+        assert_eq!(info.sampling_factors, [(2, 1), (1, 1), (1, 1)]);
+        assert_eq!(info.density, Some(Density::default()));
+        assert_eq!(info.entropy_coding, EntropyCoding::Huffman);
+        assert_eq!(info.xmp, None);
+
+        // 算术编码的文件不能解码，但可以读取信息。
+        let options = EncodeOptions {
+            entropy_coding: EntropyCoding::Arithmetic,
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&image, &options).unwrap();
+        let info = read_info(&jpeg).unwrap();
+        assert_eq!((info.width, info.height), (20, 10));
+        assert_eq!(info.entropy_coding, EntropyCoding::Arithmetic);
+
+        let density = Density {
+            units: DensityUnits::Inch,
+            x: 300,
+            y: 150,
+        };
+        let options = EncodeOptions {
+            density,
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&image, &options).unwrap();
+        assert_eq!(read_info(&jpeg).unwrap().density, Some(density));
+        // APP0 中的单位为 1，密度为 300 和 150。
+        assert_eq!(jpeg[13..18], [1, 0x01, 0x2C, 0x00, 0x96]);
+
+        let xmp = XmpPacket::new("<x:xmpmeta/>".to_string()).unwrap();
+        let options = EncodeOptions {
+            xmp: Some(xmp.clone()),
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&image, &options).unwrap();
+        assert_eq!(read_info(&jpeg).unwrap().xmp, Some(xmp));
+        // 解码时跳过 XMP。
+        assert!(decode_to_image(&jpeg, &Default::default()).is_ok());
+    }
+
+    #[test]
+    fn test_read_iptc() {
+        let image = image::RgbImage::from_fn(32, 16, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        assert_eq!(read_info(&jpeg).unwrap().iptc, None);
+
+        let app13 = Segment {
+            marker: 0xED,
+            data: &make_app13(&["a caption"], &["sky", "sea"]),
+        }
+        .to_vec();
+        let jpeg = [&jpeg[..2], &app13, &jpeg[2..]].concat();
+        let iptc = read_info(&jpeg).unwrap().iptc.unwrap();
+        assert_eq!(iptc.captions, ["a caption"]);
+        assert_eq!(iptc.keywords, ["sky", "sea"]);
+
+        // 无损变换后原样保留。
+        let options = TransformOptions {
+            operation: Operation::Rotate(Rotation::R180),
+            trim: false,
+            strip: false,
+        };
+        let rotated = transform(&jpeg, &options).unwrap();
+        assert!(rotated.windows(app13.len()).any(|w| w == app13));
+        assert_eq!(read_info(&rotated).unwrap().iptc, Some(iptc));
+    }
+}
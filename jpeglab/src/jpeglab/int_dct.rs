@@ -0,0 +1,349 @@
+//! libjpeg 的两种整数 DCT 和 IDCT，用于与浮点实现比较。
+//!
+//! - 精确（islow，jfdctint.c 和 jidctint.c）：Loeffler、Ligtenberg 和 Moschytz 的算法，13 位定点常数，
+//!   两遍之间多保留 2 位。结果与浮点实现只差舍入。
+//! - 快速（ifast，jfdctfst.c 和 jidctfst.c）：Arai、Agui 和 Nakajima 的算法，只有 5 次乘法，
+//!   8 位定点常数，乘积直接截断，精度明显较差。
+//!
+//! 算法本身与 libjpeg 6b 相同。libjpeg 把输出的缩放（islow 为 8 倍，ifast 另有 AAN 的比例因子）
+//! 合并到量化表中，这里的量化表是独立的，因此正变换的结果除以缩放后以 `DctDu` 返回，
+//! 逆变换的输入按 libjpeg 反量化时的缩放乘上相应的因子。
+//! 用 64 位整数计算，畸形数据的系数很大时也不会溢出；正常的系数与 libjpeg 的 32 位计算完全相同。
+
+use std::f64::consts::PI;
+
+use lazy_static::lazy_static;
+
+use super::encode_step2::Du;
+use super::encode_step3::DctDu;
+
+/// 四舍五入地右移 `n` 位。
+fn descale(x: i64, n: u32) -> i64 {
+    (x + (1 << (n - 1))) >> n
+}
+
+// islow 的常数，13 位定点。
+const ISLOW_CONST_BITS: u32 = 13;
+const ISLOW_PASS1_BITS: u32 = 2;
+const FIX_0_298631336: i64 = 2446;
+const FIX_0_390180644: i64 = 3196;
+const FIX_0_541196100: i64 = 4433;
+const FIX_0_765366865: i64 = 6270;
+const FIX_0_899976223: i64 = 7373;
+const FIX_1_175875602: i64 = 9633;
+const FIX_1_501321110: i64 = 12299;
+const FIX_1_847759065: i64 = 15137;
+const FIX_1_961570560: i64 = 16069;
+const FIX_2_053119869: i64 = 16819;
+const FIX_2_562915447: i64 = 20995;
+const FIX_3_072711026: i64 = 25172;
+
+// ifast 的常数，8 位定点。
+const IFAST_CONST_BITS: u32 = 8;
+const IFAST_PASS1_BITS: u32 = 2;
+const IFAST_FIX_0_382683433: i64 = 98;
+const IFAST_FIX_0_541196100: i64 = 139;
+const IFAST_FIX_0_707106781: i64 = 181;
+const IFAST_FIX_1_082392200: i64 = 277;
+const IFAST_FIX_1_306562965: i64 = 334;
+const IFAST_FIX_1_414213562: i64 = 362;
+const IFAST_FIX_1_847759065: i64 = 473;
+const IFAST_FIX_2_613125930: i64 = 669;
+/// ifast 反量化的乘数中的小数位数。
+const IFAST_SCALE_BITS: u32 = 2;
+
+/// ifast 的乘法，截断而不舍入。
+fn ifast_multiply(x: i64, c: i64) -> i64 {
+    (x * c) >> IFAST_CONST_BITS
+}
+
+/// AAN 的比例因子，k = 0 时为 1，否则为 cos(kπ/16)·√2。
+fn make_aan_scale_factors() -> [f64; 8] {
+    std::array::from_fn(|k| {
+        if k == 0 {
+            1.0
+        } else {
+            (k as f64 * PI / 16.0).cos() * std::f64::consts::SQRT_2
+        }
+    })
+}
+
+lazy_static! {
+    static ref AAN_SCALE_FACTORS: [f64; 8] = make_aan_scale_factors();
+    /// libjpeg 的 aanscales：14 位定点的二维比例因子。
+    static ref AAN_SCALES: [[i64; 8]; 8] = std::array::from_fn(|u| {
+        std::array::from_fn(|v| {
+            (AAN_SCALE_FACTORS[u] * AAN_SCALE_FACTORS[v] * 16384.0).round() as i64
+        })
+    });
+}
+
+/// islow 正变换的一维部分。第一遍结果放大 2^PASS1_BITS，第二遍去掉。
+fn fdct_islow_1d(d: [i64; 8], first_pass: bool) -> [i64; 8] {
+    let (even_shift, odd_shift) = if first_pass {
+        (0, ISLOW_CONST_BITS - ISLOW_PASS1_BITS)
+    } else {
+        (ISLOW_PASS1_BITS, ISLOW_CONST_BITS + ISLOW_PASS1_BITS)
+    };
+    let scale_even = |x: i64| {
+        if first_pass {
+            x << ISLOW_PASS1_BITS
+        } else {
+            descale(x, even_shift)
+        }
+    };
+    let mut out = [0; 8];
+
+    let tmp0 = d[0] + d[7];
+    let tmp7 = d[0] - d[7];
+    let tmp1 = d[1] + d[6];
+    let tmp6 = d[1] - d[6];
+    let tmp2 = d[2] + d[5];
+    let tmp5 = d[2] - d[5];
+    let tmp3 = d[3] + d[4];
+    let tmp4 = d[3] - d[4];
+
+    // 偶数部分。
+    let tmp10 = tmp0 + tmp3;
+    let tmp13 = tmp0 - tmp3;
+    let tmp11 = tmp1 + tmp2;
+    let tmp12 = tmp1 - tmp2;
+    out[0] = scale_even(tmp10 + tmp11);
+    out[4] = scale_even(tmp10 - tmp11);
+    let z1 = (tmp12 + tmp13) * FIX_0_541196100;
+    out[2] = descale(z1 + tmp13 * FIX_0_765366865, odd_shift);
+    out[6] = descale(z1 - tmp12 * FIX_1_847759065, odd_shift);
+
+    // 奇数部分。
+    let z1 = tmp4 + tmp7;
+    let z2 = tmp5 + tmp6;
+    let z3 = tmp4 + tmp6;
+    let z4 = tmp5 + tmp7;
+    let z5 = (z3 + z4) * FIX_1_175875602;
+    let tmp4 = tmp4 * FIX_0_298631336;
+    let tmp5 = tmp5 * FIX_2_053119869;
+    let tmp6 = tmp6 * FIX_3_072711026;
+    let tmp7 = tmp7 * FIX_1_501321110;
+    let z1 = -z1 * FIX_0_899976223;
+    let z2 = -z2 * FIX_2_562915447;
+    let z3 = -z3 * FIX_1_961570560 + z5;
+    let z4 = -z4 * FIX_0_390180644 + z5;
+    out[7] = descale(tmp4 + z1 + z3, odd_shift);
+    out[5] = descale(tmp5 + z2 + z4, odd_shift);
+    out[3] = descale(tmp6 + z2 + z3, odd_shift);
+    out[1] = descale(tmp7 + z1 + z4, odd_shift);
+    out
+}
+
+/// ifast 正变换的一维部分，两遍相同。
+fn fdct_ifast_1d(d: [i64; 8]) -> [i64; 8] {
+    let mut out = [0; 8];
+
+    let tmp0 = d[0] + d[7];
+    let tmp7 = d[0] - d[7];
+    let tmp1 = d[1] + d[6];
+    let tmp6 = d[1] - d[6];
+    let tmp2 = d[2] + d[5];
+    let tmp5 = d[2] - d[5];
+    let tmp3 = d[3] + d[4];
+    let tmp4 = d[3] - d[4];
+
+    // 偶数部分。
+    let tmp10 = tmp0 + tmp3;
+    let tmp13 = tmp0 - tmp3;
+    let tmp11 = tmp1 + tmp2;
+    let tmp12 = tmp1 - tmp2;
+    out[0] = tmp10 + tmp11;
+    out[4] = tmp10 - tmp11;
+    let z1 = ifast_multiply(tmp12 + tmp13, IFAST_FIX_0_707106781);
+    out[2] = tmp13 + z1;
+    out[6] = tmp13 - z1;
+
+    // 奇数部分。
+    let tmp10 = tmp4 + tmp5;
+    let tmp11 = tmp5 + tmp6;
+    let tmp12 = tmp6 + tmp7;
+    let z5 = ifast_multiply(tmp10 - tmp12, IFAST_FIX_0_382683433);
+    let z2 = ifast_multiply(tmp10, IFAST_FIX_0_541196100) + z5;
+    let z4 = ifast_multiply(tmp12, IFAST_FIX_1_306562965) + z5;
+    let z3 = ifast_multiply(tmp11, IFAST_FIX_0_707106781);
+    let z11 = tmp7 + z3;
+    let z13 = tmp7 - z3;
+    out[5] = z13 + z2;
+    out[3] = z13 - z2;
+    out[1] = z11 + z4;
+    out[7] = z11 - z4;
+    out
+}
+
+/// islow 逆变换的一维部分。第一遍结果保留 PASS1_BITS 位小数，第二遍还要除以 8。
+fn idct_islow_1d(d: [i64; 8], first_pass: bool) -> [i64; 8] {
+    let shift = if first_pass {
+        ISLOW_CONST_BITS - ISLOW_PASS1_BITS
+    } else {
+        ISLOW_CONST_BITS + ISLOW_PASS1_BITS + 3
+    };
+
+    // 偶数部分。
+    let z1 = (d[2] + d[6]) * FIX_0_541196100;
+    let tmp2 = z1 - d[6] * FIX_1_847759065;
+    let tmp3 = z1 + d[2] * FIX_0_765366865;
+    let tmp0 = (d[0] + d[4]) << ISLOW_CONST_BITS;
+    let tmp1 = (d[0] - d[4]) << ISLOW_CONST_BITS;
+    let tmp10 = tmp0 + tmp3;
+    let tmp13 = tmp0 - tmp3;
+    let tmp11 = tmp1 + tmp2;
+    let tmp12 = tmp1 - tmp2;
+
+    // 奇数部分。
+    let (tmp0, tmp1, tmp2, tmp3) = (d[7], d[5], d[3], d[1]);
+    let z1 = tmp0 + tmp3;
+    let z2 = tmp1 + tmp2;
+    let z3 = tmp0 + tmp2;
+    let z4 = tmp1 + tmp3;
+    let z5 = (z3 + z4) * FIX_1_175875602;
+    let z1 = -z1 * FIX_0_899976223;
+    let z2 = -z2 * FIX_2_562915447;
+    let z3 = -z3 * FIX_1_961570560 + z5;
+    let z4 = -z4 * FIX_0_390180644 + z5;
+    let tmp0 = tmp0 * FIX_0_298631336 + z1 + z3;
+    let tmp1 = tmp1 * FIX_2_053119869 + z2 + z4;
+    let tmp2 = tmp2 * FIX_3_072711026 + z2 + z3;
+    let tmp3 = tmp3 * FIX_1_501321110 + z1 + z4;
+
+    [
+        descale(tmp10 + tmp3, shift),
+        descale(tmp11 + tmp2, shift),
+        descale(tmp12 + tmp1, shift),
+        descale(tmp13 + tmp0, shift),
+        descale(tmp13 - tmp0, shift),
+        descale(tmp12 - tmp1, shift),
+        descale(tmp11 - tmp2, shift),
+        descale(tmp10 - tmp3, shift),
+    ]
+}
+
+/// ifast 逆变换的一维部分。第一遍不移位，第二遍去掉 PASS1_BITS 位小数并除以 8，截断而不舍入。
+fn idct_ifast_1d(d: [i64; 8], first_pass: bool) -> [i64; 8] {
+    // 偶数部分。
+    let tmp10 = d[0] + d[4];
+    let tmp11 = d[0] - d[4];
+    let tmp13 = d[2] + d[6];
+    let tmp12 = ifast_multiply(d[2] - d[6], IFAST_FIX_1_414213562) - tmp13;
+    let tmp0 = tmp10 + tmp13;
+    let tmp3 = tmp10 - tmp13;
+    let tmp1 = tmp11 + tmp12;
+    let tmp2 = tmp11 - tmp12;
+
+    // 奇数部分。
+    let z13 = d[5] + d[3];
+    let z10 = d[5] - d[3];
+    let z11 = d[1] + d[7];
+    let z12 = d[1] - d[7];
+    let tmp7 = z11 + z13;
+    let tmp11 = ifast_multiply(z11 - z13, IFAST_FIX_1_414213562);
+    let z5 = ifast_multiply(z10 + z12, IFAST_FIX_1_847759065);
+    let tmp10 = ifast_multiply(z12, IFAST_FIX_1_082392200) - z5;
+    let tmp12 = ifast_multiply(z10, -IFAST_FIX_2_613125930) + z5;
+    let tmp6 = tmp12 - tmp7;
+    let tmp5 = tmp11 - tmp6;
+    let tmp4 = tmp10 + tmp5;
+
+    let out = [
+        tmp0 + tmp7,
+        tmp1 + tmp6,
+        tmp2 + tmp5,
+        tmp3 - tmp4,
+        tmp3 + tmp4,
+        tmp2 - tmp5,
+        tmp1 - tmp6,
+        tmp0 - tmp7,
+    ];
+    if first_pass {
+        out
+    } else {
+        out.map(|x| x >> (IFAST_PASS1_BITS + 3))
+    }
+}
+
+/// 先对每一行、再对每一列做一维变换。
+fn transform_2d(
+    input: [[i64; 8]; 8],
+    rows: impl Fn([i64; 8]) -> [i64; 8],
+    columns: impl Fn([i64; 8]) -> [i64; 8],
+) -> [[i64; 8]; 8] {
+    let rows_done = input.map(rows);
+    let mut ret = [[0; 8]; 8];
+    for x in 0..8 {
+        let column = columns(std::array::from_fn(|y| rows_done[y][x]));
+        for y in 0..8 {
+            ret[y][x] = column[y];
+        }
+    }
+    ret
+}
+
+fn du_to_i64(du: &Du) -> [[i64; 8]; 8] {
+    du.0.map(|row| row.map(|it| it as i64))
+}
+
+fn to_du(samples: [[i64; 8]; 8]) -> Du {
+    Du(samples.map(|row| row.map(|it| it.clamp(-128, 127) as i16)))
+}
+
+/// islow 的 DCT。
+pub(super) fn fdct_islow(du: &Du) -> DctDu {
+    let ret = transform_2d(
+        du_to_i64(du),
+        |row| fdct_islow_1d(row, true),
+        |column| fdct_islow_1d(column, false),
+    );
+    // 去掉 8 倍的缩放。
+    DctDu(ret.map(|row| row.map(|it| it as f64 / 8.0)))
+}
+
+/// ifast 的 DCT。
+pub(super) fn fdct_ifast(du: &Du) -> DctDu {
+    let ret = transform_2d(du_to_i64(du), fdct_ifast_1d, fdct_ifast_1d);
+    // 去掉 8 倍的缩放和 AAN 的比例因子。
+    let mut dct_du = [[0.0; 8]; 8];
+    for u in 0..8 {
+        for v in 0..8 {
+            dct_du[u][v] = ret[u][v] as f64 / (8.0 * AAN_SCALE_FACTORS[u] * AAN_SCALE_FACTORS[v]);
+        }
+    }
+    DctDu(dct_du)
+}
+
+/// islow 的 IDCT。输入即为反量化后的系数。
+pub(super) fn idct_islow(dct_du: &DctDu) -> Du {
+    let input = dct_du.0.map(|row| row.map(|it| it.round() as i64));
+    // libjpeg 先处理列，再处理行。
+    let columns_first = transform_2d(
+        transpose(input),
+        |column| idct_islow_1d(column, true),
+        |row| idct_islow_1d(row, false),
+    );
+    to_du(transpose(columns_first))
+}
+
+/// ifast 的 IDCT。反量化的乘数带有 AAN 的比例因子和 IFAST_SCALE_BITS 位小数。
+pub(super) fn idct_ifast(dct_du: &DctDu) -> Du {
+    let mut input = [[0; 8]; 8];
+    for u in 0..8 {
+        for v in 0..8 {
+            let coefficient = dct_du.0[u][v].round() as i64;
+            input[u][v] = descale(coefficient * AAN_SCALES[u][v], 14 - IFAST_SCALE_BITS);
+        }
+    }
+    let columns_first = transform_2d(
+        transpose(input),
+        |column| idct_ifast_1d(column, true),
+        |row| idct_ifast_1d(row, false),
+    );
+    to_du(transpose(columns_first))
+}
+
+fn transpose(input: [[i64; 8]; 8]) -> [[i64; 8]; 8] {
+    std::array::from_fn(|y| std::array::from_fn(|x| input[x][y]))
+}
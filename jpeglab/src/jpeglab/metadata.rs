@@ -0,0 +1,978 @@
+//! 元数据段的解析和生成。
+
+use std::io;
+
+use bytebuffer::ByteBuffer;
+use bytebuffer::Endian;
+
+use super::encode_step7::ToVec;
+use super::extract::extract_metadata;
+use super::thumbnail::TiffReader;
+
+/// XMP 所在的 APP1 以该标识开头，之后是 XML。
+pub(super) const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// 扩展 XMP 所在的 APP1 以该标识开头，之后是 32 字节的 GUID、4 字节的总长度、4 字节的偏移和扩展 XMP 的一块。
+pub(super) const EXTENDED_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+
+/// EXIF 所在的 APP1 以该标识开头，之后是 TIFF 结构。
+pub(super) const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// ICC 配置文件所在的 APP2 以该标识开头，之后是 1 字节的序号（从 1 开始）、1 字节的总块数和配置文件的一块。
+pub(super) const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+
+/// EXIF 中用到的标签。
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_GPS_INFO: u16 = 0x8825;
+const TAG_GPS_VERSION_ID: u16 = 0x0000;
+const TAG_GPS_LATITUDE_REF: u16 = 0x0001;
+const TAG_GPS_LATITUDE: u16 = 0x0002;
+const TAG_GPS_LONGITUDE_REF: u16 = 0x0003;
+const TAG_GPS_LONGITUDE: u16 = 0x0004;
+
+/// TIFF 的数据类型。
+const TYPE_BYTE: u16 = 1;
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+
+/// Photoshop 的 APP13 以该标识开头，之后是若干个资源块。
+const PHOTOSHOP_HEADER: &[u8] = b"Photoshop 3.0\0";
+
+/// 存放 IPTC-NAA 记录的资源块的 ID。
+const RESOURCE_IPTC_NAA: u16 = 0x0404;
+
+/// IPTC 应用记录（记录 2）中的数据集编号。
+const IPTC_KEYWORDS: u8 = 25;
+const IPTC_CAPTION: u8 = 120;
+
+/// 一个 XMP 包，即完整的 XML。不超过一个段的上限时放在一个 APP1 中，
+/// 否则按 XMP 规范第 3 部分写为扩展 XMP，见 `ToVec` 的实现。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmpPacket(String);
+
+impl XmpPacket {
+    /// 一个段最长 65535 字节，除去长度和标识后剩下的字节数。
+    const MAX_LEN: usize = u16::MAX as usize - 2 - XMP_HEADER.len();
+
+    /// 扩展 XMP 的一个段中，除去长度、标识、GUID、总长度和偏移后剩下的字节数。
+    const MAX_EXTENDED_CHUNK_LEN: usize =
+        u16::MAX as usize - 2 - EXTENDED_XMP_HEADER.len() - 32 - 4 - 4;
+
+    pub fn new(xml: String) -> io::Result<Self> {
+        if u32::try_from(xml.len()).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The XMP packet is {} bytes, more than extended XMP can hold",
+                    xml.len()
+                ),
+            ));
+        }
+        Ok(Self(xml))
+    }
+
+    /// 从 APP1 的内容中解析。不是 XMP 或者不是 UTF-8 时返回 `None`。
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let xml = data.strip_prefix(XMP_HEADER)?;
+        String::from_utf8(xml.to_vec()).ok().map(Self)
+    }
+
+    /// 从各个 APP1 的内容中读取第一个 XMP。其中有 `xmpNote:HasExtendedXMP` 时，
+    /// 找到这个 GUID 的扩展 XMP 的各块并拼接，再合并进来，见 `merge_extended`。
+    pub fn read<'a>(app1s: impl IntoIterator<Item = &'a [u8]>) -> io::Result<Option<Self>> {
+        let mut standard = None;
+        let mut chunks = vec![];
+        for data in app1s {
+            if let Some(chunk) = data.strip_prefix(EXTENDED_XMP_HEADER) {
+                chunks.push(chunk);
+            } else if standard.is_none() {
+                standard = Self::parse(data);
+            }
+        }
+        let Some(standard) = standard else {
+            return Ok(None);
+        };
+        let Some(guid) = standard.extended_guid() else {
+            return Ok(Some(standard));
+        };
+        let extended = assemble_extended_xmp(&chunks, guid)?;
+        Ok(Some(standard.merge_extended(guid, &extended)))
+    }
+
+    /// `xmpNote:HasExtendedXMP` 的值，即 32 个十六进制数字的 GUID。
+    fn extended_guid(&self) -> Option<&str> {
+        let (_, rest) = self.0.split_once("xmpNote:HasExtendedXMP")?;
+        let rest = rest
+            .strip_prefix("=\"")
+            .or_else(|| rest.strip_prefix("='"))
+            .or_else(|| rest.strip_prefix('>'))?;
+        rest.get(..32)
+            .filter(|guid| guid.bytes().all(|v| v.is_ascii_hexdigit()))
+    }
+
+    /// 把扩展 XMP 的 `rdf:RDF` 中的内容放到标准 XMP 的 `rdf:RDF` 的末尾，并去掉 `xmpNote:HasExtendedXMP`。
+    /// 有一方没有 `rdf:RDF` 时只取扩展 XMP。
+    fn merge_extended(&self, guid: &str, extended: &str) -> Self {
+        let standard = self
+            .0
+            .replace(&format!(" xmpNote:HasExtendedXMP=\"{}\"", guid), "");
+        let content = extended.find("<rdf:RDF").and_then(|start| {
+            let start = start + extended[start..].find('>')? + 1;
+            extended.get(start..extended.rfind("</rdf:RDF>")?)
+        });
+        match (content, standard.rfind("</rdf:RDF>")) {
+            (Some(content), Some(end)) => Self(format!(
+                "{}{}{}",
+                &standard[..end],
+                content,
+                &standard[end..]
+            )),
+            _ => Self(extended.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// 去掉 XMP 包的 `<?xpacket ...?>` 包装，扩展 XMP 不带包装。
+fn strip_xpacket(xml: &str) -> &str {
+    let mut ret = xml.trim();
+    if ret.starts_with("<?xpacket") {
+        ret = ret.split_once("?>").map_or(ret, |(_, rest)| rest);
+    }
+    if let Some(end) = ret.rfind("<?xpacket") {
+        ret = &ret[..end];
+    }
+    ret.trim()
+}
+
+impl ToVec for XmpPacket {
+    /// 太长时，整个 XMP 作为扩展 XMP 分块写在之后的 APP1 中，每块标明 GUID、总长度和偏移。
+    /// 标准的 APP1 中只有 `xmpNote:HasExtendedXMP`，值为 GUID，即扩展 XMP 的 MD5 的十六进制大写。
+    fn to_vec(&self) -> Vec<u8> {
+        let segment = |header: &[u8], content: &[&[u8]]| {
+            let length = 2 + header.len() + content.iter().map(|v| v.len()).sum::<usize>();
+            [
+                &[0xFF, 0xE1],
+                (length as u16).to_be_bytes().as_slice(),
+                header,
+                &content.concat(),
+            ]
+            .concat()
+        };
+        if self.0.len() <= Self::MAX_LEN {
+            return segment(XMP_HEADER, &[self.0.as_bytes()]);
+        }
+
+        let extended = strip_xpacket(&self.0).as_bytes();
+        let guid: String = md5(extended).iter().map(|v| format!("{:02X}", v)).collect();
+        let standard = format!(
+            concat!(
+                "<?xpacket begin=\"\u{FEFF}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+                "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+                "<rdf:Description rdf:about=\"\" xmlns:xmpNote=\"http://ns.adobe.com/xmp/note/\" ",
+                "xmpNote:HasExtendedXMP=\"{}\"/>",
+                "</rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>"
+            ),
+            guid
+        );
+        let mut ret = segment(XMP_HEADER, &[standard.as_bytes()]);
+        let full_length = (extended.len() as u32).to_be_bytes();
+        for (i, chunk) in extended.chunks(Self::MAX_EXTENDED_CHUNK_LEN).enumerate() {
+            let offset = ((i * Self::MAX_EXTENDED_CHUNK_LEN) as u32).to_be_bytes();
+            ret.extend(segment(
+                EXTENDED_XMP_HEADER,
+                &[guid.as_bytes(), &full_length, &offset, chunk],
+            ));
+        }
+        ret
+    }
+}
+
+/// 按偏移拼接 GUID 为 `guid` 的扩展 XMP 的各块。总长度不一致、缺块、重复的块或 MD5 与 GUID 不符时出错。
+fn assemble_extended_xmp(chunks: &[&[u8]], guid: &str) -> io::Result<String> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut full_length = None;
+    let mut parts = vec![];
+    for chunk in chunks {
+        let Some(rest) = chunk.strip_prefix(guid.as_bytes()) else {
+            continue;
+        };
+        let header = rest
+            .get(..8)
+            .ok_or_else(|| invalid("Invalid extended XMP chunk".to_string()))?;
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let offset = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+        if *full_length.get_or_insert(length) != length {
+            return Err(invalid(
+                "The extended XMP chunks disagree on the full length".to_string(),
+            ));
+        }
+        parts.push((offset, &rest[8..]));
+    }
+    let Some(full_length) = full_length else {
+        return Err(invalid(format!("Extended XMP {} is missing", guid)));
+    };
+
+    parts.sort_by_key(|&(offset, _)| offset);
+    let mut ret = vec![];
+    for (offset, data) in parts {
+        if offset != ret.len() {
+            return Err(invalid(format!(
+                "Extended XMP chunk at offset {} is missing or appears more than once",
+                ret.len().min(offset)
+            )));
+        }
+        ret.extend_from_slice(data);
+    }
+    if ret.len() != full_length {
+        return Err(invalid(format!(
+            "Extended XMP is {} bytes, expected {}",
+            ret.len(),
+            full_length
+        )));
+    }
+    let digest: String = md5(&ret).iter().map(|v| format!("{:02X}", v)).collect();
+    if !digest.eq_ignore_ascii_case(guid) {
+        return Err(invalid(format!(
+            "The MD5 of extended XMP is {}, expected {}",
+            digest, guid
+        )));
+    }
+    String::from_utf8(ret).map_err(|_| invalid("Extended XMP is not UTF-8".to_string()))
+}
+
+/// MD5（RFC 1321），只用于扩展 XMP 的 GUID。
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let constants: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    // 填充 0x80 和若干个 0，使长度模 64 为 56，之后是以位为单位的原长度，小端。
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), 7 * i % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(constants[i])
+                .wrapping_add(words[g])
+                .rotate_left(SHIFTS[i / 16 * 4 + i % 4]);
+            (a, b, c, d) = (d, b.wrapping_add(rotated), b, c);
+        }
+        for (value, v) in state.iter_mut().zip([a, b, c, d]) {
+            *value = value.wrapping_add(v);
+        }
+    }
+
+    let mut ret = [0; 16];
+    for (bytes, value) in ret.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    ret
+}
+
+/// 一个 ICC 配置文件。超过一个段的上限时分为多个 APP2，最多 255 块。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IccProfile(Vec<u8>);
+
+impl IccProfile {
+    /// 一个段最长 65535 字节，除去长度、标识、序号和块数后剩下的字节数。
+    const MAX_CHUNK_LEN: usize = u16::MAX as usize - 2 - ICC_HEADER.len() - 2;
+
+    /// 只检查配置文件头中的长度和签名 "acsp"。
+    pub fn new(data: Vec<u8>) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let is_valid = data.len() >= 128
+            && u32::from_be_bytes(data[..4].try_into().unwrap()) as usize == data.len()
+            && &data[36..40] == b"acsp";
+        if !is_valid {
+            return Err(invalid(
+                "The file is not an ICC profile or its length is wrong".to_string(),
+            ));
+        }
+        if data.len().div_ceil(Self::MAX_CHUNK_LEN) > u8::MAX as usize {
+            return Err(invalid(format!(
+                "The ICC profile is {} bytes, more than fits in 255 APP2 segments",
+                data.len()
+            )));
+        }
+        Ok(Self(data))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl ToVec for IccProfile {
+    fn to_vec(&self) -> Vec<u8> {
+        let chunks: Vec<_> = self.0.chunks(Self::MAX_CHUNK_LEN).collect();
+        let mut ret = vec![];
+        for (i, chunk) in chunks.iter().enumerate() {
+            let length = (2 + ICC_HEADER.len() + 2 + chunk.len()) as u16;
+            ret.extend_from_slice(&[0xFF, 0xE2]);
+            ret.extend_from_slice(&length.to_be_bytes());
+            ret.extend_from_slice(ICC_HEADER);
+            ret.extend_from_slice(&[i as u8 + 1, chunks.len() as u8]);
+            ret.extend_from_slice(chunk);
+        }
+        ret
+    }
+}
+
+/// 编码时写入 APP1 的 EXIF，只包含方向、相机、拍摄时间和位置。
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExifData {
+    /// (纬度, 经度)，单位为度，北纬和东经为正。
+    gps: Option<(f64, f64)>,
+    /// EXIF 格式的时间 "YYYY:MM:DD HH:MM:SS"。
+    datetime: Option<String>,
+    /// 显示时的方向，1 到 8，1 为不旋转。编码时不旋转图像，只照原样记录。
+    orientation: Option<u16>,
+    /// 相机的厂商。
+    make: Option<String>,
+    /// 相机的型号。
+    model: Option<String>,
+}
+
+impl ExifData {
+    pub fn new(gps: Option<(f64, f64)>, datetime: Option<String>) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        if let Some((latitude, longitude)) = gps {
+            if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+                return Err(invalid(format!(
+                    "The GPS position {},{} is out of range",
+                    latitude, longitude
+                )));
+            }
+        }
+        if let Some(datetime) = &datetime {
+            // 除了分隔符都是数字。
+            let is_valid = datetime.len() == 19
+                && datetime.bytes().enumerate().all(|(i, c)| match i {
+                    4 | 7 | 13 | 16 => c == b':',
+                    10 => c == b' ',
+                    _ => c.is_ascii_digit(),
+                });
+            if !is_valid {
+                return Err(invalid(format!(
+                    "The date and time {:?} is not in the format \"YYYY:MM:DD HH:MM:SS\"",
+                    datetime
+                )));
+            }
+        }
+        Ok(Self {
+            gps,
+            datetime,
+            ..Default::default()
+        })
+    }
+
+    /// 设置方向。
+    pub fn with_orientation(self, orientation: Option<u16>) -> io::Result<Self> {
+        if orientation.is_some_and(|v| !(1..=8).contains(&v)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The orientation {} is not from 1 to 8",
+                    orientation.unwrap()
+                ),
+            ));
+        }
+        Ok(Self {
+            orientation,
+            ..self
+        })
+    }
+
+    /// 设置相机的厂商和型号。
+    pub fn with_camera(self, make: Option<String>, model: Option<String>) -> Self {
+        Self {
+            make,
+            model,
+            ..self
+        }
+    }
+
+    /// 从 TIFF 结构中读取，例如 EXIF 的 APP1 中标识之后的部分，或者整个 TIFF 文件。
+    /// 只读取 IFD0 和 GPS IFD 中这里用到的标签，无效的值被忽略。不是 TIFF 结构时返回 `None`。
+    pub fn parse(tiff: &[u8]) -> Option<Self> {
+        let is_big_endian = match tiff.get(0..2)? {
+            b"MM" => true,
+            b"II" => false,
+            _ => return None,
+        };
+        let reader = TiffReader {
+            data: tiff,
+            is_big_endian,
+        };
+        if reader.u16(2)? != 42 {
+            return None;
+        }
+        let ifd0 = reader.u32(4)? as usize;
+        let ascii = |ifd: usize, tag: u16| -> Option<String> {
+            let (kind, count, offset) = reader.entry(ifd, tag)?;
+            let bytes = tiff.get(offset..offset + count)?;
+            let text = bytes.split(|&c| c == 0).next()?;
+            (kind == TYPE_ASCII && !text.is_empty())
+                .then(|| String::from_utf8_lossy(text).into_owned())
+        };
+        let degrees = |ifd: usize, tag: u16| -> Option<f64> {
+            let (kind, count, offset) = reader.entry(ifd, tag)?;
+            if kind != TYPE_RATIONAL || count != 3 {
+                return None;
+            }
+            let mut ret = 0.0;
+            for (i, unit) in [1.0, 60.0, 3600.0].into_iter().enumerate() {
+                let numerator = reader.u32(offset + 8 * i)? as f64;
+                let denominator = reader.u32(offset + 8 * i + 4)? as f64;
+                ret += numerator / denominator / unit;
+            }
+            ret.is_finite().then_some(ret)
+        };
+
+        let gps = reader
+            .ifd_value(ifd0, TAG_GPS_INFO)
+            .and_then(|gps| {
+                let gps = gps as usize;
+                let latitude = degrees(gps, TAG_GPS_LATITUDE)?;
+                let longitude = degrees(gps, TAG_GPS_LONGITUDE)?;
+                let sign = |tag, negative: &str| match ascii(gps, tag) {
+                    Some(v) if v == negative => -1.0,
+                    _ => 1.0,
+                };
+                Some((
+                    sign(TAG_GPS_LATITUDE_REF, "S") * latitude,
+                    sign(TAG_GPS_LONGITUDE_REF, "W") * longitude,
+                ))
+            })
+            .filter(|&(latitude, longitude)| latitude.abs() <= 90.0 && longitude.abs() <= 180.0);
+        let datetime =
+            ascii(ifd0, TAG_DATE_TIME).filter(|v| Self::new(None, Some(v.clone())).is_ok());
+        let orientation = reader
+            .ifd_value(ifd0, TAG_ORIENTATION)
+            .and_then(|v| u16::try_from(v).ok())
+            .filter(|v| (1..=8).contains(v));
+        Some(Self {
+            gps,
+            datetime,
+            orientation,
+            make: ascii(ifd0, TAG_MAKE),
+            model: ascii(ifd0, TAG_MODEL),
+        })
+    }
+
+    /// 从输入的图像文件中读取：JPEG 中 EXIF 的 APP1，或者 TIFF 文件本身。没有时返回 `None`。
+    pub fn read_from_file(bytes: &[u8]) -> Option<Self> {
+        let tiff = if bytes.starts_with(&[0xFF, 0xD8]) {
+            extract_metadata(bytes).ok()?.exif?
+        } else {
+            bytes
+        };
+        Self::parse(tiff).filter(|exif| !exif.is_empty())
+    }
+
+    /// 用 `other` 补上没有的值。
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            gps: self.gps.or(other.gps),
+            datetime: self.datetime.or(other.datetime),
+            orientation: self.orientation.or(other.orientation),
+            make: self.make.or(other.make),
+            model: self.model.or(other.model),
+        }
+    }
+
+    /// 是否没有任何值。
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// 将度数表示为度、分、秒三个有理数，秒精确到 1/100。
+fn to_dms(degrees: f64) -> [(u32, u32); 3] {
+    let hundredths = (degrees.abs() * 360000.0).round() as u32;
+    [
+        (hundredths / 360000, 1),
+        (hundredths / 6000 % 60, 1),
+        (hundredths % 6000, 100),
+    ]
+}
+
+/// IFD 的一个条目。
+struct IfdEntry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    /// 大端的值。不超过 4 个字节时直接存放在条目中，否则存放在 IFD 之后，条目中为偏移。
+    value: Vec<u8>,
+}
+
+impl IfdEntry {
+    fn ascii(tag: u16, text: &str) -> Self {
+        let value = [text.as_bytes(), &[0]].concat();
+        Self {
+            tag,
+            kind: TYPE_ASCII,
+            count: value.len() as u32,
+            value,
+        }
+    }
+
+    fn rationals(tag: u16, values: &[(u32, u32)]) -> Self {
+        Self {
+            tag,
+            kind: TYPE_RATIONAL,
+            count: values.len() as u32,
+            value: values
+                .iter()
+                .flat_map(|(n, d)| [n.to_be_bytes(), d.to_be_bytes()].concat())
+                .collect(),
+        }
+    }
+}
+
+/// 一个 IFD 连同存放在它之后的值的字节数。值填充到偶数字节。
+fn ifd_len(entries: &[IfdEntry]) -> usize {
+    let values: usize = entries
+        .iter()
+        .filter(|entry| entry.value.len() > 4)
+        .map(|entry| entry.value.len().next_multiple_of(2))
+        .sum();
+    2 + 12 * entries.len() + 4 + values
+}
+
+/// 在 `tiff` 的末尾写出 IFD，之后是超过 4 个字节的值。偏移相对于 TIFF 头，即 `tiff` 的开头。
+/// `entries` 应当按标签升序排列。没有下一个 IFD。
+fn write_ifd(tiff: &mut ByteBuffer, entries: &[IfdEntry]) {
+    let mut value_offset = tiff.len() + 2 + 12 * entries.len() + 4;
+    tiff.write_u16(entries.len() as u16);
+    for entry in entries {
+        tiff.write_u16(entry.tag);
+        tiff.write_u16(entry.kind);
+        tiff.write_u32(entry.count);
+        if entry.value.len() > 4 {
+            tiff.write_u32(value_offset as u32);
+            value_offset += entry.value.len().next_multiple_of(2);
+        } else {
+            let mut value = [0; 4];
+            value[..entry.value.len()].copy_from_slice(&entry.value);
+            tiff.write_bytes(&value);
+        }
+    }
+    tiff.write_u32(0);
+    for entry in entries.iter().filter(|entry| entry.value.len() > 4) {
+        tiff.write_bytes(&entry.value);
+        if entry.value.len() % 2 == 1 {
+            tiff.write_u8(0);
+        }
+    }
+}
+
+impl ToVec for ExifData {
+    /// 大端的 TIFF 结构。IFD0 之后是 GPS IFD，各自的值跟在 IFD 之后。
+    fn to_vec(&self) -> Vec<u8> {
+        // IFD0，条目按标签升序排列。
+        let mut ifd0 = vec![];
+        if let Some(make) = &self.make {
+            ifd0.push(IfdEntry::ascii(TAG_MAKE, make));
+        }
+        if let Some(model) = &self.model {
+            ifd0.push(IfdEntry::ascii(TAG_MODEL, model));
+        }
+        if let Some(orientation) = self.orientation {
+            ifd0.push(IfdEntry {
+                tag: TAG_ORIENTATION,
+                kind: TYPE_SHORT,
+                count: 1,
+                value: orientation.to_be_bytes().to_vec(),
+            });
+        }
+        if let Some(datetime) = &self.datetime {
+            ifd0.push(IfdEntry::ascii(TAG_DATE_TIME, datetime));
+        }
+
+        let gps = self.gps.map(|(latitude, longitude)| {
+            let latitude_ref = if latitude < 0.0 { "S" } else { "N" };
+            let longitude_ref = if longitude < 0.0 { "W" } else { "E" };
+            vec![
+                IfdEntry {
+                    tag: TAG_GPS_VERSION_ID,
+                    kind: TYPE_BYTE,
+                    count: 4,
+                    value: vec![2, 3, 0, 0],
+                },
+                IfdEntry::ascii(TAG_GPS_LATITUDE_REF, latitude_ref),
+                IfdEntry::rationals(TAG_GPS_LATITUDE, &to_dms(latitude)),
+                IfdEntry::ascii(TAG_GPS_LONGITUDE_REF, longitude_ref),
+                IfdEntry::rationals(TAG_GPS_LONGITUDE, &to_dms(longitude)),
+            ]
+        });
+        if gps.is_some() {
+            // GPS IFD 紧跟在 IFD0 之后。指针的值在条目中，使 IFD0 多一个条目的 12 个字节。
+            let gps_offset = (8 + ifd_len(&ifd0) + 12) as u32;
+            ifd0.push(IfdEntry {
+                tag: TAG_GPS_INFO,
+                kind: TYPE_LONG,
+                count: 1,
+                value: gps_offset.to_be_bytes().to_vec(),
+            });
+        }
+
+        let mut tiff = ByteBuffer::new();
+        tiff.set_endian(Endian::BigEndian);
+        tiff.write_bytes(b"MM");
+        tiff.write_u16(42);
+        tiff.write_u32(8);
+        write_ifd(&mut tiff, &ifd0);
+        if let Some(gps) = &gps {
+            write_ifd(&mut tiff, gps);
+        }
+
+        let length = (2 + EXIF_HEADER.len() + tiff.len()) as u16;
+        [
+            &[0xFF, 0xE1],
+            length.to_be_bytes().as_slice(),
+            EXIF_HEADER,
+            tiff.as_bytes(),
+        ]
+        .concat()
+    }
+}
+
+/// APP13 中的 IPTC 信息，只解析标题和关键词。文本按 UTF-8 解码，无效的字节被替换。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IptcInfo {
+    /// Caption/Abstract，即 2:120。
+    pub captions: Vec<String>,
+    /// Keywords，即 2:25，可以有多个。
+    pub keywords: Vec<String>,
+}
+
+impl IptcInfo {
+    /// 从 APP13 的内容中解析。不是 Photoshop 的 APP13 或者没有 IPTC 资源块时返回 `None`。
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let resources = data.strip_prefix(PHOTOSHOP_HEADER)?;
+        let iptc = photoshop_resource(resources, RESOURCE_IPTC_NAA)?;
+
+        let mut ret = Self::default();
+        let mut idx = 0;
+        // 每个数据集为 1C、记录号、数据集号、2 字节长度和内容。
+        // 长度的最高位为 1 时是扩展长度，标题和关键词不会用到，遇到时停止。
+        while let Some(&[0x1C, record, dataset, hi, lo]) = iptc.get(idx..idx + 5) {
+            let length = u16::from_be_bytes([hi, lo]) as usize;
+            if length & 0x8000 != 0 {
+                break;
+            }
+            let Some(value) = iptc.get(idx + 5..idx + 5 + length) else {
+                break;
+            };
+            let value = String::from_utf8_lossy(value).into_owned();
+            match (record, dataset) {
+                (2, IPTC_CAPTION) => ret.captions.push(value),
+                (2, IPTC_KEYWORDS) => ret.keywords.push(value),
+                _ => {}
+            }
+            idx += 5 + length;
+        }
+        Some(ret)
+    }
+}
+
+/// 在 Photoshop 的资源块中找到 `id` 的内容。
+/// 每个资源块为 "8BIM"、2 字节 ID、Pascal 字符串的名字和 4 字节长度的内容，名字和内容都填充到偶数字节。
+fn photoshop_resource(mut data: &[u8], id: u16) -> Option<&[u8]> {
+    while let Some(rest) = data.strip_prefix(b"8BIM") {
+        let resource_id = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+        let name_length = *rest.get(2)? as usize;
+        let idx = 2 + (1 + name_length).next_multiple_of(2);
+        let length = u32::from_be_bytes(rest.get(idx..idx + 4)?.try_into().ok()?) as usize;
+        let content = rest.get(idx + 4..idx + 4 + length)?;
+        if resource_id == id {
+            return Some(content);
+        }
+        data = rest.get(idx + 4 + length.next_multiple_of(2)..)?;
+    }
+    None
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use super::super::encode_to_vec;
+    use super::super::options::EncodeOptions;
+    use super::super::segments::read_segments;
+
+    #[test]
+    fn test_xmp_packet() {
+        let xml = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"></x:xmpmeta>"#.to_string();
+        let packet = XmpPacket::new(xml.clone()).unwrap();
+        let bytes = packet.to_vec();
+        assert_eq!(&bytes[..2], [0xFF, 0xE1]);
+        assert_eq!(
+            u16::from_be_bytes([bytes[2], bytes[3]]) as usize,
+            bytes.len() - 2
+        );
+        assert_eq!(XmpPacket::parse(&bytes[4..]), Some(packet));
+
+        assert_eq!(
+            XmpPacket::new("x".repeat(XmpPacket::MAX_LEN))
+                .unwrap()
+                .to_vec()
+                .len(),
+            u16::MAX as usize + 2
+        );
+        assert_eq!(XmpPacket::parse(b"Exif\0\0"), None);
+    }
+
+    #[test]
+    fn test_md5() {
+        let hex =
+            |data: &[u8]| -> String { md5(data).iter().map(|v| format!("{:02x}", v)).collect() };
+        assert_eq!(hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            hex(
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890"
+            ),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+    }
+
+    #[test]
+    fn test_extended_xmp() {
+        let body = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">".to_string()
+            + &"<!-- padding -->".repeat(10000)
+            + "</x:xmpmeta>";
+        let xml = format!(
+            "<?xpacket begin=\"\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>{}<?xpacket end=\"w\"?>",
+            body
+        );
+        let bytes = XmpPacket::new(xml).unwrap().to_vec();
+
+        // 标准的 XMP 之后是 3 个扩展 XMP 的段。
+        let jpeg = [&[0xFF, 0xD8], bytes.as_slice(), &[0xFF, 0xD9]].concat();
+        let segments = read_segments(&jpeg).unwrap();
+        assert_eq!(segments.len(), 4);
+        let standard = XmpPacket::parse(segments[0].data).unwrap();
+        let guid = md5(body.as_bytes())
+            .iter()
+            .map(|v| format!("{:02X}", v))
+            .collect::<String>();
+        assert!(standard
+            .as_str()
+            .contains(&format!("xmpNote:HasExtendedXMP=\"{}\"", guid)));
+
+        let mut extended = vec![0; body.len()];
+        for segment in &segments[1..] {
+            assert!(segment.data.len() + 2 <= u16::MAX as usize);
+            let rest = segment.data.strip_prefix(EXTENDED_XMP_HEADER).unwrap();
+            assert_eq!(&rest[..32], guid.as_bytes());
+            assert_eq!(
+                u32::from_be_bytes(rest[32..36].try_into().unwrap()) as usize,
+                body.len()
+            );
+            let offset = u32::from_be_bytes(rest[36..40].try_into().unwrap()) as usize;
+            extended[offset..offset + rest.len() - 40].copy_from_slice(&rest[40..]);
+        }
+        assert_eq!(extended, body.as_bytes());
+    }
+
+    #[test]
+    fn test_exif_data() {
+        let exif = ExifData::new(
+            Some((31.2304, -121.4737)),
+            Some("2024:05:06 07:08:09".to_string()),
+        )
+        .unwrap();
+        let bytes = exif.to_vec();
+        assert_eq!(&bytes[..2], [0xFF, 0xE1]);
+        assert_eq!(
+            u16::from_be_bytes([bytes[2], bytes[3]]) as usize,
+            bytes.len() - 2
+        );
+
+        let tiff = bytes[4..].strip_prefix(EXIF_HEADER).unwrap();
+        let reader = TiffReader {
+            data: tiff,
+            is_big_endian: true,
+        };
+        let ifd0 = reader.u32(4).unwrap() as usize;
+        // 第一个条目是 DateTime，值超过 4 个字节，条目中是偏移。
+        assert_eq!(reader.u16(ifd0 + 2), Some(TAG_DATE_TIME));
+        let datetime = reader.u32(ifd0 + 2 + 8).unwrap() as usize;
+        assert_eq!(&tiff[datetime..datetime + 20], b"2024:05:06 07:08:09\0");
+
+        let gps = reader.ifd_value(ifd0, TAG_GPS_INFO).unwrap() as usize;
+        assert_eq!(reader.u16(gps), Some(5));
+        // GPSLatitudeRef 和 GPSLongitudeRef 的值在条目中。
+        assert_eq!(tiff[gps + 2 + 12 + 8], b'N');
+        assert_eq!(tiff[gps + 2 + 3 * 12 + 8], b'W');
+        let rationals = reader.u32(gps + 2 + 2 * 12 + 8).unwrap() as usize;
+        let values: Vec<u32> = (0..12)
+            .map(|i| reader.u32(rationals + 4 * i).unwrap())
+            .collect();
+        // 31°13'49.44" 和 121°28'25.32"。
+        assert_eq!(values, [31, 1, 13, 1, 4944, 100, 121, 1, 28, 1, 2532, 100]);
+
+        assert!(ExifData::new(Some((91.0, 0.0)), None).is_err());
+        assert!(ExifData::new(None, Some("2024-05-06T07:08:09".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_exif_parse() {
+        let exif = ExifData::new(
+            Some((-33.8568, 151.2153)),
+            Some("2024:05:06 07:08:09".to_string()),
+        )
+        .unwrap()
+        .with_orientation(Some(6))
+        .unwrap()
+        .with_camera(Some("Maker".to_string()), Some("A".to_string()));
+        let jpeg = encode_to_vec(
+            &image::RgbImage::new(8, 8),
+            &EncodeOptions {
+                exif: Some(exif.clone()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let parsed = ExifData::read_from_file(&jpeg).unwrap();
+        // 位置精确到 1/100 秒。
+        let (latitude, longitude) = parsed.gps.unwrap();
+        assert!((latitude + 33.8568).abs() < 1e-5 && (longitude - 151.2153).abs() < 1e-5);
+        assert_eq!(
+            ExifData {
+                gps: exif.gps,
+                ..parsed
+            },
+            exif
+        );
+
+        // 命令行的值优先。
+        let merged = ExifData::default()
+            .with_orientation(Some(3))
+            .unwrap()
+            .or(exif.clone());
+        assert_eq!(merged.orientation, Some(3));
+        assert_eq!(merged.model.as_deref(), Some("A"));
+
+        assert!(ExifData::default().with_orientation(Some(9)).is_err());
+        let plain = encode_to_vec(&image::RgbImage::new(8, 8), &Default::default()).unwrap();
+        assert_eq!(ExifData::read_from_file(&plain), None);
+        assert_eq!(ExifData::parse(b"not a tiff"), None);
+    }
+
+    /// 构造长度为 `len` 的 ICC 配置文件，只有文件头是有效的。
+    fn make_icc_profile(len: usize) -> Vec<u8> {
+        let mut ret: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        ret[..4].copy_from_slice(&(len as u32).to_be_bytes());
+        ret[36..40].copy_from_slice(b"acsp");
+        ret
+    }
+
+    #[test]
+    fn test_icc_profile() {
+        let data = make_icc_profile(150000);
+        let profile = IccProfile::new(data.clone()).unwrap();
+        let jpeg = encode_to_vec(
+            &image::RgbImage::new(8, 8),
+            &EncodeOptions {
+                icc_profile: Some(profile),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // 分为 3 块，每个段都不超过上限。
+        let segments = read_segments(&jpeg).unwrap();
+        let chunks: Vec<_> = segments.iter().filter(|s| s.marker == 0xE2).collect();
+        assert_eq!(chunks.len(), 3);
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(&chunk.data[ICC_HEADER.len()..][..2], [i as u8 + 1, 3]);
+        }
+        assert_eq!(extract_metadata(&jpeg).unwrap().icc_profile, Some(data));
+
+        assert!(IccProfile::new(make_icc_profile(200)).is_ok());
+        assert!(IccProfile::new(vec![0; 200]).is_err());
+        let mut wrong_length = make_icc_profile(200);
+        wrong_length.pop();
+        assert!(IccProfile::new(wrong_length).is_err());
+        assert!(IccProfile::new(make_icc_profile(256 * IccProfile::MAX_CHUNK_LEN)).is_err());
+    }
+
+    /// 构造 APP13 的内容：一个无关的资源块，之后是 IPTC 资源块。
+    pub fn make_app13(captions: &[&str], keywords: &[&str]) -> Vec<u8> {
+        let dataset = |dataset: u8, value: &str| {
+            let length = (value.len() as u16).to_be_bytes();
+            [&[0x1C, 2, dataset], length.as_slice(), value.as_bytes()].concat()
+        };
+        // 1:90 为字符集，与标题和关键词无关。
+        let mut iptc = vec![0x1C, 1, 90, 0, 3, 0x1B, 0x25, 0x47];
+        for caption in captions {
+            iptc.extend(dataset(IPTC_CAPTION, caption));
+        }
+        for keyword in keywords {
+            iptc.extend(dataset(IPTC_KEYWORDS, keyword));
+        }
+
+        let resource = |id: u16, content: &[u8]| {
+            // 空的名字，长度字节加填充共 2 字节。
+            let length = (content.len() as u32).to_be_bytes();
+            let padding: &[u8] = if content.len() % 2 == 1 { &[0] } else { &[] };
+            [
+                b"8BIM",
+                id.to_be_bytes().as_slice(),
+                &[0, 0],
+                &length,
+                content,
+                padding,
+            ]
+            .concat()
+        };
+        [
+            PHOTOSHOP_HEADER,
+            &resource(0x03ED, &[0; 15]),
+            &resource(RESOURCE_IPTC_NAA, &iptc),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_iptc_info() {
+        let data = make_app13(&["a caption"], &["sky", "sea"]);
+        let info = IptcInfo::parse(&data).unwrap();
+        assert_eq!(info.captions, ["a caption"]);
+        assert_eq!(info.keywords, ["sky", "sea"]);
+
+        assert_eq!(IptcInfo::parse(b"Photoshop 3.0\0"), None);
+        assert_eq!(IptcInfo::parse(b"Exif\0\0"), None);
+    }
+}
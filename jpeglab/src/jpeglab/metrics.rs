@@ -0,0 +1,495 @@
+//! 两幅同样大小的 RGB 图像之间的质量指标：PSNR、SSIM 和 MS-SSIM。
+//! SSIM 和 MS-SSIM 在亮度上计算，窗口为 σ = 1.5 的 11x11 高斯窗口，只统计完全落在图像内的窗口，
+//! 参数与 Wang 等人的论文相同。
+//!
+//! 另有两个不需要原图的指标：块效应 `blockiness` 和振铃 `ringing`，同样在亮度上计算。
+
+use std::io;
+
+use image::RgbImage;
+
+use super::analysis::heat_color;
+
+const K1: f64 = 0.01;
+const K2: f64 = 0.03;
+const C1: f64 = (K1 * 255.0) * (K1 * 255.0);
+const C2: f64 = (K2 * 255.0) * (K2 * 255.0);
+const WINDOW_RADIUS: usize = 5;
+const WINDOW_SIGMA: f64 = 1.5;
+
+/// 块的边长。
+const BLOCK_SIZE: usize = 8;
+/// 亮度梯度（|dx| + |dy|）不小于该值的像素视为边缘。
+const EDGE_THRESHOLD: f64 = 64.0;
+/// 与边缘的距离（切比雪夫距离）不小于该值的像素才统计振铃，避免把边缘本身算进去。
+const RINGING_MIN_DISTANCE: usize = 2;
+
+/// MS-SSIM 各个尺度的权重，从原始尺度开始。
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+/// 所有指标。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metrics {
+    /// 单位为 dB，完全相同时为正无穷。
+    pub psnr: f64,
+    pub ssim: f64,
+    pub ms_ssim: f64,
+}
+
+impl Metrics {
+    pub fn new(a: &RgbImage, b: &RgbImage) -> io::Result<Self> {
+        Ok(Self {
+            psnr: psnr(a, b)?,
+            ssim: ssim(a, b)?,
+            ms_ssim: ms_ssim(a, b)?,
+        })
+    }
+}
+
+fn check_dimensions(a: &RgbImage, b: &RgbImage) -> io::Result<()> {
+    if a.dimensions() != b.dimensions() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Image sizes differ: {}x{} and {}x{}",
+                a.width(),
+                a.height(),
+                b.width(),
+                b.height()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// 三个通道合在一起计算的 PSNR（dB）。
+pub fn psnr(a: &RgbImage, b: &RgbImage) -> io::Result<f64> {
+    check_dimensions(a, b)?;
+    let mse = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&x, &y)| (x as f64 - y as f64).powi(2))
+        .sum::<f64>()
+        / a.as_raw().len() as f64;
+    Ok(if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    })
+}
+
+/// 逐像素、逐通道的差的绝对值乘以 `gain`，超过 255 的截断。差异很小的压缩失真放大后才能看清。
+pub fn difference_image(a: &RgbImage, b: &RgbImage, gain: u8) -> io::Result<RgbImage> {
+    check_dimensions(a, b)?;
+    Ok(RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        let (p, q) = (a.get_pixel(x, y), b.get_pixel(x, y));
+        image::Rgb(std::array::from_fn(|c| {
+            p[c].abs_diff(q[c]).saturating_mul(gain)
+        }))
+    }))
+}
+
+/// 平均 SSIM，取值不超过 1，完全相同时为 1。
+pub fn ssim(a: &RgbImage, b: &RgbImage) -> io::Result<f64> {
+    check_dimensions(a, b)?;
+    let (ssim, _) = ssim_and_cs(&Plane::luma(a), &Plane::luma(b));
+    Ok(ssim)
+}
+
+/// MS-SSIM。每个尺度缩小一半，图像太小时只用能容纳一个窗口的尺度，权重按实际用到的尺度归一化。
+pub fn ms_ssim(a: &RgbImage, b: &RgbImage) -> io::Result<f64> {
+    check_dimensions(a, b)?;
+    let mut a = Plane::luma(a);
+    let mut b = Plane::luma(b);
+    let mut values = vec![];
+    for _ in MS_SSIM_WEIGHTS {
+        values.push(ssim_and_cs(&a, &b));
+        if a.width.min(a.height) < 2 * (2 * WINDOW_RADIUS + 1) {
+            break;
+        }
+        a = a.downsample();
+        b = b.downsample();
+    }
+
+    let weights = &MS_SSIM_WEIGHTS[..values.len()];
+    let total: f64 = weights.iter().sum();
+    let last = values.len() - 1;
+    // 最后一个尺度用完整的 SSIM，之前的尺度只用对比度和结构的部分。负值截断为 0。
+    Ok(values
+        .iter()
+        .zip(weights)
+        .enumerate()
+        .map(|(i, (&(ssim, cs), weight))| {
+            let value = if i == last { ssim } else { cs };
+            value.max(0.0).powf(weight / total)
+        })
+        .product())
+}
+
+/// 块效应：跨越 8 像素网格线的相邻像素差的平方均值，除以块内相邻像素差的平方均值。
+/// 约为 1 时看不出网格，越大块效应越明显。没有网格线或块内完全平坦时为 1。
+pub fn blockiness(image: &RgbImage) -> f64 {
+    let plane = Plane::luma(image);
+    // (平方和, 个数)，分别为跨网格线和块内。
+    let mut boundary = (0.0, 0);
+    let mut inner = (0.0, 0);
+    let mut add = |a: f64, b: f64, on_boundary: bool| {
+        let sum = if on_boundary {
+            &mut boundary
+        } else {
+            &mut inner
+        };
+        sum.0 += (a - b) * (a - b);
+        sum.1 += 1;
+    };
+    for y in 0..plane.height {
+        for x in 0..plane.width {
+            let value = plane.get(x, y);
+            if x + 1 < plane.width {
+                add(value, plane.get(x + 1, y), (x + 1) % BLOCK_SIZE == 0);
+            }
+            if y + 1 < plane.height {
+                add(value, plane.get(x, y + 1), (y + 1) % BLOCK_SIZE == 0);
+            }
+        }
+    }
+
+    if boundary.1 == 0 || inner.1 == 0 || inner.0 == 0.0 {
+        return 1.0;
+    }
+    (boundary.0 / boundary.1 as f64) / (inner.0 / inner.1 as f64)
+}
+
+/// 振铃的简单估计：在含有强边缘的块中，离边缘较远的像素的平均拉普拉斯绝对值。
+/// 压缩越重，边缘附近平坦区域中的波纹越强，值越大。没有这样的像素时为 0。
+pub fn ringing(image: &RgbImage) -> f64 {
+    let plane = Plane::luma(image);
+    let (width, height) = (plane.width, plane.height);
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let mut edges = vec![false; width * height];
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let dx = plane.get(x + 1, y) - plane.get(x - 1, y);
+            let dy = plane.get(x, y + 1) - plane.get(x, y - 1);
+            edges[y * width + x] = dx.abs() + dy.abs() >= EDGE_THRESHOLD;
+        }
+    }
+
+    let mut sum = 0.0;
+    let mut count = 0;
+    for by in (0..height).step_by(BLOCK_SIZE) {
+        for bx in (0..width).step_by(BLOCK_SIZE) {
+            let ys = by..(by + BLOCK_SIZE).min(height);
+            let xs = bx..(bx + BLOCK_SIZE).min(width);
+            if !ys.clone().any(|y| xs.clone().any(|x| edges[y * width + x])) {
+                continue;
+            }
+            for y in ys.clone().filter(|&y| y > 0 && y < height - 1) {
+                for x in xs.clone().filter(|&x| x > 0 && x < width - 1) {
+                    let d = RINGING_MIN_DISTANCE;
+                    let near_edge = (y.saturating_sub(d)..(y + d + 1).min(height)).any(|ny| {
+                        (x.saturating_sub(d)..(x + d + 1).min(width))
+                            .any(|nx| edges[ny * width + nx])
+                    });
+                    if near_edge {
+                        continue;
+                    }
+                    let laplacian = 4.0 * plane.get(x, y)
+                        - plane.get(x - 1, y)
+                        - plane.get(x + 1, y)
+                        - plane.get(x, y - 1)
+                        - plane.get(x, y + 1);
+                    sum += laplacian.abs();
+                    count += 1;
+                }
+            }
+        }
+    }
+
+    if count == 0 {
+        0.0
+    } else {
+        sum / count as f64
+    }
+}
+
+/// 逐像素的 SSIM 图，与图像同样大小，按 1 - SSIM 从黑（无损失）经红、黄到白（SSIM 不大于 0）着色。
+/// 每个像素取以它为中心的窗口的 SSIM，靠近边缘时窗口不能居中，取最近的完整窗口。
+pub fn ssim_map_image(a: &RgbImage, b: &RgbImage) -> io::Result<RgbImage> {
+    check_dimensions(a, b)?;
+    let (a, b) = (Plane::luma(a), Plane::luma(b));
+    let (ssim, _) = ssim_map(&a, &b);
+    let n = gaussian_window(a.width, a.height).len();
+    let (map_width, map_height) = (a.width + 1 - n, a.height + 1 - n);
+    let radius = n / 2;
+    Ok(RgbImage::from_fn(
+        a.width as u32,
+        a.height as u32,
+        |x, y| {
+            let x = (x as usize).saturating_sub(radius).min(map_width - 1);
+            let y = (y as usize).saturating_sub(radius).min(map_height - 1);
+            heat_color((1.0 - ssim[y * map_width + x]).clamp(0.0, 1.0))
+        },
+    ))
+}
+
+/// 按行存储的浮点平面。
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f64>,
+}
+
+impl Plane {
+    /// BT.601 的亮度。
+    fn luma(image: &RgbImage) -> Self {
+        Self {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            data: image
+                .pixels()
+                .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+                .collect(),
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> f64 {
+        self.data[y * self.width + x]
+    }
+
+    /// 2x2 平均，奇数的最后一行或一列丢弃。
+    fn downsample(&self) -> Self {
+        let width = self.width / 2;
+        let height = self.height / 2;
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let i = 2 * y * self.width + 2 * x;
+                let sum = self.data[i]
+                    + self.data[i + 1]
+                    + self.data[i + self.width]
+                    + self.data[i + self.width + 1];
+                data.push(sum / 4.0);
+            }
+        }
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    fn map2(&self, other: &Self, f: impl Fn(f64, f64) -> f64) -> Self {
+        Self {
+            width: self.width,
+            height: self.height,
+            data: self
+                .data
+                .iter()
+                .zip(&other.data)
+                .map(|(&a, &b)| f(a, b))
+                .collect(),
+        }
+    }
+
+    /// 可分离的窗口滤波，只保留窗口完全落在平面内的位置。
+    fn filter(&self, window: &[f64]) -> Self {
+        let n = window.len();
+        let width = self.width + 1 - n;
+        let height = self.height + 1 - n;
+        let mut rows = Vec::with_capacity(width * self.height);
+        for y in 0..self.height {
+            let row = &self.data[y * self.width..(y + 1) * self.width];
+            rows.extend(
+                row.windows(n)
+                    .map(|v| v.iter().zip(window).map(|(a, b)| a * b).sum::<f64>()),
+            );
+        }
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(
+                    window
+                        .iter()
+                        .enumerate()
+                        .map(|(i, w)| w * rows[(y + i) * width + x])
+                        .sum(),
+                );
+            }
+        }
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+/// 归一化的一维高斯窗口。平面小于 11x11 时缩小窗口。
+fn gaussian_window(width: usize, height: usize) -> Vec<f64> {
+    let radius = WINDOW_RADIUS.min((width.min(height).max(1) - 1) / 2) as i32;
+    let window: Vec<f64> = (-radius..=radius)
+        .map(|i| (-(i * i) as f64 / (2.0 * WINDOW_SIGMA * WINDOW_SIGMA)).exp())
+        .collect();
+    let sum: f64 = window.iter().sum();
+    window.into_iter().map(|w| w / sum).collect()
+}
+
+/// 所有窗口的 SSIM 和对比度结构项（cs）。
+fn ssim_map(a: &Plane, b: &Plane) -> (Vec<f64>, Vec<f64>) {
+    let window = gaussian_window(a.width, a.height);
+    let mu_a = a.filter(&window);
+    let mu_b = b.filter(&window);
+    let aa = a.map2(a, |x, y| x * y).filter(&window);
+    let bb = b.map2(b, |x, y| x * y).filter(&window);
+    let ab = a.map2(b, |x, y| x * y).filter(&window);
+
+    let mut ssim = Vec::with_capacity(mu_a.data.len());
+    let mut cs = Vec::with_capacity(mu_a.data.len());
+    for i in 0..mu_a.data.len() {
+        let (ma, mb) = (mu_a.data[i], mu_b.data[i]);
+        let var_a = aa.data[i] - ma * ma;
+        let var_b = bb.data[i] - mb * mb;
+        let cov = ab.data[i] - ma * mb;
+        let c = (2.0 * cov + C2) / (var_a + var_b + C2);
+        cs.push(c);
+        ssim.push((2.0 * ma * mb + C1) / (ma * ma + mb * mb + C1) * c);
+    }
+    (ssim, cs)
+}
+
+fn ssim_and_cs(a: &Plane, b: &Plane) -> (f64, f64) {
+    let (ssim, cs) = ssim_map(a, b);
+    let mean = |v: &[f64]| v.iter().sum::<f64>() / v.len() as f64;
+    (mean(&ssim), mean(&cs))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::decode_to_image;
+    use super::super::encode_to_vec;
+
+    fn gradient(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 3) as u8, (y * 5) as u8, ((x ^ y) * 2) as u8])
+        })
+    }
+
+    #[test]
+    fn test_identical() {
+        let image = gradient(64, 48);
+        let metrics = Metrics::new(&image, &image).unwrap();
+        assert_eq!(metrics.psnr, f64::INFINITY);
+        assert!((metrics.ssim - 1.0).abs() < 1e-9);
+        assert!((metrics.ms_ssim - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_psnr() {
+        let a = RgbImage::from_pixel(4, 4, image::Rgb([100, 100, 100]));
+        let b = RgbImage::from_pixel(4, 4, image::Rgb([110, 100, 100]));
+        // MSE = 100 / 3。
+        let expected = 10.0 * (255.0_f64 * 255.0 * 3.0 / 100.0).log10();
+        assert!((psnr(&a, &b).unwrap() - expected).abs() < 1e-9);
+        assert!(psnr(&a, &gradient(4, 5)).is_err());
+    }
+
+    #[test]
+    fn test_difference_image() {
+        let a = RgbImage::from_pixel(2, 1, image::Rgb([100, 100, 100]));
+        let mut b = a.clone();
+        b.put_pixel(0, 0, image::Rgb([110, 97, 100]));
+        b.put_pixel(1, 0, image::Rgb([0, 100, 255]));
+        let diff = difference_image(&a, &b, 10).unwrap();
+        assert_eq!(diff.get_pixel(0, 0).0, [100, 30, 0]);
+        assert_eq!(diff.get_pixel(1, 0).0, [255, 0, 255]);
+    }
+
+    #[test]
+    fn test_ordering() {
+        // 编码后有损失，但结构基本保留；反相后结构完全不同。
+        let image = gradient(200, 120);
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        let decoded = decode_to_image(&jpeg, &Default::default()).unwrap();
+        let mut inverted = image.clone();
+        inverted
+            .pixels_mut()
+            .for_each(|p| p.0 = p.0.map(|v| 255 - v));
+
+        let coded = Metrics::new(&image, &decoded).unwrap();
+        let bad = Metrics::new(&image, &inverted).unwrap();
+        assert!(coded.psnr > 25.0);
+        assert!(coded.ssim > 0.8 && coded.ssim < 1.0);
+        assert!(coded.ms_ssim > 0.8 && coded.ms_ssim < 1.0);
+        assert!(bad.ssim < coded.ssim);
+        assert!(bad.ms_ssim < coded.ms_ssim);
+    }
+
+    #[test]
+    fn test_artifacts() {
+        // 平滑的渐变没有块效应；每个 8x8 块取块内平均值后，网格线处的跳变明显。
+        let image = RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 2 + y) as u8; 3]));
+        let blocky = RgbImage::from_fn(64, 64, |x, y| {
+            let (x, y) = (x / 8 * 8 + 4, y / 8 * 8 + 4);
+            image::Rgb([(x * 2 + y) as u8; 3])
+        });
+        assert!((blockiness(&image) - 1.0).abs() < 0.01);
+        assert_eq!(blockiness(&blocky), 1.0);
+        let mut noisy = blocky.clone();
+        noisy
+            .pixels_mut()
+            .enumerate()
+            .for_each(|(i, p)| p.0 = p.0.map(|v| v + (i % 3) as u8));
+        assert!(blockiness(&noisy) > 10.0);
+
+        // 竖直的强边缘。压缩后边缘附近出现波纹。
+        let edge = RgbImage::from_fn(64, 64, |x, _| {
+            image::Rgb([if x < 28 { 40 } else { 220 }; 3])
+        });
+        assert_eq!(ringing(&edge), 0.0);
+        let jpeg = encode_to_vec(&edge, &Default::default()).unwrap();
+        let decoded = decode_to_image(&jpeg, &Default::default()).unwrap();
+        assert!(ringing(&decoded) > 1.0);
+        // 压缩后的渐变在网格线处出现台阶。
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        let decoded = decode_to_image(&jpeg, &Default::default()).unwrap();
+        assert!(blockiness(&decoded) > 2.0);
+    }
+
+    #[test]
+    fn test_ssim_map_image() {
+        let image = gradient(64, 48);
+        let map = ssim_map_image(&image, &image).unwrap();
+        assert_eq!(map.dimensions(), (64, 48));
+        assert!(map.pixels().all(|p| p.0 == [0, 0, 0]));
+
+        // 只有右下角变化，SSIM 图中只有那里变亮。
+        let mut damaged = image.clone();
+        for y in 32..48 {
+            for x in 48..64 {
+                damaged.put_pixel(x, y, image::Rgb([(x * y) as u8; 3]));
+            }
+        }
+        let map = ssim_map_image(&image, &damaged).unwrap();
+        assert_eq!(map.get_pixel(0, 0).0, [0, 0, 0]);
+        assert!(map.get_pixel(56, 40)[0] > 128);
+        assert!(ssim_map_image(&image, &gradient(8, 8)).is_err());
+    }
+
+    #[test]
+    fn test_small_images() {
+        // 小于一个窗口，以及只够一个尺度。
+        for (width, height) in [(1, 1), (5, 3), (30, 30)] {
+            let image = gradient(width, height);
+            let metrics = Metrics::new(&image, &image).unwrap();
+            assert!((metrics.ssim - 1.0).abs() < 1e-9);
+            assert!((metrics.ms_ssim - 1.0).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Motion JPEG：把一系列帧的 JPEG 文件首尾相接（每帧从 SOI 到 EOI）写成一个流，供简单的视频管线使用。
+//! 所有帧使用同样的参数和同样的表，尺寸也必须相同。
+
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use image::RgbImage;
+
+use super::encode_step6::HuffmanMode;
+use super::encode_to_vec;
+use super::options::EncodeOptions;
+
+/// 依次写入帧的 MJPEG 流。
+pub struct MjpegWriter<W: Write> {
+    writer: W,
+    options: EncodeOptions,
+    /// 第一帧的尺寸，之后的帧必须相同。
+    dimensions: Option<(u32, u32)>,
+    frame_count: usize,
+}
+
+impl<W: Write> MjpegWriter<W> {
+    /// 优化的霍夫曼表因帧而异，不能用于 MJPEG。
+    pub fn new(writer: W, options: &EncodeOptions) -> io::Result<Self> {
+        if options.huffman == HuffmanMode::Optimized {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "All frames of an MJPEG stream share the same tables; optimized Huffman tables cannot be used",
+            ));
+        }
+        Ok(Self {
+            writer,
+            options: options.clone(),
+            dimensions: None,
+            frame_count: 0,
+        })
+    }
+
+    /// 编码一帧并写入流的末尾。
+    pub fn write_frame(&mut self, frame: &RgbImage) -> io::Result<()> {
+        let dimensions = *self.dimensions.get_or_insert(frame.dimensions());
+        if frame.dimensions() != dimensions {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Frame {} is {}x{}, but the stream is {}x{}",
+                    self.frame_count,
+                    frame.width(),
+                    frame.height(),
+                    dimensions.0,
+                    dimensions.1
+                ),
+            ));
+        }
+        self.writer
+            .write_all(&encode_to_vec(frame, &self.options)?)?;
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// 刷新并返回底层的 writer。
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// `*` 匹配任意个字符，`?` 匹配一个字符。
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, _) => name.is_empty(),
+        (Some('*'), _) => {
+            wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) => p == n && wildcard_match(&pattern[1..], &name[1..]),
+        (Some(_), None) => false,
+    }
+}
+
+/// 展开帧的列表。含有 `*` 或 `?` 的项在其所在的目录中按文件名匹配（只有文件名部分可以有通配符），
+/// 匹配的文件按名称排序；没有匹配时报错。其余的项原样保留。
+/// 在 shell 不展开通配符的平台上（如 Windows）也可以直接传入 `frames/*.png`。
+pub fn expand_frames(patterns: &[String]) -> io::Result<Vec<PathBuf>> {
+    let mut ret = vec![];
+    for pattern in patterns {
+        let path = Path::new(pattern);
+        let file_name = path.file_name().and_then(|v| v.to_str()).unwrap_or("");
+        if !file_name.contains(['*', '?']) {
+            ret.push(path.to_path_buf());
+            continue;
+        }
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let file_pattern: Vec<char> = file_name.chars().collect();
+        let mut matches = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name: Vec<char> = entry.file_name().to_string_lossy().chars().collect();
+            if entry.file_type()?.is_file() && wildcard_match(&file_pattern, &name) {
+                matches.push(dir.join(entry.file_name()));
+            }
+        }
+        if matches.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No file matches {}", pattern),
+            ));
+        }
+        matches.sort();
+        ret.extend(matches);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::decode_all_to_images;
+    use super::super::decode_to_image;
+
+    fn frame(i: u32) -> RgbImage {
+        RgbImage::from_fn(24, 16, |x, y| {
+            image::Rgb([(x * 10 + i) as u8, (y * 15) as u8, 90])
+        })
+    }
+
+    #[test]
+    fn test_mjpeg_writer() {
+        let options = EncodeOptions::default();
+        let mut writer = MjpegWriter::new(vec![], &options).unwrap();
+        let mut expected = vec![];
+        for i in 0..3 {
+            writer.write_frame(&frame(i)).unwrap();
+            expected.extend(encode_to_vec(&frame(i), &options).unwrap());
+        }
+        assert!(writer.write_frame(&RgbImage::new(8, 8)).is_err());
+        assert_eq!(writer.frame_count(), 3);
+        let stream = writer.finish().unwrap();
+        assert_eq!(stream, expected);
+        // 扫描数据中的 0xFF 后总是 0x00，因此 SOI 只出现在每帧的开头。
+        let soi_count = stream.windows(2).filter(|w| w == &[0xFF, 0xD8]).count();
+        assert_eq!(soi_count, 3);
+
+        // 最后一个 EOI 之后的数据忽略。
+        let mut stream = stream;
+        stream.extend([0x00, 0x12, 0xFF]);
+        let frames = decode_all_to_images(&stream, &Default::default()).unwrap();
+        assert_eq!(frames.len(), 3);
+        for (i, decoded) in frames.iter().enumerate() {
+            let jpeg = encode_to_vec(&frame(i as u32), &options).unwrap();
+            assert!(*decoded == decode_to_image(&jpeg, &Default::default()).unwrap());
+        }
+
+        let options = EncodeOptions {
+            huffman: HuffmanMode::Optimized,
+            ..Default::default()
+        };
+        assert!(MjpegWriter::new(vec![], &options).is_err());
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        let matches = |pattern: &str, name: &str| {
+            wildcard_match(
+                &pattern.chars().collect::<Vec<_>>(),
+                &name.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(matches("*.png", "frame_001.png"));
+        assert!(matches("frame_??1.png", "frame_001.png"));
+        assert!(matches("*", ""));
+        assert!(!matches("*.png", "frame.bmp"));
+        assert!(!matches("frame_?.png", "frame_10.png"));
+    }
+}
@@ -1,7 +1,23 @@
+pub mod analysis;
+pub mod arena;
+pub mod arithmetic;
+pub mod bd_rate;
+pub mod bit_reader;
+pub mod bit_trace;
+pub mod coefficients;
+pub mod component;
+pub mod convert;
+pub mod cross_check;
+pub mod decode16;
+pub mod decode_progressive;
 pub mod decode_step1;
 pub mod decode_step2;
 pub mod decode_step3;
 pub mod decode_step4;
+pub mod diff;
+#[cfg(feature = "differential")]
+pub mod differential;
+pub mod dump;
 pub mod encode_step1;
 pub mod encode_step2;
 pub mod encode_step3;
@@ -9,62 +25,415 @@ pub mod encode_step4;
 pub mod encode_step5;
 pub mod encode_step6;
 pub mod encode_step7;
+pub mod extract;
+pub mod frames;
+pub mod grid;
+pub mod hvs;
+pub mod info;
+pub mod int_dct;
+pub mod metadata;
+pub mod metrics;
+pub mod mjpeg;
+pub mod options;
+pub mod rate;
+pub mod report;
+pub mod segments;
+pub mod service;
+pub mod sharpen;
+pub mod smooth;
+pub mod stego;
+pub mod stripe;
+pub mod tables;
+#[cfg(test)]
+mod test_util;
+pub mod thumbnail;
+pub mod transcode;
+pub mod transform;
+pub mod verify;
+pub mod zigzag;
 
 use std::io;
+use std::path::Path;
 
+use image::DynamicImage;
 use image::RgbImage;
 
+use arena::ScratchArena;
+use bit_trace::format_trace;
+use bit_trace::trace_decode;
+use bit_trace::trace_encode;
+use decode16::Rgb16Image;
 use decode_step1::decode_step1;
-use decode_step2::decode_step2;
+use decode_step1::decode_step1_all;
+use decode_step1::decode_step1_with;
+use decode_step1::CompleteJpegData;
 use decode_step3::decode_step3;
 use decode_step4::decode_step4;
+use decode_step4::save_bmp;
+use decode_step4::save_bmp_to;
+use decode_step4::sharpen_output;
+use decode_step4::to_rgb_image;
+use dump::StepDumper;
 use encode_step1::encode_step1;
+use encode_step1::encode_step1_12;
 use encode_step1::show_step1;
+use encode_step1::SamplePrecision;
 use encode_step2::encode_step2;
 use encode_step2::show_step2;
 use encode_step3::encode_step3;
-use encode_step3::show_step3;
+use encode_step3::DctMcuCollection;
 use encode_step4::encode_step4;
-use encode_step4::show_step4;
 use encode_step5::encode_step5;
-use encode_step5::show_step5;
 use encode_step6::encode_step6;
+use encode_step6::JpegOutputData;
 use encode_step7::encode_step7;
+use encode_step7::make_jpeg;
+use rate::search_quality;
+use report::render_report;
+use smooth::smooth_input;
+use stripe::decode_striped;
+use stripe::decode_striped_with;
+use stripe::encode_striped;
+use thumbnail::make_jfif_thumbnail;
+use verify::verify_jpeg;
 
-pub fn encode(image: &RgbImage) -> io::Result<()> {
-    // 第一步：输入 RGB 的图像，输出 YUV422 的图像。
-    let yuv_image = encode_step1(image)?;
+pub use extract::extract_metadata;
+pub use info::read_info;
+pub use options::DecodeOptions;
+pub use options::EncodeOptions;
+pub use options::TranscodeOptions;
+pub use options::TransformOptions;
+pub use stripe::RowEncoder;
+pub use thumbnail::extract_thumbnail;
+pub use transcode::transcode;
+pub use transform::transform;
+
+pub fn encode(image: &RgbImage, options: &EncodeOptions) -> io::Result<()> {
+    if options.sample_precision == SamplePrecision::Twelve {
+        return encode16(&DynamicImage::ImageRgb8(image.clone()).to_rgb16(), options);
+    }
+    // 按条带编码时不输出中间结果。
+    if options.striped {
+        let jpeg_output_data = with_thumbnail(encode_striped(image, options)?, image, options);
+        encode_step7(&jpeg_output_data, options)?;
+        // 报告需要整幅图像，此时才平滑整幅图像。
+        if options.report.is_some() {
+            save_report(&smooth_input(image, options), &jpeg_output_data, options)?;
+        }
+        return Ok(());
+    }
+    let original = image;
+    let image = smooth_input(image, options);
+    let image = image.as_ref();
+
+    let mut arena = ScratchArena::default();
+    let mut dumper = options
+        .dump_steps_dir
+        .as_deref()
+        .map(StepDumper::new)
+        .transpose()?;
+
+    // 第一步：输入 RGB 的图像，输出子采样的 YUV 图像。
+    let yuv_image = encode_step1(image, &options.color_conversion, options.subsampling)?;
     show_step1(&yuv_image);
+    if let Some(dumper) = &mut dumper {
+        dumper.yuv_image(&yuv_image)?;
+    }
 
-    // 第二步：输入 YUV422 图像，输出所有 MCU。
-    let mcu_collection = encode_step2(&yuv_image)?;
+    // 第二步：输入 YUV 图像，输出所有 MCU。
+    let mcu_collection = encode_step2(&yuv_image, &mut arena)?;
     show_step2(&mcu_collection);
+    if let Some(dumper) = &mut dumper {
+        dumper.dus("step2", &mcu_collection.dus)?;
+    }
 
     // 第三步：离散余弦变换。
-    let dct_mcu_collection = encode_step3(&mcu_collection)?;
-    show_step3(&dct_mcu_collection);
+    let dct_mcu_collection = encode_step3(&mcu_collection, options.dct_precision, &mut arena)?;
+    if let Some(dumper) = &mut dumper {
+        dumper.dus("step3", &dct_mcu_collection.dct_dus)?;
+    }
+    let options = &apply_target_size(&dct_mcu_collection, options)?;
+    if let (Some(target_size), Some(quality)) = (options.target_size, options.quality) {
+        println!(
+            "[INFO] 目标大小为 {} 字节，使用质量 {}",
+            target_size, quality
+        );
+    }
 
     // 第四步：量化。
-    let quantized_mcu_collection = encode_step4(&dct_mcu_collection)?;
-    show_step4(&quantized_mcu_collection);
+    let quantized_mcu_collection = encode_step4(
+        &dct_mcu_collection,
+        &options.quantization_tables(),
+        &mut arena,
+    )?;
+    if let Some(dumper) = &mut dumper {
+        dumper.dus("step4", &quantized_mcu_collection.quantized_dus)?;
+    }
 
     // 第五步：Zigzag。
-    let zigzag_mcu_collection = encode_step5(&quantized_mcu_collection)?;
-    show_step5(&zigzag_mcu_collection);
+    let zigzag_mcu_collection = encode_step5(&quantized_mcu_collection, &mut arena)?;
+    if let Some(mut dumper) = dumper {
+        dumper.dus("step5", &zigzag_mcu_collection.zigzag_dus)?;
+        dumper.finish()?;
+    }
 
     // 第六步：编码。
-    let jpeg_output_data = encode_step6(&zigzag_mcu_collection)?;
+    let jpeg_output_data = with_thumbnail(
+        encode_step6(&zigzag_mcu_collection, options)?,
+        original,
+        options,
+    );
+    if let Some(path) = &options.trace_bits {
+        let trace = trace_encode(
+            &zigzag_mcu_collection.zigzag_dus,
+            &jpeg_output_data.huffman_tables,
+        );
+        std::fs::write(path, format_trace(&trace))?;
+        println!(
+            "[INFO] 编码的 {} 个符号写入 {}",
+            trace.len(),
+            path.display()
+        );
+    }
 
     // 第七步：输出 JPEG 文件。
-    encode_step7(&jpeg_output_data)
+    encode_step7(&jpeg_output_data, options)?;
+    save_report(image, &jpeg_output_data, options)
+}
+
+/// 设置了目标大小时，查找质量代替 `options.quality`，见 `rate::search_quality`。
+fn apply_target_size(
+    dct_mcu_collection: &DctMcuCollection,
+    options: &EncodeOptions,
+) -> io::Result<EncodeOptions> {
+    let mut options = options.clone();
+    if let Some(target_size) = options.target_size {
+        let (quality, _) = search_quality(dct_mcu_collection, &options, target_size)?;
+        options.quality = Some(quality);
+    }
+    Ok(options)
+}
+
+/// 要求时加上 JFIF 缩略图，由未平滑的输入生成。
+fn with_thumbnail(
+    data: JpegOutputData,
+    image: &RgbImage,
+    options: &EncodeOptions,
+) -> JpegOutputData {
+    JpegOutputData {
+        jfif_thumbnail: options
+            .jfif_thumbnail
+            .map(|size| make_jfif_thumbnail(image, size)),
+        ..data
+    }
+}
+
+/// 要求时写出 HTML 报告。
+fn save_report(image: &RgbImage, data: &JpegOutputData, options: &EncodeOptions) -> io::Result<()> {
+    if let Some(path) = &options.report {
+        std::fs::write(path, render_report(image, data, options)?)?;
+        println!("[INFO] 报告写入 {}", path.display());
+    }
+    Ok(())
+}
+
+/// 与 `encode` 相同，但不输出中间结果，直接返回 JPEG 文件的内容。
+pub fn encode_to_vec(image: &RgbImage, options: &EncodeOptions) -> io::Result<Vec<u8>> {
+    if options.sample_precision == SamplePrecision::Twelve {
+        return encode16_to_vec(&DynamicImage::ImageRgb8(image.clone()).to_rgb16(), options);
+    }
+    let jpeg_output_data = if options.striped {
+        with_thumbnail(encode_striped(image, options)?, image, options)
+    } else {
+        let mut arena = ScratchArena::default();
+        let yuv_image = encode_step1(
+            &smooth_input(image, options),
+            &options.color_conversion,
+            options.subsampling,
+        )?;
+        let mcu_collection = encode_step2(&yuv_image, &mut arena)?;
+        let dct_mcu_collection = encode_step3(&mcu_collection, options.dct_precision, &mut arena)?;
+        let options = &apply_target_size(&dct_mcu_collection, options)?;
+        let quantized_mcu_collection = encode_step4(
+            &dct_mcu_collection,
+            &options.quantization_tables(),
+            &mut arena,
+        )?;
+        let zigzag_mcu_collection = encode_step5(&quantized_mcu_collection, &mut arena)?;
+        with_thumbnail(
+            encode_step6(&zigzag_mcu_collection, options)?,
+            image,
+            options,
+        )
+    };
+    let jpeg = make_jpeg(&jpeg_output_data, options);
+    if options.verify {
+        verify_jpeg(&jpeg, &jpeg_output_data, &options.frame_layout())?;
+    }
+    Ok(jpeg)
+}
+
+/// 与 `encode` 相同，但输入 16 位的图像。`options.sample_precision` 为 8 位时先转换为 8 位的图像。
+pub fn encode16(image: &Rgb16Image, options: &EncodeOptions) -> io::Result<()> {
+    if options.sample_precision == SamplePrecision::Eight {
+        return encode(&DynamicImage::ImageRgb16(image.clone()).to_rgb8(), options);
+    }
+    let (jpeg_output_data, options) = encode_twelve_bit(image, options)?;
+    encode_step7(&jpeg_output_data, &options)
+}
+
+/// 与 `encode_to_vec` 相同，但输入 16 位的图像，见 `encode16`。
+pub fn encode16_to_vec(image: &Rgb16Image, options: &EncodeOptions) -> io::Result<Vec<u8>> {
+    if options.sample_precision == SamplePrecision::Eight {
+        return encode_to_vec(&DynamicImage::ImageRgb16(image.clone()).to_rgb8(), options);
+    }
+    let (jpeg_output_data, options) = encode_twelve_bit(image, options)?;
+    Ok(make_jpeg(&jpeg_output_data, &options))
 }
 
-pub fn decode(buf: &[u8]) -> io::Result<()> {
-    let complete_jpeg_data = decode_step1(buf)?;
+/// 以 12 位的样本完成第一步到第六步，同时返回按目标大小调整后的参数。
+/// 自己的解码器只支持 8 位，因此不能检查输出，也不支持需要解码或只实现了 8 位的选项。
+fn encode_twelve_bit(
+    image: &Rgb16Image,
+    options: &EncodeOptions,
+) -> io::Result<(JpegOutputData, EncodeOptions)> {
+    let unsupported = [
+        (options.striped, "striped encoding"),
+        (options.smoothing.is_some(), "smoothing"),
+        (options.verify, "verification"),
+        (options.huffman_stats, "Huffman statistics"),
+        (options.report.is_some(), "reports"),
+        (options.dump_steps_dir.is_some(), "step dumps"),
+        (options.trace_bits.is_some(), "bit traces"),
+    ];
+    if let Some((_, name)) = unsupported.iter().find(|(set, _)| *set) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("12-bit samples cannot be used with {}", name),
+        ));
+    }
 
-    let zigzag_mcu_collection = decode_step2(&complete_jpeg_data)?;
+    let mut arena = ScratchArena::default();
+    let yuv_image = encode_step1_12(image, &options.color_conversion, options.subsampling)?;
+    let mcu_collection = encode_step2(&yuv_image, &mut arena)?;
+    let dct_mcu_collection = encode_step3(&mcu_collection, options.dct_precision, &mut arena)?;
+    let options = apply_target_size(&dct_mcu_collection, options)?;
+    let quantized_mcu_collection = encode_step4(
+        &dct_mcu_collection,
+        &options.quantization_tables(),
+        &mut arena,
+    )?;
+    let zigzag_mcu_collection = encode_step5(&quantized_mcu_collection, &mut arena)?;
+    let mut jpeg_output_data = encode_step6(&zigzag_mcu_collection, &options)?;
+    // 缩略图总是 8 位的，只在需要时转换。
+    if options.jfif_thumbnail.is_some() {
+        let image = DynamicImage::ImageRgb16(image.clone()).to_rgb8();
+        jpeg_output_data = with_thumbnail(jpeg_output_data, &image, &options);
+    }
+    Ok((jpeg_output_data, options))
+}
+
+/// 解码并写入 out.bmp，多幅图像时写入 out_0000.bmp 等文件。返回图像的个数。
+pub fn decode(buf: &[u8], options: &DecodeOptions) -> io::Result<usize> {
+    let mut frames = decode_step1_all(buf)?;
+    if let Some(path) = &options.trace_bits {
+        let mut text = String::new();
+        let mut count = 0;
+        for (i, jpeg_data) in frames.iter().enumerate() {
+            let trace = trace_decode(jpeg_data)?;
+            if frames.len() > 1 {
+                text += &format!("frame {}\n", i);
+            }
+            text += &format_trace(&trace);
+            count += trace.len();
+        }
+        std::fs::write(path, text)?;
+        println!("[INFO] 解码的 {} 个符号写入 {}", count, path.display());
+    }
+    // 多幅图像（如 MJPEG）依次写入 out_0000.bmp、out_0001.bmp……
+    if frames.len() > 1 {
+        for (i, jpeg_data) in frames.iter().enumerate() {
+            let path = format!("out_{:04}.bmp", i);
+            save_bmp_to(&to_image(jpeg_data, options)?, Path::new(&path))?;
+        }
+        println!(
+            "[INFO] 共 {} 幅图像，写入 out_0000.bmp 等文件",
+            frames.len()
+        );
+        return Ok(frames.len());
+    }
+
+    let complete_jpeg_data = frames.remove(0);
+    if options.striped {
+        save_bmp(&to_image(&complete_jpeg_data, options)?)?;
+        return Ok(1);
+    }
+
+    let decoded_yuv_image = decode_step3(&complete_jpeg_data, options.dct_precision)?;
+
+    decode_step4(&decoded_yuv_image, options)?;
+    Ok(1)
+}
+
+/// 第二步到第四步，返回 RGB 图像。
+fn to_image(jpeg_data: &CompleteJpegData, options: &DecodeOptions) -> io::Result<RgbImage> {
+    let image = if options.striped {
+        decode_striped(jpeg_data, options)?
+    } else {
+        let decoded_yuv_image = decode_step3(jpeg_data, options.dct_precision)?;
+        to_rgb_image(&decoded_yuv_image, &options.color_conversion)
+    };
+    Ok(sharpen_output(image, options))
+}
 
-    let decoded_yuv_image = decode_step3(&zigzag_mcu_collection)?;
+/// 与 `decode` 相同，但不输出 BMP 文件，直接返回 RGB 图像。有多幅图像时只解码第一幅。
+pub fn decode_to_image(buf: &[u8], options: &DecodeOptions) -> io::Result<RgbImage> {
+    to_image(&decode_step1(buf)?, options)
+}
+
+/// 逐行 MCU 解码，每重建一行 MCU 就调用一次 `on_rows`，供查看器在解码大文件时逐步显示。
+/// `on_rows` 的参数为目前的图像（尚未解码的行为黑色）和刚完成的像素行，此时尚未锐化。
+/// 渐进式编码或有多个扫描的文件改为每解码一个扫描调用一次，参数为由已解码的扫描重建的整幅图像和所有的行，
+/// 图像逐次细化，最后一次即最终的结果。返回的图像与 `decode_to_image` 相同。
+pub fn decode_incremental(
+    buf: &[u8],
+    options: &DecodeOptions,
+    mut on_rows: impl FnMut(&RgbImage, std::ops::Range<u32>),
+) -> io::Result<RgbImage> {
+    let mut refined = None;
+    let jpeg_data = decode_step1_with(buf, |partial| {
+        let image = decode_striped(partial, options)?;
+        on_rows(&image, 0..image.height());
+        refined = Some(image);
+        Ok(())
+    })?;
+    let image = match refined {
+        Some(image) => image,
+        None => decode_striped_with(&jpeg_data, options, on_rows)?,
+    };
+    Ok(sharpen_output(image, options))
+}
+
+/// 解码首尾相接的每一幅图像，例如 MJPEG 流。
+pub fn decode_all_to_images(buf: &[u8], options: &DecodeOptions) -> io::Result<Vec<RgbImage>> {
+    decode_step1_all(buf)?
+        .iter()
+        .map(|jpeg_data| to_image(jpeg_data, options))
+        .collect()
+}
+
+/// `encode_to_vec` 的异步版本。编码是 CPU 密集的，因此放到 tokio 的阻塞线程池中运行，不阻塞异步执行器。
+#[cfg(feature = "async")]
+pub async fn encode_async(image: RgbImage, options: EncodeOptions) -> io::Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || encode_to_vec(&image, &options))
+        .await
+        .map_err(io::Error::other)?
+}
 
-    decode_step4(&decoded_yuv_image)
+/// `decode_to_image` 的异步版本。解码是 CPU 密集的，因此放到 tokio 的阻塞线程池中运行，不阻塞异步执行器。
+#[cfg(feature = "async")]
+pub async fn decode_async(buf: Vec<u8>, options: DecodeOptions) -> io::Result<RgbImage> {
+    tokio::task::spawn_blocking(move || decode_to_image(&buf, &options))
+        .await
+        .map_err(io::Error::other)?
 }
@@ -0,0 +1,147 @@
+//! 编码和解码的可选参数。默认值与最初的实现一致。
+
+use std::path::PathBuf;
+
+use super::encode_step1::ColorConversion;
+use super::encode_step1::SamplePrecision;
+use super::encode_step1::Subsampling;
+use super::encode_step3::DctPrecision;
+use super::encode_step4::QuantizationPreset;
+use super::encode_step4::QuantizationTable;
+use super::encode_step6::EntropyCoding;
+use super::encode_step6::HuffmanMode;
+use super::encode_step7::Density;
+use super::encode_step7::DhtLayout;
+use super::encode_step7::DqtLayout;
+use super::encode_step7::DqtPrecision;
+use super::encode_step7::FrameLayout;
+use super::hvs::ViewingConditions;
+use super::metadata::ExifData;
+use super::metadata::IccProfile;
+use super::metadata::XmpPacket;
+use super::sharpen::Sharpening;
+use super::transcode::Scale;
+use super::transform::Operation;
+
+/// 编码参数。
+#[derive(Debug, Clone, Default)]
+pub struct EncodeOptions {
+    /// RGB 转换为 YCbCr 的参数。
+    pub color_conversion: ColorConversion,
+    /// 色度的子采样方式。
+    pub subsampling: Subsampling,
+    /// 样本的位数。12 位时写入 SOF1，总是使用优化的霍夫曼表；不能按条带或渐进式编码，也不能检查输出。
+    pub sample_precision: SamplePrecision,
+    /// 编码前平滑输入的系数，范围是 1 到 100，见 `smooth::smooth_rows`。为 `None` 时不平滑。
+    pub smoothing: Option<u8>,
+    /// DCT 的计算精度。
+    pub dct_precision: DctPrecision,
+    /// 量化表的预设。
+    pub quantization: QuantizationPreset,
+    /// 观看条件，只用于 `QuantizationPreset::Perceptual`。
+    pub viewing: ViewingConditions,
+    /// 按 IJG 的做法缩放预设量化表的质量，范围是 1 到 100，见 `QuantizationTable::scaled`。为 `None` 时不缩放。
+    pub quality: Option<u8>,
+    /// 目标文件大小（字节）。设置时对质量二分查找并代替 `quality`，见 `rate::search_quality`。不能按条带编码。
+    pub target_size: Option<usize>,
+    /// 是否按条带编码，每次只处理一行 MCU，使内存占用与图像高度无关。输出与不分条带时相同。
+    pub striped: bool,
+    /// 熵编码使用的霍夫曼表。优化时输出与默认的表相比节省的位数。不能按条带编码。
+    pub huffman: HuffmanMode,
+    /// 熵编码的方式。算术编码不能与优化的霍夫曼表或渐进式编码同时使用。
+    pub entropy_coding: EntropyCoding,
+    /// 是否渐进式编码（SOF2），见 `encode_step6::PROGRESSIVE_SCRIPT`。不能按条带编码。
+    pub progressive: bool,
+    /// 是否每个分量一个扫描（非交织），依次为 Y、Cb、Cr。不能与渐进式编码或算术编码同时使用，不能按条带编码。
+    pub non_interleaved: bool,
+    /// 每隔多少个 MCU 插入一个 RSTn，同时写入 DRI 段。为 `None` 时不插入。
+    pub restart_interval: Option<u16>,
+    /// 编码后是否用自己的解码器重新解析输出并检查。
+    pub verify: bool,
+    /// 编码后是否输出每个霍夫曼表的统计，见 `analysis::print_huffman_statistics`。
+    pub huffman_stats: bool,
+    /// 编码后把每个 MCU 占用的位数写入该目录，见 `analysis::save_bit_cost`。
+    pub bit_cost_dir: Option<PathBuf>,
+    /// 把第一步到第五步的完整结果写入该目录，见 `dump::StepDumper`。按条带编码时不写出。
+    pub dump_steps_dir: Option<PathBuf>,
+    /// 把第六步编码的每个符号写入该文件，见 `bit_trace::trace_encode`。按条带编码时不写出。
+    pub trace_bits: Option<PathBuf>,
+    /// 编码后把 HTML 报告写入该文件，见 `report::render_report`。
+    pub report: Option<PathBuf>,
+    /// 量化表的组织方式。
+    pub dqt_layout: DqtLayout,
+    /// 量化表的精度。
+    pub dqt_precision: DqtPrecision,
+    /// 霍夫曼表的组织方式。
+    pub dht_layout: DhtLayout,
+    /// 写入 APP1 的 EXIF。
+    pub exif: Option<ExifData>,
+    /// 写入 APP1 的 XMP。
+    pub xmp: Option<XmpPacket>,
+    /// 写入 APP0 的像素密度。
+    pub density: Density,
+    /// 是否在 APP0 之后写入 Adobe 的 APP14，标明分量为 YCbCr。
+    pub adobe_app14: bool,
+    /// 写入 APP0 的 JFIF 缩略图的长边，见 `thumbnail::make_jfif_thumbnail`。为 `None` 时没有缩略图。
+    pub jfif_thumbnail: Option<u32>,
+    /// 写入 APP2 的 ICC 配置文件。
+    pub icc_profile: Option<IccProfile>,
+}
+
+impl EncodeOptions {
+    /// 编码使用的亮度和色度量化表。
+    pub fn quantization_tables(&self) -> [QuantizationTable; 2] {
+        let tables = self.quantization.tables(&self.viewing);
+        match self.quality {
+            Some(quality) => tables.map(|table| table.scaled(quality)),
+            None => tables,
+        }
+    }
+
+    /// 编码使用的采样因子和量化表，写入 SOF0 和 DQT。
+    pub fn frame_layout(&self) -> FrameLayout {
+        FrameLayout {
+            sampling_factors: self.subsampling.sampling_factors(),
+            quantization_tables: self.quantization_tables(),
+        }
+    }
+}
+
+/// 解码参数。
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    /// YCbCr 转换为 RGB 的参数。应当与编码时一致。
+    pub color_conversion: ColorConversion,
+    /// IDCT 的计算精度。
+    pub dct_precision: DctPrecision,
+    /// 是否按条带解码，每次只重建一行 MCU，不保存整幅图像的系数。输出与不分条带时相同。
+    pub striped: bool,
+    /// 把熵解码的每个符号写入该文件，见 `bit_trace::trace_decode`。
+    pub trace_bits: Option<PathBuf>,
+    /// 转换为 RGB 后锐化，见 `sharpen::unsharp_mask`。为 `None` 时不锐化。
+    pub sharpening: Option<Sharpening>,
+}
+
+/// 无损变换参数。
+#[derive(Debug, Clone)]
+pub struct TransformOptions {
+    /// 旋转或翻转。
+    pub operation: Operation,
+    /// 被翻转的方向上不足一个 MCU 时，是否丢弃这部分。不丢弃时无法无损旋转，返回错误。
+    pub trim: bool,
+    /// 是否丢弃 APPn 和 COM 段。默认原样保留。
+    pub strip: bool,
+}
+
+/// 转码参数。
+#[derive(Debug, Clone, Default)]
+pub struct TranscodeOptions {
+    /// 重新量化的质量，范围是 1 到 100，见 `QuantizationTable::scaled`。为 `None` 时保留原来的量化表。
+    pub quality: Option<u8>,
+    /// 缩小的比例。先缩小，再重新量化。
+    pub scale: Scale,
+    /// 是否丢弃 APPn 和 COM 段。默认原样保留。
+    pub strip: bool,
+    /// 重新熵编码使用的霍夫曼表。只优化霍夫曼表时，转码是无损的。
+    pub huffman: HuffmanMode,
+}
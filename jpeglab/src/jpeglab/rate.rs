@@ -0,0 +1,123 @@
+//! 按目标文件大小编码（码率控制）。
+//!
+//! 文件大小随质量单调（近似）增大，因此对质量二分查找，找出文件不超过目标大小的最高质量。
+//! 第一步到第三步与质量无关，只计算一次；每次尝试只重新量化、Zigzag 和熵编码。
+
+use std::io;
+
+use super::arena::ScratchArena;
+use super::encode_step3::DctMcuCollection;
+use super::encode_step4::encode_step4;
+use super::encode_step5::encode_step5;
+use super::encode_step6::encode_step6;
+use super::encode_step7::make_jpeg;
+use super::options::EncodeOptions;
+
+/// 文件比目标小不超过这个比例时，不再继续查找。
+const TOLERANCE: f64 = 0.02;
+
+/// 按 `options` 重新量化和熵编码后的文件大小。
+fn encoded_size(
+    dct_mcu_collection: &DctMcuCollection,
+    options: &EncodeOptions,
+    arena: &mut ScratchArena,
+) -> io::Result<usize> {
+    let quantized_mcu_collection =
+        encode_step4(dct_mcu_collection, &options.quantization_tables(), arena)?;
+    let zigzag_mcu_collection = encode_step5(&quantized_mcu_collection, arena)?;
+    quantized_mcu_collection
+        .quantized_dus
+        .recycle(&mut arena.quantized_du);
+    let jpeg_output_data = encode_step6(&zigzag_mcu_collection, options)?;
+    zigzag_mcu_collection
+        .zigzag_dus
+        .recycle(&mut arena.zigzag_du);
+    Ok(make_jpeg(&jpeg_output_data, options).len())
+}
+
+/// 查找文件不超过 `target_size` 字节的最高质量，返回质量和此时的文件大小。
+/// 大小包括标记段和元数据。质量为 1 时仍然超过目标大小时返回错误。
+pub fn search_quality(
+    dct_mcu_collection: &DctMcuCollection,
+    options: &EncodeOptions,
+    target_size: usize,
+) -> io::Result<(u8, usize)> {
+    let mut arena = ScratchArena::default();
+    let mut size_at = |quality| {
+        let options = EncodeOptions {
+            quality: Some(quality),
+            ..options.clone()
+        };
+        encoded_size(dct_mcu_collection, &options, &mut arena)
+    };
+
+    // 不变式：质量 `low` 时不超过目标大小，质量 `high` 时超过。
+    let mut best = (1, size_at(1)?);
+    if best.1 > target_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "The target size of {} bytes cannot be reached, the file has {} bytes even at quality 1",
+                target_size, best.1
+            ),
+        ));
+    }
+    let (mut low, mut high) = (1_u8, 101_u8);
+    while high - low > 1 {
+        if best.1 as f64 >= target_size as f64 * (1.0 - TOLERANCE) {
+            break;
+        }
+        let quality = low + (high - low) / 2;
+        let size = size_at(quality)?;
+        if size <= target_size {
+            low = quality;
+            best = (quality, size);
+        } else {
+            high = quality;
+        }
+    }
+    Ok(best)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::encode_to_vec;
+    use super::super::test_util::test_image_of_size;
+
+    #[test]
+    fn test_target_size() {
+        let image = test_image_of_size(64, 48);
+        let size_at = |quality| {
+            let options = EncodeOptions {
+                quality: Some(quality),
+                ..Default::default()
+            };
+            encode_to_vec(&image, &options).unwrap().len()
+        };
+        let target_size = (size_at(40) + size_at(90)) / 2;
+
+        let options = EncodeOptions {
+            target_size: Some(target_size),
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&image, &options).unwrap();
+        // 不超过目标大小，且不比质量 40 时小。
+        assert!(jpeg.len() <= target_size);
+        assert!(jpeg.len() >= size_at(40));
+
+        // 目标太小时无法达到。
+        let options = EncodeOptions {
+            target_size: Some(100),
+            ..Default::default()
+        };
+        assert!(encode_to_vec(&image, &options).is_err());
+        let options = EncodeOptions {
+            target_size: Some(target_size),
+            striped: true,
+            ..Default::default()
+        };
+        assert!(encode_to_vec(&image, &options).is_err());
+    }
+}
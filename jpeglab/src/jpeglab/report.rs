@@ -0,0 +1,230 @@
+//! 编码过程的 HTML 报告：输入图像、第一步的 Y、U、V 平面、各分量系数的热力图、位密度图、
+//! 所用的量化表和霍夫曼表，以及最终的大小和质量。图像以 PNG 的 data URI 内嵌，报告是一个独立的文件，
+//! 可以直接放进实验报告。
+
+use std::io;
+use std::io::Cursor;
+
+use image::GrayImage;
+use image::ImageFormat;
+use image::RgbImage;
+
+use super::analysis::average_magnitudes;
+use super::analysis::entropy_report;
+use super::analysis::render_bit_density;
+use super::analysis::render_heatmap;
+use super::analysis::COMPONENT_NAMES;
+use super::decode_to_image;
+use super::encode_step1::encode_step1;
+use super::encode_step1::PlaneLayout;
+use super::encode_step6::JpegOutputData;
+use super::encode_step7::make_jpeg;
+use super::metrics::Metrics;
+use super::options::DecodeOptions;
+use super::options::EncodeOptions;
+use super::tables::read_tables;
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; }
+figure { display: inline-block; margin: 0 1em 1em 0; vertical-align: top; }
+img { image-rendering: pixelated; max-width: 100%; border: 1px solid #ccc; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+td, th { border: 1px solid #ccc; padding: 2px 6px; text-align: right; }";
+
+/// 标准的 Base64，有填充。
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut ret = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0_u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                ret.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+    ret
+}
+
+/// 编码为 PNG 并写成带标题的 `<figure>`。
+fn figure(image: impl Into<image::DynamicImage>, caption: &str) -> io::Result<String> {
+    let mut png = vec![];
+    image
+        .into()
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(io::Error::other)?;
+    Ok(format!(
+        "<figure><img src=\"data:image/png;base64,{}\" alt=\"{caption}\"><figcaption>{caption}</figcaption></figure>\n",
+        base64(&png)
+    ))
+}
+
+fn plane_image(plane: &[u8], layout: PlaneLayout) -> GrayImage {
+    GrayImage::from_fn(layout.width as u32, layout.height as u32, |x, y| {
+        image::Luma([plane[layout.index(x as usize, y as usize)]])
+    })
+}
+
+fn html_table(header: &[String], rows: &[Vec<String>]) -> String {
+    let row = |cells: &[String], tag: &str| {
+        let cells: String = cells
+            .iter()
+            .map(|cell| format!("<{tag}>{cell}</{tag}>"))
+            .collect();
+        format!("<tr>{}</tr>\n", cells)
+    };
+    let rows: String = rows.iter().map(|cells| row(cells, "td")).collect();
+    format!("<table>\n{}{}</table>\n", row(header, "th"), rows)
+}
+
+/// 生成报告。`data` 为第六步的结果，`image` 为编码的原图，其余的中间结果由这两者重新计算。
+pub fn render_report(
+    image: &RgbImage,
+    data: &JpegOutputData,
+    options: &EncodeOptions,
+) -> io::Result<String> {
+    let jpeg = make_jpeg(data, options);
+    let mut body = String::new();
+
+    body += "<h2>输入图像</h2>\n";
+    body += &figure(
+        image.clone(),
+        &format!("{}x{}", image.width(), image.height()),
+    )?;
+
+    body += "<h2>第一步：YUV 平面（填充后）</h2>\n";
+    let yuv_image = encode_step1(image, &options.color_conversion, options.subsampling)?;
+    body += &figure(plane_image(&yuv_image.y, yuv_image.y_layout()), "Y")?;
+    body += &figure(plane_image(&yuv_image.u, yuv_image.chroma_layout()), "U")?;
+    body += &figure(plane_image(&yuv_image.v, yuv_image.chroma_layout()), "V")?;
+
+    body += "<h2>系数热力图</h2>\n<p>反量化后各频率系数绝对值的平均值，左上角为 DC，按对数从黑到白。</p>\n";
+    for (magnitudes, name) in average_magnitudes(&jpeg)?.iter().zip(COMPONENT_NAMES) {
+        body += &figure(render_heatmap(magnitudes), name)?;
+    }
+
+    body += "<h2>位密度</h2>\n<p>每个 MCU 占用的位数，越亮越多。</p>\n";
+    body += &figure(render_bit_density(data, options.subsampling), "bit density")?;
+
+    body += "<h2>量化表</h2>\n";
+    let tables = read_tables(&jpeg)?;
+    for (id, table) in &tables.quantization_tables {
+        body += &format!("<h3>ID {}</h3>\n", id);
+        let rows: Vec<Vec<String>> = table
+            .0
+            .iter()
+            .map(|row| row.iter().map(|v| v.to_string()).collect())
+            .collect();
+        body += &html_table(
+            &(0..8).map(|u| format!("u={}", u)).collect::<Vec<_>>(),
+            &rows,
+        );
+    }
+
+    body += "<h2>霍夫曼表</h2>\n";
+    for (table_class, id, table) in &tables.huffman_tables {
+        body += &format!(
+            "<h3>{} ID {}</h3>\n",
+            if *table_class == 0 { "DC" } else { "AC" },
+            id
+        );
+        let mut values = table.values.iter();
+        let rows: Vec<Vec<String>> = (1..=16)
+            .zip(table.codes)
+            .filter(|&(_, count)| count != 0)
+            .map(|(length, count)| {
+                let symbols: Vec<String> = values
+                    .by_ref()
+                    .take(count as usize)
+                    .map(|symbol| format!("{:02X}", symbol))
+                    .collect();
+                vec![length.to_string(), count.to_string(), symbols.join(" ")]
+            })
+            .collect();
+        let header = ["码长", "个数", "符号"].map(String::from);
+        body += &html_table(&header, &rows);
+    }
+
+    body += "<h2>统计</h2>\n";
+    let decode_options = DecodeOptions {
+        color_conversion: options.color_conversion,
+        dct_precision: options.dct_precision,
+        ..Default::default()
+    };
+    let metrics = Metrics::new(image, &decode_to_image(&jpeg, &decode_options)?)?;
+    let pixels = (image.width() * image.height()) as f64;
+    let statistics = [
+        ("文件大小", format!("{} 字节", jpeg.len())),
+        (
+            "每像素位数",
+            format!("{:.3}", jpeg.len() as f64 * 8.0 / pixels),
+        ),
+        ("压缩比", format!("{:.2}", pixels * 3.0 / jpeg.len() as f64)),
+        ("MCU 个数", data.summary.mcu_bits.len().to_string()),
+        ("PSNR", format!("{:.2} dB", metrics.psnr)),
+        ("SSIM", format!("{:.4}", metrics.ssim)),
+        ("MS-SSIM", format!("{:.4}", metrics.ms_ssim)),
+    ];
+    let rows: Vec<Vec<String>> = statistics
+        .into_iter()
+        .map(|(name, value)| vec![name.to_string(), value])
+        .collect();
+    body += &html_table(&["项目", "值"].map(String::from), &rows);
+
+    let report = entropy_report(&jpeg)?;
+    let rows: Vec<Vec<String>> = report
+        .tables
+        .iter()
+        .map(|table| {
+            vec![
+                table.name.clone(),
+                table.count().to_string(),
+                format!("{:.3}", table.average_code_length()),
+                format!("{:.3}", table.entropy_per_symbol()),
+                format!("{:.3}", table.redundancy()),
+            ]
+        })
+        .collect();
+    let header = ["霍夫曼表", "符号数", "平均码长", "熵", "冗余"].map(String::from);
+    body += &html_table(&header, &rows);
+
+    Ok(format!(
+        "<!DOCTYPE html>\n<html lang=\"zh\">\n<head>\n<meta charset=\"utf-8\">\n<title>jpeglab 编码报告</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>jpeglab 编码报告</h1>\n{}</body>\n</html>\n",
+        STYLE, body
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::stripe::encode_striped;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_render_report() {
+        let image = RgbImage::from_fn(40, 20, |x, y| {
+            image::Rgb([(x * 6) as u8, (y * 12) as u8, ((x + y) * 4) as u8])
+        });
+        let options = EncodeOptions::default();
+        let data = encode_striped(&image, &options).unwrap();
+        let html = render_report(&image, &data, &options).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        // 原图、三个平面、三个热力图和位密度图。
+        assert_eq!(html.matches("data:image/png;base64,").count(), 8);
+        assert_eq!(html.matches("<h3>").count(), 2 + 4);
+        assert!(!html.contains("src=\"http"));
+    }
+}
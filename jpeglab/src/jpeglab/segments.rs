@@ -0,0 +1,78 @@
+//! 逐个读取 JPEG 文件的标记段，不解析内容，也不解码图像。
+
+use std::io;
+
+use super::encode_step7::ToVec;
+
+/// 一个标记段。
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    /// 标记的第二个字节，例如 APP1 为 0xE1。
+    pub marker: u8,
+    /// 段的内容，不含标记和长度。
+    pub data: &'a [u8],
+}
+
+impl Segment<'_> {
+    /// 是否是转码时要原样保留的段：APPn 和 COM，例如 EXIF、XMP 和厂商的数据。
+    /// JFIF 的 APP0 由编码器重新生成，不保留。
+    pub fn is_metadata(&self) -> bool {
+        match self.marker {
+            0xE0 => !self.data.starts_with(b"JFIF\0"),
+            0xE1..=0xEF | 0xFE => true,
+            _ => false,
+        }
+    }
+}
+
+impl ToVec for Segment<'_> {
+    fn to_vec(&self) -> Vec<u8> {
+        let length = self.data.len() as u16 + 2;
+        [&[0xFF, self.marker], &length.to_be_bytes(), self.data].concat()
+    }
+}
+
+/// 读取 SOI 之后、SOS 之前的所有标记段。遇到 SOS 或 EOI 时停止。
+pub fn read_segments(jpeg: &[u8]) -> io::Result<Vec<Segment<'_>>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Err(invalid("Missing SOI"));
+    }
+
+    let mut ret = vec![];
+    let mut idx = 2;
+    loop {
+        if jpeg.get(idx) != Some(&0xFF) {
+            return Err(invalid("Invalid block heading"));
+        }
+        // 标记之前可以有任意多个填充的 0xFF。
+        while jpeg.get(idx) == Some(&0xFF) {
+            idx += 1;
+        }
+        let marker = *jpeg
+            .get(idx)
+            .ok_or_else(|| invalid("Unexpected end of file"))?;
+        idx += 1;
+        match marker {
+            0xDA | 0xD9 => break,
+            // 没有长度的标记。
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+
+        let length = jpeg
+            .get(idx..idx + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+            .ok_or_else(|| invalid("Unexpected end of file"))?;
+        if length < 2 {
+            return Err(invalid("Invalid block length"));
+        }
+        let data = jpeg
+            .get(idx + 2..idx + length)
+            .ok_or_else(|| invalid("Unexpected end of file"))?;
+        ret.push(Segment { marker, data });
+        idx += length;
+    }
+
+    Ok(ret)
+}
@@ -0,0 +1,230 @@
+//! HTTP 服务的请求处理，与具体的 HTTP 实现无关，供 `jpeglab-server` 使用。
+//!
+//! - `POST /encode`：请求体为任意格式的图像，返回 JPEG。查询参数 `quality`（1 到 100，
+//!   见 `QuantizationTable::scaled`，省略时使用标准量化表）和 `subsampling`（`422`、`411` 或 `440`，
+//!   见 `Subsampling`，默认为 `422`）。
+//! - `POST /decode`：请求体为 JPEG，返回 PNG。查询参数 `format` 可以是 `png`（默认）或 `bmp`。
+
+use std::io;
+use std::io::Cursor;
+
+use clap::ValueEnum;
+use image::io::Reader as ImageReader;
+use image::ImageFormat;
+
+use super::convert::to_rgb8;
+use super::decode_to_image;
+use super::encode_step1::Subsampling;
+use super::encode_to_vec;
+use super::info::read_info;
+use super::options::DecodeOptions;
+use super::options::EncodeOptions;
+
+/// 请求中的图像最多的像素数（8192x8192）。在解码之前按头部中的大小检查，
+/// 以免一个请求就耗尽服务的内存。
+pub const MAX_PIXELS: u64 = 1 << 26;
+
+/// 处理的结果。
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    /// 纯文本的错误信息。
+    pub fn error(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: message.into().into_bytes(),
+        }
+    }
+}
+
+/// 解析查询字符串，只接受 `names` 中的参数。
+fn parse_query<'a>(query: &'a str, names: &[&str]) -> io::Result<Vec<(&'a str, &'a str)>> {
+    let mut ret = vec![];
+    for pair in query.split('&').filter(|v| !v.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if !names.contains(&name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown query parameter {}", name),
+            ));
+        }
+        ret.push((name, value));
+    }
+    Ok(ret)
+}
+
+fn check_pixels(width: u64, height: u64) -> io::Result<()> {
+    if width * height > MAX_PIXELS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The image exceeds {} pixels", MAX_PIXELS),
+        ));
+    }
+    Ok(())
+}
+
+fn encode(query: &str, body: &[u8]) -> io::Result<Response> {
+    let mut quality = None;
+    let mut subsampling = Subsampling::default();
+    for (name, value) in parse_query(query, &["quality", "subsampling"])? {
+        match name {
+            "quality" => match value.parse::<u8>() {
+                Ok(value @ 1..=100) => quality = Some(value),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "quality must be an integer from 1 to 100",
+                    ))
+                }
+            },
+            _ => {
+                let value = value.replace("%3A", ":").replace("%3a", ":");
+                subsampling = Subsampling::from_str(&value, true).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "subsampling must be 422, 411 or 440",
+                    )
+                })?
+            }
+        }
+    }
+    let invalid = |_| io::Error::new(io::ErrorKind::InvalidData, "Fail to decode the image");
+    let (width, height) = ImageReader::new(Cursor::new(body))
+        .with_guessed_format()?
+        .into_dimensions()
+        .map_err(invalid)?;
+    check_pixels(width as u64, height as u64)?;
+    let image = image::load_from_memory(body).map_err(invalid)?;
+    let options = EncodeOptions {
+        quality,
+        subsampling,
+        ..Default::default()
+    };
+    let jpeg = encode_to_vec(&to_rgb8(image), &options)?;
+    Ok(Response::ok("image/jpeg", jpeg))
+}
+
+fn decode(query: &str, body: &[u8]) -> io::Result<Response> {
+    let mut format = ImageFormat::Png;
+    for (_, value) in parse_query(query, &["format"])? {
+        format = match value {
+            "png" => ImageFormat::Png,
+            "bmp" => ImageFormat::Bmp,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "format must be png or bmp",
+                ))
+            }
+        };
+    }
+    let info = read_info(body)?;
+    check_pixels(info.width as u64, info.height as u64)?;
+    let image = decode_to_image(body, &DecodeOptions::default())?;
+    let mut ret = vec![];
+    image
+        .write_to(&mut Cursor::new(&mut ret), format)
+        .map_err(io::Error::other)?;
+    Ok(Response::ok(format.to_mime_type(), ret))
+}
+
+/// 处理一个请求。`url` 为请求行中的路径，可以带查询字符串。
+/// 参数或请求体有误时返回 400，其他错误返回 500。
+pub fn handle(method: &str, url: &str, body: &[u8]) -> Response {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let handler = match path {
+        "/encode" => encode,
+        "/decode" => decode,
+        _ => return Response::error(404, format!("No such endpoint {}", path)),
+    };
+    if method != "POST" {
+        return Response::error(405, format!("{} only accepts POST", path));
+    }
+    handler(query, body).unwrap_or_else(|e| {
+        let status = match e.kind() {
+            io::ErrorKind::InvalidInput
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::UnexpectedEof => 400,
+            _ => 500,
+        };
+        Response::error(status, e.to_string())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::test_util::test_image;
+
+    use image::RgbImage;
+
+    fn png(image: &RgbImage) -> Vec<u8> {
+        let mut ret = vec![];
+        image
+            .write_to(&mut Cursor::new(&mut ret), ImageFormat::Png)
+            .unwrap();
+        ret
+    }
+
+    #[test]
+    fn test_handle() {
+        let image = test_image();
+        let response = handle("POST", "/encode", &png(&image));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, "image/jpeg");
+        assert_eq!(
+            response.body,
+            encode_to_vec(&image, &Default::default()).unwrap()
+        );
+
+        let low = handle("POST", "/encode?quality=10&subsampling=422", &png(&image));
+        assert_eq!(low.status, 200);
+        assert!(low.body.len() < response.body.len());
+        let yuv440 = handle("POST", "/encode?subsampling=4%3A4%3A0", &png(&image));
+        assert_eq!(yuv440.status, 200);
+        let options = EncodeOptions {
+            subsampling: Subsampling::Yuv440,
+            ..Default::default()
+        };
+        assert_eq!(yuv440.body, encode_to_vec(&image, &options).unwrap());
+        let lower = handle("POST", "/encode?subsampling=4%3a4%3a0", &png(&image));
+        assert_eq!(lower.body, yuv440.body);
+
+        let decoded = handle("POST", "/decode", &response.body);
+        assert_eq!(decoded.status, 200);
+        assert_eq!(decoded.content_type, "image/png");
+        let decoded = image::load_from_memory(&decoded.body).unwrap().to_rgb8();
+        assert!(decoded == decode_to_image(&response.body, &Default::default()).unwrap());
+
+        assert_eq!(handle("GET", "/encode", &[]).status, 405);
+        assert_eq!(handle("POST", "/other", &[]).status, 404);
+        assert_eq!(handle("POST", "/encode", b"not an image").status, 400);
+        assert_eq!(handle("POST", "/decode", b"not a jpeg").status, 400);
+        // SOF 中的大小为 9000x9000，超过像素数的上限。
+        let mut large = response.body.clone();
+        let sof = large.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        large[sof + 5..sof + 9].copy_from_slice(&[0x23, 0x28, 0x23, 0x28]);
+        let rejected = handle("POST", "/decode", &large);
+        assert_eq!(rejected.status, 400);
+        assert!(String::from_utf8(rejected.body).unwrap().contains("pixels"));
+        for query in ["quality=0", "quality=abc", "subsampling=420", "speed=1"] {
+            let url = format!("/encode?{}", query);
+            assert_eq!(handle("POST", &url, &png(&image)).status, 400, "{}", query);
+        }
+    }
+}
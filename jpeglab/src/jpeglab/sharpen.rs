@@ -0,0 +1,62 @@
+//! 解码后的锐化（USM，unsharp mask），用于显示。
+//!
+//! 量化去掉了高频，色度的上采样也使颜色边缘变软。锐化把原图与高斯模糊后的差加回原图：
+//! 输出 = 原图 + 强度 × (原图 - 模糊)。只改善观感，不能恢复丢失的信息，PSNR 通常会下降。
+
+use image::imageops::blur;
+use image::RgbImage;
+
+/// 锐化的参数。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sharpening {
+    /// 加回的细节的倍数，0 为不锐化。
+    pub strength: f64,
+    /// 高斯模糊的标准差，单位为像素。越大，被增强的细节越粗。
+    pub radius: f64,
+}
+
+impl Default for Sharpening {
+    fn default() -> Self {
+        Self {
+            strength: 0.5,
+            radius: 1.0,
+        }
+    }
+}
+
+/// 锐化 `image`。
+pub fn unsharp_mask(image: &RgbImage, sharpening: &Sharpening) -> RgbImage {
+    let blurred = blur(image, sharpening.radius as f32);
+    let mut ret = image.clone();
+    for (pixel, blurred) in ret.pixels_mut().zip(blurred.pixels()) {
+        for (value, &blurred) in pixel.0.iter_mut().zip(&blurred.0) {
+            let original = *value as f64;
+            let sharpened = original + sharpening.strength * (original - blurred as f64);
+            *value = sharpened.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unsharp_mask() {
+        // 竖直的边缘，左边 100，右边 150。
+        let image = RgbImage::from_fn(16, 4, |x, _| image::Rgb([if x < 8 { 100 } else { 150 }; 3]));
+        let sharpened = unsharp_mask(&image, &Sharpening::default());
+        // 远离边缘处不变，边缘两侧的对比增强。
+        assert_eq!(sharpened.get_pixel(1, 1)[0], 100);
+        assert_eq!(sharpened.get_pixel(14, 1)[0], 150);
+        assert!(sharpened.get_pixel(7, 1)[0] < 100);
+        assert!(sharpened.get_pixel(8, 1)[0] > 150);
+
+        let none = Sharpening {
+            strength: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(unsharp_mask(&image, &none), image);
+    }
+}
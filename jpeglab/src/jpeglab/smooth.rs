@@ -0,0 +1,99 @@
+//! 编码前的输入平滑，与 cjpeg 的 `-smooth` 相同。
+//!
+//! 每个样本与周围 8 个样本加权平均，权重与 libjpeg 的 `fullsize_smooth_downsample` 相同：
+//! 中心乘以 65536 - 512·SF，每个邻居乘以 64·SF，再除以 65536。SF 为 1 到 100 的平滑系数，越大越模糊。
+//! 图像边缘复制最外面一行（列）。libjpeg 在 YCbCr 上平滑，这里在颜色转换之前的 RGB 上平滑，
+//! 两者只差舍入，因为平滑和颜色转换都是线性的。
+//! 可以抑制 GIF 等来源的抖动噪声，减少高频系数，提高压缩率。
+
+use std::borrow::Cow;
+
+use image::RgbImage;
+
+use super::options::EncodeOptions;
+
+/// 平滑 `image` 中从 `top` 开始的 `height` 行，返回这些行。
+/// 上下的邻居取自整幅图像，因此按条带平滑的结果与整幅图像平滑后再截取相同。
+pub fn smooth_rows(image: &RgbImage, top: u32, height: u32, factor: u8) -> RgbImage {
+    let factor = factor.clamp(1, 100) as u32;
+    let member_scale = 65536 - factor * 512;
+    let neighbour_scale = factor * 64;
+    let (width, image_height) = image.dimensions();
+    let sample = |x: i64, y: i64, c: usize| {
+        let x = x.clamp(0, width as i64 - 1) as u32;
+        let y = y.clamp(0, image_height as i64 - 1) as u32;
+        image.get_pixel(x, y)[c] as u32
+    };
+    RgbImage::from_fn(width, height, |x, y| {
+        let (x, y) = (x as i64, (top + y) as i64);
+        image::Rgb(std::array::from_fn(|c| {
+            let mut neighbour_sum = 0;
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    if (dx, dy) != (0, 0) {
+                        neighbour_sum += sample(x + dx, y + dy, c);
+                    }
+                }
+            }
+            let sum = sample(x, y, c) * member_scale + neighbour_sum * neighbour_scale;
+            ((sum + 32768) >> 16) as u8
+        }))
+    })
+}
+
+/// 按 `EncodeOptions::smoothing` 平滑整幅图像，不平滑时不复制。
+pub fn smooth_input<'a>(image: &'a RgbImage, options: &EncodeOptions) -> Cow<'a, RgbImage> {
+    match options.smoothing {
+        Some(factor) => Cow::Owned(smooth_rows(image, 0, image.height(), factor)),
+        None => Cow::Borrowed(image),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_smooth_rows() {
+        // 棋盘格是最强的抖动。
+        let image = RgbImage::from_fn(6, 5, |x, y| {
+            image::Rgb([if (x + y) % 2 == 0 { 200 } else { 0 }, 100, (x * 40) as u8])
+        });
+        let smoothed = smooth_rows(&image, 0, 5, 100);
+        // 常数不变，线性的渐变在内部不变。
+        assert!(smoothed.pixels().all(|p| p[1] == 100));
+        assert_eq!(smoothed.get_pixel(2, 2)[2], 80);
+        // 中心 200，4 个 0 和 4 个 200：200·(14336 + 4·6400) / 65536 = 122。
+        assert_eq!(smoothed.get_pixel(2, 2)[0], 122);
+        // 中心 0：200·4·6400 / 65536 = 78。
+        assert_eq!(smoothed.get_pixel(3, 2)[0], 78);
+
+        // 按条带平滑与整幅图像相同。
+        for (top, height) in [(0, 2), (2, 2), (4, 1)] {
+            let stripe = smooth_rows(&image, top, height, 30);
+            let whole = smooth_rows(&image, 0, 5, 30);
+            for (x, y, p) in stripe.enumerate_pixels() {
+                assert_eq!(p, whole.get_pixel(x, top + y));
+            }
+        }
+    }
+
+    #[test]
+    fn test_smoothing_compresses_dithering() {
+        let image = RgbImage::from_fn(64, 48, |x, y| {
+            let level = (x * 4) as u8;
+            image::Rgb([level.saturating_add(((x ^ y) & 1) as u8 * 40); 3])
+        });
+        let size = |smoothing, striped| {
+            let options = EncodeOptions {
+                smoothing,
+                striped,
+                ..Default::default()
+            };
+            super::super::encode_to_vec(&image, &options).unwrap()
+        };
+        let smoothed = size(Some(50), false);
+        assert!(smoothed.len() < size(None, false).len());
+        assert_eq!(smoothed, size(Some(50), true));
+    }
+}
@@ -0,0 +1,194 @@
+//! DCT 域的隐写。把数据藏在量化后 AC 系数的最低位中，与 JSteg 相同。
+//! 只使用绝对值不小于 2 的 AC 系数：改变最低位后绝对值仍不小于 2，
+//! 提取时能选出同样的系数，而 0 和 ±1 不变，不会改变游程，对文件大小的影响也小。
+
+use std::io;
+
+use image::RgbImage;
+
+use super::arena::ScratchArena;
+use super::decode_step1::decode_step1;
+use super::decode_step2::ScanDecoder;
+use super::encode_step1::encode_step1;
+use super::encode_step2::encode_step2;
+use super::encode_step3::encode_step3;
+use super::encode_step4::encode_step4;
+use super::encode_step5::encode_step5;
+use super::encode_step5::ZigzagDu;
+use super::encode_step6::encode_step6;
+use super::encode_step7::make_jpeg;
+use super::options::EncodeOptions;
+
+/// 数据之前以 4 字节大端整数记录数据的字节数。
+const LENGTH_BYTES: usize = 4;
+
+/// 是否用于隐写的系数。
+fn is_carrier(value: i16) -> bool {
+    value.unsigned_abs() >= 2
+}
+
+/// 依次取出 DU 中用于隐写的系数的最低位。
+fn read_bits(du: &ZigzagDu, bits: &mut Vec<bool>) {
+    bits.extend(
+        du.0[1..]
+            .iter()
+            .filter(|&&value| is_carrier(value))
+            .map(|&value| value.unsigned_abs() & 1 == 1),
+    );
+}
+
+/// 依次把 `bits` 写入 DU 中用于隐写的系数的最低位，只改变绝对值，不改变符号。
+fn write_bits(du: &mut ZigzagDu, bits: &mut impl Iterator<Item = bool>) {
+    for value in du.0[1..].iter_mut().filter(|value| is_carrier(**value)) {
+        let Some(bit) = bits.next() else {
+            return;
+        };
+        let magnitude = (value.unsigned_abs() & !1 | bit as u16) as i16;
+        *value = magnitude * value.signum();
+    }
+}
+
+/// 数据按字节、高位在前展开为位。
+fn to_bits(bytes: &[u8]) -> impl Iterator<Item = bool> + '_ {
+    bytes
+        .iter()
+        .flat_map(|&byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+}
+
+fn to_bytes(bits: &[bool]) -> Vec<u8> {
+    bits.chunks_exact(8)
+        .map(|chunk| chunk.iter().fold(0, |byte, &bit| byte << 1 | bit as u8))
+        .collect()
+}
+
+/// 编码 `image`，并在熵编码之前把 `payload` 写入系数。不按条带编码。
+pub fn embed(image: &RgbImage, payload: &[u8], options: &EncodeOptions) -> io::Result<Vec<u8>> {
+    let mut arena = ScratchArena::default();
+    let yuv_image = encode_step1(image, &options.color_conversion, options.subsampling)?;
+    let mcu_collection = encode_step2(&yuv_image, &mut arena)?;
+    let dct_mcu_collection = encode_step3(&mcu_collection, options.dct_precision, &mut arena)?;
+    let quantized_mcu_collection = encode_step4(
+        &dct_mcu_collection,
+        &options.quantization_tables(),
+        &mut arena,
+    )?;
+    let mut zigzag_mcu_collection = encode_step5(&quantized_mcu_collection, &mut arena)?;
+
+    let dus = &mut zigzag_mcu_collection.zigzag_dus;
+    let capacity = dus
+        .y
+        .iter()
+        .chain(&dus.cb)
+        .chain(&dus.cr)
+        .map(|du| du.0[1..].iter().filter(|&&value| is_carrier(value)).count())
+        .sum::<usize>()
+        / 8;
+    if LENGTH_BYTES + payload.len() > capacity {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "The payload is {} bytes, but the image can only hide {} bytes",
+                payload.len(),
+                capacity.saturating_sub(LENGTH_BYTES)
+            ),
+        ));
+    }
+
+    // 按熵编码的顺序写入：每个 MCU 中依次是所有 Y、Cb、Cr。
+    let length = (payload.len() as u32).to_be_bytes();
+    let mut bits = to_bits(&length).chain(to_bits(payload));
+    let n = dus.luma_per_mcu();
+    for i in 0..dus.mcu_count() {
+        for y in &mut dus.y[n * i..n * (i + 1)] {
+            write_bits(y, &mut bits);
+        }
+        write_bits(&mut dus.cb[i], &mut bits);
+        write_bits(&mut dus.cr[i], &mut bits);
+    }
+
+    let jpeg_output_data = encode_step6(&zigzag_mcu_collection, options)?;
+    Ok(make_jpeg(&jpeg_output_data, options))
+}
+
+/// 熵解码 `jpeg`，取出 `embed` 写入的数据。
+pub fn extract(jpeg: &[u8]) -> io::Result<Vec<u8>> {
+    let jpeg_data = decode_step1(jpeg)?;
+    let (mcus_per_row, mcu_rows) = jpeg_data.get_mcu_grid();
+    let mut decoder = ScanDecoder::new(&jpeg_data);
+    let mut bits = vec![];
+    for _ in 0..mcus_per_row * mcu_rows {
+        decoder.decode_mcu(|_, _, du| read_bits(du, &mut bits))?;
+    }
+
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No hidden payload found in the coefficients",
+        )
+    };
+    let (length, rest) = bits
+        .split_at_checked(8 * LENGTH_BYTES)
+        .ok_or_else(invalid)?;
+    let length = u32::from_be_bytes(to_bytes(length).try_into().unwrap()) as usize;
+    let payload = rest.get(..8 * length).ok_or_else(invalid)?;
+    Ok(to_bytes(payload))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::decode_to_image;
+    use super::super::encode_to_vec;
+
+    fn make_image(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([
+                (x * 7 + y * 3) as u8,
+                (x * y % 251) as u8,
+                ((x ^ y) * 5) as u8,
+            ])
+        })
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let image = make_image(96, 64);
+        let payload = b"hidden in the least significant bits";
+        let jpeg = embed(&image, payload, &Default::default()).unwrap();
+        assert_eq!(extract(&jpeg).unwrap(), payload);
+
+        // 与不隐写的结果看起来几乎一样。
+        let cover = encode_to_vec(&image, &Default::default()).unwrap();
+        let expected = decode_to_image(&cover, &Default::default()).unwrap();
+        let decoded = decode_to_image(&jpeg, &Default::default()).unwrap();
+        let diff = decoded
+            .as_raw()
+            .iter()
+            .zip(expected.as_raw())
+            .map(|(a, b)| a.abs_diff(*b) as f64)
+            .sum::<f64>()
+            / expected.as_raw().len() as f64;
+        assert!(diff < 2.0, "{}", diff);
+
+        assert_eq!(
+            extract(&embed(&image, b"", &Default::default()).unwrap()).unwrap(),
+            b""
+        );
+    }
+
+    #[test]
+    fn test_capacity() {
+        let image = make_image(16, 8);
+        let error = embed(&image, &[0; 1024], &Default::default()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        // 没有隐写的文件中读出的长度一般是无意义的。
+        let flat = RgbImage::from_pixel(16, 8, image::Rgb([128, 128, 128]));
+        let jpeg = encode_to_vec(&flat, &Default::default()).unwrap();
+        assert_eq!(
+            extract(&jpeg).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,417 @@
+//! 按条带处理。每个条带为一行 MCU，从颜色转换到熵编码（或从熵解码到颜色转换）都在条带内完成，
+//! 因此占用的内存与图像高度无关。DC 的差分预测在条带之间延续，输出与不分条带时完全相同。
+
+use std::io;
+use std::ops::Range;
+
+use image::GenericImage;
+use image::GenericImageView;
+use image::RgbImage;
+
+use super::arena::ScratchArena;
+use super::arithmetic::ArithmeticEncoder;
+use super::decode_step1::CompleteJpegData;
+use super::decode_step2::ScanDecoder;
+use super::decode_step3::decode_mcu_rows;
+use super::decode_step4::to_rgb_image;
+use super::encode_step1::check_dimensions;
+use super::encode_step1::encode_step1;
+use super::encode_step1::SamplePrecision;
+use super::encode_step2::encode_step2;
+use super::encode_step3::encode_step3;
+use super::encode_step4::encode_step4;
+use super::encode_step5::encode_step5;
+use super::encode_step6::EntropyCoding;
+use super::encode_step6::HuffmanMode;
+use super::encode_step6::JpegOutputData;
+use super::encode_step6::ScanEncoder;
+use super::encode_step7::make_jpeg;
+use super::options::DecodeOptions;
+use super::options::EncodeOptions;
+use super::smooth::smooth_rows;
+use super::verify::verify_jpeg;
+
+/// 按条带完成第一步到第六步。
+#[tracing::instrument(skip_all, fields(width = image.width(), height = image.height()))]
+pub fn encode_striped(image: &RgbImage, options: &EncodeOptions) -> io::Result<JpegOutputData> {
+    let (width, height) = image.dimensions();
+    let mut encoder = RowEncoder::with_options(width, height, options)?;
+    // 每次输入一行 MCU，不复制整幅图像。
+    let (_, mcu_height) = options.subsampling.mcu_size();
+    for rows in image.as_raw().chunks(width as usize * 3 * mcu_height) {
+        encoder.push_rows(rows)?;
+    }
+    encoder.finish_data()
+}
+
+/// 逐行输入像素的编码器。每凑齐一行 MCU 的像素就完成这个条带的第一步到第六步，
+/// 只保留还不足一个条带的像素，因此可以编码不能整幅放入内存的图像。输出与 `encode_to_vec` 相同。
+pub struct RowEncoder {
+    options: EncodeOptions,
+    width: u32,
+    height: u32,
+    /// 已经编码的行数。
+    encoded_rows: u32,
+    /// `pending` 中第一行的行号。平滑时还保留上一个条带的最后一行。
+    pending_top: u32,
+    /// 还没有编码的像素行，每个像素依次为 R、G、B。
+    pending: Vec<u8>,
+    encoder: ScanEncoder,
+    arithmetic_encoder: Option<ArithmeticEncoder>,
+    /// 各个条带大小相同，每一步用完的 DU 缓冲区归还后由下一个条带复用。
+    arena: ScratchArena,
+}
+
+impl RowEncoder {
+    /// 编码 `width` x `height` 的图像。与按条带编码相同，不能使用需要整幅图像的参数；
+    /// 也不能写入 JFIF 缩略图。
+    pub fn new(width: u32, height: u32, options: &EncodeOptions) -> io::Result<Self> {
+        if options.jfif_thumbnail.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A JFIF thumbnail needs the whole image and cannot be used with the row encoder",
+            ));
+        }
+        Self::with_options(width, height, options)
+    }
+
+    fn with_options(width: u32, height: u32, options: &EncodeOptions) -> io::Result<Self> {
+        check_dimensions(width, height)?;
+        if options.huffman == HuffmanMode::Optimized {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Optimized Huffman tables need the statistics of the whole image and cannot be used when striped",
+            ));
+        }
+        if options.target_size.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A target size needs the coefficients of the whole image and cannot be used when striped",
+            ));
+        }
+        if options.sample_precision == SamplePrecision::Twelve {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "12-bit samples cannot be used when striped",
+            ));
+        }
+        if options.progressive {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Progressive encoding needs the coefficients of the whole image and cannot be used when striped",
+            ));
+        }
+        if options.non_interleaved {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Non-interleaved scans need the coefficients of the whole image and cannot be used when striped",
+            ));
+        }
+
+        // 每个条带为一行 MCU。
+        let (mcu_width, mcu_height) = options.subsampling.mcu_size();
+        let mcu_count =
+            (width as usize).div_ceil(mcu_width) * (height as usize).div_ceil(mcu_height);
+        let mut encoder = ScanEncoder::new(mcu_count);
+        encoder.set_restart_interval(options.restart_interval);
+        let arithmetic_encoder = (options.entropy_coding == EntropyCoding::Arithmetic)
+            .then(|| ArithmeticEncoder::new(options.restart_interval));
+        Ok(Self {
+            options: options.clone(),
+            width,
+            height,
+            encoded_rows: 0,
+            pending_top: 0,
+            pending: vec![],
+            encoder,
+            arithmetic_encoder,
+            arena: ScratchArena::default(),
+        })
+    }
+
+    /// 输入接下来的若干行像素，每个像素依次为 R、G、B。凑齐的条带立即编码。
+    pub fn push_rows(&mut self, rows: &[u8]) -> io::Result<()> {
+        let row_len = self.width as usize * 3;
+        let pushed_rows = self.pending_rows() + (rows.len() / row_len) as u32;
+        if !rows.len().is_multiple_of(row_len) || self.pending_top + pushed_rows > self.height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Expected whole rows of {} bytes, at most {} rows in total",
+                    row_len, self.height
+                ),
+            ));
+        }
+        self.pending.extend_from_slice(rows);
+
+        // 一次输入很多行时，先编码所有凑齐的条带，最后一起丢弃已经编码的行。
+        let (_, mcu_height) = self.options.subsampling.mcu_size();
+        let pending_end = self.pending_top + self.pending_rows();
+        let mut top = self.pending_top;
+        while self.encoded_rows < self.height {
+            let y = self.encoded_rows;
+            // 最后一个条带不足一行 MCU 时，由第一步复制最下面一行填充，与整幅图像的填充方式相同。
+            let stripe_height = (mcu_height as u32).min(self.height - y);
+            // 平滑时还需要上一个条带的最后一行和下一个条带的第一行。
+            let context = self.options.smoothing.is_some() as u32;
+            let bottom = (y + stripe_height + context).min(self.height);
+            if pending_end < bottom {
+                break;
+            }
+            let rows = &self.pending[(top - self.pending_top) as usize * row_len..];
+            let rows = &rows[..(bottom - top) as usize * row_len];
+            let stripe = RgbImage::from_raw(self.width, bottom - top, rows.to_vec())
+                .expect("pending rows are whole rows");
+            self.encode_stripe(&stripe, y - top, stripe_height)?;
+            self.encoded_rows = y + stripe_height;
+            top = self.encoded_rows - context;
+        }
+        self.pending
+            .drain(..(top - self.pending_top) as usize * row_len);
+        self.pending_top = top;
+        Ok(())
+    }
+
+    fn pending_rows(&self) -> u32 {
+        (self.pending.len() / (self.width as usize * 3)) as u32
+    }
+
+    /// 编码 `buffer` 中从 `top` 开始的 `stripe_height` 行。
+    /// `buffer` 的上下边界与图像相同，或者还有一行邻居，因此平滑的结果与整幅图像平滑后再截取相同。
+    fn encode_stripe(&mut self, buffer: &RgbImage, top: u32, stripe_height: u32) -> io::Result<()> {
+        let y = self.encoded_rows;
+        let _span = tracing::info_span!("stripe", y, height = stripe_height).entered();
+        let stripe = match self.options.smoothing {
+            Some(factor) => smooth_rows(buffer, top, stripe_height, factor),
+            None => buffer.view(0, top, self.width, stripe_height).to_image(),
+        };
+
+        let options = &self.options;
+        let arena = &mut self.arena;
+        let yuv_image = encode_step1(&stripe, &options.color_conversion, options.subsampling)?;
+        let mcu_collection = encode_step2(&yuv_image, arena)?;
+        let dct_mcu_collection = encode_step3(&mcu_collection, options.dct_precision, arena)?;
+        mcu_collection.dus.recycle(&mut arena.du);
+        let quantized_mcu_collection =
+            encode_step4(&dct_mcu_collection, &options.quantization_tables(), arena)?;
+        dct_mcu_collection.dct_dus.recycle(&mut arena.dct_du);
+        let zigzag_mcu_collection = encode_step5(&quantized_mcu_collection, arena)?;
+        quantized_mcu_collection
+            .quantized_dus
+            .recycle(&mut arena.quantized_du);
+        self.encoder.encode_mcus(&zigzag_mcu_collection.zigzag_dus);
+        if let Some(arithmetic_encoder) = &mut self.arithmetic_encoder {
+            arithmetic_encoder.encode_mcus(&zigzag_mcu_collection.zigzag_dus);
+        }
+        zigzag_mcu_collection
+            .zigzag_dus
+            .recycle(&mut arena.zigzag_du);
+        Ok(())
+    }
+
+    /// 结束编码，返回第六步的结果。所有行都已输入时才能结束。
+    fn finish_data(self) -> io::Result<JpegOutputData> {
+        if self.encoded_rows != self.height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Only {} of {} rows were pushed",
+                    self.pending_top + self.pending_rows(),
+                    self.height
+                ),
+            ));
+        }
+        Ok(JpegOutputData {
+            arithmetic_scan: self.arithmetic_encoder.map(ArithmeticEncoder::finish),
+            ..self
+                .encoder
+                .finish(self.width as usize, self.height as usize)
+        })
+    }
+
+    /// 结束编码，返回 JPEG 文件的内容。要求自检时与 `encode_to_vec` 相同地检查输出。
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        let options = self.options.clone();
+        let data = self.finish_data()?;
+        let jpeg = make_jpeg(&data, &options);
+        if options.verify {
+            verify_jpeg(&jpeg, &data, &options.frame_layout())?;
+        }
+        Ok(jpeg)
+    }
+}
+
+/// 按条带完成第二步到第四步，返回 RGB 图像。
+pub fn decode_striped(
+    jpeg_data: &CompleteJpegData,
+    options: &DecodeOptions,
+) -> io::Result<RgbImage> {
+    decode_striped_with(jpeg_data, options, |_, _| {})
+}
+
+/// 与 `decode_striped` 相同，但每重建一行 MCU 就调用一次 `on_rows`，
+/// 参数为目前的图像（之后的行仍为黑色）和刚完成的像素行。
+#[tracing::instrument(skip_all, fields(width = jpeg_data.width, height = jpeg_data.height))]
+pub fn decode_striped_with(
+    jpeg_data: &CompleteJpegData,
+    options: &DecodeOptions,
+    mut on_rows: impl FnMut(&RgbImage, Range<u32>),
+) -> io::Result<RgbImage> {
+    let (_, mcu_height) = jpeg_data.get_mcu_size();
+    let (_, mcu_rows) = jpeg_data.get_mcu_grid();
+    let mut decoder = ScanDecoder::new(jpeg_data);
+    let mut ret = RgbImage::new(jpeg_data.width as u32, jpeg_data.height as u32);
+
+    for row in 0..mcu_rows {
+        let y = row * mcu_height;
+        let stripe_height = mcu_height.min(jpeg_data.height - y);
+        let decoded_yuv_image = decode_mcu_rows(
+            &mut decoder,
+            jpeg_data,
+            1,
+            stripe_height,
+            options.dct_precision,
+        )?;
+        let rgb = to_rgb_image(&decoded_yuv_image, &options.color_conversion);
+        ret.copy_from(&rgb, 0, y as u32)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        on_rows(&ret, y as u32..(y + stripe_height) as u32);
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::decode_step1::decode_step1;
+    use super::super::decode_step3::decode_step3;
+    use super::super::encode_step1::Subsampling;
+    use super::super::encode_step6::encode_step6;
+    use super::super::encode_step7::make_jpeg;
+    use super::super::test_util::test_image;
+    use super::super::test_util::test_image_of_size;
+
+    fn encode_whole(image: &RgbImage, options: &EncodeOptions) -> JpegOutputData {
+        let arena = &mut ScratchArena::default();
+        let yuv_image =
+            encode_step1(image, &options.color_conversion, options.subsampling).unwrap();
+        let mcu_collection = encode_step2(&yuv_image, arena).unwrap();
+        let dct_mcu_collection =
+            encode_step3(&mcu_collection, options.dct_precision, arena).unwrap();
+        let quantized_mcu_collection =
+            encode_step4(&dct_mcu_collection, &options.quantization_tables(), arena).unwrap();
+        let zigzag_mcu_collection = encode_step5(&quantized_mcu_collection, arena).unwrap();
+        encode_step6(&zigzag_mcu_collection, options).unwrap()
+    }
+
+    #[test]
+    fn test_striped_matches_whole() {
+        for (subsampling, width, height) in [
+            (Subsampling::Yuv422, 1, 1),
+            (Subsampling::Yuv422, 16, 8),
+            (Subsampling::Yuv422, 37, 21),
+            (Subsampling::Yuv422, 40, 33),
+            (Subsampling::Yuv411, 37, 21),
+            (Subsampling::Yuv440, 37, 21),
+        ] {
+            let options = EncodeOptions {
+                subsampling,
+                ..Default::default()
+            };
+            let image = test_image_of_size(width, height);
+
+            let whole_data = encode_whole(&image, &options);
+            let striped_data = encode_striped(&image, &options).unwrap();
+            assert_eq!(whole_data.summary, striped_data.summary);
+            let whole = make_jpeg(&whole_data, &options);
+            let striped = make_jpeg(&striped_data, &options);
+            assert!(whole == striped, "{:?} {}x{}", subsampling, width, height);
+
+            let jpeg_data = decode_step1(&whole).unwrap();
+            let decoded = decode_striped(&jpeg_data, &DecodeOptions::default()).unwrap();
+            let expected = {
+                let yuv = decode_step3(&jpeg_data, Default::default()).unwrap();
+                to_rgb_image(&yuv, &Default::default())
+            };
+            assert!(
+                decoded == expected,
+                "{:?} {}x{}",
+                subsampling,
+                width,
+                height
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_striped_with() {
+        let image = test_image();
+        let jpeg = super::super::encode_to_vec(&image, &EncodeOptions::default()).unwrap();
+        let jpeg_data = decode_step1(&jpeg).unwrap();
+        let expected = decode_striped(&jpeg_data, &DecodeOptions::default()).unwrap();
+
+        let mut calls = vec![];
+        let decoded =
+            decode_striped_with(&jpeg_data, &DecodeOptions::default(), |partial, rows| {
+                // 已完成的行与最终结果相同，之后的行还没有写入。
+                let done = partial.view(0, 0, 37, rows.end).to_image();
+                assert!(done == expected.view(0, 0, 37, rows.end).to_image());
+                if rows.end < 21 {
+                    assert_eq!(partial.get_pixel(36, 20), &image::Rgb([0, 0, 0]));
+                }
+                calls.push(rows);
+            })
+            .unwrap();
+        assert!(decoded == expected);
+        assert_eq!(calls, [0..8, 8..16, 16..21]);
+    }
+
+    #[test]
+    fn test_row_encoder() {
+        let image = test_image();
+        let row_len = 37 * 3;
+        for options in [
+            EncodeOptions {
+                verify: true,
+                ..Default::default()
+            },
+            EncodeOptions {
+                subsampling: Subsampling::Yuv440,
+                smoothing: Some(50),
+                ..Default::default()
+            },
+            EncodeOptions {
+                entropy_coding: EntropyCoding::Arithmetic,
+                restart_interval: Some(2),
+                ..Default::default()
+            },
+        ] {
+            let expected = super::super::encode_to_vec(&image, &options).unwrap();
+            // 每次输入的行数与条带无关，可以不足一行 MCU，也可以跨过多个条带。
+            for chunk_rows in [1, 3, 8, 17, 21] {
+                let mut encoder = RowEncoder::new(37, 21, &options).unwrap();
+                for rows in image.as_raw().chunks(row_len * chunk_rows) {
+                    encoder.push_rows(rows).unwrap();
+                }
+                let jpeg = encoder.finish().unwrap();
+                assert!(jpeg == expected, "{:?} {}", options, chunk_rows);
+            }
+        }
+
+        let mut encoder = RowEncoder::new(37, 21, &Default::default()).unwrap();
+        // 不是整行，或者超过图像的高度。
+        assert!(encoder.push_rows(&image.as_raw()[..row_len + 1]).is_err());
+        encoder.push_rows(&image.as_raw()[..row_len * 20]).unwrap();
+        assert!(encoder.push_rows(&image.as_raw()[..row_len * 2]).is_err());
+        // 还差一行。
+        assert!(encoder.finish().is_err());
+
+        let options = EncodeOptions {
+            jfif_thumbnail: Some(16),
+            ..Default::default()
+        };
+        assert!(RowEncoder::new(37, 21, &options).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! 导出 JPEG 文件中的量化表和霍夫曼表，便于比较不同编码器的选择，或在自己的编码中复用。
+
+use std::io;
+
+use super::decode_step1::parse_dht;
+use super::decode_step1::parse_dqt;
+use super::encode_step4::QuantizationTable;
+use super::encode_step6::JpegHuffmanTable;
+use super::segments::read_segments;
+
+/// 导出的格式。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TableFormat {
+    /// 一个对象，包含 `quantization_tables` 和 `huffman_tables` 两个数组。
+    #[default]
+    Json,
+    /// 每行一个表，没有表头。量化表为 `dqt,ID,64 个值`，
+    /// 霍夫曼表为 `dht,dc 或 ac,ID,16 个位表的值,值表`。
+    Csv,
+}
+
+/// JPEG 文件中按出现顺序排列的所有表。
+#[derive(Debug, Clone, Default)]
+pub struct TableSet {
+    /// (ID, 量化表)。
+    pub quantization_tables: Vec<(u8, QuantizationTable)>,
+    /// (类别, ID, 霍夫曼表)，类别 0 表示 DC，1 表示 AC。
+    pub huffman_tables: Vec<(u8, u8, JpegHuffmanTable)>,
+}
+
+/// 读取 JPEG 文件中所有的 DQT 和 DHT，不解码图像。
+pub fn read_tables(jpeg: &[u8]) -> io::Result<TableSet> {
+    let mut ret = TableSet::default();
+    for segment in read_segments(jpeg)? {
+        match segment.marker {
+            0xDB => ret.quantization_tables.extend(parse_dqt(segment.data)?),
+            0xC4 => ret.huffman_tables.extend(
+                parse_dht(segment.data)?
+                    .into_iter()
+                    .map(|(table, table_class, id)| (table_class, id, table)),
+            ),
+            _ => {}
+        }
+    }
+    Ok(ret)
+}
+
+fn join(values: impl IntoIterator<Item = impl ToString>) -> String {
+    values
+        .into_iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn class_name(table_class: u8) -> &'static str {
+    match table_class {
+        0 => "dc",
+        _ => "ac",
+    }
+}
+
+impl TableSet {
+    /// 量化表按自然顺序（行优先）输出，不是 Zigzag 顺序。
+    pub fn format(&self, format: TableFormat) -> String {
+        match format {
+            TableFormat::Json => self.to_json(),
+            TableFormat::Csv => self.to_csv(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let quantization_tables: Vec<String> = self
+            .quantization_tables
+            .iter()
+            .map(|(id, table)| {
+                format!(
+                    "    {{\"id\": {}, \"values\": [{}]}}",
+                    id,
+                    join(table.0.as_flattened())
+                )
+            })
+            .collect();
+        let huffman_tables: Vec<String> = self
+            .huffman_tables
+            .iter()
+            .map(|(table_class, id, table)| {
+                format!(
+                    "    {{\"class\": \"{}\", \"id\": {}, \"bits\": [{}], \"values\": [{}]}}",
+                    class_name(*table_class),
+                    id,
+                    join(table.codes),
+                    join(&table.values)
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"quantization_tables\": [\n{}\n  ],\n  \"huffman_tables\": [\n{}\n  ]\n}}\n",
+            quantization_tables.join(",\n"),
+            huffman_tables.join(",\n")
+        )
+    }
+
+    fn to_csv(&self) -> String {
+        let mut ret = String::new();
+        for (id, table) in &self.quantization_tables {
+            ret += &format!("dqt,{},{}\n", id, join(table.0.as_flattened()));
+        }
+        for (table_class, id, table) in &self.huffman_tables {
+            ret += &format!(
+                "dht,{},{},{},{}\n",
+                class_name(*table_class),
+                id,
+                join(table.codes),
+                join(&table.values)
+            );
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::encode_step4::LUMINANCE_QUANTIZATION_TABLE;
+    use super::super::encode_step6::DEFAULT_CHROMA_AC_HUFFMAN_TABLE;
+    use super::super::encode_step6::DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE;
+    use super::super::encode_to_vec;
+
+    #[test]
+    fn test_read_tables() {
+        let image = image::RgbImage::from_fn(16, 8, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        let jpeg = encode_to_vec(&image, &Default::default()).unwrap();
+        let tables = read_tables(&jpeg).unwrap();
+
+        assert_eq!(tables.quantization_tables.len(), 2);
+        let (id, table) = &tables.quantization_tables[0];
+        assert_eq!((*id, table.0), (0, LUMINANCE_QUANTIZATION_TABLE.0));
+
+        let classes_and_ids: Vec<(u8, u8)> = tables
+            .huffman_tables
+            .iter()
+            .map(|&(table_class, id, _)| (table_class, id))
+            .collect();
+        assert_eq!(classes_and_ids, [(0, 0), (1, 0), (0, 1), (1, 1)]);
+        let (_, _, table) = &tables.huffman_tables[3];
+        assert_eq!(table.codes, DEFAULT_CHROMA_AC_HUFFMAN_TABLE.codes);
+        assert_eq!(table.values, DEFAULT_CHROMA_AC_HUFFMAN_TABLE.values);
+
+        let csv = tables.format(TableFormat::Csv);
+        let first_line = csv.lines().next().unwrap();
+        assert!(first_line.starts_with("dqt,0,16,11,10,16,"));
+        assert_eq!(first_line.split(',').count(), 2 + 64);
+        assert_eq!(csv.lines().count(), 6);
+
+        let json = tables.format(TableFormat::Json);
+        let dc_bits = format!(
+            "\"bits\": [{}]",
+            join(DEFAULT_LUMINANCE_DC_HUFFMAN_TABLE.codes)
+        );
+        assert!(json.contains(&format!("{{\"class\": \"dc\", \"id\": 0, {}", dc_bits)));
+    }
+}
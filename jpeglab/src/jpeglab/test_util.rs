@@ -0,0 +1,48 @@
+//! 单元测试共用的工具函数：测试图像，以及手工构造 JPEG 文件的工具。
+
+use image::RgbImage;
+
+use super::encode_step6::JpegHuffmanTable;
+
+/// 37x21 的测试图像。宽和高都不是 8 或 16 的倍数，三个通道的变化方向各不相同。
+pub(crate) fn test_image() -> RgbImage {
+    test_image_of_size(37, 21)
+}
+
+/// 与 `test_image` 相同的图案，大小任意。
+pub(crate) fn test_image_of_size(width: u32, height: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([(x * 7) as u8, (y * 12) as u8, ((x ^ y) * 8) as u8])
+    })
+}
+
+/// 带长度的段。
+pub(crate) fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+    let mut ret = vec![0xFF, marker];
+    ret.extend((payload.len() as u16 + 2).to_be_bytes());
+    ret.extend(payload);
+    ret
+}
+
+/// 符号的霍夫曼码字，写成 0 和 1 组成的字符串。
+pub(crate) fn code(table: &JpegHuffmanTable, symbol: u8) -> String {
+    let (code, length) = table.to_decode_table().code(symbol).unwrap();
+    format!("{:0width$b}", code, width = length as usize)
+}
+
+/// 按位写出的熵编码数据：末尾补 1，0xFF 之后填充 0x00。
+pub(crate) fn to_scan_bytes(bits: &str) -> Vec<u8> {
+    let mut bits = bits.replace(' ', "");
+    while !bits.len().is_multiple_of(8) {
+        bits.push('1');
+    }
+    let mut ret = vec![];
+    for i in (0..bits.len()).step_by(8) {
+        let byte = u8::from_str_radix(&bits[i..i + 8], 2).unwrap();
+        ret.push(byte);
+        if byte == 0xFF {
+            ret.push(0x00);
+        }
+    }
+    ret
+}
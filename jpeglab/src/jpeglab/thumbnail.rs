@@ -0,0 +1,244 @@
+//! 提取嵌入的 JPEG 缩略图。缩略图可以在 EXIF 的 APP1 中，也可以在 JFXX 扩展的 APP0 中。
+//! 编码时还可以在 JFIF 的 APP0 中写入未压缩的 RGB 缩略图。
+
+use std::io;
+
+use image::imageops::resize;
+use image::imageops::FilterType;
+use image::RgbImage;
+
+use super::segments::read_segments;
+
+/// EXIF 中 IFD1 的标签：缩略图的偏移和长度。
+const TAG_JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;
+const TAG_JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;
+
+/// JFXX 扩展中表示 JPEG 缩略图的扩展码。
+const JFXX_JPEG_THUMBNAIL: u8 = 0x10;
+
+/// TIFF 结构的读取，字节序由文件头决定。越界时返回 `None`。
+pub struct TiffReader<'a> {
+    pub data: &'a [u8],
+    pub is_big_endian: bool,
+}
+
+impl TiffReader<'_> {
+    pub fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(match self.is_big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    pub fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(match self.is_big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    /// IFD 中 `tag` 的值，只取 SHORT 或 LONG 类型的第一个值。
+    pub fn ifd_value(&self, ifd: usize, tag: u16) -> Option<u32> {
+        let count = self.u16(ifd)? as usize;
+        (0..count).find_map(|i| {
+            let entry = ifd + 2 + 12 * i;
+            if self.u16(entry)? != tag {
+                return None;
+            }
+            match self.u16(entry + 2)? {
+                // SHORT
+                3 => self.u16(entry + 8).map(u32::from),
+                // LONG
+                4 => self.u32(entry + 8),
+                _ => None,
+            }
+        })
+    }
+
+    /// IFD 中 `tag` 的条目，返回类型、个数和值的位置。值不超过 4 个字节时在条目中，否则由偏移给出。
+    pub fn entry(&self, ifd: usize, tag: u16) -> Option<(u16, usize, usize)> {
+        let count = self.u16(ifd)? as usize;
+        let entry = (0..count)
+            .map(|i| ifd + 2 + 12 * i)
+            .find(|&entry| self.u16(entry) == Some(tag))?;
+        let kind = self.u16(entry + 2)?;
+        let n_values = self.u32(entry + 4)? as usize;
+        let size = match kind {
+            // BYTE、ASCII、SBYTE、UNDEFINED
+            1 | 2 | 6 | 7 => 1,
+            // SHORT、SSHORT
+            3 | 8 => 2,
+            // LONG、SLONG、FLOAT
+            4 | 9 | 11 => 4,
+            _ => 8,
+        };
+        let offset = match n_values.checked_mul(size)? {
+            0..=4 => entry + 8,
+            _ => self.u32(entry + 8)? as usize,
+        };
+        Some((kind, n_values, offset))
+    }
+
+    /// IFD 之后的下一个 IFD 的偏移，为 0 时表示没有。
+    fn next_ifd(&self, ifd: usize) -> Option<usize> {
+        let count = self.u16(ifd)? as usize;
+        match self.u32(ifd + 2 + 12 * count)? {
+            0 => None,
+            offset => Some(offset as usize),
+        }
+    }
+}
+
+/// 从 EXIF 的 APP1 中找到缩略图。缩略图在 IFD1 中，由偏移和长度给出，偏移相对于 TIFF 头。
+fn exif_thumbnail(data: &[u8]) -> Option<&[u8]> {
+    let tiff = data.strip_prefix(b"Exif\0\0")?;
+    let is_big_endian = match tiff.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let reader = TiffReader {
+        data: tiff,
+        is_big_endian,
+    };
+    if reader.u16(2)? != 42 {
+        return None;
+    }
+
+    let ifd0 = reader.u32(4)? as usize;
+    let ifd1 = reader.next_ifd(ifd0)?;
+    let offset = reader.ifd_value(ifd1, TAG_JPEG_INTERCHANGE_FORMAT)? as usize;
+    let length = reader.ifd_value(ifd1, TAG_JPEG_INTERCHANGE_FORMAT_LENGTH)? as usize;
+    tiff.get(offset..offset.checked_add(length)?)
+}
+
+/// 从 JFXX 扩展的 APP0 中找到 JPEG 缩略图。扩展码之后就是完整的 JPEG 文件。
+fn jfxx_thumbnail(data: &[u8]) -> Option<&[u8]> {
+    match data.strip_prefix(b"JFXX\0")? {
+        [JFXX_JPEG_THUMBNAIL, thumbnail @ ..] => Some(thumbnail),
+        _ => None,
+    }
+}
+
+/// JFIF 缩略图的最大边长。APP0 最长 65535 字节，85x85 的 RGB 缩略图是不超过上限的最大正方形。
+pub const MAX_JFIF_THUMBNAIL_SIZE: u32 = 85;
+
+/// 缩小 `image` 作为 JFIF 缩略图，保持宽高比，长边为 `size`（不超过 `MAX_JFIF_THUMBNAIL_SIZE`）。
+/// 图像本身更小时不放大。
+pub fn make_jfif_thumbnail(image: &RgbImage, size: u32) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let size = size
+        .clamp(1, MAX_JFIF_THUMBNAIL_SIZE)
+        .min(width.max(height));
+    let scale = |v: u32| {
+        (v as f64 * size as f64 / width.max(height) as f64)
+            .round()
+            .max(1.0) as u32
+    };
+    resize(image, scale(width), scale(height), FilterType::Triangle)
+}
+
+/// 找到嵌入的 JPEG 缩略图，不解码主图像。没有缩略图时返回 `None`。
+pub fn extract_thumbnail(jpeg: &[u8]) -> io::Result<Option<&[u8]>> {
+    let thumbnail = read_segments(jpeg)?
+        .into_iter()
+        .find_map(|segment| match segment.marker {
+            // APP0
+            0xE0 => jfxx_thumbnail(segment.data),
+            // APP1
+            0xE1 => exif_thumbnail(segment.data),
+            _ => None,
+        });
+    Ok(thumbnail.filter(|thumbnail| thumbnail.starts_with(&[0xFF, 0xD8])))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::super::encode_to_vec;
+
+    fn make_jpeg(width: u32, height: u32) -> Vec<u8> {
+        let image =
+            image::RgbImage::from_fn(width, height, |x, y| image::Rgb([x as u8, y as u8, 50]));
+        encode_to_vec(&image, &Default::default()).unwrap()
+    }
+
+    /// 在 SOI 之后插入一个段。
+    fn insert_segment(jpeg: &[u8], marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut ret = jpeg[..2].to_vec();
+        ret.extend_from_slice(&[0xFF, marker]);
+        ret.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        ret.extend_from_slice(data);
+        ret.extend_from_slice(&jpeg[2..]);
+        ret
+    }
+
+    /// 构造只有 IFD0（无条目）和 IFD1 的 EXIF。
+    fn make_exif(thumbnail: &[u8], is_big_endian: bool) -> Vec<u8> {
+        let u16_bytes = |v: u16| match is_big_endian {
+            true => v.to_be_bytes(),
+            false => v.to_le_bytes(),
+        };
+        let u32_bytes = |v: u32| match is_big_endian {
+            true => v.to_be_bytes(),
+            false => v.to_le_bytes(),
+        };
+
+        let mut tiff = vec![];
+        tiff.extend_from_slice(if is_big_endian { b"MM" } else { b"II" });
+        tiff.extend_from_slice(&u16_bytes(42));
+        tiff.extend_from_slice(&u32_bytes(8));
+        // IFD0：没有条目，下一个 IFD 在 14。
+        tiff.extend_from_slice(&u16_bytes(0));
+        tiff.extend_from_slice(&u32_bytes(14));
+        // IFD1：两个条目，缩略图紧接在 IFD1 之后。
+        let thumbnail_offset = 14 + 2 + 2 * 12 + 4;
+        tiff.extend_from_slice(&u16_bytes(2));
+        for (tag, value) in [
+            (TAG_JPEG_INTERCHANGE_FORMAT, thumbnail_offset),
+            (TAG_JPEG_INTERCHANGE_FORMAT_LENGTH, thumbnail.len() as u32),
+        ] {
+            tiff.extend_from_slice(&u16_bytes(tag));
+            tiff.extend_from_slice(&u16_bytes(4));
+            tiff.extend_from_slice(&u32_bytes(1));
+            tiff.extend_from_slice(&u32_bytes(value));
+        }
+        tiff.extend_from_slice(&u32_bytes(0));
+        tiff.extend_from_slice(thumbnail);
+
+        [b"Exif\0\0".as_slice(), &tiff].concat()
+    }
+
+    #[test]
+    fn test_exif_thumbnail() {
+        let main = make_jpeg(64, 48);
+        let thumbnail = make_jpeg(8, 6);
+        for is_big_endian in [true, false] {
+            let jpeg = insert_segment(&main, 0xE1, &make_exif(&thumbnail, is_big_endian));
+            assert_eq!(
+                extract_thumbnail(&jpeg).unwrap(),
+                Some(thumbnail.as_slice())
+            );
+        }
+    }
+
+    #[test]
+    fn test_jfxx_thumbnail() {
+        let main = make_jpeg(64, 48);
+        let thumbnail = make_jpeg(8, 6);
+        let data = [b"JFXX\0".as_slice(), &[JFXX_JPEG_THUMBNAIL], &thumbnail].concat();
+        let jpeg = insert_segment(&main, 0xE0, &data);
+        assert_eq!(
+            extract_thumbnail(&jpeg).unwrap(),
+            Some(thumbnail.as_slice())
+        );
+    }
+
+    #[test]
+    fn test_no_thumbnail() {
+        assert_eq!(extract_thumbnail(&make_jpeg(16, 8)).unwrap(), None);
+    }
+}
@@ -0,0 +1,278 @@
+//! 转码。只做熵解码，在系数上缩小或重新量化后再熵编码，不解码为像素，
+//! 避免完整解码再编码带来的额外损失，也快得多。
+
+use std::f64::consts::PI;
+use std::io;
+
+use lazy_static::lazy_static;
+
+use super::coefficients::BlockGrid;
+use super::coefficients::CoefficientImage;
+use super::encode_step3::DctDu;
+use super::encode_step3::COS_TABLE;
+use super::encode_step4::QuantizedDu;
+use super::encode_step4::CHROMINANCE_QUANTIZATION_TABLE;
+use super::encode_step4::LUMINANCE_QUANTIZATION_TABLE;
+use super::options::TranscodeOptions;
+
+/// 缩小的比例。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Scale {
+    /// 不缩小。
+    #[default]
+    #[value(name = "1")]
+    Full,
+    /// 缩小为 1/2。
+    #[value(name = "1/2")]
+    Half,
+    /// 缩小为 1/4。
+    #[value(name = "1/4")]
+    Quarter,
+}
+
+impl Scale {
+    /// 缩小的倍数，即新的一个块由原来多少个块拼成。
+    fn factor(self) -> usize {
+        match self {
+            Scale::Full => 1,
+            Scale::Half => 2,
+            Scale::Quarter => 4,
+        }
+    }
+}
+
+/// 缩小用的一维变换矩阵。每个块只保留前 `k` 个系数，`8 / k` 个块依次拼接成 8 个系数，
+/// 乘以该矩阵即得到新的块的 8 个系数：先以 k 点 IDCT 得到缩小后的 k 个像素，再对拼接的 8 个像素做 8 点 DCT。
+fn make_downscale_matrix(k: usize) -> [[f64; 8]; 8] {
+    let factor = |u: usize, n: usize| (if u == 0 { 1.0 } else { 2.0 } / n as f64).sqrt();
+    // 同样的像素，k 点 DCT 的系数是 8 点 DCT 的 sqrt(k / 8) 倍。
+    let gain = (k as f64 / 8.0).sqrt();
+    std::array::from_fn(|u| {
+        std::array::from_fn(|column| {
+            let (tile, v) = (column / k, column % k);
+            (0..k)
+                .map(|x| {
+                    let dct = factor(u, 8) * COS_TABLE[u][tile * k + x];
+                    let idct =
+                        factor(v, k) * (((2 * x + 1) * v) as f64 * PI / (2 * k) as f64).cos();
+                    gain * dct * idct
+                })
+                .sum()
+        })
+    })
+}
+
+lazy_static! {
+    static ref DOWNSCALE_HALF: [[f64; 8]; 8] = make_downscale_matrix(4);
+    static ref DOWNSCALE_QUARTER: [[f64; 8]; 8] = make_downscale_matrix(2);
+}
+
+/// 缩小所有分量。新的块由 `factor` x `factor` 个块的低频部分拼成，超出原图的块取最近的块。
+/// 不改变量化表：先反量化，拼成新的块后再以同一个量化表量化。
+fn downscale(image: &mut CoefficientImage, scale: Scale) {
+    let factor = scale.factor();
+    let matrix: &[[f64; 8]; 8] = match scale {
+        Scale::Full => return,
+        Scale::Half => &DOWNSCALE_HALF,
+        Scale::Quarter => &DOWNSCALE_QUARTER,
+    };
+    let k = 8 / factor;
+
+    image.width = image.width.div_ceil(factor);
+    image.height = image.height.div_ceil(factor);
+    let (mcus_per_row, mcu_rows) = image.mcu_grid();
+    for (i, grid) in image.grids.iter_mut().enumerate() {
+        let table = &image.layout.quantization_tables[i.min(1)];
+        let (h, v) = image.layout.sampling_factors[i];
+        let mut new_grid = BlockGrid::new(mcus_per_row * h as usize, mcu_rows * v as usize);
+        for y in 0..new_grid.height {
+            for x in 0..new_grid.width {
+                // 拼接各个块的低频部分，行为垂直频率，列为水平频率。
+                let mut tiled = [[0_f64; 8]; 8];
+                for ty in 0..factor {
+                    for tx in 0..factor {
+                        let source_x = (x * factor + tx).min(grid.width - 1);
+                        let source_y = (y * factor + ty).min(grid.height - 1);
+                        let dct_du = QuantizedDu(*grid.get(source_x, source_y)).to_dct_du(table);
+                        for r in 0..k {
+                            tiled[ty * k + r][tx * k..tx * k + k]
+                                .copy_from_slice(&dct_du.0[r][..k]);
+                        }
+                    }
+                }
+                // 两个方向分别变换：matrix * tiled * matrix^T。
+                let rows: [[f64; 8]; 8] = std::array::from_fn(|u| {
+                    std::array::from_fn(|c| (0..8).map(|r| matrix[u][r] * tiled[r][c]).sum())
+                });
+                let block: [[f64; 8]; 8] = std::array::from_fn(|u| {
+                    std::array::from_fn(|w| (0..8).map(|c| rows[u][c] * matrix[w][c]).sum())
+                });
+                *new_grid.get_mut(x, y) = DctDu(block).quantize(table).0;
+            }
+        }
+        *grid = new_grid;
+    }
+}
+
+/// 用新的量化表重新量化所有系数：先以原来的量化表反量化，再以新的量化表量化。
+fn requantize(image: &mut CoefficientImage, quality: u8) {
+    let new_tables = [
+        LUMINANCE_QUANTIZATION_TABLE.scaled(quality),
+        CHROMINANCE_QUANTIZATION_TABLE.scaled(quality),
+    ];
+    let old_tables = &image.layout.quantization_tables;
+    for (i, grid) in image.grids.iter_mut().enumerate() {
+        // Cb 和 Cr 共用色度量化表。
+        let table_idx = i.min(1);
+        for block in grid.blocks_mut() {
+            *block = QuantizedDu(*block)
+                .to_dct_du(&old_tables[table_idx])
+                .quantize(&new_tables[table_idx])
+                .0;
+        }
+    }
+    image.layout.quantization_tables = new_tables;
+}
+
+/// 在系数上转码 JPEG 文件，返回新的 JPEG 文件内容。支持的文件见 `CoefficientImage::read`。
+/// 输出总是基线 JPEG，保留输入的重启间隔。
+#[tracing::instrument(skip_all, fields(bytes = jpeg.len(), quality = options.quality, ?options.scale, ?options.huffman))]
+pub fn transcode(jpeg: &[u8], options: &TranscodeOptions) -> io::Result<Vec<u8>> {
+    let mut image = CoefficientImage::read(jpeg)?;
+    if options.strip {
+        image.metadata.clear();
+    }
+    downscale(&mut image, options.scale);
+    if let Some(quality) = options.quality {
+        requantize(&mut image, quality);
+    }
+    Ok(image.to_jpeg_with(options.huffman))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::RgbImage;
+
+    use super::super::decode_to_image;
+    use super::super::encode_step6::HuffmanMode;
+    use super::super::encode_to_vec;
+    use super::super::options::EncodeOptions;
+    use super::super::segments::read_segments;
+
+    fn make_image(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 5 + y * 2) as u8, (y * 7) as u8, ((x * y) % 256) as u8])
+        })
+    }
+
+    #[test]
+    fn test_requantize() {
+        let jpeg = encode_to_vec(&make_image(40, 20), &Default::default()).unwrap();
+
+        // 编码器使用的默认量化表即质量 50，重新量化后不变。
+        let options = |quality| TranscodeOptions {
+            quality: Some(quality),
+            ..Default::default()
+        };
+        let same = transcode(&jpeg, &options(50)).unwrap();
+        assert_eq!(same, jpeg);
+
+        let lower = transcode(&jpeg, &options(20)).unwrap();
+        assert!(lower.len() < jpeg.len());
+        let image = CoefficientImage::read(&lower).unwrap();
+        assert_eq!(
+            image.layout.quantization_tables[0].0,
+            LUMINANCE_QUANTIZATION_TABLE.scaled(20).0
+        );
+        let decoded = decode_to_image(&lower, &Default::default()).unwrap();
+        assert_eq!(decoded.dimensions(), (40, 20));
+    }
+
+    #[test]
+    fn test_downscale() {
+        let original = RgbImage::from_fn(64, 32, |x, y| {
+            image::Rgb([(40 + 2 * x) as u8, (60 + 3 * y) as u8, (100 + x + y) as u8])
+        });
+        let jpeg = encode_to_vec(&original, &Default::default()).unwrap();
+        let decoded = decode_to_image(&jpeg, &Default::default()).unwrap();
+
+        for (scale, factor) in [(Scale::Half, 2), (Scale::Quarter, 4)] {
+            let options = TranscodeOptions {
+                scale,
+                ..Default::default()
+            };
+            let smaller = transcode(&jpeg, &options).unwrap();
+            let downscaled = decode_to_image(&smaller, &Default::default()).unwrap();
+            assert_eq!(downscaled.dimensions(), (64 / factor, 32 / factor));
+
+            // 与解码后按块平均缩小的结果相近。
+            let expected = image::imageops::resize(
+                &decoded,
+                64 / factor,
+                32 / factor,
+                image::imageops::FilterType::Triangle,
+            );
+            let diff: f64 = downscaled
+                .as_raw()
+                .iter()
+                .zip(expected.as_raw())
+                .map(|(a, b)| a.abs_diff(*b) as f64)
+                .sum::<f64>()
+                / expected.as_raw().len() as f64;
+            assert!(diff < 3.0, "{:?}: {}", scale, diff);
+        }
+    }
+
+    #[test]
+    fn test_optimize_huffman() {
+        let jpeg = encode_to_vec(&make_image(40, 20), &Default::default()).unwrap();
+        let options = TranscodeOptions {
+            huffman: HuffmanMode::Optimized,
+            ..Default::default()
+        };
+        let optimized = transcode(&jpeg, &options).unwrap();
+        assert!(optimized.len() < jpeg.len());
+        // 与编码时直接优化霍夫曼表的结果相同。
+        let encoder_options = EncodeOptions {
+            huffman: HuffmanMode::Optimized,
+            ..Default::default()
+        };
+        assert_eq!(
+            optimized,
+            encode_to_vec(&make_image(40, 20), &encoder_options).unwrap()
+        );
+        assert!(
+            decode_to_image(&optimized, &Default::default()).unwrap()
+                == decode_to_image(&jpeg, &Default::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_optimize_progressive_with_restart_interval() {
+        let encoder_options = EncodeOptions {
+            progressive: true,
+            restart_interval: Some(3),
+            ..Default::default()
+        };
+        let jpeg = encode_to_vec(&make_image(40, 20), &encoder_options).unwrap();
+        let options = TranscodeOptions {
+            huffman: HuffmanMode::Optimized,
+            ..Default::default()
+        };
+        let optimized = transcode(&jpeg, &options).unwrap();
+        // 重新编码为基线 JPEG，保留重启间隔。
+        let markers: Vec<_> = read_segments(&optimized)
+            .unwrap()
+            .iter()
+            .map(|segment| (segment.marker, segment.data.to_vec()))
+            .collect();
+        assert!(markers.iter().any(|(marker, _)| *marker == 0xC0));
+        assert!(markers.contains(&(0xDD, vec![0, 3])));
+        assert!(
+            decode_to_image(&optimized, &Default::default()).unwrap()
+                == decode_to_image(&jpeg, &Default::default()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,352 @@
+//! 无损变换。只做熵解码，在 DCT 域中旋转或翻转系数块并重新排列 MCU，再重新熵编码。
+//! 不经过 IDCT 和量化，因此没有任何损失。
+
+use std::io;
+
+use super::coefficients::BlockGrid;
+use super::coefficients::CoefficientImage;
+use super::encode_step4::QuantizationTable;
+use super::encode_step7::FrameLayout;
+use super::options::TransformOptions;
+
+/// 顺时针旋转的角度。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Rotation {
+    #[value(name = "90")]
+    R90,
+    #[value(name = "180")]
+    R180,
+    #[value(name = "270")]
+    R270,
+}
+
+/// 翻转的方向。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Flip {
+    /// 左右翻转。
+    Horizontal,
+    /// 上下翻转。
+    Vertical,
+}
+
+/// 无损变换的操作。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Rotate(Rotation),
+    Flip(Flip),
+}
+
+impl Operation {
+    /// 是否交换宽和高。
+    fn is_transposed(self) -> bool {
+        matches!(
+            self,
+            Operation::Rotate(Rotation::R90) | Operation::Rotate(Rotation::R270)
+        )
+    }
+
+    /// 原图的水平方向和垂直方向是否被翻转。被翻转的方向上必须是整数个 MCU，否则填充的部分会转到图像的开头。
+    fn flips(self) -> (bool, bool) {
+        match self {
+            Operation::Rotate(Rotation::R90) => (false, true),
+            Operation::Rotate(Rotation::R180) => (true, true),
+            Operation::Rotate(Rotation::R270) => (true, false),
+            Operation::Flip(Flip::Horizontal) => (true, false),
+            Operation::Flip(Flip::Vertical) => (false, true),
+        }
+    }
+
+    /// 变换后位于 (x, y) 的块在原图中的位置。原图的块数为 `width` x `height`。
+    fn source_position(
+        self,
+        (x, y): (usize, usize),
+        width: usize,
+        height: usize,
+    ) -> (usize, usize) {
+        match self {
+            Operation::Rotate(Rotation::R90) => (y, height - 1 - x),
+            Operation::Rotate(Rotation::R180) => (width - 1 - x, height - 1 - y),
+            Operation::Rotate(Rotation::R270) => (width - 1 - y, x),
+            Operation::Flip(Flip::Horizontal) => (width - 1 - x, y),
+            Operation::Flip(Flip::Vertical) => (x, height - 1 - y),
+        }
+    }
+
+    /// 变换一个系数块。行号为垂直频率，列号为水平频率。
+    /// 转置对应系数的转置；像素水平翻转对应奇数列的系数取反，垂直翻转对应奇数行的系数取反。
+    fn transform_block(self, block: &[[i16; 8]; 8]) -> [[i16; 8]; 8] {
+        let sign = |n: usize| if n.is_multiple_of(2) { 1 } else { -1 };
+        std::array::from_fn(|u| {
+            std::array::from_fn(|v| match self {
+                Operation::Rotate(Rotation::R90) => sign(v) * block[v][u],
+                Operation::Rotate(Rotation::R180) => sign(u + v) * block[u][v],
+                Operation::Rotate(Rotation::R270) => sign(u) * block[v][u],
+                Operation::Flip(Flip::Horizontal) => sign(v) * block[u][v],
+                Operation::Flip(Flip::Vertical) => sign(u) * block[u][v],
+            })
+        })
+    }
+
+    /// 变换后的量化表。旋转 90 度和 270 度时系数被转置，量化表也要转置。
+    fn transform_table(self, table: &QuantizationTable) -> QuantizationTable {
+        if self.is_transposed() {
+            QuantizationTable(std::array::from_fn(|u| {
+                std::array::from_fn(|v| table.0[v][u])
+            }))
+        } else {
+            table.clone()
+        }
+    }
+}
+
+/// 被翻转的方向上不足一个 MCU 时，按 `trim` 丢弃多余的部分，或者返回错误。返回保留的像素数。
+fn trim_axis(size: usize, mcu_size: usize, trim: bool, name: &str) -> io::Result<usize> {
+    if size.is_multiple_of(mcu_size) {
+        return Ok(size);
+    }
+    if !trim {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "The {} {} is not a multiple of the MCU {} {}, so it cannot be rotated losslessly without trimming",
+                name, size, name, mcu_size
+            ),
+        ));
+    }
+    match size / mcu_size * mcu_size {
+        0 => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The {} is smaller than one MCU", name),
+        )),
+        trimmed => Ok(trimmed),
+    }
+}
+
+/// 需要时交换一对值，用于转置宽和高或采样因子。
+fn swap_if<T>(swap: bool, (a, b): (T, T)) -> (T, T) {
+    if swap {
+        (b, a)
+    } else {
+        (a, b)
+    }
+}
+
+/// 在 DCT 域中无损旋转或翻转 JPEG 文件，返回新的 JPEG 文件内容。
+/// 支持的文件见 `CoefficientImage::read`。
+#[tracing::instrument(skip_all, fields(bytes = jpeg.len(), operation = ?options.operation))]
+pub fn transform(jpeg: &[u8], options: &TransformOptions) -> io::Result<Vec<u8>> {
+    let operation = options.operation;
+    let mut image = CoefficientImage::read(jpeg)?;
+    if options.strip {
+        image.metadata.clear();
+    }
+
+    // 被翻转的方向上只保留整数个 MCU。
+    let (mcu_width, mcu_height) = image.mcu_size();
+    let (flip_x, flip_y) = operation.flips();
+    let width = match flip_x {
+        true => trim_axis(image.width, mcu_width, options.trim, "width")?,
+        false => image.width,
+    };
+    let height = match flip_y {
+        true => trim_axis(image.height, mcu_height, options.trim, "height")?,
+        false => image.height,
+    };
+    let mcus_per_row = width.div_ceil(mcu_width);
+    let mcu_rows = height.div_ceil(mcu_height);
+
+    let transposed = operation.is_transposed();
+    let (new_width, new_height) = swap_if(transposed, (width, height));
+    let layout = &image.layout;
+    let new_layout = FrameLayout {
+        sampling_factors: layout
+            .sampling_factors
+            .map(|pair| swap_if(transposed, pair)),
+        quantization_tables: [
+            operation.transform_table(&layout.quantization_tables[0]),
+            operation.transform_table(&layout.quantization_tables[1]),
+        ],
+    };
+
+    // 新位置 (x, y) 的块来自原图中变换前的位置。
+    let grids = [0, 1, 2].map(|i| {
+        let (h, v) = layout.sampling_factors[i];
+        let (old_width, old_height) = (mcus_per_row * h as usize, mcu_rows * v as usize);
+        let (grid_width, grid_height) = swap_if(transposed, (old_width, old_height));
+        let mut grid = BlockGrid::new(grid_width, grid_height);
+        for y in 0..grid_height {
+            for x in 0..grid_width {
+                let (old_x, old_y) = operation.source_position((x, y), old_width, old_height);
+                *grid.get_mut(x, y) = operation.transform_block(image.grids[i].get(old_x, old_y));
+            }
+        }
+        grid
+    });
+
+    Ok(CoefficientImage {
+        width: new_width,
+        height: new_height,
+        layout: new_layout,
+        grids,
+        metadata: image.metadata,
+        restart_interval: image.restart_interval,
+    }
+    .to_jpeg())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::RgbImage;
+
+    use super::super::decode_to_image;
+    use super::super::encode_step7::ToVec;
+    use super::super::encode_to_vec;
+    use super::super::segments::read_segments;
+    use super::super::segments::Segment;
+
+    fn make_image(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 5 + y * 2) as u8, (y * 7) as u8, ((x * y) % 256) as u8])
+        })
+    }
+
+    fn apply(jpeg: &[u8], operation: Operation) -> Vec<u8> {
+        let options = TransformOptions {
+            operation,
+            trim: false,
+            strip: false,
+        };
+        transform(jpeg, &options).unwrap()
+    }
+
+    fn rotate(jpeg: &[u8], rotation: Rotation) -> Vec<u8> {
+        apply(jpeg, Operation::Rotate(rotation))
+    }
+
+    fn flip(jpeg: &[u8], flip: Flip) -> Vec<u8> {
+        apply(jpeg, Operation::Flip(flip))
+    }
+
+    #[test]
+    fn test_rotate_round_trip() {
+        // 系数只是换了位置和符号，转回原来的方向后与原文件完全相同。
+        let jpeg = encode_to_vec(&make_image(48, 24), &Default::default()).unwrap();
+        let mut rotated = jpeg.clone();
+        for _ in 0..4 {
+            rotated = rotate(&rotated, Rotation::R90);
+        }
+        assert_eq!(rotated, jpeg);
+        assert_eq!(rotate(&rotate(&jpeg, Rotation::R180), Rotation::R180), jpeg);
+        assert_eq!(rotate(&rotate(&jpeg, Rotation::R90), Rotation::R270), jpeg);
+    }
+
+    #[test]
+    fn test_flip_round_trip() {
+        let jpeg = encode_to_vec(&make_image(48, 24), &Default::default()).unwrap();
+        assert_eq!(flip(&flip(&jpeg, Flip::Horizontal), Flip::Horizontal), jpeg);
+        assert_eq!(flip(&flip(&jpeg, Flip::Vertical), Flip::Vertical), jpeg);
+        // 先左右翻转再上下翻转，等于旋转 180 度。
+        assert_eq!(
+            flip(&flip(&jpeg, Flip::Horizontal), Flip::Vertical),
+            rotate(&jpeg, Rotation::R180)
+        );
+    }
+
+    #[test]
+    fn test_transform_pixels() {
+        let jpeg = encode_to_vec(&make_image(48, 24), &Default::default()).unwrap();
+        let decoded = decode_to_image(&jpeg, &Default::default()).unwrap();
+
+        let cases = [
+            (
+                Operation::Rotate(Rotation::R90),
+                image::imageops::rotate90(&decoded),
+            ),
+            (
+                Operation::Rotate(Rotation::R180),
+                image::imageops::rotate180(&decoded),
+            ),
+            (
+                Operation::Rotate(Rotation::R270),
+                image::imageops::rotate270(&decoded),
+            ),
+            (
+                Operation::Flip(Flip::Horizontal),
+                image::imageops::flip_horizontal(&decoded),
+            ),
+            (
+                Operation::Flip(Flip::Vertical),
+                image::imageops::flip_vertical(&decoded),
+            ),
+        ];
+        for (operation, expected) in cases {
+            let transformed =
+                decode_to_image(&apply(&jpeg, operation), &Default::default()).unwrap();
+            assert_eq!(transformed.dimensions(), expected.dimensions());
+            // 只有 IDCT 的舍入误差。
+            for (a, b) in transformed.as_raw().iter().zip(expected.as_raw()) {
+                assert!(a.abs_diff(*b) <= 2, "{:?}", operation);
+            }
+        }
+    }
+
+    #[test]
+    fn test_metadata() {
+        let exif = Segment {
+            marker: 0xE1,
+            data: b"Exif\0\0MM\0\x2A\0\0\0\x08\0\0",
+        };
+        let comment = Segment {
+            marker: 0xFE,
+            data: b"a comment",
+        };
+        let jpeg = encode_to_vec(&make_image(48, 24), &Default::default()).unwrap();
+        let jpeg = [&jpeg[..2], &exif.to_vec(), &comment.to_vec(), &jpeg[2..]].concat();
+
+        // 原样保留，且顺序不变。
+        let rotated = rotate(&jpeg, Rotation::R90);
+        let metadata: Vec<Vec<u8>> = read_segments(&rotated)
+            .unwrap()
+            .iter()
+            .filter(|segment| segment.is_metadata())
+            .map(|segment| segment.to_vec())
+            .collect();
+        assert_eq!(metadata, [exif.to_vec(), comment.to_vec()]);
+
+        let options = TransformOptions {
+            operation: Operation::Rotate(Rotation::R90),
+            trim: false,
+            strip: true,
+        };
+        let stripped = transform(&jpeg, &options).unwrap();
+        assert!(!read_segments(&stripped)
+            .unwrap()
+            .iter()
+            .any(|segment| segment.is_metadata()));
+    }
+
+    #[test]
+    fn test_trim() {
+        let jpeg = encode_to_vec(&make_image(37, 21), &Default::default()).unwrap();
+        let options = |operation, trim| TransformOptions {
+            operation,
+            trim,
+            strip: false,
+        };
+        let rotate_90 = Operation::Rotate(Rotation::R90);
+        assert!(transform(&jpeg, &options(rotate_90, false)).is_err());
+
+        let rotated = transform(&jpeg, &options(rotate_90, true)).unwrap();
+        let decoded = decode_to_image(&rotated, &Default::default()).unwrap();
+        assert_eq!(decoded.dimensions(), (16, 37));
+
+        // 左右翻转只要求宽度是整数个 MCU，高度不受限制。
+        let flip_horizontal = Operation::Flip(Flip::Horizontal);
+        assert!(transform(&jpeg, &options(flip_horizontal, false)).is_err());
+        let flipped = transform(&jpeg, &options(flip_horizontal, true)).unwrap();
+        let decoded = decode_to_image(&flipped, &Default::default()).unwrap();
+        assert_eq!(decoded.dimensions(), (32, 21));
+    }
+}
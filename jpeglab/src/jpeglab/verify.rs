@@ -0,0 +1,190 @@
+//! 编码后的自检：用自己的解码器重新解析刚生成的 JPEG 文件并熵解码，
+//! 与编码时的统计信息比较，尽早发现码流的错误（字节填充、末尾填充、码表不一致等）。
+
+use std::io;
+
+use super::decode_step1::decode_step1;
+use super::decode_step2::ScanDecoder;
+use super::encode_step6::JpegOutputData;
+use super::encode_step6::ScanSummary;
+use super::encode_step7::FrameLayout;
+
+fn mismatch(what: &str, expected: impl std::fmt::Debug, actual: impl std::fmt::Debug) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Verification failed: {} mismatch, expected {:?}, found {:?}",
+            what, expected, actual
+        ),
+    )
+}
+
+/// 检查 `jpeg` 是否为 `data` 的正确编码结果，`layout` 为编码时使用的采样因子和量化表。
+#[tracing::instrument(skip_all, fields(bytes = jpeg.len()))]
+pub fn verify_jpeg(jpeg: &[u8], data: &JpegOutputData, layout: &FrameLayout) -> io::Result<()> {
+    if data.arithmetic_scan.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Verification of arithmetic coding is not supported",
+        ));
+    }
+    if !data.component_scans.is_empty() {
+        // 非交织的扫描不编码为补齐 MCU 而填充的 DU，解码出的 DC 之和与编码时不同。
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Verification of non-interleaved scans is not supported",
+        ));
+    }
+    let jpeg_data = decode_step1(jpeg)?;
+
+    // 文件头。
+    let dimensions = (jpeg_data.width, jpeg_data.height);
+    let expected_dimensions = (data.original_width, data.original_height);
+    if dimensions != expected_dimensions {
+        return Err(mismatch("dimension", expected_dimensions, dimensions));
+    }
+    let sampling_factors: Vec<_> = jpeg_data
+        .components
+        .iter()
+        .map(|c| (c.horizontal_sampling_factor, c.vertical_sampling_factor))
+        .collect();
+    let expected_sampling_factors = layout.sampling_factors;
+    if sampling_factors != expected_sampling_factors {
+        return Err(mismatch(
+            "sampling factor",
+            expected_sampling_factors,
+            sampling_factors,
+        ));
+    }
+    let [luminance_table, chrominance_table] = &layout.quantization_tables;
+    let quantization_tables = [luminance_table, chrominance_table, chrominance_table];
+    for (i, (component, expected)) in jpeg_data
+        .components
+        .iter()
+        .zip(quantization_tables)
+        .enumerate()
+    {
+        if component.quatization_table.0 != expected.0 {
+            return Err(mismatch(
+                &format!("quantization table of component {}", i),
+                expected.0,
+                component.quatization_table.0,
+            ));
+        }
+    }
+
+    // 熵解码，重新统计。
+    let (mcus_per_row, mcu_rows) = jpeg_data.get_mcu_grid();
+    let mut summary = ScanSummary {
+        mcu_count: mcus_per_row * mcu_rows,
+        ..Default::default()
+    };
+    if summary.mcu_count != data.summary.mcu_count {
+        return Err(mismatch(
+            "MCU count",
+            data.summary.mcu_count,
+            summary.mcu_count,
+        ));
+    }
+    let mut decoder = ScanDecoder::new(&jpeg_data);
+    for _ in 0..summary.mcu_count {
+        decoder.decode_mcu(|i, _, du| summary.dc_sums[i] += du.0[0] as i64)?;
+    }
+    if summary.dc_sums != data.summary.dc_sums {
+        return Err(mismatch("DC sum", data.summary.dc_sums, summary.dc_sums));
+    }
+    // 最后一个字节中只能剩下填充的位。
+    if decoder.remaining_bits() >= 8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Verification failed: {} bits left after the last MCU",
+                decoder.remaining_bits()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use image::RgbImage;
+
+    use super::super::encode_step1::Subsampling;
+    use super::super::encode_step4::QuantizationPreset;
+    use super::super::encode_step7::make_jpeg;
+    use super::super::encode_to_vec;
+    use super::super::stripe::encode_striped;
+    use super::super::test_util::test_image;
+    use super::super::EncodeOptions;
+
+    #[test]
+    fn test_verify() {
+        let image = test_image();
+        let options = EncodeOptions::default();
+        let data = encode_striped(&image, &options).unwrap();
+        let jpeg = make_jpeg(&data, &options);
+        verify_jpeg(&jpeg, &data, &options.frame_layout()).unwrap();
+
+        // 文件中的量化表与编码时不一致。
+        let flat = EncodeOptions {
+            quantization: QuantizationPreset::Flat,
+            ..Default::default()
+        };
+        assert!(verify_jpeg(&jpeg, &data, &flat.frame_layout()).is_err());
+
+        // 文件中的采样因子与编码时不一致。
+        let yuv440 = EncodeOptions {
+            subsampling: Subsampling::Yuv440,
+            ..Default::default()
+        };
+        assert!(verify_jpeg(&jpeg, &data, &yuv440.frame_layout()).is_err());
+
+        // 码流被截断。
+        let eoi = jpeg.len() - 2;
+        let mut truncated = jpeg[..eoi - 4].to_vec();
+        truncated.extend_from_slice(&jpeg[eoi..]);
+        assert!(verify_jpeg(&truncated, &data, &options.frame_layout()).is_err());
+
+        // 编码时的统计与文件不一致。
+        let mut wrong = encode_striped(&image, &options).unwrap();
+        wrong.summary.dc_sums[1] += 1;
+        assert!(verify_jpeg(&jpeg, &wrong, &options.frame_layout()).is_err());
+    }
+
+    #[test]
+    fn test_verify_presets() {
+        use clap::ValueEnum;
+
+        let image = RgbImage::from_fn(24, 16, |x, y| {
+            image::Rgb([(x * 10) as u8, (y * 15) as u8, ((x + y) * 6) as u8])
+        });
+        for &quantization in QuantizationPreset::value_variants() {
+            let options = EncodeOptions {
+                quantization,
+                verify: true,
+                ..Default::default()
+            };
+            encode_to_vec(&image, &options).unwrap();
+        }
+        for &subsampling in Subsampling::value_variants() {
+            let options = EncodeOptions {
+                subsampling,
+                verify: true,
+                ..Default::default()
+            };
+            encode_to_vec(&image, &options).unwrap();
+        }
+        for restart_interval in [1, 2, 5] {
+            let options = EncodeOptions {
+                restart_interval: Some(restart_interval),
+                verify: true,
+                ..Default::default()
+            };
+            encode_to_vec(&image, &options).unwrap();
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Zigzag 顺序。DU 的系数和量化表都以 Zigzag 顺序存储。
+
+/// `ZIGZAG[i]` 为 Zigzag 顺序中第 i 个元素在 8x8 块中的位置 `(x, y)`，x 为行，y 为列。
+pub const ZIGZAG: [(usize, usize); 64] = [
+    (0, 0),
+    (0, 1),
+    (1, 0),
+    (2, 0),
+    (1, 1),
+    (0, 2),
+    (0, 3),
+    (1, 2),
+    (2, 1),
+    (3, 0),
+    (4, 0),
+    (3, 1),
+    (2, 2),
+    (1, 3),
+    (0, 4),
+    (0, 5),
+    (1, 4),
+    (2, 3),
+    (3, 2),
+    (4, 1),
+    (5, 0),
+    (6, 0),
+    (5, 1),
+    (4, 2),
+    (3, 3),
+    (2, 4),
+    (1, 5),
+    (0, 6),
+    (0, 7),
+    (1, 6),
+    (2, 5),
+    (3, 4),
+    (4, 3),
+    (5, 2),
+    (6, 1),
+    (7, 0),
+    (7, 1),
+    (6, 2),
+    (5, 3),
+    (4, 4),
+    (3, 5),
+    (2, 6),
+    (1, 7),
+    (2, 7),
+    (3, 6),
+    (4, 5),
+    (5, 4),
+    (6, 3),
+    (7, 2),
+    (7, 3),
+    (6, 4),
+    (5, 5),
+    (4, 6),
+    (3, 7),
+    (4, 7),
+    (5, 6),
+    (6, 5),
+    (7, 4),
+    (7, 5),
+    (6, 6),
+    (5, 7),
+    (6, 7),
+    (7, 6),
+    (7, 7),
+];
+
+/// 将 8x8 块按 Zigzag 顺序展开。
+pub fn to_zigzag<T: Copy>(block: &[[T; 8]; 8]) -> [T; 64] {
+    std::array::from_fn(|i| {
+        let (x, y) = ZIGZAG[i];
+        block[x][y]
+    })
+}
+
+/// 将 Zigzag 顺序的数据还原为 8x8 块。
+pub fn from_zigzag<T: Copy + Default>(data: &[T; 64]) -> [[T; 8]; 8] {
+    let mut ret = [[T::default(); 8]; 8];
+    for (value, &(x, y)) in data.iter().zip(ZIGZAG.iter()) {
+        ret[x][y] = *value;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_zigzag_table() {
+        // 每个位置恰好出现一次，且相邻两个元素在块中相邻。
+        let mut seen = [[false; 8]; 8];
+        for &(x, y) in &ZIGZAG {
+            assert!(!seen[x][y]);
+            seen[x][y] = true;
+        }
+        for pair in ZIGZAG.windows(2) {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            assert!(x0.abs_diff(x1) <= 1 && y0.abs_diff(y1) <= 1);
+        }
+
+        let data: [u16; 64] = std::array::from_fn(|i| i as u16);
+        assert_eq!(to_zigzag(&from_zigzag(&data)), data);
+    }
+}
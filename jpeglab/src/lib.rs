@@ -0,0 +1,3 @@
+mod jpeglab;
+
+pub use jpeglab::*;
@@ -1,69 +1,1599 @@
-mod jpeglab;
-
 use std::fs::File;
 use std::io;
 use std::io::Read;
 use std::path::Path;
+use std::path::PathBuf;
 
 use clap::Parser;
+use clap::Subcommand;
 use image::io::Reader as ImageReader;
 use image::ColorType;
 use image::GenericImageView;
+use jpeglab::component::Component;
+use jpeglab::encode_step1::ColorConversion;
+use jpeglab::encode_step1::ColorMatrix;
+use jpeglab::encode_step1::ColorRange;
+use jpeglab::encode_step1::SamplePrecision;
+use jpeglab::encode_step1::Subsampling;
+use jpeglab::encode_step3::DctPrecision;
+use jpeglab::encode_step4::QuantizationPreset;
+use jpeglab::encode_step6::EntropyCoding;
+use jpeglab::encode_step6::HuffmanMode;
+use jpeglab::encode_step7::Density;
+use jpeglab::encode_step7::DensityUnits;
+use jpeglab::encode_step7::DhtLayout;
+use jpeglab::encode_step7::DqtLayout;
+use jpeglab::encode_step7::DqtPrecision;
+use jpeglab::hvs::ViewingConditions;
+use jpeglab::metadata::ExifData;
+use jpeglab::metadata::IccProfile;
+use jpeglab::metadata::XmpPacket;
+use jpeglab::sharpen::Sharpening;
+use jpeglab::tables::TableFormat;
+use jpeglab::transcode::Scale;
+use jpeglab::transform::Flip;
+use jpeglab::transform::Operation;
+use jpeglab::transform::Rotation;
+use jpeglab::DecodeOptions;
+use jpeglab::EncodeOptions;
+use jpeglab::TranscodeOptions;
+use jpeglab::TransformOptions;
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(
+        required = true,
         help = "Input image file",
         long_help = "Input image file. To compress an image, the extension must be bmp. To uncompress an image, the extension must be jpg."
     )]
+    input: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ColorMatrix::Bt601,
+        help = "YCbCr color matrix",
+        long_help = "YCbCr color matrix used by both encoding and decoding. JFIF files are expected to use bt601; a file encoded with bt709 must also be decoded with bt709."
+    )]
+    matrix: ColorMatrix,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ColorRange::Full,
+        help = "YCbCr value range",
+        long_help = "YCbCr value range used by both encoding and decoding. JFIF files are expected to use full; limited (studio swing, Y in 16-235 and Cb/Cr in 16-240) is for interop with video-derived YUV data."
+    )]
+    range: ColorRange,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Subsampling::Yuv422,
+        help = "Chroma subsampling used when encoding",
+        long_help = "Chroma subsampling used when encoding. 422 halves the chroma horizontally (16x8 MCUs), 411 keeps a quarter of it horizontally (32x8 MCUs), and 440 halves it vertically (8x16 MCUs). Each chroma sample is taken from the top left pixel it covers. 4:2:2, 4:1:1 and 4:4:0 are accepted as well."
+    )]
+    subsampling: Subsampling,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = SamplePrecision::Eight,
+        help = "Bits per sample when encoding, 8 or 12",
+        long_help = "Bits per sample when encoding. 12 writes an extended sequential JPEG (SOF1, or SOF9 with arithmetic coding) that keeps more of the precision of 16-bit PNG or TIFF input, whose samples are scaled to 0-4095; 8-bit input is scaled up. 12-bit files always use optimized Huffman tables, because the standard tables lack the larger categories. Many decoders, including the one of this program, only read 8-bit files, so 12-bit encoding cannot be combined with --verify, --striped, --progressive, --smooth, --report, --huffman-stats, --dump-steps or --trace-bits."
+    )]
+    sample_precision: SamplePrecision,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = DctPrecision::F64,
+        visible_alias = "dct",
+        help = "Implementation of the DCT and IDCT",
+        long_help = "Implementation of the DCT and IDCT, used by both encoding and decoding. f64 (alias float) is the reference floating point transform and f32 the same in single precision; fast and accurate are the integer transforms of libjpeg (JDCT_IFAST, the AAN algorithm with 8-bit constants and truncation, and JDCT_ISLOW, the LLM algorithm with 13-bit constants), for comparing their error against the reference."
+    )]
+    dct_precision: DctPrecision,
+
+    #[arg(
+        long,
+        value_name = "FACTOR",
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        help = "Smooth the input before encoding, from 1 to 100",
+        long_help = "Smooth the input before encoding, like cjpeg -smooth. Every sample is averaged with its 8 neighbours, and the larger the factor, the more the neighbours weigh. Suppresses dithering noise, e.g. in images converted from GIF, which otherwise costs many bits in high frequencies."
+    )]
+    smooth: Option<u8>,
+
+    #[arg(
+        long,
+        value_name = "STRENGTH",
+        value_parser = parse_positive,
+        help = "Sharpen the image after decoding",
+        long_help = "Sharpen the image after decoding with an unsharp mask: the difference between the image and its Gaussian blur, times STRENGTH, is added back. Counteracts the softness from quantization and chroma upsampling for display; it does not recover lost detail and usually lowers PSNR. 0.5 is a moderate strength."
+    )]
+    sharpen: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        value_parser = parse_positive,
+        default_value_t = Sharpening::default().radius,
+        help = "Radius of the blur used by --sharpen",
+        long_help = "Standard deviation of the Gaussian blur used by --sharpen, in pixels. The larger the radius, the coarser the details that are enhanced."
+    )]
+    sharpen_radius: f64,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = QuantizationPreset::AnnexK,
+        help = "Quantization tables used when encoding",
+        long_help = "Quantization tables used when encoding. jpeg-annexk is the example tables of the JPEG standard (Annex K.1), used so far; flat quantizes every frequency by 16 and keeps much more high-frequency detail at a much larger size; mozjpeg is the default table of mozjpeg (by N. Robidoux), the same for luma and chroma, smoother towards high frequencies with less blocking and ringing; hvs is the mozjpeg table tuned for PSNR-HVS, finer than Annex K at low luma frequencies and very coarse for chroma; perceptual generates the tables from a contrast sensitivity model of the eye for the viewing conditions given by --viewing-distance and --dpi."
+    )]
+    preset: QuantizationPreset,
+
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        help = "Scale the quantization tables to this quality, from 1 to 100",
+        long_help = "Scale the quantization tables of --preset to this quality when encoding, from 1 to 100, as libjpeg does: 50 keeps the tables, higher values quantize more finely and lower values more coarsely. The scaled values are clamped to 1..255, and the DQT segments contain the scaled tables. Without this option the tables of --preset are used unchanged."
+    )]
+    quality: Option<u8>,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        conflicts_with = "quality",
+        help = "Choose the quality so that the file fits in SIZE bytes, e.g. 200k",
+        long_help = "Choose the --quality so that the encoded file, including all marker segments, is at most SIZE bytes. SIZE is a number of bytes, optionally followed by k or M for 1024 or 1024*1024 bytes, e.g. 200k. The encoder bisects over the quality from 1 to 100, reusing the DCT coefficients and only quantizing and entropy coding again, and stops at the highest quality that fits or once the file is within 2% below SIZE. Fails if the file is too large even at quality 1. Cannot be combined with --striped."
+    )]
+    target_size: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "INCHES",
+        default_value_t = ViewingConditions::default().distance,
+        help = "Viewing distance for --preset perceptual",
+        long_help = "Distance from the eye to the screen in inches, used by --preset perceptual. The farther away, the higher the DCT frequencies appear, and the coarser they are quantized."
+    )]
+    viewing_distance: f64,
+
+    #[arg(
+        long,
+        default_value_t = ViewingConditions::default().dpi,
+        help = "Pixel density of the screen for --preset perceptual",
+        long_help = "Pixel density of the screen in pixels per inch, used by --preset perceptual. The denser the pixels, the higher the DCT frequencies appear, and the coarser they are quantized."
+    )]
+    dpi: f64,
+
+    #[arg(
+        long,
+        help = "Process the image one MCU row at a time",
+        long_help = "Process the image one MCU row at a time, so that memory usage does not grow with the image height. The output is identical. Intermediate results are not shown in this mode."
+    )]
+    striped: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = DqtLayout::Combined,
+        help = "How quantization tables are written when encoding",
+        long_help = "How quantization tables are written when encoding. combined puts all tables in one DQT segment, which most encoders do; separate writes one DQT segment per table."
+    )]
+    dqt_layout: DqtLayout,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = HuffmanMode::Default,
+        help = "Huffman tables used when encoding",
+        long_help = "Huffman tables used when encoding. default uses the example tables of the JPEG standard (Annex K.3); optimized counts the symbols of this image first and builds the optimal tables for them, encoding the image twice, and prints how many bits of Y, Cb and Cr it saves compared with the default tables. optimized cannot be combined with --striped."
+    )]
+    huffman: HuffmanMode,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = EntropyCoding::Huffman,
+        help = "Entropy coding used when encoding",
+        long_help = "Entropy coding used when encoding. huffman writes a baseline file (SOF0) with DHT segments; arithmetic uses the adaptive binary arithmetic coder of the JPEG standard (Annex D and F.1.4) instead and writes SOF9 with a DAC segment. Arithmetic coding needs no tables and is usually 5-10% smaller, and the size of the Huffman-coded scan is printed for comparison, but many decoders, including ours, cannot read it. Cannot be combined with --progressive or --huffman optimized."
+    )]
+    entropy_coding: EntropyCoding,
+
+    #[arg(
+        long,
+        help = "Write a progressive JPEG (SOF2)",
+        long_help = "Write a progressive JPEG (SOF2) with spectral selection: the DC coefficients of all components first, then the AC coefficients 1 to 5 of Y, the AC coefficients of Cb and Cr, and the remaining AC coefficients of Y, each in its own scan. Successive approximation is not used. Cannot be combined with --striped or --huffman optimized."
+    )]
+    progressive: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "progressive",
+        help = "Write one scan per component instead of one interleaved scan",
+        long_help = "Write a baseline JPEG with one scan per component, Y first, then Cb and Cr, instead of a single scan in which the components are interleaved MCU by MCU. Each scan only codes the blocks that overlap the image, in raster order of the component. The coefficients are the same, so the decoded image is identical. Cannot be combined with --striped, --progressive or arithmetic coding."
+    )]
+    non_interleaved: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Insert a restart marker every N MCUs when encoding",
+        long_help = "Insert a restart marker (RST0 to RST7, in turn) every N MCUs when encoding, and write the interval in a DRI segment. At every marker the bitstream is padded to a byte boundary and the DC prediction starts over, so a decoder can resynchronize after corrupted data, or decode the intervals in parallel."
+    )]
+    restart_interval: Option<u16>,
+
+    #[arg(
+        long,
+        help = "Check the encoded file with our own decoder",
+        long_help = "After encoding, re-parse and entropy-decode the written file with our own decoder, and check the header, the MCU count and the sums of the DC coefficients against the encoder. Catches bitstream bugs before the file is opened elsewhere."
+    )]
+    verify: bool,
+
+    #[arg(
+        long,
+        help = "Print the statistics of every Huffman table after encoding",
+        long_help = "After encoding, print for every Huffman table the number of symbols, the average code length, the zeroth-order entropy and the redundancy, i.e. how many bits per symbol a table fitted to this image could save at most. The entropy subcommand prints the same report for any JPEG file."
+    )]
+    huffman_stats: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = DqtPrecision::Auto,
+        help = "Precision of the quantization tables written when encoding",
+        long_help = "Precision of the quantization tables written when encoding. auto uses 8-bit precision when every value fits in a byte, which is what baseline decoders expect; bits16 always writes 16-bit tables."
+    )]
+    dqt_precision: DqtPrecision,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = DhtLayout::Separate,
+        help = "How Huffman tables are written when encoding",
+        long_help = "How Huffman tables are written when encoding. separate writes one DHT segment per table; combined puts all tables in one DHT segment, as libjpeg does."
+    )]
+    dht_layout: DhtLayout,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Write the bits spent on every MCU to DIR when encoding",
+        long_help = "Write the bits spent on every MCU to DIR when encoding: mcu_bits.csv lists the bits of Y, Cb and Cr in every MCU, and bit_density.png is a grayscale map aligned to the image, brighter where more bits are spent."
+    )]
+    bit_cost: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Write the complete output of steps 1 to 5 to DIR when encoding",
+        long_help = "Write the complete output of steps 1 to 5 to DIR when encoding: the padded Y, U and V planes as PGM, and the DUs of every later step as little-endian binary files, one per component. DIR/steps.json describes the type, order and count of every file. Ignored with --striped."
+    )]
+    dump_steps: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Log every entropy-coded symbol to FILE",
+        long_help = "Log every entropy-coded symbol to FILE, one line per symbol: the MCU, the component and DU, the zigzag position, the DC difference or the AC run and value, the category, the Huffman symbol and the emitted bits. Encoding logs what step 6 writes and decoding logs what step 2 reads, so the two logs of the same file are identical. Meant for small images; ignored with --striped when encoding."
+    )]
+    trace_bits: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write an HTML report of the encoding to FILE",
+        long_help = "Write an HTML report of the encoding to FILE: the input image, the Y, U and V planes of step 1, the coefficient heatmaps, the bit density map, the quantization and Huffman tables, and the final size, PSNR, SSIM and Huffman statistics. Images are embedded, so the file can be opened or shared on its own."
+    )]
+    report: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the frames of an animated input to one Motion JPEG stream",
+        long_help = "Write every frame of an animated input (e.g. an animated GIF) to FILE as one Motion JPEG stream, instead of one numbered JPEG file per frame. Ignored when the input has a single frame."
+    )]
+    mjpeg: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Decode to a 16-bit PNG or TIFF file instead of out.bmp",
+        long_help = "Decode to FILE with 16 bits per sample instead of out.bmp. The format follows the extension, .png or .tif. The IDCT and the color conversion are not rounded to 8 bits in between, which avoids rounding twice when the image is processed further. The IDCT is always the f64 one, and --striped and --sharpen are ignored."
+    )]
+    output16: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Give the output files the modification time of the input",
+        long_help = "Give the output files of encoding or decoding (out.jpg, out.bmp, the numbered frames, --mjpeg or --output16) the modification time of the input, and on Unix also its permission bits, so that archive and sync tools treat them like the source."
+    )]
+    preserve_times: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Embed the XMP packet in FILE when encoding",
+        long_help = "Embed the XMP packet in FILE when encoding. The file must contain the whole XML packet, and it is written unchanged in an APP1 segment. A packet that does not fit in one segment (about 64KB) is written as extended XMP: the whole packet, without its xpacket wrapper, is split across APP1 segments marked http://ns.adobe.com/xmp/extension/, and the main APP1 only holds xmpNote:HasExtendedXMP with the MD5 digest that identifies them."
+    )]
+    xmp: Option<String>,
+
+    #[arg(
+        long,
+        help = "Write an Adobe APP14 segment marking the components as YCbCr",
+        long_help = "Write an Adobe APP14 segment after APP0 when encoding, with the color transform set to YCbCr. Some decoders, especially those written for Adobe files, decide the color space from APP14 rather than from JFIF. The image data is unchanged."
+    )]
+    adobe_app14: bool,
+
+    #[arg(
+        long,
+        value_name = "X[xY]",
+        value_parser = parse_density,
+        help = "Pixel density written to JFIF APP0, e.g. 300 or 300x150",
+        long_help = "Pixel density written to the JFIF APP0 segment when encoding, in the units of --density-units: one number for both directions, or XxY for different horizontal and vertical densities, e.g. 300 or 300x150, each from 1 to 65535. Without this option APP0 has no units and a density of 1x1, i.e. square pixels, as before. Unrelated to --dpi, which describes the screen for --preset perceptual."
+    )]
+    density: Option<(u16, u16)>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = DensityUnits::Inch,
+        requires = "density",
+        help = "Units of --density",
+        long_help = "Units of --density: inch for dots per inch, cm for dots per centimetre, or none when the density only gives the aspect ratio of the pixels."
+    )]
+    density_units: DensityUnits,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = clap::value_parser!(u32).range(1..=85),
+        help = "Embed an RGB thumbnail of at most SIZE pixels in APP0 when encoding",
+        long_help = "Embed an uncompressed RGB thumbnail in the JFIF APP0 segment when encoding. The image is scaled down so that its longer side is SIZE pixels, keeping the aspect ratio; 85 is the largest size that fits in one segment. Without this option no thumbnail is written, as before; some file browsers show the thumbnail without decoding the whole image."
+    )]
+    jfif_thumbnail: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Embed the ICC profile in FILE when encoding",
+        long_help = "Embed the ICC profile in FILE when encoding, for color-managed viewers. The profile is split into APP2 segments marked ICC_PROFILE as in the ICC specification, each holding at most 65519 bytes, so profiles larger than 64KB take several segments. Only the header of the profile is checked."
+    )]
+    icc: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "LAT,LON",
+        value_parser = parse_gps,
+        allow_hyphen_values = true,
+        help = "Write the GPS position into EXIF when encoding",
+        long_help = "Write the GPS position into the EXIF GPS IFD when encoding. LAT and LON are decimal degrees, positive for north and east, e.g. 31.2304,121.4737."
+    )]
+    gps: Option<(f64, f64)>,
+
+    #[arg(
+        long,
+        value_name = "\"YYYY:MM:DD HH:MM:SS\"",
+        help = "Write the date and time into EXIF when encoding",
+        long_help = "Write the date and time into the EXIF DateTime tag when encoding, in the EXIF format \"YYYY:MM:DD HH:MM:SS\"."
+    )]
+    datetime: Option<String>,
+
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u16).range(1..=8),
+        help = "Write the orientation into EXIF when encoding, from 1 to 8",
+        long_help = "Write the orientation into the EXIF Orientation tag when encoding, from 1 to 8 as in TIFF: 1 is upright, 6 means the viewer should rotate the image 90 degrees clockwise. The pixels are encoded as they are and not rotated."
+    )]
+    orientation: Option<u16>,
+
+    #[arg(
+        long,
+        help = "Write the camera maker into EXIF when encoding",
+        long_help = "Write the camera maker into the EXIF Make tag when encoding."
+    )]
+    make: Option<String>,
+
+    #[arg(
+        long,
+        help = "Write the camera model into EXIF when encoding",
+        long_help = "Write the camera model into the EXIF Model tag when encoding."
+    )]
+    model: Option<String>,
+
+    #[arg(
+        long,
+        help = "Do not copy EXIF from the input file when encoding",
+        long_help = "Do not copy EXIF from the input file when encoding. By default the orientation, camera maker and model, date and time and GPS position are read from the EXIF of a JPEG input or the tags of a TIFF input and written into out.jpg, with --orientation, --make, --model, --datetime and --gps taking precedence. Other EXIF tags, e.g. the exposure, are not copied."
+    )]
+    no_source_exif: bool,
+
+    #[cfg(feature = "trace-chrome")]
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the tracing spans of every step to FILE in Chrome tracing format",
+        long_help = "Write the tracing spans of every step to FILE in Chrome tracing format. Open it in chrome://tracing or Perfetto to see where time goes."
+    )]
+    trace_chrome: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Losslessly transform a JPEG file without decoding it to pixels
+    Transform(TransformArgs),
+    /// Downscale or re-quantize a JPEG file without decoding it to pixels
+    Transcode(TranscodeArgs),
+    /// Losslessly shrink a JPEG file by rebuilding optimal Huffman tables for its coefficients
+    Optimize(OptimizeArgs),
+    /// Print the size and the metadata of a JPEG file without decoding it
+    Info(InfoArgs),
+    /// Decode only one component of a JPEG file into a grayscale image of its own resolution
+    Component(ComponentArgs),
+    /// Write the embedded JPEG thumbnail (EXIF or JFXX) to a standalone file
+    ExtractThumb(ExtractThumbArgs),
+    /// Write the EXIF, ICC profile and XMP payloads of a JPEG file to separate files
+    ExtractMetadata(ExtractMetadataArgs),
+    /// Print the quantization and Huffman tables of a JPEG file
+    DumpTables(DumpTablesArgs),
+    /// Decode a JPEG file with both jpeglab and the image crate and report how far the results differ
+    Verify(VerifyArgs),
+    /// Compare the marker segments of two JPEG files without decoding them
+    #[command(visible_alias = "diff-markers")]
+    Diff(DiffArgs),
+    /// Render the average coefficient magnitude of each frequency as 8x8 heatmaps
+    Heatmap(HeatmapArgs),
+    /// Write the distribution of DC differences and the DC category usage as CSV
+    DcStats(DcStatsArgs),
+    /// Print per-table Huffman statistics and compare the entropy with the actual scan size
+    Entropy(EntropyArgs),
+    /// Hide a payload in the DCT coefficients, or recover it
+    Stego(StegoArgs),
+    /// Print PSNR, SSIM and MS-SSIM between an original and a reconstruction
+    Compare(CompareArgs),
+    /// Encode and decode an image, then print the size and the quality of the result
+    Roundtrip(RoundtripArgs),
+    /// Estimate blockiness and ringing of an image without the original
+    Artifacts(ArtifactsArgs),
+    /// Render the original, the Y/Cb/Cr planes and the reconstructions after each lossy step side by side
+    Grid(GridArgs),
+    /// Encode a sequence of frames into one Motion JPEG stream
+    Mjpeg(MjpegArgs),
+    /// Summarize the difference between two rate-distortion curves as BD-rate and BD-PSNR
+    BdRate(BdRateArgs),
+    /// Decode with both jpeglab and jpeg-decoder and report the first step that diverges
+    #[cfg(feature = "differential")]
+    Differential(DifferentialArgs),
+}
+
+#[derive(clap::Args)]
+#[command(group = clap::ArgGroup::new("operation").required(true))]
+struct TransformArgs {
+    #[arg(help = "Input JPEG file")]
+    input: String,
+
+    #[arg(
+        long,
+        value_enum,
+        group = "operation",
+        help = "Rotate clockwise by this many degrees",
+        long_help = "Rotate clockwise by this many degrees. The coefficients are moved in the DCT domain and entropy-coded again, so there is no generation loss."
+    )]
+    rotate: Option<Rotation>,
+
+    #[arg(
+        long,
+        value_enum,
+        group = "operation",
+        help = "Flip the image",
+        long_help = "Flip the image. Like --rotate, the coefficients are only negated and moved in the DCT domain."
+    )]
+    flip: Option<Flip>,
+
+    #[arg(
+        long,
+        help = "Drop the partial MCU at the edge that would be flipped",
+        long_help = "Drop the partial MCU at the edge that would be flipped. Without this, an image whose flipped side is not a multiple of the MCU size is rejected, because its padding would end up inside the image."
+    )]
+    trim: bool,
+
+    #[arg(long, help = "Drop APPn and COM segments such as EXIF and XMP")]
+    strip: bool,
+
+    #[arg(short, long, default_value = "out.jpg", help = "Output JPEG file")]
+    output: String,
+}
+
+#[derive(clap::Args)]
+struct OptimizeArgs {
+    #[arg(
+        help = "Input JPEG file",
+        long_help = "Input JPEG file, with the chroma sampling factors (1, 1) like for transcode. The scan is only entropy-decoded; the coefficients and the quantization tables are kept, and the scan is entropy-coded again with the optimal Huffman tables for these coefficients, like jpegtran -optimize. The restart interval is kept. A progressive file is written as a baseline file with a single scan, which is usually smaller but is no longer displayed progressively; a warning is printed in that case."
+    )]
     input: String,
+
+    #[arg(long, help = "Drop APPn and COM segments such as EXIF and XMP")]
+    strip: bool,
+
+    #[arg(short, long, default_value = "out.jpg", help = "Output JPEG file")]
+    output: String,
 }
 
-fn handle_others(path: &Path) -> io::Result<()> {
-    let reader = ImageReader::open(path)?;
-    let image = reader.decode().map_err(|_| {
-        io::Error::new(io::ErrorKind::InvalidData, "Fail to decode the bitmap file")
-    })?;
+#[derive(clap::Args)]
+#[command(group = clap::ArgGroup::new("operation").required(true).multiple(true))]
+struct TranscodeArgs {
+    #[arg(help = "Input JPEG file")]
+    input: String,
+
+    #[arg(
+        long,
+        group = "operation",
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        help = "Quality of the new quantization tables, from 1 to 100",
+        long_help = "Quality of the new quantization tables, from 1 to 100. The tables are the standard ones scaled as libjpeg does; 50 gives the tables of our encoder. The coefficients are dequantized with the old tables and quantized with the new ones, so the image is not decoded to pixels and re-encoded."
+    )]
+    quality: Option<u8>,
+
+    #[arg(
+        long,
+        value_enum,
+        group = "operation",
+        help = "Downscale by this factor",
+        long_help = "Downscale by this factor. Only the low-frequency corner of each block is kept, and neighbouring blocks are combined in the DCT domain, which is much faster than decoding, resizing and encoding again."
+    )]
+    scale: Option<Scale>,
+
+    #[arg(long, help = "Drop APPn and COM segments such as EXIF and XMP")]
+    strip: bool,
+
+    #[arg(short, long, default_value = "out.jpg", help = "Output JPEG file")]
+    output: String,
+}
+
+#[derive(clap::Args)]
+struct InfoArgs {
+    #[arg(help = "Input JPEG file")]
+    input: String,
+}
+
+#[derive(clap::Args)]
+struct VerifyArgs {
+    #[arg(help = "Input JPEG file")]
+    input: String,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Fail if any sample differs by more than N",
+        long_help = "Fail if any sample differs by more than N. The two decoders use different IDCT precisions and chroma upsampling, so even a correct file can differ by dozens of levels at sharp colour edges while the mean deviation stays small. Without this option the deviation is only reported. The global decoding options apply to jpeglab."
+    )]
+    tolerance: Option<u8>,
+}
+
+#[derive(clap::Args)]
+struct ExtractThumbArgs {
+    #[arg(help = "Input JPEG file")]
+    input: String,
+
+    #[arg(short, long, default_value = "thumb.jpg", help = "Output JPEG file")]
+    output: String,
+}
+
+#[derive(clap::Args)]
+struct ExtractMetadataArgs {
+    #[arg(help = "Input JPEG file")]
+    input: String,
+
+    #[arg(
+        long,
+        default_value = "output",
+        help = "Directory to write exif.tiff, profile.icc and metadata.xmp to",
+        long_help = "Directory to write exif.tiff, profile.icc and metadata.xmp to. exif.tiff is the TIFF structure after the Exif header of APP1, profile.icc is the ICC profile assembled from its APP2 chunks, and metadata.xmp is the XMP packet, into which the extended XMP of later APP1 segments is merged after checking its MD5. Only the payloads present in the file are written."
+    )]
+    output_dir: String,
+}
+
+#[derive(clap::Args)]
+struct ComponentArgs {
+    #[arg(help = "Input JPEG file")]
+    input: String,
+
+    #[arg(
+        short,
+        long,
+        value_enum,
+        default_value_t = Component::Y,
+        help = "Component to decode"
+    )]
+    component: Component,
+
+    #[arg(
+        short,
+        long,
+        default_value = "component.png",
+        help = "Output grayscale image",
+        long_help = "Output grayscale image. It has the resolution of the component, e.g. half the width of the image for Cb and Cr of a YUV422 file, and is not upsampled or color converted."
+    )]
+    output: String,
+}
+
+#[derive(clap::Args)]
+struct DiffArgs {
+    #[arg(
+        help = "First JPEG file",
+        long_help = "First JPEG file. Differences in dimensions, sampling factors, frame type, quantization tables, Huffman tables, restart interval and APPn/COM segments are printed one per line, first file first."
+    )]
+    first: String,
+
+    #[arg(help = "Second JPEG file")]
+    second: String,
+}
+
+#[derive(clap::Args)]
+struct DumpTablesArgs {
+    #[arg(help = "Input JPEG file")]
+    input: String,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = TableFormat::Json,
+        help = "Output format",
+        long_help = "Output format. Quantization tables are written in natural (row-major) order, not in zigzag order. json writes one object with quantization_tables and huffman_tables; csv writes one table per line, as dqt,ID,64 values or dht,dc|ac,ID,16 bit counts,values."
+    )]
+    format: TableFormat,
+
+    #[arg(short, long, help = "Output file. Print to stdout if not given")]
+    output: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct HeatmapArgs {
+    #[arg(help = "Input JPEG file")]
+    input: String,
 
-    let (width, height) = image.dimensions();
+    #[arg(
+        long,
+        default_value = "output",
+        help = "Directory to write heatmap_y.png, heatmap_cb.png and heatmap_cr.png to",
+        long_help = "Directory to write heatmap_y.png, heatmap_cb.png and heatmap_cr.png to. Each heatmap shows the average absolute value of the dequantized coefficients at the 64 frequencies of one component, with DC at the top left, on a logarithmic scale from black to white."
+    )]
+    output_dir: String,
+}
+
+#[derive(clap::Args)]
+struct DcStatsArgs {
+    #[arg(help = "Input JPEG file")]
+    input: String,
+
+    #[arg(
+        long,
+        default_value = "output",
+        help = "Directory to write dc_diffs.csv and dc_categories.csv to",
+        long_help = "Directory to write dc_diffs.csv and dc_categories.csv to. dc_diffs.csv counts every DC difference per component; dc_categories.csv counts every category together with its code length in the DC Huffman table of the file. A summary comparing the bits spent on DC with the entropy of the categories is printed."
+    )]
+    output_dir: String,
+}
+
+#[derive(clap::Args)]
+struct EntropyArgs {
+    #[arg(
+        help = "Input JPEG file",
+        long_help = "Input JPEG file. The scan is entropy-decoded and turned back into the symbol stream of step 6, so for a file written by our encoder the report describes exactly what the encoder produced."
+    )]
+    input: String,
+}
+
+#[derive(clap::Args)]
+struct CompareArgs {
+    #[arg(help = "Original image")]
+    original: String,
+
+    #[arg(
+        help = "Reconstructed image",
+        long_help = "Reconstructed image. A .jpg file is decoded by jpeglab with the global decoding options; any other format is read by the image crate."
+    )]
+    reconstructed: String,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the amplified absolute difference between the two images to FILE",
+        long_help = "Write the absolute difference between the two images to FILE, per channel and multiplied by --diff-gain, so that compression artifacts are visible at a glance. The format follows the extension."
+    )]
+    diff_image: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value_t = 8,
+        help = "Factor applied to the differences in --diff-image"
+    )]
+    diff_gain: u8,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the per-pixel SSIM map between the two images to FILE",
+        long_help = "Write the per-pixel SSIM map between the two images to FILE, computed on luma with the same 11x11 Gaussian window as the SSIM value and colored by 1 - SSIM from black (no loss) through red and yellow to white, so that you can see where the structure is lost. The format follows the extension."
+    )]
+    ssim_map: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct RoundtripArgs {
+    #[arg(
+        help = "Input image",
+        long_help = "Input image. It is encoded and decoded in memory with the global encoding and decoding options; nothing is written except --diff-image and --ssim-map."
+    )]
+    input: String,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the amplified absolute difference between the two images to FILE",
+        long_help = "Write the absolute difference between the two images to FILE, per channel and multiplied by --diff-gain, so that compression artifacts are visible at a glance. The format follows the extension."
+    )]
+    diff_image: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value_t = 8,
+        help = "Factor applied to the differences in --diff-image"
+    )]
+    diff_gain: u8,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the per-pixel SSIM map between the two images to FILE",
+        long_help = "Write the per-pixel SSIM map between the two images to FILE, computed on luma with the same 11x11 Gaussian window as the SSIM value and colored by 1 - SSIM from black (no loss) through red and yellow to white, so that you can see where the structure is lost. The format follows the extension."
+    )]
+    ssim_map: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct ArtifactsArgs {
+    #[arg(
+        help = "Input image",
+        long_help = "Input image, usually a decoded JPEG. A .jpg file is decoded by jpeglab with the global decoding options. Blockiness is the energy of the differences across the 8-pixel grid relative to the differences inside blocks, so about 1 means no visible grid. Ringing is the mean absolute Laplacian in flat areas of blocks that contain a strong edge."
+    )]
+    input: String,
+}
+
+#[derive(clap::Args)]
+struct GridArgs {
+    #[arg(
+        help = "Input image",
+        long_help = "Input image. It is encoded with the global encoding options. The grid has two rows of three tiles: the original, the Y, Cb and Cr planes of step 1 (chroma stretched back to full width), the reconstruction after quantization only (chroma not subsampled), and the final decoded output. The difference between the last two is the effect of chroma subsampling."
+    )]
+    input: String,
+
+    #[arg(help = "Output image file, usually a .png")]
+    output: String,
+}
+
+#[derive(clap::Args)]
+struct MjpegArgs {
+    #[arg(
+        required = true,
+        help = "Input frames, in order",
+        long_help = "Input frames, in order. An argument whose file name contains * or ? is matched against the files of its directory and expands to the matches sorted by name, so frames/*.png works even where the shell does not expand it. All frames must have the same size; they are encoded with the global encoding options and the same tables."
+    )]
+    frames: Vec<String>,
+
+    #[arg(short, long, help = "Output stream, usually a .mjpeg file")]
+    output: String,
+}
+
+#[derive(clap::Args)]
+struct BdRateArgs {
+    #[arg(
+        help = "CSV of the anchor curve",
+        long_help = "CSV of the anchor curve. The first two columns of each line are the rate and the PSNR; lines that are not numbers, such as a header, are skipped. The rate may be in any unit, as long as both files use the same one. At least 4 points are needed."
+    )]
+    anchor: String,
+
+    #[arg(help = "CSV of the tested curve, in the same format")]
+    test: String,
+}
+
+#[cfg(feature = "differential")]
+#[derive(clap::Args)]
+struct DifferentialArgs {
+    #[arg(
+        required = true,
+        help = "Input JPEG files or images",
+        long_help = "Input JPEG files or images. A .jpg file is decoded by both decoders. Any other image is encoded by both jpeglab and the image crate, and both files are decoded by both decoders. The global encoding and decoding options apply."
+    )]
+    inputs: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct StegoArgs {
+    #[command(subcommand)]
+    command: StegoCommand,
+}
+
+#[derive(Subcommand)]
+enum StegoCommand {
+    /// Encode an image and hide a payload in its quantized coefficients
+    Embed(StegoEmbedArgs),
+    /// Recover the payload hidden by embed
+    Extract(StegoExtractArgs),
+}
+
+#[derive(clap::Args)]
+struct StegoEmbedArgs {
+    #[arg(help = "Input image file, in any format other than JPEG")]
+    input: String,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "File to hide",
+        long_help = "File to hide. It is written into the least significant bits of the quantized AC coefficients whose magnitude is at least 2, so the image must be large and detailed enough to hold it."
+    )]
+    payload: String,
+
+    #[arg(short, long, default_value = "out.jpg", help = "Output JPEG file")]
+    output: String,
+}
+
+#[derive(clap::Args)]
+struct StegoExtractArgs {
+    #[arg(help = "Input JPEG file")]
+    input: String,
+
+    #[arg(
+        short,
+        long,
+        default_value = "payload.bin",
+        help = "File to write the payload to"
+    )]
+    output: String,
+}
+
+impl Args {
+    fn color_conversion(&self) -> ColorConversion {
+        ColorConversion {
+            matrix: self.matrix,
+            range: self.range,
+        }
+    }
+
+    fn encode_options(&self) -> io::Result<EncodeOptions> {
+        let xmp = match &self.xmp {
+            Some(path) => Some(XmpPacket::new(std::fs::read_to_string(path)?)?),
+            None => None,
+        };
+        let icc_profile = match &self.icc {
+            Some(path) => Some(IccProfile::new(std::fs::read(path)?)?),
+            None => None,
+        };
+        let exif = ExifData::new(self.gps, self.datetime.clone())?
+            .with_orientation(self.orientation)?
+            .with_camera(self.make.clone(), self.model.clone());
+        let exif = Some(exif).filter(|exif| !exif.is_empty());
+        Ok(EncodeOptions {
+            color_conversion: self.color_conversion(),
+            subsampling: self.subsampling,
+            sample_precision: self.sample_precision,
+            smoothing: self.smooth,
+            dct_precision: self.dct_precision,
+            quantization: self.preset,
+            quality: self.quality,
+            target_size: self.target_size,
+            viewing: ViewingConditions {
+                distance: self.viewing_distance,
+                dpi: self.dpi,
+            },
+            striped: self.striped,
+            huffman: self.huffman,
+            entropy_coding: self.entropy_coding,
+            progressive: self.progressive,
+            non_interleaved: self.non_interleaved,
+            restart_interval: self.restart_interval,
+            verify: self.verify,
+            huffman_stats: self.huffman_stats,
+            bit_cost_dir: self.bit_cost.clone(),
+            dump_steps_dir: self.dump_steps.clone(),
+            trace_bits: self.trace_bits.clone(),
+            report: self.report.clone(),
+            dqt_layout: self.dqt_layout,
+            dqt_precision: self.dqt_precision,
+            dht_layout: self.dht_layout,
+            exif,
+            xmp,
+            density: match self.density {
+                Some((x, y)) => Density {
+                    units: self.density_units,
+                    x,
+                    y,
+                },
+                None => Density::default(),
+            },
+            adobe_app14: self.adobe_app14,
+            jfif_thumbnail: self.jfif_thumbnail,
+            icc_profile,
+        })
+    }
+
+    fn decode_options(&self) -> DecodeOptions {
+        DecodeOptions {
+            color_conversion: self.color_conversion(),
+            dct_precision: self.dct_precision,
+            striped: self.striped,
+            trace_bits: self.trace_bits.clone(),
+            sharpening: self.sharpen.map(|strength| Sharpening {
+                strength,
+                radius: self.sharpen_radius,
+            }),
+        }
+    }
+}
+
+/// 解析 `--gps` 的 "LAT,LON"。范围由 `ExifData::new` 检查。
+fn parse_gps(value: &str) -> Result<(f64, f64), String> {
+    let (latitude, longitude) = value
+        .split_once(',')
+        .ok_or_else(|| "expected LAT,LON".to_string())?;
+    let parse = |v: &str| v.trim().parse::<f64>().map_err(|e| e.to_string());
+    Ok((parse(latitude)?, parse(longitude)?))
+}
+
+/// 解析 `--target-size`：字节数，可以带 k 或 M 的后缀。
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (number, unit) = match value.strip_suffix(['k', 'K']) {
+        Some(number) => (number, 1024.0),
+        None => match value.strip_suffix('M') {
+            Some(number) => (number, 1024.0 * 1024.0),
+            None => (value, 1.0),
+        },
+    };
+    let size = parse_positive(number)? * unit;
+    if size < 1.0 {
+        return Err("must be at least 1 byte".to_string());
+    }
+    Ok(size as usize)
+}
+
+/// 解析像素密度：一个数，或者 XxY。
+fn parse_density(value: &str) -> Result<(u16, u16), String> {
+    let parse = |v: &str| match v.parse::<u16>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(v) => Ok(v),
+        Err(e) => Err(e.to_string()),
+    };
+    match value.split_once(['x', 'X']) {
+        Some((x, y)) => Ok((parse(x)?, parse(y)?)),
+        None => parse(value).map(|v| (v, v)),
+    }
+}
+
+/// 解析正数。
+fn parse_positive(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        Ok(_) => Err("must be positive".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// 把 `source` 的修改时间（Unix 上还有权限位）复制到 `targets`。
+fn preserve_times(source: &Path, targets: &[PathBuf]) -> io::Result<()> {
+    let metadata = std::fs::metadata(source)?;
+    let modified = metadata.modified()?;
+    for target in targets {
+        File::options()
+            .write(true)
+            .open(target)?
+            .set_modified(modified)?;
+        #[cfg(unix)]
+        std::fs::set_permissions(target, metadata.permissions())?;
+    }
+    Ok(())
+}
+
+/// 编码，返回写出的文件。
+fn handle_others(path: &Path, args: &Args) -> io::Result<Vec<PathBuf>> {
+    let mut options = args.encode_options()?;
+    let mut frames = jpeglab::frames::read_frames(path)?;
+    if !args.no_source_exif {
+        if let Some(source) = ExifData::read_from_file(&std::fs::read(path)?) {
+            println!("[INFO] 复制输入文件的 EXIF");
+            options.exif = Some(options.exif.unwrap_or_default().or(source));
+        }
+    }
+
+    let (width, height) = frames[0].dimensions();
     println!("[INFO] 输入位图的尺寸为 {}x{}", width, height);
 
-    let color = image.color();
-    if color != ColorType::Rgb8 {
-        println!("[WARNING] 不是 Rgb8 的颜色类型：{:#?}", color);
+    // 12 位编码时保留 16 位输入的精度。
+    let twelve_bit = args.sample_precision == SamplePrecision::Twelve;
+    let target = if twelve_bit {
+        ColorType::Rgb16
+    } else {
+        ColorType::Rgb8
+    };
+    let color = frames[0].color();
+    if color != target {
+        println!("[INFO] 输入的颜色类型为 {:?}，转换为 {:?}", color, target);
+    }
+
+    if frames.len() > 1 {
+        return encode_frames(frames, args.mjpeg.as_deref(), &options);
+    }
+    if twelve_bit {
+        let rgb16 = jpeglab::convert::to_rgb16(frames.remove(0));
+        jpeglab::encode16(&rgb16, &options)?;
+        return Ok(vec![PathBuf::from("out.jpg")]);
     }
+    let rgb = jpeglab::convert::to_rgb8(frames.remove(0));
 
-    let rgb = image.into_rgb8();
+    jpeglab::encode(&rgb, &options)?;
+    Ok(vec![PathBuf::from("out.jpg")])
+}
 
-    jpeglab::encode(&rgb)
+/// 把各帧写成 MJPEG 流，返回帧数。先写入 `path` 加上 .tmp 的临时文件，全部成功后再重命名，
+/// 某一帧失败（如尺寸不同）时删除临时文件，不留下不完整的流。
+fn write_mjpeg(
+    path: &Path,
+    options: &EncodeOptions,
+    frames: impl Iterator<Item = io::Result<image::RgbImage>>,
+) -> io::Result<usize> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let write = || {
+        let file = io::BufWriter::new(File::create(&temp)?);
+        let mut writer = jpeglab::mjpeg::MjpegWriter::new(file, options)?;
+        for frame in frames {
+            writer.write_frame(&frame?)?;
+        }
+        let count = writer.frame_count();
+        writer.finish()?;
+        Ok(count)
+    };
+    match write() {
+        Ok(count) => {
+            std::fs::rename(&temp, path)?;
+            Ok(count)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp);
+            Err(e)
+        }
+    }
 }
 
-fn handle_jpg(path: &Path) -> io::Result<()> {
+/// 多帧的输入：每帧写入 out_0000.jpg、out_0001.jpg……，或者全部写入一个 MJPEG 流。
+fn encode_frames(
+    frames: Vec<image::DynamicImage>,
+    mjpeg: Option<&Path>,
+    options: &EncodeOptions,
+) -> io::Result<Vec<PathBuf>> {
+    let count = frames.len();
+    if let Some(path) = mjpeg {
+        let frames = frames
+            .into_iter()
+            .map(|frame| Ok(jpeglab::convert::to_rgb8(frame)));
+        write_mjpeg(path, options, frames)?;
+        println!("[INFO] 共 {} 帧，写入 {}", count, path.display());
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut outputs = vec![];
+    for (i, frame) in frames.into_iter().enumerate() {
+        let jpeg = match options.sample_precision {
+            SamplePrecision::Eight => {
+                jpeglab::encode_to_vec(&jpeglab::convert::to_rgb8(frame), options)?
+            }
+            SamplePrecision::Twelve => {
+                jpeglab::encode16_to_vec(&jpeglab::convert::to_rgb16(frame), options)?
+            }
+        };
+        let output = PathBuf::from(format!("out_{:04}.jpg", i));
+        std::fs::write(&output, jpeg)?;
+        outputs.push(output);
+    }
+    println!("[INFO] 共 {} 帧，写入 out_0000.jpg 等文件", count);
+    Ok(outputs)
+}
+
+/// 解码，返回写出的文件。
+fn handle_jpg(
+    path: &Path,
+    options: &DecodeOptions,
+    output16: Option<&Path>,
+) -> io::Result<Vec<PathBuf>> {
     let mut file = File::open(path)?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)?;
 
-    jpeglab::decode(&buffer)
+    if let Some(output) = output16 {
+        let image = jpeglab::decode16::decode_to_image16(&buffer, options)?;
+        jpeglab::decode16::save_image16(&image, output)?;
+        println!("[INFO] 16 位的解码结果写入 {}", output.display());
+        return Ok(vec![output.to_path_buf()]);
+    }
+    let count = jpeglab::decode(&buffer, options)?;
+    if count > 1 {
+        return Ok((0..count)
+            .map(|i| PathBuf::from(format!("out_{:04}.bmp", i)))
+            .collect());
+    }
+    Ok(vec![PathBuf::from("out.bmp")])
+}
+
+fn handle_transform(args: &TransformArgs) -> io::Result<()> {
+    let jpeg = std::fs::read(&args.input)?;
+    // 两者恰好给出一个，由 clap 保证。
+    let operation = match (args.rotate, args.flip) {
+        (Some(rotation), _) => Operation::Rotate(rotation),
+        (None, flip) => Operation::Flip(flip.unwrap()),
+    };
+    let options = TransformOptions {
+        operation,
+        trim: args.trim,
+        strip: args.strip,
+    };
+    std::fs::write(&args.output, jpeglab::transform(&jpeg, &options)?)?;
+    println!("[INFO] 无损变换的结果写入 {}", args.output);
+    Ok(())
+}
+
+fn handle_transcode(args: &TranscodeArgs) -> io::Result<()> {
+    let jpeg = std::fs::read(&args.input)?;
+    let options = TranscodeOptions {
+        quality: args.quality,
+        scale: args.scale.unwrap_or_default(),
+        strip: args.strip,
+        huffman: HuffmanMode::Default,
+    };
+    std::fs::write(&args.output, jpeglab::transcode(&jpeg, &options)?)?;
+    println!("[INFO] 转码的结果写入 {}", args.output);
+    Ok(())
+}
+
+fn handle_optimize(args: &OptimizeArgs) -> io::Result<()> {
+    let jpeg = std::fs::read(&args.input)?;
+    if jpeglab::segments::read_segments(&jpeg)?
+        .iter()
+        .any(|segment| segment.marker == 0xC2)
+    {
+        println!("[WARN] 输入是渐进式 JPEG，将写为基线 JPEG");
+    }
+    let options = TranscodeOptions {
+        strip: args.strip,
+        huffman: HuffmanMode::Optimized,
+        ..Default::default()
+    };
+    let optimized = jpeglab::transcode(&jpeg, &options)?;
+    std::fs::write(&args.output, &optimized)?;
+    println!(
+        "[INFO] {} 字节 -> {} 字节，写入 {}",
+        jpeg.len(),
+        optimized.len(),
+        args.output
+    );
+    Ok(())
+}
+
+fn handle_info(args: &InfoArgs) -> io::Result<()> {
+    let info = jpeglab::read_info(&std::fs::read(&args.input)?)?;
+    println!("[INFO] 尺寸为 {}x{}", info.width, info.height);
+    println!("[INFO] 各分量的采样因子为 {:?}", info.sampling_factors);
+    if info.entropy_coding == EntropyCoding::Arithmetic {
+        println!("[INFO] 使用算术编码，本程序不能解码");
+    }
+    if let Some(density) = &info.density {
+        match density.units {
+            DensityUnits::None => println!("[INFO] 像素的宽高比为 {}:{}", density.y, density.x),
+            DensityUnits::Inch => println!("[INFO] 像素密度为 {}x{} DPI", density.x, density.y),
+            DensityUnits::Cm => println!("[INFO] 像素密度为每厘米 {}x{}", density.x, density.y),
+        }
+    }
+    if let Some(xmp) = &info.xmp {
+        println!("[INFO] XMP：\n{}", xmp.as_str());
+    }
+    if let Some(iptc) = &info.iptc {
+        for caption in &iptc.captions {
+            println!("[INFO] IPTC 标题：{}", caption);
+        }
+        if !iptc.keywords.is_empty() {
+            println!("[INFO] IPTC 关键词：{}", iptc.keywords.join(", "));
+        }
+    }
+    Ok(())
+}
+
+fn handle_extract_thumb(args: &ExtractThumbArgs) -> io::Result<()> {
+    let jpeg = std::fs::read(&args.input)?;
+    let thumbnail = jpeglab::extract_thumbnail(&jpeg)?.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "No embedded JPEG thumbnail found")
+    })?;
+    std::fs::write(&args.output, thumbnail)?;
+    println!("[INFO] 缩略图写入 {}", args.output);
+    Ok(())
+}
+
+fn handle_extract_metadata(args: &ExtractMetadataArgs) -> io::Result<()> {
+    let jpeg = std::fs::read(&args.input)?;
+    let payloads = jpeglab::extract_metadata(&jpeg)?;
+    let output_dir = Path::new(&args.output_dir);
+    let mut written = 0;
+    for (payload, name, file_name) in [
+        (payloads.exif, "EXIF", "exif.tiff"),
+        (
+            payloads.icc_profile.as_deref(),
+            "ICC 配置文件",
+            "profile.icc",
+        ),
+        (payloads.xmp.as_deref(), "XMP", "metadata.xmp"),
+    ] {
+        if let Some(payload) = payload {
+            let path = output_dir.join(file_name);
+            std::fs::write(&path, payload)?;
+            println!(
+                "[INFO] {}（{} 字节）写入 {}",
+                name,
+                payload.len(),
+                path.display()
+            );
+            written += 1;
+        }
+    }
+    if written == 0 {
+        println!("[INFO] 没有 EXIF、ICC 配置文件或 XMP");
+    }
+    Ok(())
+}
+
+fn handle_component(args: &ComponentArgs) -> io::Result<()> {
+    let jpeg = std::fs::read(&args.input)?;
+    let plane =
+        jpeglab::component::decode_component(&jpeg, args.component, DctPrecision::default())?;
+    plane.save(&args.output).map_err(io::Error::other)?;
+    println!(
+        "[INFO] {:?} 分量（{}x{}）写入 {}",
+        args.component,
+        plane.width(),
+        plane.height(),
+        args.output
+    );
+    Ok(())
+}
+
+fn handle_verify(args: &VerifyArgs, options: &Args) -> io::Result<()> {
+    let deviation =
+        jpeglab::cross_check::cross_check(&std::fs::read(&args.input)?, &options.decode_options())?;
+    println!(
+        "[INFO] 最大偏差 {}（像素 ({}, {})），平均偏差 {:.4}，{} 个像素不同",
+        deviation.max,
+        deviation.max_position.0,
+        deviation.max_position.1,
+        deviation.mean,
+        deviation.differing_pixels
+    );
+    match args.tolerance {
+        Some(tolerance) if deviation.max > tolerance => Err(io::Error::other(format!(
+            "The decoders differ by {}, more than the tolerance {}",
+            deviation.max, tolerance
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn handle_diff(args: &DiffArgs) -> io::Result<()> {
+    let diff =
+        jpeglab::diff::diff_markers(&std::fs::read(&args.first)?, &std::fs::read(&args.second)?)?;
+    if diff.is_empty() {
+        println!("[INFO] 标记段没有差别");
+    }
+    for line in diff {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn handle_dump_tables(args: &DumpTablesArgs) -> io::Result<()> {
+    let tables = jpeglab::tables::read_tables(&std::fs::read(&args.input)?)?;
+    let text = tables.format(args.format);
+    match &args.output {
+        Some(path) => std::fs::write(path, text)?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+fn handle_heatmap(args: &HeatmapArgs) -> io::Result<()> {
+    let magnitudes = jpeglab::analysis::average_magnitudes(&std::fs::read(&args.input)?)?;
+    for (magnitudes, name) in magnitudes.iter().zip(jpeglab::analysis::COMPONENT_NAMES) {
+        let path = Path::new(&args.output_dir).join(format!("heatmap_{}.png", name));
+        jpeglab::analysis::render_heatmap(magnitudes)
+            .save_with_format(&path, image::ImageFormat::Png)
+            .map_err(io::Error::other)?;
+        println!("[INFO] {} 分量的热力图写入 {}", name, path.display());
+    }
+    Ok(())
+}
+
+fn handle_dc_stats(args: &DcStatsArgs) -> io::Result<()> {
+    let statistics = jpeglab::analysis::dc_statistics(&std::fs::read(&args.input)?)?;
+    for (stats, name) in statistics.iter().zip(jpeglab::analysis::COMPONENT_NAMES) {
+        println!(
+            "[INFO] {}：{} 个 DU，差分均值 {:.2}，标准差 {:.2}，平均每个 DC {:.2} 位，按熵至少 {:.2} 位",
+            name,
+            stats.count(),
+            stats.mean(),
+            stats.std_dev(),
+            stats.average_bits(),
+            stats.ideal_bits()
+        );
+    }
+    let output_dir = Path::new(&args.output_dir);
+    std::fs::write(
+        output_dir.join("dc_diffs.csv"),
+        jpeglab::analysis::dc_diffs_csv(&statistics),
+    )?;
+    std::fs::write(
+        output_dir.join("dc_categories.csv"),
+        jpeglab::analysis::dc_categories_csv(&statistics),
+    )?;
+    println!("[INFO] 统计结果写入 {}", output_dir.display());
+    Ok(())
+}
+
+fn handle_entropy(args: &EntropyArgs) -> io::Result<()> {
+    let report = jpeglab::analysis::entropy_report(&std::fs::read(&args.input)?)?;
+    jpeglab::analysis::print_huffman_statistics(&report);
+    Ok(())
+}
+
+/// 读入任意格式的图像，.jpg 由 jpeglab 解码。
+fn load_rgb(path: &Path, options: &DecodeOptions) -> io::Result<image::RgbImage> {
+    if path.extension().and_then(|v| v.to_str()) == Some("jpg") {
+        return jpeglab::decode_to_image(&std::fs::read(path)?, options);
+    }
+    let image = ImageReader::open(path)?.decode().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, "Fail to decode the bitmap file")
+    })?;
+    Ok(jpeglab::convert::to_rgb8(image))
+}
+
+/// 输出原图与重建结果之间的指标，需要时写出差异图和 SSIM 图。
+fn report_quality(
+    original: &image::RgbImage,
+    reconstructed: &image::RgbImage,
+    diff_image: Option<&Path>,
+    diff_gain: u8,
+    ssim_map: Option<&Path>,
+) -> io::Result<()> {
+    let metrics = jpeglab::metrics::Metrics::new(original, reconstructed)?;
+    println!("[INFO] PSNR：{:.2} dB", metrics.psnr);
+    println!("[INFO] SSIM：{:.4}", metrics.ssim);
+    println!("[INFO] MS-SSIM：{:.4}", metrics.ms_ssim);
+    if let Some(path) = diff_image {
+        jpeglab::metrics::difference_image(original, reconstructed, diff_gain)?
+            .save(path)
+            .map_err(io::Error::other)?;
+        println!("[INFO] 差异放大 {} 倍，写入 {}", diff_gain, path.display());
+    }
+    if let Some(path) = ssim_map {
+        jpeglab::metrics::ssim_map_image(original, reconstructed)?
+            .save(path)
+            .map_err(io::Error::other)?;
+        println!("[INFO] SSIM 图写入 {}", path.display());
+    }
+    Ok(())
+}
+
+fn handle_compare(args: &CompareArgs, options: &Args) -> io::Result<()> {
+    let decode_options = options.decode_options();
+    let original = load_rgb(Path::new(&args.original), &decode_options)?;
+    let reconstructed = load_rgb(Path::new(&args.reconstructed), &decode_options)?;
+    report_quality(
+        &original,
+        &reconstructed,
+        args.diff_image.as_deref(),
+        args.diff_gain,
+        args.ssim_map.as_deref(),
+    )
+}
+
+fn handle_roundtrip(args: &RoundtripArgs, options: &Args) -> io::Result<()> {
+    let original = load_rgb(Path::new(&args.input), &options.decode_options())?;
+    let jpeg = jpeglab::encode_to_vec(&original, &options.encode_options()?)?;
+    let decoded = jpeglab::decode_to_image(&jpeg, &options.decode_options())?;
+    println!(
+        "[INFO] 编码为 {} 字节，每像素 {:.3} 位",
+        jpeg.len(),
+        jpeg.len() as f64 * 8.0 / (original.width() * original.height()) as f64
+    );
+    report_quality(
+        &original,
+        &decoded,
+        args.diff_image.as_deref(),
+        args.diff_gain,
+        args.ssim_map.as_deref(),
+    )
+}
+
+fn handle_artifacts(args: &ArtifactsArgs, options: &Args) -> io::Result<()> {
+    let image = load_rgb(Path::new(&args.input), &options.decode_options())?;
+    println!("[INFO] 块效应：{:.3}", jpeglab::metrics::blockiness(&image));
+    println!("[INFO] 振铃：{:.3}", jpeglab::metrics::ringing(&image));
+    Ok(())
+}
+
+fn handle_grid(args: &GridArgs, options: &Args) -> io::Result<()> {
+    let image = load_rgb(Path::new(&args.input), &options.decode_options())?;
+    jpeglab::grid::render_grid(&image, &options.encode_options()?)?
+        .save(&args.output)
+        .map_err(io::Error::other)?;
+    println!("[INFO] 对比图写入 {}", args.output);
+    Ok(())
+}
+
+fn handle_mjpeg(args: &MjpegArgs, options: &Args) -> io::Result<()> {
+    let frames = jpeglab::mjpeg::expand_frames(&args.frames)?;
+    let decode_options = options.decode_options();
+    let frames = frames.iter().map(|frame| load_rgb(frame, &decode_options));
+    let count = write_mjpeg(Path::new(&args.output), &options.encode_options()?, frames)?;
+    println!("[INFO] {} 帧写入 {}", count, args.output);
+    Ok(())
+}
+
+fn handle_bd_rate(args: &BdRateArgs) -> io::Result<()> {
+    use jpeglab::bd_rate::parse_rd_csv;
+
+    let anchor = parse_rd_csv(&std::fs::read_to_string(&args.anchor)?)?;
+    let test = parse_rd_csv(&std::fs::read_to_string(&args.test)?)?;
+    println!(
+        "[INFO] BD-rate：{:+.2}%",
+        jpeglab::bd_rate::bd_rate(&anchor, &test)?
+    );
+    println!(
+        "[INFO] BD-PSNR：{:+.3} dB",
+        jpeglab::bd_rate::bd_psnr(&anchor, &test)?
+    );
+    Ok(())
+}
+
+fn handle_stego(args: &StegoArgs) -> io::Result<()> {
+    match &args.command {
+        StegoCommand::Embed(args) => {
+            let image = ImageReader::open(&args.input)?.decode().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "Fail to decode the bitmap file")
+            })?;
+            let payload = std::fs::read(&args.payload)?;
+            let jpeg = jpeglab::stego::embed(
+                &jpeglab::convert::to_rgb8(image),
+                &payload,
+                &Default::default(),
+            )?;
+            std::fs::write(&args.output, jpeg)?;
+            println!(
+                "[INFO] 隐写了 {} 字节，结果写入 {}",
+                payload.len(),
+                args.output
+            );
+        }
+        StegoCommand::Extract(args) => {
+            let payload = jpeglab::stego::extract(&std::fs::read(&args.input)?)?;
+            std::fs::write(&args.output, &payload)?;
+            println!("[INFO] 提取出 {} 字节，写入 {}", payload.len(), args.output);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "differential")]
+fn handle_differential(args: &DifferentialArgs, options: &Args) -> io::Result<()> {
+    use jpeglab::differential::compare_decoders;
+    use jpeglab::differential::compare_encoders;
+
+    let mut diverged = 0;
+    for input in &args.inputs {
+        let path = Path::new(input);
+        let reports = match path.extension().and_then(|v| v.to_str()) {
+            Some("jpg") => vec![(
+                "input",
+                compare_decoders(&std::fs::read(path)?, &options.decode_options())?,
+            )],
+            _ => {
+                let image = ImageReader::open(path)?.decode().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Fail to decode the bitmap file")
+                })?;
+                compare_encoders(
+                    &jpeglab::convert::to_rgb8(image),
+                    &options.encode_options()?,
+                    &options.decode_options(),
+                )?
+                .into()
+            }
+        };
+        for (encoder, report) in reports {
+            println!("[INFO] {}（{}）：\n{}", input, encoder, report);
+            if report.first_divergence().is_some() {
+                diverged += 1;
+            }
+        }
+    }
+    if diverged > 0 {
+        return Err(io::Error::other(format!(
+            "{} file(s) diverge from the reference decoder",
+            diverged
+        )));
+    }
+    Ok(())
 }
 
 fn main() -> io::Result<()> {
     let args = Args::parse();
-    let path = Path::new(&args.input);
-    match path.extension().and_then(|v| v.to_str()) {
-        Some("jpg") => {
+    // 在程序结束时析构，写出全部的 span。
+    #[cfg(feature = "trace-chrome")]
+    let _trace_guard = args.trace_chrome.as_ref().map(|path| {
+        use tracing_subscriber::prelude::*;
+        let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new().file(path).build();
+        tracing_subscriber::registry().with(layer).init();
+        guard
+    });
+    match &args.command {
+        Some(Command::Transform(transform_args)) => return handle_transform(transform_args),
+        Some(Command::Transcode(transcode_args)) => return handle_transcode(transcode_args),
+        Some(Command::Optimize(optimize_args)) => return handle_optimize(optimize_args),
+        Some(Command::Info(info_args)) => return handle_info(info_args),
+        Some(Command::ExtractThumb(extract_thumb_args)) => {
+            return handle_extract_thumb(extract_thumb_args)
+        }
+        Some(Command::ExtractMetadata(extract_metadata_args)) => {
+            return handle_extract_metadata(extract_metadata_args)
+        }
+        Some(Command::Component(component_args)) => return handle_component(component_args),
+        Some(Command::DumpTables(dump_tables_args)) => return handle_dump_tables(dump_tables_args),
+        Some(Command::Verify(verify_args)) => return handle_verify(verify_args, &args),
+        Some(Command::Diff(diff_args)) => return handle_diff(diff_args),
+        Some(Command::Heatmap(heatmap_args)) => return handle_heatmap(heatmap_args),
+        Some(Command::DcStats(dc_stats_args)) => return handle_dc_stats(dc_stats_args),
+        Some(Command::Entropy(entropy_args)) => return handle_entropy(entropy_args),
+        Some(Command::Stego(stego_args)) => return handle_stego(stego_args),
+        Some(Command::Compare(compare_args)) => return handle_compare(compare_args, &args),
+        Some(Command::Roundtrip(roundtrip_args)) => return handle_roundtrip(roundtrip_args, &args),
+        Some(Command::Artifacts(artifacts_args)) => return handle_artifacts(artifacts_args, &args),
+        Some(Command::Grid(grid_args)) => return handle_grid(grid_args, &args),
+        Some(Command::Mjpeg(mjpeg_args)) => return handle_mjpeg(mjpeg_args, &args),
+        Some(Command::BdRate(bd_rate_args)) => return handle_bd_rate(bd_rate_args),
+        #[cfg(feature = "differential")]
+        Some(Command::Differential(differential_args)) => {
+            return handle_differential(differential_args, &args)
+        }
+        None => {}
+    }
+    let path = Path::new(args.input.as_deref().unwrap_or_default());
+    let outputs = match path.extension().and_then(|v| v.to_str()) {
+        // MJPEG 流是首尾相接的多幅 JPEG，同样解码为位图。
+        Some("jpg" | "jpeg" | "mjpg" | "mjpeg") => {
             println!(
                 "[INFO] 输入 JPEG 文件 {}，解压为位图",
                 path.to_str().unwrap_or_default()
             );
-            handle_jpg(path)
+            handle_jpg(path, &args.decode_options(), args.output16.as_deref())
         }
         _ => {
             println!(
                 "[INFO] 输入其他格式的图片文件 {}，压缩为 JPEG",
                 path.to_str().unwrap_or_default()
             );
-            handle_others(path)
+            handle_others(path, &args)
         }
+    }?;
+    if args.preserve_times {
+        preserve_times(path, &outputs)?;
     }
+    Ok(())
 }
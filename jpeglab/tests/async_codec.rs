@@ -0,0 +1,34 @@
+//! 异步接口的测试，需要 `async` feature：`cargo test --features async --test async_codec`。
+
+#![cfg(feature = "async")]
+
+use image::RgbImage;
+
+#[test]
+fn async_matches_sync() {
+    let image = RgbImage::from_fn(40, 24, |x, y| {
+        image::Rgb([(x * 6) as u8, (y * 10) as u8, 128])
+    });
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let jpeg = runtime
+        .block_on(jpeglab::encode_async(image.clone(), Default::default()))
+        .unwrap();
+    assert_eq!(
+        jpeg,
+        jpeglab::encode_to_vec(&image, &Default::default()).unwrap()
+    );
+
+    let decoded = runtime
+        .block_on(jpeglab::decode_async(jpeg.clone(), Default::default()))
+        .unwrap();
+    assert!(decoded == jpeglab::decode_to_image(&jpeg, &Default::default()).unwrap());
+
+    let result = runtime.block_on(jpeglab::decode_async(
+        b"not a jpeg".to_vec(),
+        Default::default(),
+    ));
+    assert!(result.is_err());
+}
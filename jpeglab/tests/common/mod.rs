@@ -0,0 +1,32 @@
+//! 集成测试共用的工具函数。
+
+#![allow(dead_code)]
+
+use std::path::Path;
+
+use image::RgbImage;
+
+pub const GOLDEN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+
+pub fn load_image(path: &Path) -> RgbImage {
+    image::open(path)
+        .unwrap_or_else(|e| panic!("Fail to open {}: {}", path.display(), e))
+        .into_rgb8()
+}
+
+/// 两幅同样大小的 RGB 图像之间的 PSNR（dB）。完全相同时为正无穷。
+pub fn psnr(a: &RgbImage, b: &RgbImage) -> f64 {
+    assert_eq!(a.dimensions(), b.dimensions());
+    let mse = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&x, &y)| (x as f64 - y as f64).powi(2))
+        .sum::<f64>()
+        / a.as_raw().len() as f64;
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+}
@@ -0,0 +1,127 @@
+//! ITU-T T.83 一致性测试。
+//!
+//! 测试数据不随仓库分发。把一致性码流和参考解码结果放到同一个目录，并用环境变量
+//! `JPEGLAB_CONFORMANCE_DIR` 指定该目录，例如：
+//!
+//! ```text
+//! JPEGLAB_CONFORMANCE_DIR=/path/to/t83 cargo test --test conformance -- --nocapture
+//! ```
+//!
+//! 目录中的每个 `<case>.jpg`（或 `.jpeg`、`.jpg` 大写）是一个用例，参考解码结果为同名的
+//! `<case>.ppm`、`<case>.png` 或 `<case>.bmp`（RGB）。未设置环境变量时跳过测试。
+//!
+//! 已知能通过的用例记录在 `tests/conformance_passing.txt` 中，它们失败时测试失败。
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::panic;
+use std::path::Path;
+use std::path::PathBuf;
+
+mod common;
+
+use common::load_image;
+use common::psnr;
+
+/// 参考解码结果为 YCbCr 分量，转换为 RGB 后会放大 IDCT 的舍入误差，因此用 PSNR 判断。
+const MIN_PSNR: f64 = 40.0;
+
+const PASSING_LIST: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/conformance_passing.txt");
+
+const REFERENCE_EXTENSIONS: [&str; 3] = ["ppm", "png", "bmp"];
+
+fn find_reference(stream: &Path) -> Option<PathBuf> {
+    REFERENCE_EXTENSIONS
+        .iter()
+        .map(|ext| stream.with_extension(ext))
+        .find(|path| path.exists())
+}
+
+/// 运行一个用例。通过返回 `Ok`，否则返回原因。
+fn run_case(stream: &Path, reference: &Path) -> Result<f64, String> {
+    let jpeg = fs::read(stream).map_err(|e| e.to_string())?;
+    let decoded = jpeglab::decode_to_image(&jpeg, &Default::default())
+        .map_err(|e| format!("decode: {}", e))?;
+    let expected = load_image(reference);
+    if decoded.dimensions() != expected.dimensions() {
+        return Err(format!(
+            "size {:?}, expected {:?}",
+            decoded.dimensions(),
+            expected.dimensions()
+        ));
+    }
+    let value = psnr(&decoded, &expected);
+    if value < MIN_PSNR {
+        return Err(format!("PSNR {:.2} dB", value));
+    }
+    Ok(value)
+}
+
+fn known_passing() -> BTreeSet<String> {
+    fs::read_to_string(PASSING_LIST)
+        .unwrap()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn conformance() {
+    let Some(dir) = env::var_os("JPEGLAB_CONFORMANCE_DIR") else {
+        println!("[INFO] 未设置 JPEGLAB_CONFORMANCE_DIR，跳过一致性测试");
+        return;
+    };
+
+    let mut streams: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| {
+                    ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg")
+                })
+        })
+        .collect();
+    streams.sort();
+
+    let known_passing = known_passing();
+    let mut regressions = vec![];
+    let mut passed = 0;
+    for stream in &streams {
+        let case = stream.file_stem().unwrap().to_string_lossy().to_string();
+        let Some(reference) = find_reference(stream) else {
+            println!("[WARNING] {}: 没有参考解码结果，跳过", case);
+            continue;
+        };
+
+        // 解码器遇到不支持的码流时可能 panic，也当作失败记录下来。
+        let result = panic::catch_unwind(|| run_case(stream, &reference))
+            .unwrap_or_else(|_| Err("panicked".to_string()));
+        match result {
+            Ok(value) => {
+                passed += 1;
+                if known_passing.contains(&case) {
+                    println!("[PASS] {} ({:.2} dB)", case, value);
+                } else {
+                    println!(
+                        "[PASS] {} ({:.2} dB)，新通过，可加入 conformance_passing.txt",
+                        case, value
+                    );
+                }
+            }
+            Err(reason) => {
+                println!("[FAIL] {}: {}", case, reason);
+                if known_passing.contains(&case) {
+                    regressions.push(case);
+                }
+            }
+        }
+    }
+    println!("[INFO] 通过 {}/{}", passed, streams.len());
+
+    assert!(regressions.is_empty(), "Regressed cases: {:?}", regressions);
+}
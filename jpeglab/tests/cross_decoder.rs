@@ -0,0 +1,128 @@
+//! 交叉验证：用 jpeglab 编码、用 image 库解码，以及用 image 库编码、用 jpeglab 解码。
+//! 字节填充、表 ID 等错误往往只有换一个实现才能发现。
+
+use std::io::Cursor;
+
+use clap::ValueEnum;
+use image::codecs::jpeg::JpegEncoder;
+use image::ImageFormat;
+use image::RgbImage;
+use jpeglab::encode_step1::Subsampling;
+use jpeglab::EncodeOptions;
+
+mod common;
+
+use common::load_image;
+use common::psnr;
+use common::GOLDEN_DIR;
+
+/// 默认量化表大约相当于质量 50，再加上两个解码器上采样方式的不同，阈值不宜过高。
+const MIN_PSNR: f64 = 25.0;
+
+/// 同一个文件由两个解码器解码，差异只来自 IDCT 精度和色度上采样方式。
+const MIN_AGREEMENT_PSNR: f64 = 40.0;
+
+/// 平滑的渐变图像，尺寸故意不是 MCU 的整数倍。
+fn gradient(width: u32, height: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([
+            (x * 255 / width) as u8,
+            (y * 255 / height) as u8,
+            ((x + y) * 255 / (width + height)) as u8,
+        ])
+    })
+}
+
+fn inputs() -> Vec<(String, RgbImage)> {
+    let mut ret = vec![
+        ("gradient 64x48".to_string(), gradient(64, 48)),
+        ("gradient 77x35".to_string(), gradient(77, 35)),
+    ];
+    // 只用平滑的图像。色度剧烈变化的图像（如 `checker_24x16`）在两个解码器上采样方式不同时差异很大。
+    for name in ["gradient_16x8", "solid_8x8"] {
+        let path = format!("{}/{}.bmp", GOLDEN_DIR, name);
+        ret.push((name.to_string(), load_image(path.as_ref())));
+    }
+    ret
+}
+
+#[test]
+fn encode_with_jpeglab_decode_with_image() {
+    for (name, original) in inputs() {
+        let jpeg = jpeglab::encode_to_vec(&original, &Default::default()).unwrap();
+        let decoded = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg)
+            .unwrap_or_else(|e| panic!("image rejects the encoding of {}: {}", name, e))
+            .into_rgb8();
+
+        assert_eq!(decoded.dimensions(), original.dimensions(), "{}", name);
+        let value = psnr(&decoded, &original);
+        assert!(value >= MIN_PSNR, "{}: PSNR {:.2} dB", name, value);
+    }
+}
+
+/// 每种子采样方式的输出都能被解码，帧头中的采样因子与选择的一致。
+/// image 库的解码器不支持 4:1:1 的上采样，此时只用 jpeglab 解码。
+#[test]
+fn subsampling_layouts() {
+    let original = gradient(77, 35);
+    for &subsampling in Subsampling::value_variants() {
+        let options = EncodeOptions {
+            subsampling,
+            ..Default::default()
+        };
+        let jpeg = jpeglab::encode_to_vec(&original, &options).unwrap();
+        let info = jpeglab::read_info(&jpeg).unwrap();
+        assert_eq!(info.sampling_factors, subsampling.sampling_factors());
+
+        let mut decoded = vec![jpeglab::decode_to_image(&jpeg, &Default::default()).unwrap()];
+        if subsampling != Subsampling::Yuv411 {
+            let theirs = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg)
+                .unwrap_or_else(|e| panic!("image rejects {:?}: {}", subsampling, e));
+            decoded.push(theirs.into_rgb8());
+        }
+        for decoded in &decoded {
+            let value = psnr(decoded, &original);
+            assert!(value >= MIN_PSNR, "{:?}: PSNR {:.2} dB", subsampling, value);
+        }
+    }
+}
+
+#[test]
+fn encode_with_image_decode_with_jpeglab() {
+    for (name, original) in inputs() {
+        let mut jpeg = vec![];
+        JpegEncoder::new_with_quality(Cursor::new(&mut jpeg), 90)
+            .encode_image(&original)
+            .unwrap();
+        let decoded = jpeglab::decode_to_image(&jpeg, &Default::default())
+            .unwrap_or_else(|e| panic!("jpeglab rejects the encoding of {}: {}", name, e));
+
+        assert_eq!(decoded.dimensions(), original.dimensions(), "{}", name);
+        let value = psnr(&decoded, &original);
+        assert!(value >= MIN_PSNR, "{}: PSNR {:.2} dB", name, value);
+    }
+}
+
+/// 两个解码器解码同一个文件，结果应当非常接近。
+#[test]
+fn decoders_agree() {
+    for (name, original) in inputs() {
+        let mut jpeg = vec![];
+        JpegEncoder::new_with_quality(Cursor::new(&mut jpeg), 90)
+            .encode_image(&original)
+            .unwrap();
+        let ours = jpeglab::decode_to_image(&jpeg, &Default::default()).unwrap();
+        let theirs = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg)
+            .unwrap()
+            .into_rgb8();
+
+        assert_eq!(ours.dimensions(), theirs.dimensions(), "{}", name);
+        let value = psnr(&ours, &theirs);
+        assert!(
+            value >= MIN_AGREEMENT_PSNR,
+            "{}: PSNR {:.2} dB",
+            name,
+            value
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! 差分测试：与 jpeg-decoder 逐步比较，需要 `differential` feature：
+//! `cargo test --features differential --test differential`。
+
+#![cfg(feature = "differential")]
+
+use image::RgbImage;
+use jpeglab::differential::compare_encoders;
+use jpeglab::differential::Stage;
+
+mod common;
+
+use common::load_image;
+use common::GOLDEN_DIR;
+
+fn inputs() -> Vec<(String, RgbImage)> {
+    let mut ret = vec![(
+        "gradient 77x35".to_string(),
+        RgbImage::from_fn(77, 35, |x, y| {
+            image::Rgb([(x * 3) as u8, (y * 7) as u8, ((x + y) * 2) as u8])
+        }),
+    )];
+    for name in ["gradient_16x8", "solid_8x8", "checker_24x16"] {
+        let path = format!("{}/{}.bmp", GOLDEN_DIR, name);
+        ret.push((name.to_string(), load_image(path.as_ref())));
+    }
+    ret
+}
+
+#[test]
+fn no_divergence() {
+    for (name, image) in inputs() {
+        for (encoder, report) in
+            compare_encoders(&image, &Default::default(), &Default::default()).unwrap()
+        {
+            println!("{} encoded by {}:\n{}", name, encoder, report);
+            let stage = report.first_divergence();
+            // 色度剧烈变化时，两个解码器上采样方式的不同会超过阈值，但 Y 平面必须一致。
+            assert!(
+                stage.is_none_or(|stage| stage > Stage::Luma),
+                "{} encoded by {}: {}",
+                name,
+                encoder,
+                report
+            );
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! 金标准测试：`tests/golden` 下的每个 `<name>.bmp` 都有已提交的期望输出。
+//! - `<name>.jpg`：编码结果，要求逐字节一致。
+//! - `<name>.decoded.bmp`：解码 `<name>.jpg` 的结果，要求 PSNR 不低于 `MIN_DECODE_PSNR`。
+//!
+//! 有意改变输出时，用 `JPEGLAB_BLESS=1 cargo test --test golden` 重新生成期望输出，并检查差异后提交。
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use image::ImageFormat;
+
+mod common;
+
+use common::load_image;
+use common::psnr;
+use common::GOLDEN_DIR;
+
+/// 解码器允许有少量舍入差异（例如更换 IDCT 的实现），但不能偏离太多。
+const MIN_DECODE_PSNR: f64 = 45.0;
+
+fn is_bless() -> bool {
+    env::var_os("JPEGLAB_BLESS").is_some()
+}
+
+fn cases() -> Vec<PathBuf> {
+    let mut ret: Vec<PathBuf> = fs::read_dir(GOLDEN_DIR)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            name.ends_with(".bmp") && !name.ends_with(".decoded.bmp")
+        })
+        .collect();
+    ret.sort();
+    assert!(!ret.is_empty(), "No golden inputs found");
+    ret
+}
+
+#[test]
+fn golden_encode() {
+    for input in cases() {
+        let expected_path = input.with_extension("jpg");
+        let output = jpeglab::encode_to_vec(&load_image(&input), &Default::default()).unwrap();
+
+        if is_bless() {
+            fs::write(&expected_path, &output).unwrap();
+            continue;
+        }
+
+        let expected = fs::read(&expected_path)
+            .unwrap_or_else(|e| panic!("Fail to read {}: {}", expected_path.display(), e));
+        assert!(
+            output == expected,
+            "Encoder output of {} differs from {}",
+            input.display(),
+            expected_path.display()
+        );
+    }
+}
+
+#[test]
+fn golden_decode() {
+    for input in cases() {
+        let jpeg_path = input.with_extension("jpg");
+        let expected_path = input.with_extension("decoded.bmp");
+        // 重新生成时 `golden_encode` 可能正在并行地写 `<name>.jpg`，因此直接重新编码。
+        let jpeg = if is_bless() {
+            jpeglab::encode_to_vec(&load_image(&input), &Default::default()).unwrap()
+        } else {
+            fs::read(&jpeg_path)
+                .unwrap_or_else(|e| panic!("Fail to read {}: {}", jpeg_path.display(), e))
+        };
+        let output = jpeglab::decode_to_image(&jpeg, &Default::default()).unwrap();
+
+        if is_bless() {
+            output
+                .save_with_format(&expected_path, ImageFormat::Bmp)
+                .unwrap();
+            continue;
+        }
+
+        let expected = load_image(&expected_path);
+        let value = psnr(&output, &expected);
+        assert!(
+            value >= MIN_DECODE_PSNR,
+            "Decoded {} has PSNR {:.2} dB against {}, expected at least {} dB",
+            jpeg_path.display(),
+            value,
+            expected_path.display(),
+            MIN_DECODE_PSNR
+        );
+    }
+}
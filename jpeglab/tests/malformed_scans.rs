@@ -0,0 +1,33 @@
+//! 畸形扫描数据的回归测试：`tests/malformed` 下的每个文件都必须被解码器拒绝，而不是 panic。
+//! 这些文件由 `tests/golden/gradient_16x8.jpg` 的头部加上手工构造的扫描数据组成：
+//! - `truncated_scan.jpg`：扫描数据被截断。
+//! - `empty_scan.jpg`：没有扫描数据。
+//! - `overlong_category.jpg`：类别要求的位数超出剩余的扫描数据。
+//! - `missing_eob.jpg`：多个 ZRL 使 AC 系数超过 63 个，且没有 EOB。
+//! - `dc_overflow.jpg`：DC 差分累加超出 16 位有符号整数。
+//!
+//! 头部畸形的文件直接修改 `gradient_16x8.jpg`：
+//! - `undefined_quantization_table.jpg`：SOF 中第一个分量的量化表 ID 为没有定义的 3。
+//! - `no_scan.jpg`：去掉 SOS 及之后的扫描数据，SOF 之后直接是 EOI。
+//! - `no_frame.jpg`：只有 SOI 和 EOI。
+//! - `oversized_frame.jpg`：SOF 中的大小为 65535x65535，远超扫描数据能容纳的 DU 个数。
+
+use std::fs;
+
+const MALFORMED_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/malformed");
+
+#[test]
+fn malformed_scans_are_rejected() {
+    let mut paths: Vec<_> = fs::read_dir(MALFORMED_DIR)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        let buf = fs::read(&path).unwrap();
+        let result = jpeglab::decode_to_image(&buf, &Default::default());
+        assert!(result.is_err(), "{} should be rejected", path.display());
+    }
+}
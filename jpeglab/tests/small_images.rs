@@ -0,0 +1,55 @@
+//! 小于一个 MCU（16x8）的图像：填充后只有一个 MCU，解码后要裁剪回原始尺寸。
+
+use image::ImageFormat;
+use image::RgbImage;
+
+mod common;
+
+use common::psnr;
+
+/// 平滑图像在默认量化表下的最低 PSNR。
+const MIN_PSNR: f64 = 30.0;
+
+fn smooth(width: u32, height: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([
+            (100 + 4 * x) as u8,
+            (120 + 6 * y) as u8,
+            (90 + 2 * (x + y)) as u8,
+        ])
+    })
+}
+
+#[test]
+fn round_trip_smaller_than_one_mcu() {
+    for height in 1..=8 {
+        for width in 1..=16 {
+            let original = smooth(width, height);
+            let jpeg = jpeglab::encode_to_vec(&original, &Default::default()).unwrap();
+
+            let ours = jpeglab::decode_to_image(&jpeg, &Default::default()).unwrap();
+            assert_eq!(ours.dimensions(), (width, height));
+            let value = psnr(&ours, &original);
+            assert!(
+                value >= MIN_PSNR,
+                "{}x{}: PSNR {:.2} dB",
+                width,
+                height,
+                value
+            );
+
+            let theirs = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg)
+                .unwrap()
+                .into_rgb8();
+            assert_eq!(theirs.dimensions(), (width, height));
+            let value = psnr(&theirs, &original);
+            assert!(
+                value >= MIN_PSNR,
+                "{}x{}: PSNR {:.2} dB",
+                width,
+                height,
+                value
+            );
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! 编码结果与线程数无关：各步按 DU 的原始顺序收集并行计算的结果，熵编码仍按 MCU 的顺序进行。
+
+use image::RgbImage;
+use rayon::ThreadPoolBuilder;
+
+fn noisy(width: u32, height: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        let hash = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)) % 251;
+        image::Rgb([hash as u8, (x * 3 + hash) as u8, (y * 5 + hash) as u8])
+    })
+}
+
+#[test]
+fn identical_output_for_any_thread_count() {
+    let image = noisy(203, 117);
+    for striped in [false, true] {
+        let options = jpeglab::EncodeOptions {
+            striped,
+            ..Default::default()
+        };
+        let encode_with = |threads: usize| {
+            ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| jpeglab::encode_to_vec(&image, &options).unwrap())
+        };
+
+        let expected = encode_with(1);
+        for threads in [2, 3, 8] {
+            assert!(
+                encode_with(threads) == expected,
+                "{} threads, striped: {}",
+                threads,
+                striped
+            );
+        }
+    }
+}